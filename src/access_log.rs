@@ -0,0 +1,43 @@
+use crate::config::settings::AccessLogFormat;
+use std::time::Duration;
+
+// A handled request, as written to the access log
+pub struct AccessEntry<'a> {
+    pub peer: Option<&'a str>,
+    pub method: &'a str,
+    pub path: &'a str,
+    pub status: u16,
+    pub latency: Duration,
+    // Owner of the request's token, when it carried a valid one
+    pub user: Option<&'a str>,
+    pub request_id: Option<&'a str>,
+}
+
+impl AccessEntry<'_> {
+    pub fn format(&self, format: AccessLogFormat) -> String {
+        let latency_ms = self.latency.as_secs_f64() * 1000.0;
+
+        match format {
+            AccessLogFormat::Plain => format!(
+                "{} \"{} {}\" {} {:.3}ms user={} request_id={}",
+                self.peer.unwrap_or("-"),
+                self.method,
+                self.path,
+                self.status,
+                latency_ms,
+                self.user.unwrap_or("-"),
+                self.request_id.unwrap_or("-"),
+            ),
+            AccessLogFormat::Json => serde_json::json!({
+                "peer": self.peer,
+                "method": self.method,
+                "path": self.path,
+                "status": self.status,
+                "latency_ms": latency_ms,
+                "user": self.user,
+                "request_id": self.request_id,
+            })
+            .to_string(),
+        }
+    }
+}
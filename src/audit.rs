@@ -0,0 +1,93 @@
+use crate::config::settings::AuditTOML;
+use chrono::{DateTime, Utc};
+use log::{debug, error};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::sync::Mutex;
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::thread;
+
+// A media file served to a user, for auditing who watched what
+#[derive(Serialize, Clone)]
+pub struct AuditEntry {
+    pub at: DateTime<Utc>,
+    pub username: String,
+    pub stream_id: String,
+    pub file_path: String,
+}
+
+// Latest entries in memory, and every one appended to the configured file as a JSON line
+pub struct AuditLog {
+    entries: Mutex<VecDeque<AuditEntry>>,
+    max_entries: usize,
+    // Lines for the thread appending them to the file, so handlers never wait on the disk
+    file: Option<SyncSender<String>>,
+}
+
+// Lines waiting for the file before new entries are left out of it
+const FILE_QUEUE: usize = 1024;
+
+fn spawn_file_writer(mut file: File) -> SyncSender<String> {
+    let (sender, receiver) = mpsc::sync_channel::<String>(FILE_QUEUE);
+    thread::spawn(move || {
+        for line in receiver {
+            if let Err(e) = writeln!(file, "{}", line) {
+                error!("Error writing audit entry: {}", e);
+            }
+        }
+    });
+    sender
+}
+
+impl AuditLog {
+    pub fn open(config: &AuditTOML) -> io::Result<Self> {
+        let file = match &config.file {
+            Some(path) => Some(spawn_file_writer(
+                OpenOptions::new().create(true).append(true).open(path)?,
+            )),
+            None => None,
+        };
+
+        Ok(Self {
+            entries: Mutex::new(VecDeque::new()),
+            max_entries: config.max_entries,
+            file,
+        })
+    }
+
+    pub fn record(&self, username: &str, stream_id: &str, file_path: &str) {
+        let entry = AuditEntry {
+            at: Utc::now(),
+            username: username.to_string(),
+            stream_id: stream_id.to_string(),
+            file_path: file_path.to_string(),
+        };
+        debug!(target: "audit", "{} streamed {}/{}", username, stream_id, file_path);
+
+        if let Some(file) = &self.file {
+            let line = serde_json::to_string(&entry).unwrap_or_default();
+            match file.try_send(line) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => {
+                    error!("Audit file is falling behind, leaving an entry out of it")
+                }
+                Err(TrySendError::Disconnected(_)) => error!("Audit file writer stopped"),
+            }
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= self.max_entries {
+            entries.pop_front();
+        }
+        if self.max_entries > 0 {
+            entries.push_back(entry);
+        }
+    }
+
+    // Oldest first
+    pub fn recent(&self) -> Vec<AuditEntry> {
+        self.entries.lock().unwrap().iter().cloned().collect()
+    }
+}
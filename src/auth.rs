@@ -1,51 +1,334 @@
-use actix_web::{Error, FromRequest, HttpRequest, dev::Payload};
+use crate::UserManager;
+use crate::config::settings::AuthTOML;
+use crate::error::AppError;
+use actix_web::{Error, FromRequest, HttpMessage, HttpRequest, dev::Payload, http::header, web};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
 use chrono::{Duration, Utc};
 use futures_util::future::{Ready, err, ok};
-use jsonwebtoken::{DecodingKey, EncodingKey, Header, Validation, decode, encode};
-use serde::{Deserialize, Serialize};
+use jsonwebtoken::errors::ErrorKind;
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation, decode, encode};
 use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
 
-static SECRET: Lazy<String> = Lazy::new(|| {
+// Signing secrets, current first. SECRETS takes a comma-separated list so tokens signed with
+// previous secrets keep verifying while being rotated out, SECRET is the only one when it's
+// unset or lists none. check_secrets refuses to start without any.
+static SECRETS: Lazy<Result<Vec<String>, String>> = Lazy::new(|| {
     dotenvy::dotenv().ok();
-    std::env::var("SECRET").expect("SECRET must be set")
+    signing_secrets(std::env::var("SECRETS").ok(), std::env::var("SECRET").ok())
 });
 
+fn signing_secrets(secrets: Option<String>, secret: Option<String>) -> Result<Vec<String>, String> {
+    let listed: Vec<String> = secrets
+        .iter()
+        .flat_map(|secrets| secrets.split(','))
+        .map(str::trim)
+        .filter(|secret| !secret.is_empty())
+        .map(str::to_string)
+        .collect();
+    if !listed.is_empty() {
+        return Ok(listed);
+    }
+
+    match secret.filter(|secret| !secret.trim().is_empty()) {
+        Some(secret) => Ok(vec![secret]),
+        None => Err("SECRET or SECRETS must be set".to_string()),
+    }
+}
+
+// Compares secrets without stopping at the first difference, so the time taken doesn't
+// tell how much of a guess was right
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+pub fn check_secrets() -> Result<(), String> {
+    SECRETS.as_ref().map(|_| ()).map_err(Clone::clone)
+}
+
+fn secrets() -> &'static [String] {
+    SECRETS.as_deref().unwrap_or_default()
+}
+
+fn encoding_key() -> Result<EncodingKey, jsonwebtoken::errors::Error> {
+    match secrets().first() {
+        Some(secret) => Ok(EncodingKey::from_secret(secret.as_bytes())),
+        None => Err(ErrorKind::InvalidKeyFormat.into()),
+    }
+}
+
+// Only HS256 tokens with an expiry are accepted, whatever their header claims
+fn validation() -> Validation {
+    let mut validation = Validation::new(Algorithm::HS256);
+    validation.set_required_spec_claims(&["exp"]);
+    // Tokens are checked on the clock that issued them, no leeway past their expiry
+    validation.leeway = 0;
+    validation.set_issuer(&[ISSUER]);
+    validation
+}
+
+pub const ADMIN_ROLE: &str = "admin";
+
+// iss claim of the tokens we sign
+const ISSUER: &str = "dash-to-hls";
+
+// scope claim of the short-lived tokens passed as ?token= to fetch playlists and segments
+const MEDIA_SCOPE: &str = "media";
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Claims {
     sub: String,
     exp: usize,
+    // Tokens issued before roles existed belong to plain users
+    #[serde(default = "default_role")]
+    role: String,
+    // Issue time of the token obtained by logging in, kept across refreshes
+    #[serde(default)]
+    session_start: i64,
+    // Checked when present, tokens signed before it was added don't carry it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    iss: Option<String>,
+    // Only set on media tokens, which authorize nothing but stream files
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    scope: Option<String>,
+    // Expiry of the login token a media token was issued from, renewing it can't go past it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    session_exp: Option<usize>,
+}
+
+impl Claims {
+    pub fn username(&self) -> &str {
+        &self.sub
+    }
+
+    pub fn session_start(&self) -> i64 {
+        self.session_start
+    }
+
+    // Latest expiry of a media token issued from these claims. A media token only renews up
+    // to the login token it came from, one from before session_exp can't be renewed at all.
+    pub fn renewable_until(&self) -> usize {
+        match &self.scope {
+            Some(_) => self.session_exp.unwrap_or(self.exp),
+            None => self.exp,
+        }
+    }
 }
 
-pub fn create_token(username: &str) -> Result<String, jsonwebtoken::errors::Error> {
-    let exp = Utc::now()
-        .checked_add_signed(Duration::hours(24))
-        .unwrap()
-        .timestamp();
+pub fn default_role() -> String {
+    "user".to_string()
+}
 
+// Expiry of a token issued now, refused when the lifetime can't be represented
+fn expiry(ttl: Duration) -> anyhow::Result<usize> {
+    Utc::now()
+        .checked_add_signed(ttl)
+        .and_then(|exp| usize::try_from(exp.timestamp()).ok())
+        .ok_or_else(|| anyhow::anyhow!("Token lifetime of {} is out of range", ttl))
+}
+
+pub fn create_token(username: &str, role: &str, ttl: Duration) -> anyhow::Result<String> {
     let claims = Claims {
         sub: username.to_owned(),
-        exp: exp as usize,
+        exp: expiry(ttl)?,
+        role: role.to_owned(),
+        session_start: Utc::now().timestamp(),
+        iss: Some(ISSUER.to_string()),
+        scope: None,
+        session_exp: None,
     };
 
-    encode(
-        &Header::default(),
+    Ok(encode(
+        &Header::new(Algorithm::HS256),
         &claims,
-        &EncodingKey::from_secret(SECRET.as_bytes()),
-    )
+        &encoding_key()?,
+    )?)
 }
 
-pub fn verify_token(token: &str) -> Result<Claims, jsonwebtoken::errors::Error> {
-    decode::<Claims>(
-        token,
-        &DecodingKey::from_secret(SECRET.as_bytes()),
-        &Validation::default(),
-    )
-    .map(|data| data.claims)
+// New token for the same session, with a fresh expiry
+pub fn refresh_token(claims: &Claims, ttl: Duration) -> anyhow::Result<String> {
+    let claims = Claims {
+        sub: claims.sub.clone(),
+        exp: expiry(ttl)?,
+        role: claims.role.clone(),
+        session_start: claims.session_start,
+        iss: Some(ISSUER.to_string()),
+        scope: None,
+        session_exp: None,
+    };
+
+    Ok(encode(
+        &Header::new(Algorithm::HS256),
+        &claims,
+        &encoding_key()?,
+    )?)
+}
+
+// Short-lived token of the same session, only good for fetching playlists and segments,
+// for players that can't send the auth cookie or an Authorization header. It expires with
+// the login token at the latest.
+pub fn create_media_token(claims: &Claims, ttl: Duration) -> anyhow::Result<String> {
+    let session_exp = claims.renewable_until();
+
+    let claims = Claims {
+        sub: claims.sub.clone(),
+        exp: expiry(ttl)?.min(session_exp),
+        role: claims.role.clone(),
+        session_start: claims.session_start,
+        iss: Some(ISSUER.to_string()),
+        scope: Some(MEDIA_SCOPE.to_string()),
+        session_exp: Some(session_exp),
+    };
+
+    Ok(encode(
+        &Header::new(Algorithm::HS256),
+        &claims,
+        &encoding_key()?,
+    )?)
+}
+
+// Tries every secret, a signature mismatch only means the token was signed with another one
+fn verify_token(token: &str, secrets: &[String]) -> Result<Claims, jsonwebtoken::errors::Error> {
+    let validation = validation();
+    let mut result = Err(ErrorKind::InvalidSignature.into());
+    for secret in secrets {
+        result = decode::<Claims>(
+            token,
+            &DecodingKey::from_secret(secret.as_bytes()),
+            &validation,
+        )
+        .map(|data| data.claims);
+        match &result {
+            Err(e) if *e.kind() == ErrorKind::InvalidSignature => continue,
+            _ => break,
+        }
+    }
+    result
+}
+
+// Claims of the request's token, taken from an Authorization: Bearer header or else the
+// auth cookie. A Bearer token that doesn't verify is rejected rather than falling back.
+pub fn request_claims(req: &HttpRequest) -> Option<Claims> {
+    authenticate(req).ok()
+}
+
+// Like request_claims, telling a request without a token from one whose token was
+// rejected, and an expired token from an invalid one
+pub fn authenticate(req: &HttpRequest) -> Result<Claims, AppError> {
+    authenticate_with(req, secrets())
+}
+
+fn authenticate_with(req: &HttpRequest, secrets: &[String]) -> Result<Claims, AppError> {
+    let bearer = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| token.trim().to_string());
+    let Some(token) = bearer.or_else(|| req.cookie("auth").map(|c| c.value().to_string())) else {
+        return Err(AppError::Unauthorized("Missing token".to_string()));
+    };
+
+    // Media tokens end up in URLs, they mustn't open the rest of the API
+    match verify_claims(&token, secrets)? {
+        claims if claims.scope.is_some() => Err(AppError::InvalidToken(
+            "Media token not accepted".to_string(),
+        )),
+        claims => Ok(remember_user(req, claims)),
+    }
+}
+
+// Name of the user a request was authenticated as, kept in its extensions for the access
+// log so it doesn't verify the token again
+#[derive(Clone)]
+struct RequestUser(String);
+
+fn remember_user(req: &HttpRequest, claims: Claims) -> Claims {
+    req.extensions_mut()
+        .insert(RequestUser(claims.username().to_string()));
+    claims
+}
+
+// User the request was authenticated as by its handler, None if it wasn't
+pub fn request_user(req: &HttpRequest) -> Option<String> {
+    req.extensions()
+        .get::<RequestUser>()
+        .map(|user| user.0.clone())
+}
+
+// Claims of a request for a stream file: a media token given as ?token= is accepted on top
+// of the usual Bearer header or cookie. Tells whether the query token was the one used.
+pub fn authenticate_media(req: &HttpRequest) -> Result<(Claims, bool), AppError> {
+    let query_token = url::form_urlencoded::parse(req.query_string().as_bytes())
+        .find(|(name, _)| name == "token")
+        .map(|(_, token)| token.into_owned());
+    let Some(token) = query_token else {
+        return authenticate_user(req).map(|claims| (claims, false));
+    };
+
+    match verify_claims(&token, secrets())? {
+        claims if claims.scope.as_deref() == Some(MEDIA_SCOPE) => {
+            Ok((remember_user(req, claims), true))
+        }
+        _ => Err(AppError::InvalidToken("Not a media token".to_string())),
+    }
+}
+
+// Like authenticate, also taking HTTP Basic credentials when [auth] basic_auth allows them.
+// They're checked on every request, no token or cookie is issued for them.
+fn authenticate_user(req: &HttpRequest) -> Result<Claims, AppError> {
+    let basic = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Basic "));
+    let enabled = req
+        .app_data::<web::Data<AuthTOML>>()
+        .is_some_and(|auth_config| auth_config.basic_auth);
+    let (Some(credentials), true) = (basic, enabled) else {
+        return authenticate(req);
+    };
+
+    let invalid = || AppError::Unauthorized("Invalid credentials".to_string());
+    let decoded = BASE64.decode(credentials.trim()).map_err(|_| invalid())?;
+    let decoded = String::from_utf8(decoded).map_err(|_| invalid())?;
+    let (username, password) = decoded.split_once(':').ok_or_else(invalid)?;
+
+    let role = req
+        .app_data::<web::Data<Arc<Mutex<UserManager>>>>()
+        .and_then(|user_manager| {
+            user_manager
+                .lock()
+                .unwrap()
+                .verify_password(username, password)
+        })
+        .ok_or_else(invalid)?;
+    let now = Utc::now().timestamp();
+    let claims = Claims {
+        sub: username.to_string(),
+        exp: now as usize,
+        role,
+        session_start: now,
+        iss: Some(ISSUER.to_string()),
+        scope: None,
+        session_exp: None,
+    };
+    Ok(remember_user(req, claims))
+}
+
+fn verify_claims(token: &str, secrets: &[String]) -> Result<Claims, AppError> {
+    verify_token(token, secrets).map_err(|e| match e.kind() {
+        ErrorKind::ExpiredSignature => AppError::TokenExpired("Token expired".to_string()),
+        _ => AppError::InvalidToken("Invalid token".to_string()),
+    })
 }
 
 #[allow(unused)]
 pub struct AuthenticatedUser {
     pub username: String,
+    pub claims: Claims,
 }
 
 impl FromRequest for AuthenticatedUser {
@@ -53,16 +336,320 @@ impl FromRequest for AuthenticatedUser {
     type Future = Ready<Result<Self, Self::Error>>;
 
     fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
-        if let Some(cookie) = req.cookie("auth") {
-            if let Ok(claims) = verify_token(cookie.value()) {
-                return ok(AuthenticatedUser {
-                    username: claims.sub,
-                });
+        match authenticate_user(req) {
+            Ok(claims) => ok(AuthenticatedUser {
+                username: claims.sub.clone(),
+                claims,
+            }),
+            Err(e) => err(e.into()),
+        }
+    }
+}
+
+// Viewer of a stream file, authenticated like AuthenticatedUser or by a ?token= media token
+pub struct MediaUser {
+    pub username: String,
+    pub claims: Claims,
+    // Set when authenticated by the query token, which the playlists then pass on
+    pub query_token: bool,
+}
+
+impl FromRequest for MediaUser {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        match authenticate_media(req) {
+            Ok((claims, query_token)) => ok(MediaUser {
+                username: claims.sub.clone(),
+                claims,
+                query_token,
+            }),
+            Err(e) => err(e.into()),
+        }
+    }
+}
+
+// Like AuthenticatedUser, but only for tokens carrying the admin role
+#[allow(unused)]
+pub struct AdminUser {
+    pub username: String,
+}
+
+impl FromRequest for AdminUser {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        match authenticate_user(req) {
+            Ok(claims) if claims.role != ADMIN_ROLE => {
+                err(AppError::Forbidden("Admin role required".to_string()).into())
             }
+            Ok(claims) => ok(AdminUser {
+                username: claims.sub,
+            }),
+            Err(e) => err(e.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::ResponseError;
+    use actix_web::http::StatusCode;
+
+    fn secrets(secrets: &[&str]) -> Vec<String> {
+        secrets.iter().map(|secret| secret.to_string()).collect()
+    }
+
+    fn sign(claims: serde_json::Value, algorithm: Algorithm, secret: &str) -> String {
+        let key = EncodingKey::from_secret(secret.as_bytes());
+        encode(&Header::new(algorithm), &claims, &key).unwrap()
+    }
+
+    fn claims(exp_in: i64) -> serde_json::Value {
+        serde_json::json!({
+            "sub": "alice",
+            "exp": Utc::now().timestamp() + exp_in,
+            "iss": ISSUER,
+        })
+    }
+
+    #[test]
+    fn verify_token_accepts_hs256_with_an_expiry() {
+        let token = sign(claims(3600), Algorithm::HS256, "current");
+        let verified = verify_token(&token, &secrets(&["current"])).unwrap();
+        assert_eq!(verified.username(), "alice");
+        assert_eq!(verified.role, "user");
+
+        // Tokens signed before iss was added don't carry it
+        let mut without_iss = claims(3600);
+        without_iss.as_object_mut().unwrap().remove("iss");
+        let token = sign(without_iss, Algorithm::HS256, "current");
+        assert!(verify_token(&token, &secrets(&["current"])).is_ok());
+    }
+
+    #[test]
+    fn verify_token_rejects_other_algorithms_and_missing_expiry() {
+        let token = sign(claims(3600), Algorithm::HS384, "current");
+        assert!(verify_token(&token, &secrets(&["current"])).is_err());
+
+        let mut without_exp = claims(3600);
+        without_exp.as_object_mut().unwrap().remove("exp");
+        let token = sign(without_exp, Algorithm::HS256, "current");
+        assert!(verify_token(&token, &secrets(&["current"])).is_err());
+
+        let mut other_issuer = claims(3600);
+        other_issuer["iss"] = "someone-else".into();
+        let token = sign(other_issuer, Algorithm::HS256, "current");
+        assert!(verify_token(&token, &secrets(&["current"])).is_err());
+    }
+
+    #[test]
+    fn verify_token_accepts_rotated_out_secrets_while_listed() {
+        let token = sign(claims(3600), Algorithm::HS256, "previous");
+        assert!(verify_token(&token, &secrets(&["current", "previous"])).is_ok());
+        let rejected = verify_token(&token, &secrets(&["current"])).unwrap_err();
+        assert_eq!(*rejected.kind(), ErrorKind::InvalidSignature);
+
+        // An expired token isn't tried against the other secrets
+        let token = sign(claims(-3600), Algorithm::HS256, "current");
+        let rejected = verify_token(&token, &secrets(&["current", "previous"])).unwrap_err();
+        assert_eq!(*rejected.kind(), ErrorKind::ExpiredSignature);
+    }
+
+    fn with_cookie(token: &str) -> HttpRequest {
+        actix_web::test::TestRequest::default()
+            .cookie(actix_web::cookie::Cookie::new("auth", token))
+            .to_http_request()
+    }
+
+    fn rejection(req: &HttpRequest) -> (StatusCode, &'static str, String) {
+        let error = authenticate_with(req, &secrets(&["current"])).unwrap_err();
+        let response = error.error_response();
+        let challenge = response
+            .headers()
+            .get(header::WWW_AUTHENTICATE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+        (response.status(), error.code(), challenge)
+    }
+
+    #[test]
+    fn authenticate_tells_missing_expired_and_malformed_tokens_apart() {
+        let req = actix_web::test::TestRequest::default().to_http_request();
+        assert_eq!(
+            rejection(&req),
+            (
+                StatusCode::UNAUTHORIZED,
+                "unauthorized",
+                "Bearer realm=\"dash-to-hls\"".to_string()
+            )
+        );
+
+        let invalid_token = "Bearer realm=\"dash-to-hls\", error=\"invalid_token\"".to_string();
+        let expired = sign(claims(-3600), Algorithm::HS256, "current");
+        assert_eq!(
+            rejection(&with_cookie(&expired)),
+            (
+                StatusCode::UNAUTHORIZED,
+                "token_expired",
+                invalid_token.clone()
+            )
+        );
+        assert_eq!(
+            rejection(&with_cookie("not.a.token")),
+            (StatusCode::UNAUTHORIZED, "invalid_token", invalid_token)
+        );
+
+        let valid = sign(claims(3600), Algorithm::HS256, "current");
+        let claims = authenticate_with(&with_cookie(&valid), &secrets(&["current"])).unwrap();
+        assert_eq!(claims.username(), "alice");
+    }
+
+    fn with_authorization(value: &str) -> actix_web::test::TestRequest {
+        actix_web::test::TestRequest::default().insert_header((header::AUTHORIZATION, value))
+    }
+
+    #[test]
+    fn authenticate_takes_a_bearer_token_without_falling_back_to_the_cookie() {
+        let valid = sign(claims(3600), Algorithm::HS256, "current");
+        let req = with_authorization(&format!("Bearer {}", valid)).to_http_request();
+        let claims = authenticate_with(&req, &secrets(&["current"])).unwrap();
+        assert_eq!(claims.username(), "alice");
+
+        // A cookie that would verify doesn't rescue a bad Bearer token
+        let req = with_authorization("Bearer not.a.token")
+            .cookie(actix_web::cookie::Cookie::new("auth", valid.clone()))
+            .to_http_request();
+        assert_eq!(rejection(&req).1, "invalid_token");
+    }
+
+    #[test]
+    fn authenticate_ignores_authorization_without_the_bearer_prefix() {
+        let valid = sign(claims(3600), Algorithm::HS256, "current");
+        let req = with_authorization(&valid).to_http_request();
+        assert_eq!(rejection(&req).1, "unauthorized");
+
+        // The cookie is used instead
+        let req = with_authorization(&valid)
+            .cookie(actix_web::cookie::Cookie::new("auth", valid.clone()))
+            .to_http_request();
+        assert!(authenticate_with(&req, &secrets(&["current"])).is_ok());
+    }
+
+    fn with_basic(credentials: &str, basic_auth: bool) -> HttpRequest {
+        let users: crate::config::users::UserConfig = toml::from_str(
+            r#"
+            [[user]]
+            username = "alice"
+            password = "secret"
+
+            [[user]]
+            username = "root"
+            password = "toor"
+            role = "admin"
+            "#,
+        )
+        .unwrap();
+        let auth_config = AuthTOML {
+            basic_auth,
+            ..AuthTOML::default()
+        };
+        with_authorization(&format!("Basic {}", BASE64.encode(credentials)))
+            .app_data(web::Data::new(auth_config))
+            .app_data(web::Data::new(Arc::new(Mutex::new(
+                UserManager::from_config(users),
+            ))))
+            .to_http_request()
+    }
+
+    #[test]
+    fn basic_credentials_are_checked_against_the_users() {
+        let claims = authenticate_user(&with_basic("alice:secret", true)).unwrap();
+        assert_eq!((claims.username(), claims.role.as_str()), ("alice", "user"));
+        let claims = authenticate_user(&with_basic("root:toor", true)).unwrap();
+        assert_eq!(claims.role, ADMIN_ROLE);
+
+        for credentials in ["alice:wrong", "nobody:secret", "alice"] {
+            let error = authenticate_user(&with_basic(credentials, true)).unwrap_err();
+            assert_eq!(error.code(), "unauthorized", "{}", credentials);
         }
+    }
+
+    #[test]
+    fn basic_credentials_are_ignored_unless_enabled() {
+        let error = authenticate_user(&with_basic("alice:secret", false)).unwrap_err();
+        assert_eq!(error.to_string(), "Missing token");
+    }
+
+    #[actix_web::test]
+    async fn admin_routes_refuse_the_user_role() {
+        let refused = AdminUser::extract(&with_basic("alice:secret", true))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(
+            refused.as_response_error().status_code(),
+            StatusCode::FORBIDDEN
+        );
+
+        let admin = AdminUser::extract(&with_basic("root:toor", true)).await;
+        assert_eq!(admin.ok().unwrap().username, "root");
+        let anonymous = AuthenticatedUser::extract(&with_basic("root:wrong", true)).await;
+        assert_eq!(
+            anonymous.err().unwrap().as_response_error().status_code(),
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    #[test]
+    fn token_with_a_one_second_lifetime_is_refused_once_expired() {
+        let claims = serde_json::json!({
+            "sub": "alice",
+            "exp": expiry(Duration::seconds(1)).unwrap(),
+            "iss": ISSUER,
+        });
+        let token = sign(claims, Algorithm::HS256, "current");
+        assert!(verify_token(&token, &secrets(&["current"])).is_ok());
+
+        std::thread::sleep(std::time::Duration::from_secs(2));
+        let rejected = verify_token(&token, &secrets(&["current"])).unwrap_err();
+        assert_eq!(*rejected.kind(), ErrorKind::ExpiredSignature);
+    }
+
+    #[test]
+    fn expiry_refuses_lifetimes_out_of_range() {
+        assert!(expiry(Duration::MAX).is_err());
+        assert!(expiry(Duration::hours(24)).is_ok());
+    }
+
+    #[test]
+    fn constant_time_eq_needs_the_same_bytes() {
+        assert!(constant_time_eq(b"token", b"token"));
+        assert!(!constant_time_eq(b"token", b"tokem"));
+        assert!(!constant_time_eq(b"token", b"token2"));
+        assert!(!constant_time_eq(b"", b"token"));
+    }
 
-        err(actix_web::error::ErrorUnauthorized(
-            "Invalid or missing token",
-        ))
+    #[test]
+    fn secrets_fall_back_to_secret_when_none_are_listed() {
+        let some = |value: &str| Some(value.to_string());
+        assert_eq!(
+            signing_secrets(some("new, old"), some("single")),
+            Ok(vec!["new".to_string(), "old".to_string()])
+        );
+        assert_eq!(
+            signing_secrets(some(" , "), some("single")),
+            Ok(vec!["single".to_string()])
+        );
+        assert_eq!(
+            signing_secrets(None, some("single")),
+            Ok(vec!["single".to_string()])
+        );
+        assert!(signing_secrets(some(""), None).is_err());
+        assert!(signing_secrets(None, some("  ")).is_err());
     }
 }
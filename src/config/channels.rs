@@ -1,11 +1,97 @@
 use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
 
 #[derive(Deserialize)]
 pub struct ChannelTOML {
     pub id: String,
     pub name: String,
     pub url: String,
-    pub key: String,
+    // Artwork for channel grids, served to clients through /channels/{id}/logo
+    pub logo_url: Option<String>,
+    // Either a single key or several kid:key pairs, empty when unencrypted
+    #[serde(default)]
+    pub key: ChannelKey,
+    // Keys by DRM system, those of the systems the MPD announces take precedence
+    #[serde(default)]
+    pub drm: BTreeMap<DrmSystem, ChannelKey>,
+    // Ids of the representations to convert, best available when unset
+    pub video_rep_id: Option<String>,
+    pub audio_rep_id: Option<String>,
+    // What happens when a pinned representation isn't in the manifest
+    #[serde(default)]
+    pub rep_fallback: RepFallback,
+    // Number of video qualities to offer, from the highest bandwidth down
+    pub variants: Option<u32>,
+    #[serde(default)]
+    pub segment_format: SegmentFormat,
+    // Protocol the channel is served in
+    #[serde(default)]
+    pub output: OutputFormat,
+    #[serde(default)]
+    pub decryptor: DecryptorKind,
+    // What to do with copied video MPEG-TS can't carry (VP9, AV1)
+    #[serde(default)]
+    pub incompatible_codec: IncompatibleCodec,
+    // Which segments of the manifest are converted
+    #[serde(default)]
+    pub start_mode: StartMode,
+    // HLS segment length in seconds and playlist window, 4 and 40 when unset
+    pub hls_segment_time: Option<u32>,
+    pub hls_list_size: Option<u32>,
+    // ffmpeg video encoder, the source video is copied untouched when unset
+    pub video_codec: Option<String>,
+    // Rate control of transcoded video in ffmpeg notation (e.g. "3M"), ignored
+    // when copying. Renditions override it per variant, highest bandwidth first.
+    pub max_bitrate: Option<String>,
+    pub bufsize: Option<String>,
+    // Copy the source audio instead of transcoding it to stereo AAC, for sources
+    // already in AAC
+    #[serde(default)]
+    pub audio_copy: bool,
+    // Low-latency HLS: segments are served along their parts (EXT-X-PART), with preload
+    // hints and blocking playlist reloads (_HLS_msn, _HLS_part)
+    #[serde(default, alias = "blocking_reload")]
+    pub low_latency: bool,
+    // Length of the parts of low-latency segments in milliseconds, 1000 when unset. It
+    // must divide hls_segment_time.
+    pub part_duration_ms: Option<u32>,
+    // Segments of live $Number$ templates fetched ahead of their listing in the MPD, 0
+    // disables it
+    #[serde(default)]
+    pub prefetch_segments: usize,
+    // Bytes per second downloaded for this channel, overriding the converter's
+    // download_rate_limit (0 means unlimited)
+    #[serde(default)]
+    pub download_rate_limit: Option<u64>,
+    // Keep the output of a finished static MPD when the stream goes idle, so the next
+    // /init serves it again instead of converting it anew
+    #[serde(default)]
+    pub persist_output: bool,
+    // Encrypt the HLS segments with AES-128, under a key generated when the stream starts
+    // and only served to logged in users
+    #[serde(default)]
+    pub encrypt_output: bool,
+    #[serde(default)]
+    pub rendition: Vec<RenditionTOML>,
+    // Offer every audio language of the MPD as a selectable rendition
+    #[serde(default)]
+    pub audio_languages: bool,
+    // Convert the MPD's subtitle tracks to WebVTT renditions
+    #[serde(default)]
+    pub subtitles: bool,
+    // User-Agent and extra headers (e.g. Referer, Cookie) sent along origin fetches
+    pub user_agent: Option<String>,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    // Per-channel overrides of the language display names
+    #[serde(default)]
+    pub languages: HashMap<String, String>,
+}
+
+#[derive(Deserialize, Clone, Default)]
+pub struct RenditionTOML {
+    pub max_bitrate: Option<String>,
+    pub bufsize: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -13,3 +99,307 @@ pub struct ChannelConfig {
     pub channel: Vec<ChannelTOML>,
 }
 
+impl ChannelTOML {
+    // Every configured key, the plain ones along with those of each DRM system
+    pub fn all_keys(&self) -> anyhow::Result<HashMap<String, String>> {
+        let mut keys = self.key.keys()?;
+        for (system, key) in &self.drm {
+            let system_keys = key
+                .keys()
+                .map_err(|e| anyhow::anyhow!("drm.{}: {}", system.name(), e))?;
+            keys.extend(system_keys);
+        }
+        Ok(keys)
+    }
+}
+
+// ClearKey keys of a channel, accepted as a plain key, a "kid:key" string,
+// a table of kid = key or a list of "kid:key" strings
+#[derive(Deserialize, Clone)]
+#[serde(untagged)]
+pub enum ChannelKey {
+    Single(String),
+    Map(HashMap<String, String>),
+    List(Vec<String>),
+}
+
+impl Default for ChannelKey {
+    fn default() -> Self {
+        ChannelKey::Single(String::new())
+    }
+}
+
+// DRM systems whose keys can be configured, by name or by system id
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum DrmSystem {
+    #[serde(alias = "edef8ba9-79d6-4ace-a3c8-27dcd51d21ed")]
+    Widevine,
+    #[serde(alias = "9a04f079-9840-4286-ab92-e65be0885f95")]
+    PlayReady,
+    #[serde(alias = "e2719d58-a985-b3c9-781a-b030af78d30e")]
+    ClearKey,
+}
+
+impl DrmSystem {
+    pub fn name(self) -> &'static str {
+        match self {
+            DrmSystem::Widevine => "widevine",
+            DrmSystem::PlayReady => "playready",
+            DrmSystem::ClearKey => "clearkey",
+        }
+    }
+
+    // Id announced in ContentProtection@schemeIdUri as urn:uuid:<id>
+    pub fn system_id(self) -> &'static str {
+        match self {
+            DrmSystem::Widevine => "edef8ba9-79d6-4ace-a3c8-27dcd51d21ed",
+            DrmSystem::PlayReady => "9a04f079-9840-4286-ab92-e65be0885f95",
+            DrmSystem::ClearKey => "e2719d58-a985-b3c9-781a-b030af78d30e",
+        }
+    }
+}
+
+impl ChannelKey {
+    // Builds the kid -> key map handed to mp4decrypt. A bare key is bound to
+    // track id 1, which is what the single key form always meant.
+    pub fn keys(&self) -> anyhow::Result<HashMap<String, String>> {
+        let pairs = match self {
+            ChannelKey::Single(key) if key.is_empty() => Vec::new(),
+            ChannelKey::Single(key) => match key.split_once(':') {
+                Some((kid, key)) => vec![(kid.to_owned(), key.to_owned())],
+                None => vec![("1".to_owned(), key.clone())],
+            },
+            ChannelKey::Map(map) => map.iter().map(|(k, v)| (k.clone(), v.clone())).collect(),
+            ChannelKey::List(list) => list
+                .iter()
+                .map(|entry| match entry.split_once(':') {
+                    Some((kid, key)) => Ok((kid.to_owned(), key.to_owned())),
+                    None => anyhow::bail!("key entry \"{}\" is not in kid:key form", entry),
+                })
+                .collect::<anyhow::Result<_>>()?,
+        };
+
+        let mut keys = HashMap::new();
+        for (kid, key) in pairs {
+            let kid = kid.trim().replace('-', "").to_lowercase();
+            let key = key.trim().to_lowercase();
+            // mp4decrypt accepts either a 128 bit KID or a decimal track id
+            if !is_hex128(&kid) && kid.parse::<u32>().is_err() {
+                anyhow::bail!("\"{}\" is neither a 32 hex digit KID nor a track id", kid);
+            }
+            if !is_hex128(&key) {
+                anyhow::bail!("key for {} must be 32 hex digits", kid);
+            }
+            keys.insert(kid, key);
+        }
+        Ok(keys)
+    }
+}
+
+fn is_hex128(value: &str) -> bool {
+    value.len() == 32 && value.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+#[derive(Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RepFallback {
+    // Use the best available representation, with a warning
+    #[default]
+    Lenient,
+    // Fail the iteration, so the converter backs off until the representation is back
+    Strict,
+}
+
+// Tool decrypting the segments of a channel. mp4decrypt falls back to ffmpeg on failure.
+#[derive(Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DecryptorKind {
+    #[default]
+    Mp4decrypt,
+    Ffmpeg,
+}
+
+// Handling of copied video whose codec MPEG-TS segments can't carry
+#[derive(Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum IncompatibleCodec {
+    // Write fMP4 segments instead
+    #[default]
+    Fmp4,
+    // Transcode the video to H.264
+    Transcode,
+    // Copy it anyway, with a warning
+    Copy,
+}
+
+// Segments of a manifest a channel converts
+#[derive(Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum StartMode {
+    // The time shift buffer of live manifests (live_window segments when they don't give
+    // one), every segment of static ones
+    #[default]
+    LiveEdge,
+    // The last given number of segments, live or not
+    Dvr(usize),
+    // Every segment listed, the time shift buffer of live manifests without a list
+    Beginning,
+}
+
+// HLS playlists, or a DASH manifest with fMP4 segments for players reading DASH
+#[derive(Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    #[default]
+    Hls,
+    Dash,
+}
+
+impl OutputFormat {
+    // File players open first
+    pub fn manifest_name(self) -> &'static str {
+        match self {
+            OutputFormat::Hls => "master.m3u8",
+            OutputFormat::Dash => "manifest.mpd",
+        }
+    }
+}
+
+// Container of the HLS segments produced for a channel
+#[derive(Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SegmentFormat {
+    #[default]
+    Ts,
+    Fmp4,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KID: &str = "0123456789abcdef0123456789abcdef";
+    const KEY: &str = "fedcba9876543210fedcba9876543210";
+
+    fn keys(key: &str) -> anyhow::Result<HashMap<String, String>> {
+        #[derive(Deserialize)]
+        struct Channel {
+            key: ChannelKey,
+        }
+
+        let channel: Channel = toml::from_str(&format!("key = {}", key)).unwrap();
+        channel.key.keys()
+    }
+
+    fn single(kid: &str, key: &str) -> HashMap<String, String> {
+        HashMap::from([(kid.to_string(), key.to_string())])
+    }
+
+    #[test]
+    fn plain_key_is_bound_to_the_first_track() {
+        assert_eq!(keys(&format!("\"{}\"", KEY)).unwrap(), single("1", KEY));
+        assert!(keys("\"\"").unwrap().is_empty());
+    }
+
+    #[test]
+    fn kid_key_string_is_normalized() {
+        let kid = "01234567-89AB-CDEF-0123-456789ABCDEF";
+        assert_eq!(
+            keys(&format!("\" {}:{} \"", kid, KEY.to_uppercase())).unwrap(),
+            single(KID, KEY)
+        );
+    }
+
+    #[test]
+    fn table_and_list_forms_give_every_pair() {
+        let other_kid = "11111111111111111111111111111111";
+        let expected = HashMap::from([
+            (KID.to_string(), KEY.to_string()),
+            (other_kid.to_string(), KEY.to_string()),
+        ]);
+
+        let table = format!("{{ {} = \"{}\", {} = \"{}\" }}", KID, KEY, other_kid, KEY);
+        assert_eq!(keys(&table).unwrap(), expected);
+        let list = format!("[\"{}:{}\", \"{}:{}\"]", KID, KEY, other_kid, KEY);
+        assert_eq!(keys(&list).unwrap(), expected);
+    }
+
+    #[test]
+    fn list_entries_need_a_kid() {
+        let error = keys(&format!("[\"{}\"]", KEY)).unwrap_err();
+        assert!(error.to_string().contains("kid:key"), "{}", error);
+    }
+
+    #[test]
+    fn malformed_keys_and_kids_are_refused() {
+        // Not hex, and one digit short
+        let not_hex = KEY.replace('f', "g");
+        assert!(keys(&format!("\"{}:{}\"", KID, not_hex)).is_err());
+        assert!(keys(&format!("\"{}:{}\"", KID, &KEY[1..])).is_err());
+        assert!(keys(&format!("\"{}\"", &KEY[1..])).is_err());
+
+        // Neither 32 hex digits nor a track id
+        let error = keys(&format!("\"{}:{}\"", &KID[2..], KEY)).unwrap_err();
+        assert!(error.to_string().contains("KID"), "{}", error);
+        assert!(keys(&format!("\"{}0:{}\"", KID, KEY)).is_err());
+        assert_eq!(keys(&format!("\"2:{}\"", KEY)).unwrap(), single("2", KEY));
+    }
+
+    fn channel(drm: &str) -> Result<ChannelTOML, toml::de::Error> {
+        toml::from_str(&format!(
+            "id = \"news\"\nname = \"News\"\nurl = \"http://o/news.mpd\"\n{}",
+            drm
+        ))
+    }
+
+    #[test]
+    fn drm_keys_of_a_single_system() {
+        let channel = channel(&format!("[drm]\nwidevine = \"{}:{}\"", KID, KEY)).unwrap();
+        assert_eq!(
+            channel
+                .drm
+                .keys()
+                .map(|system| system.name())
+                .collect::<Vec<_>>(),
+            ["widevine"]
+        );
+        assert_eq!(channel.all_keys().unwrap(), single(KID, KEY));
+    }
+
+    #[test]
+    fn drm_keys_of_several_systems_by_name_or_id() {
+        let other_kid = "11111111111111111111111111111111";
+        let channel = channel(&format!(
+            "[drm]\nplayready = [\"{}:{}\"]\n\"edef8ba9-79d6-4ace-a3c8-27dcd51d21ed\" = {{ {} = \"{}\" }}",
+            KID, KEY, other_kid, KEY
+        ))
+        .unwrap();
+
+        assert_eq!(
+            channel
+                .drm
+                .keys()
+                .map(|system| system.name())
+                .collect::<Vec<_>>(),
+            ["widevine", "playready"]
+        );
+        assert_eq!(
+            channel.drm[&DrmSystem::Widevine].keys().unwrap(),
+            single(other_kid, KEY)
+        );
+        assert_eq!(channel.all_keys().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn unknown_drm_systems_are_refused() {
+        let Err(error) = channel(&format!("[drm]\nfairplay = \"{}:{}\"", KID, KEY)) else {
+            panic!("fairplay was accepted");
+        };
+        assert!(error.to_string().contains("fairplay"), "{}", error);
+
+        let channel = channel(&format!("[drm]\nclearkey = \"{}:bad\"", KID)).unwrap();
+        let error = channel.all_keys().unwrap_err();
+        assert!(error.to_string().starts_with("drm.clearkey: "), "{}", error);
+    }
+}
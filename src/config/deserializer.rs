@@ -1,52 +1,623 @@
-use super::channels::ChannelConfig;
+use super::channels::{ChannelConfig, ChannelTOML, OutputFormat};
+use super::settings::{AuthTOML, CleanupTOML, ConverterTOML, ServerTOML, SettingsConfig};
 use super::users::UserConfig;
-use log::error;
+use log::{error, info, warn};
+use serde::de::DeserializeOwned;
+use std::collections::HashMap;
+use std::env;
 use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
 
+#[derive(Clone)]
 pub struct Deserializer {
     channels_path: String,
     users_path: String,
+    settings_path: String,
 }
 
 impl Deserializer {
-    pub fn new(channels_path: String, users_path: String) -> Self {
+    pub fn new(channels_path: String, users_path: String, settings_path: String) -> Self {
         Self {
             channels_path,
             users_path,
+            settings_path,
         }
     }
 
+    // Reads the config files from `dir`. CHANNELS_FILE, USERS_FILE and SETTINGS_FILE point
+    // at a single file elsewhere, absolute or relative to `dir`.
+    pub fn from_dir(dir: &Path) -> Self {
+        Self::new(
+            config_path(dir, "CHANNELS_FILE", "channels"),
+            config_path(dir, "USERS_FILE", "users"),
+            config_path(dir, "SETTINGS_FILE", "settings"),
+        )
+    }
+
+    pub fn channels_path(&self) -> &str {
+        &self.channels_path
+    }
+
+    pub fn users_path(&self) -> &str {
+        &self.users_path
+    }
+
+    pub fn settings_path(&self) -> &str {
+        &self.settings_path
+    }
+
     pub fn load_channels(&self) -> anyhow::Result<ChannelConfig> {
         let data = load_file(&self.channels_path)?;
+        let config: ChannelConfig = parse_config(&self.channels_path, &data)?;
 
-        match toml::from_str(&data) {
-            Ok(config) => Ok(config),
-            Err(e) => {
-                error!("Failed to parse {}: {}", self.channels_path, e);
-                Err(e.into())
+        // Every entry is checked so all problems can be fixed in one go
+        let mut problems = Vec::new();
+        // A repeated id would silently replace the earlier channel
+        let mut seen: HashMap<&str, usize> = HashMap::new();
+        for (i, channel) in config.channel.iter().enumerate() {
+            problems.extend(
+                channel_problems(channel)
+                    .into_iter()
+                    .map(|problem| format!("channel[{}].{}", i, problem)),
+            );
+            let first = *seen.entry(channel.id.as_str()).or_insert(i);
+            if first != i {
+                problems.push(format!(
+                    "channel[{}].id {:?} repeats channel[{}]",
+                    i, channel.id, first
+                ));
             }
+            warn_ignored_options(channel);
         }
+
+        report_problems(&self.channels_path, problems)?;
+        Ok(config)
     }
 
     pub fn load_users(&self) -> anyhow::Result<UserConfig> {
         let data = load_file(&self.users_path)?;
+        let config: UserConfig = parse_config(&self.users_path, &data)?;
 
-        match toml::from_str(&data) {
-            Ok(config) => Ok(config),
-            Err(e) => {
-                error!("Failed to parse {}: {}", self.users_path, e);
-                Err(e.into())
+        let mut problems = Vec::new();
+        let mut seen: HashMap<&str, usize> = HashMap::new();
+        for (i, user) in config.user.iter().enumerate() {
+            if user.username.trim().is_empty() {
+                problems.push(format!("user[{}].username is empty", i));
             }
+            if user.password.is_empty() {
+                problems.push(format!("user[{}].password is empty", i));
+            }
+            let first = *seen.entry(user.username.as_str()).or_insert(i);
+            if first != i {
+                problems.push(format!(
+                    "user[{}].username {:?} repeats user[{}]",
+                    i, user.username, first
+                ));
+            }
+        }
+
+        report_problems(&self.users_path, problems)?;
+        Ok(config)
+    }
+
+    // Settings are optional: a missing file means defaults everywhere
+    pub fn load_settings(&self) -> anyhow::Result<SettingsConfig> {
+        let mut settings = if !Path::new(&self.settings_path).exists() {
+            info!("{} not found, using default settings", self.settings_path);
+            SettingsConfig::default()
+        } else {
+            let data = load_file(&self.settings_path)?;
+
+            match from_toml(&data) {
+                Ok(config) => config,
+                Err(e) => {
+                    error!("Failed to parse {}: {}", self.settings_path, e);
+                    return Err(e);
+                }
+            }
+        };
+
+        apply_server_env(&mut settings.server)?;
+        apply_auth_env(&mut settings.auth)?;
+        apply_cleanup_env(&mut settings.cleanup)?;
+        apply_converter_env(&mut settings.converter);
+        Ok(settings)
+    }
+}
+
+// Rejects missing names, bad URLs, headers and malformed keys now rather than on the first
+// segment, and points out rate control that can't apply
+pub fn validate_channel(channel: &ChannelTOML) -> anyhow::Result<()> {
+    let problems = channel_problems(channel);
+    warn_ignored_options(channel);
+    if problems.is_empty() {
+        return Ok(());
+    }
+
+    error!("Invalid channel {:?}: {}", channel.id, problems.join("; "));
+    anyhow::bail!("{}", problems.join("; "))
+}
+
+// What's wrong with a channel entry, each problem starting with the field it's about
+fn channel_problems(channel: &ChannelTOML) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    // The id names the stream's output directory, so it can't be a path
+    if channel.id.trim().is_empty() {
+        problems.push("id is empty".to_string());
+    } else if !channel
+        .id
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        problems.push("id may only contain letters, digits, '_' and '-'".to_string());
+    }
+    if channel.name.trim().is_empty() {
+        problems.push("name is empty".to_string());
+    }
+
+    if let Err(e) = url::Url::parse(&channel.url) {
+        problems.push(format!("url is invalid: {}", e));
+    }
+
+    if let Some(logo_url) = &channel.logo_url
+        && let Err(e) = url::Url::parse(logo_url)
+    {
+        problems.push(format!("logo_url is invalid: {}", e));
+    }
+
+    for (name, value) in &channel.headers {
+        if reqwest::header::HeaderName::from_bytes(name.as_bytes()).is_err()
+            || reqwest::header::HeaderValue::from_str(value).is_err()
+        {
+            problems.push(format!("headers.{} is invalid", name));
+        }
+    }
+
+    if let Err(e) = channel.key.keys() {
+        problems.push(format!("key is invalid: {}", e));
+    }
+    for (system, key) in &channel.drm {
+        if let Err(e) = key.keys() {
+            problems.push(format!("drm.{} key is invalid: {}", system.name(), e));
         }
     }
+
+    if channel.hls_segment_time == Some(0) {
+        problems.push("hls_segment_time must be positive".to_string());
+    }
+
+    // The DASH output is a single manifest written by one ffmpeg
+    if channel.output == OutputFormat::Dash
+        && (channel.variants.unwrap_or(1) > 1
+            || channel.subtitles
+            || channel.audio_languages
+            || channel.encrypt_output
+            || channel.low_latency)
+    {
+        problems.push(
+            "output \"dash\" can't be combined with variants, subtitles, audio_languages, encrypt_output or low_latency"
+                .to_string(),
+        );
+    }
+
+    // Low-latency segments are made of whole parts
+    let segment_ms = u64::from(channel.hls_segment_time.unwrap_or(4)) * 1000;
+    match channel.part_duration_ms {
+        Some(0) => problems.push("part_duration_ms must be positive".to_string()),
+        Some(part) if segment_ms % u64::from(part) != 0 => problems.push(format!(
+            "part_duration_ms must divide hls_segment_time ({} ms)",
+            segment_ms
+        )),
+        _ => {}
+    }
+    // Parts would each be encrypted on their own, and WebVTT renditions aren't split
+    if channel.low_latency && (channel.encrypt_output || channel.subtitles) {
+        problems.push("low_latency can't be combined with encrypt_output or subtitles".to_string());
+    }
+
+    problems
+}
+
+fn warn_ignored_options(channel: &ChannelTOML) {
+    let rate_control = channel.max_bitrate.is_some()
+        || channel.bufsize.is_some()
+        || channel
+            .rendition
+            .iter()
+            .any(|r| r.max_bitrate.is_some() || r.bufsize.is_some());
+    if rate_control && channel.video_codec.is_none() {
+        warn!(
+            "Channel {} sets max_bitrate/bufsize without video_codec, ignoring them since video is copied",
+            channel.id
+        );
+    }
+}
+
+// Fails with every problem found in a config file, one per line
+fn report_problems(path: &str, problems: Vec<String>) -> anyhow::Result<()> {
+    if problems.is_empty() {
+        return Ok(());
+    }
+
+    let report = problems.join("\n");
+    error!("{} problems in {}:\n{}", problems.len(), path, report);
+    anyhow::bail!("Invalid {}:\n{}", path, report)
+}
+
+fn apply_converter_env(converter: &mut ConverterTOML) {
+    if let Ok(ffmpeg_path) = env::var("FFMPEG_PATH") {
+        converter.ffmpeg_path = ffmpeg_path;
+    }
+}
+
+// Environment variables take precedence over the [server] section
+fn apply_server_env(server: &mut ServerTOML) -> anyhow::Result<()> {
+    if let Ok(bind_addr) = env::var("BIND_ADDR") {
+        server.bind_addr = bind_addr;
+    }
+    if let Ok(port) = env::var("PORT") {
+        server.port = port
+            .parse()
+            .map_err(|e| anyhow::anyhow!("Invalid PORT {}: {}", port, e))?;
+    }
+    if let Ok(workers) = env::var("WORKERS") {
+        server.workers = workers
+            .parse()
+            .map_err(|e| anyhow::anyhow!("Invalid WORKERS {}: {}", workers, e))?;
+    }
+
+    if let Ok(output_root) = env::var("OUTPUT_DIR") {
+        server.output_root = output_root;
+    }
+    if let Ok(public_url) = env::var("PUBLIC_URL") {
+        server.public_url = Some(public_url);
+    }
+
+    if server.output_root.trim().is_empty() {
+        anyhow::bail!("Output root directory must not be empty");
+    }
+    if server.bind_addr.parse::<IpAddr>().is_err() {
+        anyhow::bail!("Invalid bind address {}", server.bind_addr);
+    }
+    if server.workers == 0 {
+        anyhow::bail!("At least one worker is needed");
+    }
+    if let Some(public_url) = &server.public_url
+        && !url::Url::parse(public_url).is_ok_and(|url| matches!(url.scheme(), "http" | "https"))
+    {
+        anyhow::bail!("Invalid public URL {}", public_url);
+    }
+    Ok(())
+}
+
+fn apply_auth_env(auth: &mut AuthTOML) -> anyhow::Result<()> {
+    if let Ok(ttl) = env::var("TOKEN_TTL_HOURS") {
+        auth.token_ttl_hours = ttl
+            .parse()
+            .map_err(|e| anyhow::anyhow!("Invalid TOKEN_TTL_HOURS {}: {}", ttl, e))?;
+    }
+
+    if auth.token_ttl_hours <= 0 {
+        anyhow::bail!(
+            "Token lifetime must be positive, got {}h",
+            auth.token_ttl_hours
+        );
+    }
+    // Tokens expire that long from now, which has to be a date chrono can represent
+    let ttl_secs = auth.token_ttl_hours.checked_mul(3600).filter(|_| {
+        chrono::Duration::try_hours(auth.token_ttl_hours)
+            .is_some_and(|ttl| chrono::Utc::now().checked_add_signed(ttl).is_some())
+    });
+    let Some(ttl_secs) = ttl_secs else {
+        anyhow::bail!("Token lifetime of {}h is too long", auth.token_ttl_hours);
+    };
+    if auth.media_token_ttl_secs <= 0 || auth.media_token_ttl_secs >= ttl_secs {
+        anyhow::bail!(
+            "Media token lifetime must be positive and below the login token's, got {}s",
+            auth.media_token_ttl_secs
+        );
+    }
+    Ok(())
+}
+
+fn apply_cleanup_env(cleanup: &mut CleanupTOML) -> anyhow::Result<()> {
+    if let Ok(timeout) = env::var("STREAM_IDLE_TIMEOUT") {
+        cleanup.idle_timeout_secs = timeout
+            .parse()
+            .map_err(|e| anyhow::anyhow!("Invalid STREAM_IDLE_TIMEOUT {}: {}", timeout, e))?;
+    }
+    if let Ok(interval) = env::var("CLEANUP_INTERVAL") {
+        cleanup.interval_secs = interval
+            .parse()
+            .map_err(|e| anyhow::anyhow!("Invalid CLEANUP_INTERVAL {}: {}", interval, e))?;
+    }
+
+    // A sweep slower than the timeout would keep idle streams around for longer
+    let max_interval = cleanup.idle_timeout_secs.max(1);
+    if cleanup.interval_secs == 0 || cleanup.interval_secs > max_interval {
+        let interval = cleanup.interval_secs.clamp(1, max_interval);
+        warn!(
+            "Cleanup interval of {}s doesn't fit the idle timeout of {}s, using {}s",
+            cleanup.interval_secs, cleanup.idle_timeout_secs, interval
+        );
+        cleanup.interval_secs = interval;
+    }
+    Ok(())
+}
+
+// The file named by the environment variable, else the TOML file of a config in `dir`, or
+// its JSON counterpart when only that one exists
+fn config_path(dir: &Path, var: &str, name: &str) -> String {
+    let path = match env::var(var) {
+        Ok(file) => dir.join(file),
+        Err(_) => {
+            let toml_path = dir.join(format!("{}.toml", name));
+            let json_path = dir.join(format!("{}.json", name));
+            if !toml_path.exists() && json_path.exists() {
+                json_path
+            } else {
+                toml_path
+            }
+        }
+    };
+    path.to_string_lossy().into_owned()
+}
+
+// Channels and users can be written in TOML or JSON, told apart by the file extension
+fn parse_config<T: DeserializeOwned>(path: &str, data: &str) -> anyhow::Result<T> {
+    let parsed = match Path::new(path).extension().and_then(|ext| ext.to_str()) {
+        Some("toml") => from_toml(data),
+        Some("json") => from_json(data),
+        _ => {
+            error!("Unsupported config format for {}", path);
+            anyhow::bail!("Unsupported config format for {}, use .toml or .json", path);
+        }
+    };
+
+    parsed.inspect_err(|e| error!("Failed to parse {}: {}", path, e))
+}
+
+// Placeholders are expanded in the parsed string values, so comments are left alone and
+// values can't break out of their quotes
+fn from_toml<T: DeserializeOwned>(data: &str) -> anyhow::Result<T> {
+    let mut value: toml::Value = toml::from_str(data)?;
+    expand_toml(&mut value)?;
+    Ok(value.try_into()?)
+}
+
+fn from_json<T: DeserializeOwned>(data: &str) -> anyhow::Result<T> {
+    let mut value: serde_json::Value = serde_json::from_str(data)?;
+    expand_json(&mut value)?;
+    Ok(serde_json::from_value(value)?)
+}
+
+fn expand_toml(value: &mut toml::Value) -> anyhow::Result<()> {
+    match value {
+        toml::Value::String(s) => *s = expand_env(s)?,
+        toml::Value::Array(values) => values.iter_mut().try_for_each(expand_toml)?,
+        toml::Value::Table(table) => table
+            .iter_mut()
+            .try_for_each(|(_, value)| expand_toml(value))?,
+        _ => {}
+    }
+    Ok(())
+}
+
+fn expand_json(value: &mut serde_json::Value) -> anyhow::Result<()> {
+    match value {
+        serde_json::Value::String(s) => *s = expand_env(s)?,
+        serde_json::Value::Array(values) => values.iter_mut().try_for_each(expand_json)?,
+        serde_json::Value::Object(map) => map.values_mut().try_for_each(expand_json)?,
+        _ => {}
+    }
+    Ok(())
 }
 
 fn load_file(path: &str) -> anyhow::Result<String> {
-    match fs::read_to_string(path) {
-        Ok(content) => Ok(content),
-        Err(e) => {
-            error!("Failed to read {}: {}", path, e);
-            Err(e.into())
+    fs::read_to_string(path).map_err(|e| {
+        error!("Failed to read {}: {}", path, e);
+        e.into()
+    })
+}
+
+// Replaces ${VAR} placeholders with the variable's value, so secrets such as keys can
+// live in the environment. $${ stands for a literal ${. Referencing an undefined variable
+// is an error.
+fn expand_env(content: &str) -> anyhow::Result<String> {
+    let mut expanded = String::with_capacity(content.len());
+    let mut rest = content;
+
+    while let Some(start) = rest.find('$') {
+        expanded.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        if let Some(after) = after.strip_prefix("${") {
+            expanded.push_str("${");
+            rest = after;
+            continue;
+        }
+        let Some(after) = after.strip_prefix('{') else {
+            expanded.push('$');
+            rest = after;
+            continue;
+        };
+        let Some(end) = after.find('}') else {
+            anyhow::bail!("Unterminated ${{ placeholder");
+        };
+
+        let name = &after[..end];
+        let value = env::var(name)
+            .map_err(|_| anyhow::anyhow!("Environment variable {} is not defined", name))?;
+        expanded.push_str(&value);
+        rest = &after[end + 1..];
+    }
+
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expand_env_substitutes_defined_variables_and_keeps_escaped_ones() {
+        let path = env::var("PATH").unwrap();
+        assert_eq!(expand_env("a ${PATH} b").unwrap(), format!("a {} b", path));
+        assert_eq!(expand_env("$${PATH} costs $5").unwrap(), "${PATH} costs $5");
+    }
+
+    #[test]
+    fn expand_env_rejects_undefined_and_unterminated_placeholders() {
+        let undefined = expand_env("${DASH_TO_HLS_UNDEFINED_VAR}").unwrap_err();
+        assert!(undefined.to_string().contains("DASH_TO_HLS_UNDEFINED_VAR"));
+        assert!(expand_env("${PATH").is_err());
+    }
+
+    #[test]
+    fn placeholders_are_expanded_in_values_only() {
+        #[derive(serde::Deserialize)]
+        struct Config {
+            key: String,
+        }
+
+        let toml = "# ${DASH_TO_HLS_UNDEFINED_VAR}\nkey = \"${PATH}\"\n";
+        let config: Config = from_toml(toml).unwrap();
+        assert_eq!(config.key, env::var("PATH").unwrap());
+
+        let json = r#"{"key": "$${PATH}"}"#;
+        let config: Config = from_json(json).unwrap();
+        assert_eq!(config.key, "${PATH}");
+    }
+
+    fn channel(fields: &str) -> ChannelTOML {
+        from_toml(fields).unwrap()
+    }
+
+    #[test]
+    fn channel_problems_flag_empty_names_and_invalid_urls() {
+        let valid = channel("id = \"news\"\nname = \"News\"\nurl = \"https://o/news.mpd\"");
+        assert!(channel_problems(&valid).is_empty());
+
+        let problems =
+            channel_problems(&channel("id = \"news\"\nname = \" \"\nurl = \"news.mpd\""));
+        assert_eq!(problems.len(), 2, "{:?}", problems);
+        assert_eq!(problems[0], "name is empty");
+        assert!(problems[1].starts_with("url is invalid"), "{}", problems[1]);
+    }
+
+    #[test]
+    fn channel_problems_flag_ids_that_are_paths() {
+        for id in ["..", "a/../../x", "a/b", "news.hd", "news hd"] {
+            let problems = channel_problems(&channel(&format!(
+                "id = \"{}\"\nname = \"News\"\nurl = \"https://o/news.mpd\"",
+                id
+            )));
+            assert_eq!(
+                problems,
+                ["id may only contain letters, digits, '_' and '-'"],
+                "{}",
+                id
+            );
+        }
+
+        let valid = channel("id = \"News_HD-2\"\nname = \"News\"\nurl = \"https://o/news.mpd\"");
+        assert!(channel_problems(&valid).is_empty());
+    }
+
+    fn load_channels(file_name: &str, data: &str) -> anyhow::Result<ChannelConfig> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(file_name);
+        fs::write(&path, data).unwrap();
+        let path = path.to_str().unwrap().to_string();
+        Deserializer::new(path, String::new(), String::new()).load_channels()
+    }
+
+    #[test]
+    fn load_channels_reports_every_problem_at_once() {
+        let channels = r#"
+            [[channel]]
+            id = "news"
+            name = "News"
+            url = "https://o/news.mpd"
+
+            [[channel]]
+            id = "news"
+            name = ""
+            url = "not a url"
+
+            [[channel]]
+            id = "sports"
+            name = "Sports"
+            url = "https://o/sports.mpd"
+            key = "not hex"
+        "#;
+
+        let report = load_channels("channels.toml", channels)
+            .err()
+            .unwrap()
+            .to_string();
+        for problem in [
+            "channel[1].name is empty",
+            "channel[1].url is invalid",
+            "channel[1].id \"news\" repeats channel[0]",
+            "channel[2].key",
+        ] {
+            assert!(
+                report.contains(problem),
+                "{} missing from {}",
+                problem,
+                report
+            );
+        }
+        assert!(!report.contains("channel[0]."), "{}", report);
+    }
+
+    #[test]
+    fn toml_and_json_channels_parse_the_same() {
+        let toml = r#"
+            [[channel]]
+            id = "news"
+            name = "News"
+            url = "https://o/news.mpd"
+            key = { 0123456789abcdef0123456789abcdef = "fedcba9876543210fedcba9876543210" }
+            variants = 2
+            languages = { spa = "Castellano" }
+        "#;
+        let json = r#"{"channel": [{
+            "id": "news",
+            "name": "News",
+            "url": "https://o/news.mpd",
+            "key": {"0123456789abcdef0123456789abcdef": "fedcba9876543210fedcba9876543210"},
+            "variants": 2,
+            "languages": {"spa": "Castellano"}
+        }]}"#;
+
+        let from_toml: ChannelConfig = parse_config("channels.toml", toml).unwrap();
+        let from_json: ChannelConfig = parse_config("channels.json", json).unwrap();
+        for (a, b) in from_toml.channel.iter().zip(&from_json.channel) {
+            assert_eq!((&a.id, &a.name, &a.url), (&b.id, &b.name, &b.url));
+            assert_eq!(a.key.keys().unwrap(), b.key.keys().unwrap());
+            assert_eq!(a.variants, b.variants);
+            assert_eq!(a.languages, b.languages);
+        }
+        assert_eq!(from_json.channel.len(), 1);
+
+        assert!(load_channels("channels.toml", toml).is_ok());
+        assert!(load_channels("channels.json", json).is_ok());
+        assert!(parse_config::<ChannelConfig>("channels.yaml", toml).is_err());
+    }
+
+    #[test]
+    fn apply_auth_env_refuses_token_lifetimes_out_of_range() {
+        for token_ttl_hours in [i64::MAX, i64::MAX / 3600, 10_000_000_000] {
+            let mut auth = AuthTOML {
+                token_ttl_hours,
+                ..AuthTOML::default()
+            };
+            assert!(apply_auth_env(&mut auth).is_err(), "{}h", token_ttl_hours);
         }
+        assert!(apply_auth_env(&mut AuthTOML::default()).is_ok());
     }
 }
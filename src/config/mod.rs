@@ -1,3 +1,4 @@
+pub mod channels;
 pub mod deserializer;
-mod users;
-mod channels;
+pub mod settings;
+pub mod users;
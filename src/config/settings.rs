@@ -0,0 +1,349 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+
+#[derive(Deserialize, Default)]
+pub struct SettingsConfig {
+    // Display names for language codes, used as NAME= of HLS renditions
+    #[serde(default)]
+    pub languages: HashMap<String, String>,
+    #[serde(default)]
+    pub converter: ConverterTOML,
+    #[serde(default)]
+    pub health: HealthTOML,
+    #[serde(default)]
+    pub request_id: RequestIdTOML,
+    #[serde(default)]
+    pub segments: SegmentsTOML,
+    #[serde(default)]
+    pub metrics: MetricsTOML,
+    #[serde(default)]
+    pub cleanup: CleanupTOML,
+    #[serde(default)]
+    pub server: ServerTOML,
+    #[serde(default)]
+    pub cors: CorsTOML,
+    #[serde(default)]
+    pub auth: AuthTOML,
+    #[serde(default)]
+    pub access_log: AccessLogTOML,
+    #[serde(default)]
+    pub audit: AuditTOML,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct ConverterTOML {
+    // Allowed gap between a SegmentTimeline and its period duration before warning
+    pub timeline_tolerance_secs: f64,
+    // How long the last playlist is held during an origin outage, 0 disables holding
+    pub hold_on_outage_secs: u64,
+    // Attempts per segment download, and the delay before the first retry
+    pub segment_attempts: u32,
+    pub retry_backoff_ms: u64,
+    // How long a KID announced by the MPD stays usable after it stops being announced
+    pub key_retention_secs: u64,
+    // Finalize the HLS output and stop polling once a dynamic MPD turns static
+    pub finalize_on_static: bool,
+    // Refuse to start a converter on an output directory another one is writing to
+    pub lock_output_dir: bool,
+    // MPD polling follows minimumUpdatePeriod within these bounds, or the default without one
+    pub poll_default_ms: u64,
+    pub poll_min_ms: u64,
+    pub poll_max_ms: u64,
+    // Times in a row a crashed ffmpeg is restarted before the stream gives up, a run
+    // lasting ten segments starts the count over
+    pub max_ffmpeg_restarts: u32,
+    // How long ffmpeg may leave a segment unread before it's considered hung and restarted
+    pub mux_timeout_secs: u64,
+    // Back-to-back failed MPD refreshes after which on_repeated_failure applies, 0 retries
+    // at the normal pace forever
+    pub max_consecutive_failures: u32,
+    pub on_repeated_failure: FailureAction,
+    // Pause between refreshes of a stream in slow retry, until one succeeds
+    pub slow_retry_secs: u64,
+    // Segments downloaded at the same time, they're still handed to ffmpeg in order
+    pub download_concurrency: usize,
+    // How long ffmpeg may take to flush its last segment on stop before it's killed
+    pub stop_timeout_ms: u64,
+    // Request timeouts of MPD and segment fetches, a hung MPD fetch is retried on the
+    // next refresh
+    pub mpd_timeout_secs: u64,
+    pub segment_timeout_secs: u64,
+    // Streams converted at the same time, each costing ffmpeg processes (0 means no limit)
+    pub max_active_streams: usize,
+    // Segments kept from the end of live representations whose MPD doesn't advertise a
+    // timeShiftBufferDepth
+    pub live_window: usize,
+    // Largest segment or init segment body downloaded, bigger ones are refused
+    pub max_segment_bytes: u64,
+    // ffmpeg binary run for every conversion, overridable by FFMPEG_PATH
+    pub ffmpeg_path: String,
+    // Bytes per second downloaded by each stream, 0 means unlimited. Channels can override it.
+    pub download_rate_limit: u64,
+}
+
+impl Default for ConverterTOML {
+    fn default() -> Self {
+        Self {
+            timeline_tolerance_secs: 1.0,
+            hold_on_outage_secs: 0,
+            segment_attempts: 3,
+            retry_backoff_ms: 200,
+            key_retention_secs: 120,
+            finalize_on_static: true,
+            lock_output_dir: true,
+            poll_default_ms: 1000,
+            poll_min_ms: 500,
+            poll_max_ms: 30000,
+            max_ffmpeg_restarts: 5,
+            mux_timeout_secs: 30,
+            max_consecutive_failures: 30,
+            on_repeated_failure: FailureAction::Stop,
+            slow_retry_secs: 60,
+            download_concurrency: 4,
+            stop_timeout_ms: 2000,
+            mpd_timeout_secs: 10,
+            segment_timeout_secs: 30,
+            max_active_streams: 0,
+            live_window: 20,
+            max_segment_bytes: 64 * 1024 * 1024,
+            ffmpeg_path: "ffmpeg".to_string(),
+            download_rate_limit: 0,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct HealthTOML {
+    // Consecutive failed iterations after which a stream counts as failing
+    pub failing_after: u32,
+    // Fraction of failing active streams above which the node reports not ready
+    pub max_failing_ratio: f64,
+    // Seconds behind the live edge after which a stream counts as failing, 0 disables it
+    pub max_latency_secs: u64,
+}
+
+impl Default for HealthTOML {
+    fn default() -> Self {
+        Self {
+            failing_after: 3,
+            max_failing_ratio: 0.5,
+            max_latency_secs: 0,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct RequestIdTOML {
+    // Header carrying the request id, read from clients and set on responses
+    pub header: String,
+    // Whether origin fetches of a stream carry the id of the request that started it
+    pub forward_to_origin: bool,
+}
+
+impl Default for RequestIdTOML {
+    fn default() -> Self {
+        Self {
+            header: "X-Request-Id".to_string(),
+            forward_to_origin: true,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct SegmentsTOML {
+    // What to answer for segments on disk that no playlist lists yet
+    pub unlisted: UnlistedSegments,
+    // Retry-After sent along the 503 of the retry behavior and of playlists not yet written
+    pub retry_after_secs: u64,
+    // Cache-Control max-age of media segments, playlists and init segments aren't cached
+    pub max_age_secs: u64,
+    // Time a playlist or segment may take to be read from disk before answering 504
+    pub read_timeout_ms: u64,
+}
+
+#[derive(Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct AccessLogTOML {
+    // One line per handled request, readable text or a JSON object
+    pub format: AccessLogFormat,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct AuditTOML {
+    // Latest media requests kept for GET /audit
+    pub max_entries: usize,
+    // File every entry is appended to as a JSON line, nothing is persisted when unset
+    pub file: Option<String>,
+}
+
+impl Default for AuditTOML {
+    fn default() -> Self {
+        Self {
+            max_entries: 1000,
+            file: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AccessLogFormat {
+    #[default]
+    Plain,
+    Json,
+}
+
+impl Default for SegmentsTOML {
+    fn default() -> Self {
+        Self {
+            unlisted: UnlistedSegments::NotFound,
+            retry_after_secs: 1,
+            max_age_secs: 600,
+            read_timeout_ms: 5000,
+        }
+    }
+}
+
+// What a stream does once its refreshes keep failing
+#[derive(Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum FailureAction {
+    // Stop ffmpeg and release the stream, the reason stays in its details
+    #[default]
+    Stop,
+    // Keep trying, every slow_retry_secs
+    SlowRetry,
+}
+
+// ffmpeg only lists a segment once it's complete, anything else may be half written
+#[derive(Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum UnlistedSegments {
+    #[default]
+    NotFound,
+    Retry,
+    Serve,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct MetricsTOML {
+    // Whether /admin/metrics/{id} serves a readable snapshot of a channel's counters
+    pub per_channel: bool,
+    // Bearer token required to scrape /metrics, open to anyone when unset
+    pub token: Option<String>,
+}
+
+impl Default for MetricsTOML {
+    fn default() -> Self {
+        Self {
+            per_channel: true,
+            token: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct CleanupTOML {
+    // Idle time after which a stream without viewers is torn down, and how often idle
+    // streams are looked for. Overridable by STREAM_IDLE_TIMEOUT and CLEANUP_INTERVAL.
+    pub idle_timeout_secs: u64,
+    pub interval_secs: u64,
+    // How long a client counts as a viewer after its last playlist fetch
+    pub viewer_window_secs: u64,
+    // Time a live stream may go without pushing a segment before it counts as stalled,
+    // 0 disables the watchdog
+    pub stall_timeout_secs: u64,
+    pub stall_action: StallAction,
+    // Disk all stream outputs may use together before segments are evicted, least
+    // recently served first. 0 means unbounded.
+    pub max_output_bytes: u64,
+}
+
+// What the watchdog does with a stalled stream, besides logging it
+#[derive(Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum StallAction {
+    #[default]
+    Warn,
+    Restart,
+    Stop,
+}
+
+impl Default for CleanupTOML {
+    fn default() -> Self {
+        Self {
+            idle_timeout_secs: 120,
+            interval_secs: 15,
+            viewer_window_secs: 60,
+            stall_timeout_secs: 120,
+            stall_action: StallAction::Warn,
+            max_output_bytes: 0,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct ServerTOML {
+    // Address and port the web server listens on, overridable by BIND_ADDR and PORT
+    pub bind_addr: String,
+    pub port: u16,
+    // actix worker threads, overridable by WORKERS
+    pub workers: usize,
+    // Directory holding one output directory per stream, overridable by OUTPUT_DIR
+    pub output_root: String,
+    // URL players reach the server at, for links handed out. Links are relative without
+    // it. Overridable by PUBLIC_URL.
+    pub public_url: Option<String>,
+}
+
+impl Default for ServerTOML {
+    fn default() -> Self {
+        Self {
+            bind_addr: "::".to_string(),
+            port: 8080,
+            workers: 4,
+            output_root: "./streams".to_string(),
+            public_url: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct CorsTOML {
+    // Origins allowed to call the API with the auth cookie, none means same-origin only.
+    // "*" allows any origin, but without credentials.
+    pub allowed_origins: Vec<String>,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct AuthTOML {
+    // Lifetime of login tokens, overridable by TOKEN_TTL_HOURS
+    pub token_ttl_hours: i64,
+    // Age after which a session can't be refreshed anymore, 0 means no limit
+    pub max_session_hours: i64,
+    // Lifetime of the media tokens passed to players as ?token=, below the login tokens'
+    pub media_token_ttl_secs: i64,
+    // Also accept HTTP Basic credentials from users.toml on every request
+    pub basic_auth: bool,
+}
+
+impl Default for AuthTOML {
+    fn default() -> Self {
+        Self {
+            token_ttl_hours: 24,
+            max_session_hours: 0,
+            media_token_ttl_secs: 600,
+            basic_auth: false,
+        }
+    }
+}
@@ -4,6 +4,11 @@ use serde::Deserialize;
 pub struct UserTOML {
     pub username: String,
     pub password: String,
+    // "admin" may start streams, anything else only watches them
+    pub role: Option<String>,
+    // Channel ids the user may access, every channel when empty
+    #[serde(default)]
+    pub channels: Vec<String>,
 }
 
 #[derive(Deserialize)]
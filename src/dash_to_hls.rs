@@ -1,65 +1,152 @@
 use super::StreamInfo;
-use dash_mpd::{MPD, Representation, S};
-use log::{error, info};
+use crate::config::channels::{
+    DrmSystem, IncompatibleCodec, OutputFormat, RepFallback, SegmentFormat, StartMode,
+};
+use crate::config::settings::{ConverterTOML, FailureAction};
+use crate::events::{self, EventKind, EventSender};
+use chrono::{DateTime, Utc};
+use dash_mpd::{
+    AdaptationSet, BaseURL, MPD, Period, Representation, S, SegmentBase, SegmentList,
+    SegmentTemplate,
+};
+use log::{error, info, warn};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use std::collections::{HashMap, HashSet};
+use std::ffi::CString;
 use std::fs;
 use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
 use std::path::{Path, PathBuf};
-use std::process::{Child, ChildStdin, Command, Stdio};
-use std::sync::{Arc, Mutex};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
 use std::thread;
-use std::time::Duration;
-use tempfile::tempdir;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
 use url::Url;
 
-#[allow(unused)]
+mod check;
+mod clock;
+mod decrypt;
+mod download;
+mod fifo_dir;
+mod inspect;
+mod lock;
+mod low_latency;
+mod playlist;
+mod sidx;
+mod stats;
+mod subtitles;
+mod throttle;
+
+pub use check::check_channel;
+pub use inspect::inspect_mpd;
+pub use low_latency::{PartLayout, PartedPlaylist};
+pub use playlist::ProgramDateTimes;
+pub use stats::{SEGMENT_SIZE_BUCKETS, StatsSnapshot, StreamStats};
+
+use download::Downloader;
+
+// Long-lived ffmpeg muxing the decrypted video and audio tracks into a live HLS playlist.
+// Each track is fed as a continuous fragmented MP4 through its own FIFO, so ffmpeg is
+// spawned once per stream rather than once per segment. Alternate audio renditions get
+// pushers of their own without video, MPDs without audio pushers without audio.
 struct LiveHlsPusher {
     child: Child,
-    ffmpeg_stdin: ChildStdin,
+    video_input: Option<FifoWriter>,
+    audio_input: Option<FifoWriter>,
+    output: PusherOutput,
+    config: PusherConfig,
+    stats: Arc<StreamStats>,
+    // Times in a row ffmpeg was respawned after exiting on its own
+    crash_restarts: u32,
+    // When the current ffmpeg run was started
+    launched_at: Instant,
+    // Set until the current ffmpeg run is anchored on the MPD program date times
+    pending_anchor: Option<PendingAnchor>,
+    // Whether the codec of the video was checked against the output container
+    codec_checked: bool,
 }
 
-impl LiveHlsPusher {
-    pub fn spawn(output_dir: &str, max_segments: u32, segment_time: u32) -> anyhow::Result<Self> {
-        let mut child = Command::new("ffmpeg")
-            .args([
-                "-hide_banner",
-                "-loglevel",
-                "error",
-                "-y",
-                "-i",
-                "pipe:0",
-                "-c:v",
-                "copy",
-                "-c:a",
-                "aac",
-                "-ac",
-                "2",
-                "-channel_layout",
-                "stereo",
-                "-b:a",
-                "128k",
-                "-ar",
-                "48000",
-                "-f",
-                "hls",
-                "-hls_time",
-                &segment_time.to_string(),
-                "-hls_list_size",
-                &max_segments.to_string(),
-                "-hls_flags",
-                "delete_segments",
-                "-hls_segment_type",
-                "mpegts",
-                "-hls_segment_filename",
-                &format!("{}/segment_%03d.ts", output_dir),
-                &format!("{}/master.m3u8", output_dir),
-            ])
-            .stdin(Stdio::piped())
+// An ffmpeg run waiting to list its first segment
+struct PendingAnchor {
+    // Last segment of the playlist before the run started
+    after: Option<String>,
+    // MPD program date time of the first segment the run got, None before it got one
+    presented: Option<DateTime<Utc>>,
+}
+
+// Output settings shared by all the pushers of a converter
+#[derive(Clone)]
+struct PusherConfig {
+    max_segments: u32,
+    segment_time: u32,
+    segment_format: SegmentFormat,
+    output: OutputFormat,
+    // Encoder for the video, None copies it and disables rate control
+    video_codec: Option<String>,
+    max_bitrate: Option<String>,
+    bufsize: Option<String>,
+    // Copies the audio instead of transcoding it to stereo AAC
+    audio_copy: bool,
+    // Low-latency output: ffmpeg cuts parts, renamed into place once complete, which the
+    // served playlists group into segments
+    parts: Option<PartLayout>,
+    // ffmpeg key info file of AES-128 encrypted output
+    key_info: Option<PathBuf>,
+    // Respawns after ffmpeg dies before the pusher gives up
+    max_restarts: u32,
+    // How long a segment may wait for ffmpeg to take it before the pusher is restarted
+    write_timeout: Duration,
+    program_date_times: Arc<ProgramDateTimes>,
+}
+
+// Where a single pusher reads its inputs from and writes its playlist to
+struct PusherOutput {
+    output_dir: String,
+    fifo_dir: PathBuf,
+    playlist_name: String,
+    segment_prefix: String,
+    has_video: bool,
+    has_audio: bool,
+}
+
+impl PusherOutput {
+    fn launch(
+        &self,
+        config: &PusherConfig,
+        continue_playlist: bool,
+    ) -> anyhow::Result<(Child, Option<FifoWriter>, Option<FifoWriter>)> {
+        // Each launch gets FIFOs of its own, so the writer of a previous ffmpeg can't open
+        // the input of the next one
+        let launch = FIFO_LAUNCHES.fetch_add(1, Ordering::Relaxed);
+        let video_fifo = self.has_video.then(|| {
+            self.fifo_dir
+                .join(format!("{}_video_{}.fifo", self.segment_prefix, launch))
+        });
+        let audio_fifo = self.has_audio.then(|| {
+            self.fifo_dir
+                .join(format!("{}_audio_{}.fifo", self.segment_prefix, launch))
+        });
+        for fifo in video_fifo.iter().chain(&audio_fifo) {
+            make_fifo(fifo)?;
+        }
+
+        let mut child = ffmpeg_command()
+            .args(hls_args(
+                video_fifo.as_deref(),
+                audio_fifo.as_deref(),
+                self,
+                config,
+                continue_playlist,
+            ))
+            .stdin(Stdio::null())
             .stdout(Stdio::null())
             .stderr(Stdio::piped())
             .spawn()?;
 
-        let ffmpeg_stdin = child.stdin.take().unwrap();
-
         let stderr = child.stderr.take().map(BufReader::new);
         // Spawn a thread to read stderr
         std::thread::spawn(move || {
@@ -75,604 +162,4007 @@ impl LiveHlsPusher {
             }
         });
 
-        Ok(Self {
+        let timeout = config.write_timeout;
+        Ok((
             child,
-            ffmpeg_stdin,
-        })
+            video_fifo.map(|fifo| FifoWriter::spawn(fifo, timeout)),
+            audio_fifo.map(|fifo| FifoWriter::spawn(fifo, timeout)),
+        ))
     }
 
-    pub fn write(&mut self, data: &[u8]) -> anyhow::Result<()> {
-        self.ffmpeg_stdin.write_all(data)?;
-        self.ffmpeg_stdin.flush()?;
-        Ok(())
+    fn playlist_path(&self) -> PathBuf {
+        Path::new(&self.output_dir).join(&self.playlist_name)
     }
 
-    pub fn kill(&mut self) -> anyhow::Result<()> {
-        match self.child.kill() {
-            Ok(_) => Ok(()),
-            Err(e) => {
-                error!("Error killing ffmpeg pusher process: {}", e);
-                Err(e.into())
-            }
+    // Where the next ffmpeg run starts listing segments. A new playlist replaces the old
+    // one, which is removed first so its segments aren't taken for the run's.
+    fn pending_anchor(&self, continue_playlist: bool) -> PendingAnchor {
+        let after = if continue_playlist {
+            fs::read_to_string(self.playlist_path())
+                .ok()
+                .and_then(|content| playlist::last_segment(&content).map(str::to_string))
+        } else {
+            fs::remove_file(self.playlist_path()).ok();
+            None
+        };
+        PendingAnchor {
+            after,
+            presented: None,
         }
     }
 }
 
-// DASH-to-HLS converter implementation
-pub struct DashToHlsConverter {
-    stream_info: StreamInfo,
-    is_active: bool,
-    sequence_number: u32,
-    temp_dir: PathBuf,
-    last_processed_segments: (Vec<String>, Vec<String>),
-    pusher: LiveHlsPusher,
-}
-
-impl DashToHlsConverter {
-    pub fn new(
+impl LiveHlsPusher {
+    pub fn spawn(
         output_dir: &str,
-        stream_info: StreamInfo,
-        max_segments: u32,
-        segment_duration: u32,
-    ) -> io::Result<Self> {
-        // Create output directory
-        fs::create_dir_all(output_dir)?;
-
-        let temp_dir = match tempdir() {
-            Ok(dir) => dir.into_path(),
-            Err(_) => {
-                let path = PathBuf::from(format!("{}/temp", output_dir));
-                fs::create_dir_all(&path)?;
-                path
-            }
+        fifo_dir: &Path,
+        playlist_name: &str,
+        segment_prefix: &str,
+        config: &PusherConfig,
+        has_video: bool,
+        stats: &Arc<StreamStats>,
+    ) -> anyhow::Result<Self> {
+        let output = PusherOutput {
+            output_dir: output_dir.to_string(),
+            fifo_dir: fifo_dir.to_path_buf(),
+            playlist_name: playlist_name.to_string(),
+            segment_prefix: segment_prefix.to_string(),
+            has_video,
+            has_audio: true,
         };
-
-        let pusher = LiveHlsPusher::spawn(output_dir, max_segments, segment_duration).unwrap();
+        let pending_anchor = output.pending_anchor(false);
+        let (child, video_input, audio_input) = output.launch(config, false)?;
 
         Ok(Self {
-            stream_info,
-            is_active: false,
-            sequence_number: 0,
-            temp_dir,
-            last_processed_segments: (Vec::new(), Vec::new()),
-            pusher,
+            child,
+            video_input,
+            audio_input,
+            output,
+            config: config.clone(),
+            stats: Arc::clone(stats),
+            crash_restarts: 0,
+            launched_at: Instant::now(),
+            pending_anchor: Some(pending_anchor),
+            codec_checked: false,
         })
     }
 
-    fn start(&mut self) -> io::Result<()> {
-        if self.is_active {
+    // Moves a pusher copying video MPEG-TS can't carry to fMP4 segments or to H.264, as the
+    // channel asks. Only checked before the first segment, so ffmpeg is simply started over.
+    fn adapt_to_codec(
+        &mut self,
+        codecs: Option<&str>,
+        policy: IncompatibleCodec,
+    ) -> anyhow::Result<()> {
+        if self.codec_checked {
             return Ok(());
         }
+        self.codec_checked = true;
 
-        info!("Starting converter for stream: {}", self.stream_info.id);
-        self.is_active = true;
-
-        Ok(())
-    }
-
-    fn process_mpd(
-        &self,
-    ) -> anyhow::Result<((Vec<String>, Option<String>), (Vec<String>, Option<String>))> {
-        // Parse the MPD
-        let mpd_url = Url::parse(&self.stream_info.url)?;
-        let client = reqwest::blocking::Client::builder()
-            .user_agent("Mozilla/5.0 (Macintosh; Intel Mac OS X 10.15; rv:133.0) Gecko/20100101 Firefox/133.0")
-            .timeout(Duration::from_secs(30))
-            .build()?;
-
-        let mpd_response = client.get(mpd_url.clone()).send()?;
+        let Some(codecs) = codecs else {
+            return Ok(());
+        };
+        let Some(strategy) = codec_strategy(codecs, &self.config, policy) else {
+            return Ok(());
+        };
 
-        if !mpd_response.status().is_success() {
-            anyhow::bail!("Failed to fetch MPD: HTTP {}", mpd_response.status());
+        let playlist = &self.output.playlist_name;
+        match strategy {
+            IncompatibleCodec::Fmp4 => {
+                info!("{} carries {}, writing fMP4 segments", playlist, codecs);
+                self.config.segment_format = SegmentFormat::Fmp4;
+            }
+            IncompatibleCodec::Transcode => {
+                info!("{} carries {}, transcoding it to H.264", playlist, codecs);
+                self.config.video_codec = Some(TS_FALLBACK_ENCODER.to_string());
+            }
+            IncompatibleCodec::Copy => {
+                warn!(
+                    "{} copies {} into MPEG-TS segments, players won't be able to play it",
+                    playlist, codecs
+                );
+                return Ok(());
+            }
         }
+        self.respawn(false)
+    }
 
-        let mpd_content = mpd_response.text()?;
-        let mpd = dash_mpd::parse(&mpd_content)?;
-
-        // Find video and audio representations
-        let mut video_segments = Vec::new();
-        let mut audio_segments = Vec::new();
-        let mut video_init = None;
-        let mut audio_init = None;
-
-        // Try to find representations at specific indices first
-        // If that fails, look for highest quality video and any audio
-        self.extract_segments_from_mpd(
-            &mpd,
-            &mpd_url,
-            &mut video_segments,
-            &mut audio_segments,
-            &mut video_init,
-            &mut audio_init,
-        )?;
-
-        Ok(((video_segments, video_init), (audio_segments, audio_init)))
+    // Moves a pusher to video only, for an MPD without audio. Only done before the first
+    // segment, so ffmpeg is simply started over.
+    fn drop_audio(&mut self) -> anyhow::Result<()> {
+        self.output.has_audio = false;
+        self.respawn(false)
     }
 
-    fn extract_segments_from_mpd(
-        &self,
-        mpd: &MPD,
-        mpd_url: &Url,
-        video_segments: &mut Vec<String>,
-        audio_segments: &mut Vec<String>,
-        video_init: &mut Option<String>,
-        audio_init: &mut Option<String>,
-    ) -> anyhow::Result<()> {
-        let mut video_rep_found = false;
-        let mut audio_rep_found = false;
+    // Replaces the ffmpeg process. When continuing, the new process appends to the existing
+    // playlist after an #EXT-X-DISCONTINUITY instead of starting over.
+    pub fn respawn(&mut self, continue_playlist: bool) -> anyhow::Result<()> {
+        self.kill().ok();
 
-        // First try specific indices
-        let video_index = 6;
-        let audio_index = 9;
+        let pending_anchor = self.output.pending_anchor(continue_playlist);
+        let (child, video_input, audio_input) =
+            self.output.launch(&self.config, continue_playlist)?;
+        self.child = child;
+        self.video_input = video_input;
+        self.audio_input = audio_input;
+        self.pending_anchor = Some(pending_anchor);
+        self.launched_at = Instant::now();
 
-        for period in &mpd.periods {
-            let mut rep_index = 0;
+        Ok(())
+    }
 
-            for adaptation_set in &period.adaptations {
-                for representation in &adaptation_set.representations {
-                    if (adaptation_set.mimeType.as_deref() == Some("video/mp4")
-                        || adaptation_set.contentType.as_deref() == Some("video"))
-                        && rep_index == video_index
-                    {
-                        (*video_segments, *video_init) =
-                            self.extract_segments(&mpd, &representation, &mpd_url)?;
-                        video_rep_found = true;
-                    } else if (adaptation_set.mimeType.as_deref() == Some("audio/mp4")
-                        || adaptation_set.contentType.as_deref() == Some("audio"))
-                        && rep_index == audio_index
-                    {
-                        (*audio_segments, *audio_init) =
-                            self.extract_segments(&mpd, &representation, &mpd_url)?;
-                        audio_rep_found = true;
-                    }
+    pub fn playlist_path(&self) -> PathBuf {
+        self.output.playlist_path()
+    }
 
-                    rep_index += 1;
-                }
+    // Respawns ffmpeg when it exited on its own, continuing its playlist. Segments carry
+    // their init segment, so the new inputs start with one. A run that lasted long enough
+    // to be healthy clears the restarts before it.
+    fn ensure_running(&mut self) -> anyhow::Result<()> {
+        let Some(status) = self.child.try_wait()? else {
+            let healthy_run =
+                Duration::from_secs(u64::from(self.config.segment_time) * HEALTHY_RUN_SEGMENTS);
+            if self.crash_restarts > 0 && self.launched_at.elapsed() >= healthy_run {
+                self.crash_restarts = 0;
             }
+            return Ok(());
+        };
+
+        if self.crash_restarts >= self.config.max_restarts {
+            anyhow::bail!(
+                "ffmpeg for {} exited ({}) after {} restarts, giving up",
+                self.output.playlist_name,
+                status,
+                self.crash_restarts
+            );
         }
 
-        // If specific indices not found, try to use best available
-        if !video_rep_found || !audio_rep_found {
-            info!("Specific representation indices not found, using best available");
+        warn!(
+            "ffmpeg for {} exited ({}), restarting it",
+            self.output.playlist_name, status
+        );
+        self.respawn(true)?;
+        self.crash_restarts += 1;
+        self.stats.record_ffmpeg_restart();
+        Ok(())
+    }
 
-            for period in &mpd.periods {
-                for adaptation_set in &period.adaptations {
-                    // For video, get highest bandwidth representation
-                    if (adaptation_set.mimeType.as_deref() == Some("video/mp4")
-                        || adaptation_set.contentType.as_deref() == Some("video"))
-                        && !video_rep_found
-                    {
-                        if let Some(rep) = adaptation_set
-                            .representations
-                            .iter()
-                            .max_by_key(|r| r.bandwidth.unwrap_or(0))
-                        {
-                            info!(
-                                "Selected video representation with bandwidth: {}",
-                                rep.bandwidth.unwrap_or(0)
-                            );
-                            (*video_segments, *video_init) =
-                                self.extract_segments(&mpd, rep, &mpd_url)?;
-                            video_rep_found = true;
-                        }
-                    }
-                    // For audio, get first available representation
-                    else if (adaptation_set.mimeType.as_deref() == Some("audio/mp4")
-                        || adaptation_set.contentType.as_deref() == Some("audio"))
-                        && !audio_rep_found
-                    {
-                        if !adaptation_set.representations.is_empty() {
-                            let rep = &adaptation_set.representations[0];
-                            info!(
-                                "Selected audio representation with bandwidth: {}",
-                                rep.bandwidth.unwrap_or(0)
-                            );
-                            (*audio_segments, *audio_init) =
-                                self.extract_segments(&mpd, rep, &mpd_url)?;
-                            audio_rep_found = true;
-                        }
-                    }
+    // Ties the program date times of the current ffmpeg run to the MPD's, once the run
+    // listed the first segment it got. A run whose first segment has no MPD time isn't.
+    fn anchor_program_date_time(&mut self, program_date_time: Option<DateTime<Utc>>) {
+        let Some(pending) = &mut self.pending_anchor else {
+            return;
+        };
+        let presented = match pending.presented {
+            Some(presented) => presented,
+            None => match program_date_time {
+                Some(presented) => *pending.presented.insert(presented),
+                None => {
+                    self.pending_anchor = None;
+                    return;
                 }
-            }
-        }
+            },
+        };
 
-        if !video_rep_found {
-            info!("No video representation found");
+        let Ok(content) = fs::read_to_string(self.output.playlist_path()) else {
+            return;
+        };
+        if let Some(listed) = playlist::first_program_date_time(&content, pending.after.as_deref())
+        {
+            self.config
+                .program_date_times
+                .anchor(&self.output.playlist_name, listed, presented);
+            self.pending_anchor = None;
         }
+    }
 
-        if !audio_rep_found {
-            info!("No audio representation found");
+    // Queues a decrypted audio segment, if the pusher takes audio, and the video segments
+    // of its window, each carrying its init segment, along with the wall-clock time the MPD
+    // presents them at
+    pub async fn write(
+        &mut self,
+        videos: &[Vec<u8>],
+        audio: Option<&[u8]>,
+        program_date_time: Option<DateTime<Utc>>,
+    ) -> anyhow::Result<()> {
+        self.ensure_running()?;
+        self.anchor_program_date_time(program_date_time);
+        let Some(video_input) = &mut self.video_input else {
+            anyhow::bail!("Pusher has no video input");
+        };
+        let mut written = Ok(());
+        for video in videos {
+            written = video_input.write(video).await;
+            if written.is_err() {
+                break;
+            }
         }
-
-        Ok(())
+        if written.is_ok()
+            && let (Some(audio_input), Some(audio)) = (&mut self.audio_input, audio)
+        {
+            written = audio_input.write(audio).await;
+        }
+        self.fail_on_error(written)
     }
 
-    fn extract_segments(
-        &self,
-        mpd: &MPD,
-        representation: &Representation,
-        base_url: &Url,
-    ) -> anyhow::Result<(Vec<String>, Option<String>)> {
-        let mut segments = Vec::new();
-        let mut init_segment = None;
-        let mut base_url_str = base_url.to_string();
-
-        // Check if we have BaseURL at MPD level
-        if let Some(mpd_base_url) = representation.BaseURL.first() {
-            base_url_str = mpd_base_url.base.clone();
-        }
+    // Queues a decrypted segment of an audio-only pusher
+    pub async fn write_audio(
+        &mut self,
+        audio: &[u8],
+        program_date_time: Option<DateTime<Utc>>,
+    ) -> anyhow::Result<()> {
+        self.ensure_running()?;
+        self.anchor_program_date_time(program_date_time);
+        let Some(audio_input) = &mut self.audio_input else {
+            anyhow::bail!("Pusher has no audio input");
+        };
+        let written = audio_input.write(audio).await;
+        self.fail_on_error(written)
+    }
 
-        // Check if period has BaseURL
-        let period = mpd
-            .periods
-            .first()
-            .ok_or_else(|| anyhow::anyhow!("No period found"))?;
-        if let Some(period_base_url) = period.BaseURL.first() {
-            base_url_str = if period_base_url.base.starts_with("http") {
-                period_base_url.base.clone()
-            } else {
-                format!(
-                    "{}/{}",
-                    base_url_str.trim_end_matches('/'),
-                    period_base_url.base
-                )
-            };
+    // An ffmpeg that stopped reading its inputs is killed, the next write restarts it like
+    // one that crashed
+    fn fail_on_error(&mut self, written: anyhow::Result<()>) -> anyhow::Result<()> {
+        if written.is_err() {
+            self.kill().ok();
         }
+        written
+    }
 
-        // Handle representation BaseURL
-        if let Some(rep_base_url) = representation.BaseURL.first() {
-            base_url_str = if rep_base_url.base.starts_with("http") {
-                rep_base_url.base.clone()
-            } else {
-                format!(
-                    "{}/{}",
-                    base_url_str.trim_end_matches('/'),
-                    rep_base_url.base
-                )
-            };
+    // Ends the inputs and lets ffmpeg flush the last segment and end its playlist, killing
+    // it if that takes longer than `timeout`. Blocks meanwhile, so callers run it on the
+    // blocking pool on purpose (finish_pushers, and stop through web::block).
+    pub fn finish(mut self, timeout: Duration) -> anyhow::Result<()> {
+        self.video_input = None;
+        if let Some(audio_input) = &mut self.audio_input {
+            audio_input.close();
         }
 
-        // Handle different types of segment information
-        if let Some(segment_template) = representation.SegmentTemplate.as_ref().or_else(|| {
-            // Try to get segment template from parent adaptation set
-            mpd.periods
-                .iter()
-                .flat_map(|p| &p.adaptations)
-                .find(|a| a.representations.iter().any(|r| r.id == representation.id))
-                .and_then(|a| a.SegmentTemplate.as_ref())
-        }) {
-            if let Some(init_template) = &segment_template.initialization {
-                let init_url = init_template.replace(
-                    "$RepresentationID$",
-                    &representation.id.clone().unwrap_or_default(),
+        let deadline = Instant::now() + timeout;
+        while self.child.try_wait()?.is_none() {
+            if Instant::now() >= deadline {
+                warn!(
+                    "ffmpeg for {} didn't finish within {:?}, killing it",
+                    self.output.playlist_name, timeout
                 );
+                self.child.kill().ok();
+                self.child.wait()?;
+                break;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        Ok(())
+    }
 
-                // Resolve init segment URL against base URL
-                let full_init_url = if init_url.starts_with("http") {
-                    init_url
-                } else {
-                    if let Ok(mut parsed) = Url::parse(&base_url_str) {
-                        parsed.path_segments_mut().unwrap();
-                        parsed.join(&init_url).unwrap().to_string()
-                    } else {
-                        "broken".to_string()
-                    }
-                };
-
-                init_segment = Some(full_init_url);
+    pub fn kill(&mut self) -> anyhow::Result<()> {
+        match self.child.kill() {
+            Ok(_) => {
+                self.child.wait().ok();
+                Ok(())
+            }
+            Err(e) => {
+                error!("Error killing ffmpeg pusher process: {}", e);
+                Err(e.into())
             }
-            // Handle templated segments
-            let duration = segment_template.duration.unwrap_or(1.0);
-            let timescale = segment_template.timescale.unwrap_or(1);
+        }
+    }
+}
 
-            let segment_count = if let Some(timeline) = &segment_template.SegmentTimeline {
-                timeline.segments.len()
-            } else {
-                // Estimate number of segments from MPD duration
-                let period_duration = period.duration.unwrap_or(Duration::new(60, 0));
-                ((period_duration.as_secs() * timescale as u64) as f64 / duration) as usize
-            };
+// A pusher dropped without finish(), by an error path or a panic unwinding, still takes its
+// ffmpeg down. One already finished or killed has been reaped and is left alone.
+impl Drop for LiveHlsPusher {
+    fn drop(&mut self) {
+        if let Ok(None) = self.child.try_wait() {
+            warn!(
+                "Pusher of {} dropped while ffmpeg runs, killing it",
+                self.output.playlist_name
+            );
+            self.kill().ok();
+        }
+    }
+}
 
-            // Limit to 10-20 segments for live streams
-            let is_live = mpd.mpdtype.as_deref() == Some("dynamic");
-            let segment_count = if is_live {
-                20.min(segment_count)
-            } else {
-                segment_count
-            };
+// Feeds one input FIFO of the pusher from a dedicated thread, so a full pipe on one track
+// never blocks the other one, nor the runtime the converter runs on
+struct FifoWriter {
+    sender: mpsc::Sender<Vec<u8>>,
+    initialized: bool,
+    timeout: Duration,
+}
 
-            let times = if let Some(timeline) = &segment_template.SegmentTimeline {
-                compute_segment_times(&timeline.segments)
-            } else {
-                // Fallback to number-based generation
-                (0..segment_count)
-                    .map(|i| i as i64 * duration as i64)
-                    .collect()
-            };
+// Segments queued for the FIFO thread, beyond which writes wait for ffmpeg to catch up
+const FIFO_QUEUE_SEGMENTS: usize = 4;
 
-            for time in times {
-                if let Some(media) = &segment_template.media {
-                    let segment_url = media
-                        .replace(
-                            "$RepresentationID$",
-                            &representation.id.clone().unwrap_or_default(),
-                        )
-                        .replace("$Time$", &time.to_string());
-
-                    // Resolve segment URL against base URL
-                    let full_url = if segment_url.starts_with("http") {
-                        segment_url
-                    } else {
-                        if let Ok(mut parsed) = Url::parse(&base_url_str) {
-                            parsed.path_segments_mut().unwrap();
-                            parsed.join(&segment_url).unwrap().to_string()
-                        } else {
-                            "broken".to_string()
-                        }
-                    };
+impl FifoWriter {
+    fn spawn(path: PathBuf, timeout: Duration) -> Self {
+        let (sender, mut receiver) = mpsc::channel::<Vec<u8>>(FIFO_QUEUE_SEGMENTS);
 
-                    segments.push(full_url);
+        thread::spawn(move || {
+            match open_fifo_writer(&path, || receiver.is_closed()) {
+                Ok(Some(mut fifo)) => {
+                    while let Some(chunk) = receiver.blocking_recv() {
+                        if let Err(e) = fifo.write_all(&chunk) {
+                            error!("Error writing to {}: {}", path.display(), e);
+                            break;
+                        }
+                    }
                 }
+                Ok(None) => {}
+                Err(e) => error!("Error opening {}: {}", path.display(), e),
             }
-        } else if let Some(segment_list) = &representation.SegmentList {
-            // Handle segment list
-            for segment in &segment_list.segment_urls {
-                if let Some(media) = &segment.media {
-                    // Resolve segment URL against base URL
-                    let full_url = if media.starts_with("http") {
-                        media.clone()
-                    } else {
-                        format!("{}/{}", base_url_str.trim_end_matches('/'), media)
-                    };
 
-                    segments.push(full_url);
-                }
-            }
-        } else if let Some(base_url_str) = &representation.BaseURL.get(0) {
-            // Handle single segment representation
-            segments.push(base_url_str.base.clone());
-        } else {
-            anyhow::bail!("Could not find segment information for representation");
-        }
+            fs::remove_file(&path).ok();
+        });
 
-        // If it's a live stream, only keep the last few segments
-        let is_live = mpd.mpdtype.as_deref() == Some("dynamic");
-        if is_live && segments.len() > 20 {
-            segments = segments
-                .clone()
-                .into_iter()
-                .skip(segments.len() - 20)
-                .collect();
+        Self {
+            sender,
+            initialized: false,
+            timeout,
         }
-
-        Ok((segments, init_segment))
     }
 
-    fn decrypt_segment(&self, data: &[u8]) -> anyhow::Result<Vec<u8>> {
-        // Check if we have a key to decrypt with
-        if self.stream_info.key.is_empty() {
-            // No decryption needed, just write the data
-            return Ok(data.to_vec());
-        }
-
-        let keys =
-            std::collections::HashMap::from([("1".to_owned(), self.stream_info.key.clone())]);
-        let mp4decrypt_result = mp4decrypt::mp4decrypt(data, keys, None);
-
-        match &mp4decrypt_result {
-            Ok(output) => {
-                return Ok(output.to_vec());
-            }
-            Err(e) => {
-                error!("Failed to run ffmpeg: {}", e);
-
-                // If that fails, try using ffmpeg as fallback
-                let mut child = Command::new("ffmpeg")
-                    .arg("-y")
-                    .arg("-decryption_key")
-                    .arg(&self.stream_info.key)
-                    .arg("-i")
-                    .arg("pipe:0")
-                    .arg("-c")
-                    .arg("copy")
-                    .arg("pipe:0")
-                    .stdin(Stdio::piped())
-                    .stdout(Stdio::piped())
-                    .stderr(Stdio::null())
-                    .spawn()?;
-
-                if let Some(mut input) = child.stdin.take() {
-                    input.write_all(data)?;
-                    input.flush()?;
-                }
+    // The first segment goes through whole, later ones without their init boxes so ffmpeg
+    // reads a single continuous fragmented MP4
+    async fn write(&mut self, segment: &[u8]) -> anyhow::Result<()> {
+        let chunk = if self.initialized {
+            strip_init_boxes(segment)
+        } else {
+            self.initialized = true;
+            segment.to_vec()
+        };
 
-                if let Some(mut output) = child.stdout.take() {
-                    let mut out: Vec<u8> = Vec::new();
-                    output.read_to_end(&mut out)?;
-                    return Ok(out);
-                }
-            }
+        // A hung ffmpeg would otherwise have segments pile up in memory
+        match actix_web::rt::time::timeout(self.timeout, self.sender.send(chunk)).await {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(_)) => anyhow::bail!("ffmpeg input is closed"),
+            Err(_) => anyhow::bail!("ffmpeg didn't read its input within {:?}", self.timeout),
         }
+    }
 
-        Ok(data.to_vec())
+    // Lets the thread write what's queued and close the FIFO, ffmpeg then sees its end
+    fn close(&mut self) {
+        self.sender = mpsc::channel(1).0;
     }
+}
 
-    fn download_and_process_segments(&mut self) -> anyhow::Result<()> {
-        // Parse MPD and extract segments
-        let ((video_segments, video_init), (audio_segments, audio_init)) = self.process_mpd()?;
+static FIFO_LAUNCHES: AtomicU64 = AtomicU64::new(0);
 
-        // Skip processing if we have no new segments
-        if video_segments == self.last_processed_segments.0
-            && audio_segments == self.last_processed_segments.1
-        {
-            return Ok(());
-        }
+// How often the FIFO thread checks whether ffmpeg opened its end yet
+const FIFO_OPEN_RETRY: Duration = Duration::from_millis(20);
 
-        let client = reqwest::blocking::Client::builder()
-            .user_agent("Mozilla/5.0 (Macintosh; Intel Mac OS X 10.15; rv:133.0) Gecko/20100101 Firefox/133.0")
-            .timeout(Duration::from_secs(30))
-            .build()?;
-
-        // Download init segments (only once)
-        if let Some(video_init_url) = &video_init {
-            self.stream_info.init_segments.remove("video");
-            if let Ok(resp) = client.get(video_init_url).send() {
-                if resp.status().is_success() {
-                    if let Ok(bytes) = resp.bytes() {
-                        self.stream_info
-                            .init_segments
-                            .insert("video".to_string(), bytes.to_vec());
-                    }
+// Opens the FIFO for writing once ffmpeg opened it for reading. A blocking open would
+// hang for good if ffmpeg died first, so this retries a non-blocking one and gives up
+// with None once `abandoned`, i.e. when the FifoWriter is dropped along its ffmpeg.
+fn open_fifo_writer(path: &Path, abandoned: impl Fn() -> bool) -> io::Result<Option<fs::File>> {
+    loop {
+        let opened = fs::OpenOptions::new()
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(path);
+        match opened {
+            Ok(fifo) => {
+                // Writes block again, so ffmpeg paces them
+                let fd = fifo.as_raw_fd();
+                // SAFETY: fd belongs to `fifo`, which is open
+                let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+                if flags < 0
+                    || unsafe { libc::fcntl(fd, libc::F_SETFL, flags & !libc::O_NONBLOCK) } < 0
+                {
+                    return Err(io::Error::last_os_error());
                 }
+                return Ok(Some(fifo));
             }
-        }
-
-        if let Some(audio_init_url) = &audio_init {
-            self.stream_info.init_segments.remove("audio");
-            if let Ok(resp) = client.get(audio_init_url).send() {
-                if resp.status().is_success() {
-                    if let Ok(bytes) = resp.bytes() {
-                        self.stream_info
-                            .init_segments
-                            .insert("audio".to_string(), bytes.to_vec());
-                    }
+            // Nobody has the FIFO open for reading yet
+            Err(e) if e.raw_os_error() == Some(libc::ENXIO) => {
+                if abandoned() {
+                    return Ok(None);
                 }
+                thread::sleep(FIFO_OPEN_RETRY);
             }
+            Err(e) => return Err(e),
         }
+    }
+}
 
-        let min_len = video_segments.len().min(audio_segments.len());
+// Key of AES-128 encrypted output, served along the segments, and the ffmpeg key info
+// file pointing at it, kept out of the output directory
+const OUTPUT_KEY_FILE: &str = "stream.key";
+const KEY_INFO_FILE: &str = "stream.keyinfo";
 
-        for i in 0..min_len {
-            if !self.is_active {
-                break;
-            }
+// Generates a fresh output key, returning the path of its key info file. Playlists
+// reference the key relatively, so players fetch it through the stream URL.
+fn write_output_key(output_dir: &Path, fifo_dir: &Path) -> io::Result<PathBuf> {
+    let mut key = [0u8; 16];
+    fs::File::open("/dev/urandom")?.read_exact(&mut key)?;
 
-            let video_url = &video_segments[i];
-            let audio_url = &audio_segments[i];
+    let key_path = output_dir.join(OUTPUT_KEY_FILE);
+    fs::write(&key_path, key)?;
 
-            if self.last_processed_segments.0.contains(video_url)
-                && self.last_processed_segments.1.contains(audio_url)
-            {
-                continue;
-            }
+    let key_info_path = fifo_dir.join(KEY_INFO_FILE);
+    fs::write(
+        &key_info_path,
+        format!("{}\n{}\n", OUTPUT_KEY_FILE, key_path.display()),
+    )?;
+    Ok(key_info_path)
+}
+
+// Named pipe only the server's user can open, in place of whatever was at `path`
+fn make_fifo(path: &Path) -> anyhow::Result<()> {
+    fs::remove_file(path).ok();
 
-            // Download and decrypt video
-            let video_data = self.download_and_decrypt_segment(&client, video_url, "video")?;
-            let video_file = self
-                .temp_dir
-                .join(format!("video_{}.mp4", self.sequence_number));
-            fs::write(&video_file, &video_data)?;
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    // SAFETY: c_path is a NUL-terminated string living until the call returns
+    if unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) } != 0 {
+        anyhow::bail!(
+            "mkfifo failed for {}: {}",
+            path.display(),
+            io::Error::last_os_error()
+        );
+    }
+
+    Ok(())
+}
 
-            // Download and decrypt audio
-            let audio_data = self.download_and_decrypt_segment(&client, audio_url, "audio")?;
-            let audio_file = self
-                .temp_dir
-                .join(format!("audio_{}.mp4", self.sequence_number));
-            fs::write(&audio_file, &audio_data)?;
+// Drops the top-level boxes belonging to the init segment (ftyp, moov), keeping the fragments
+fn strip_init_boxes(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut offset = 0;
 
-            // Mux both streams with FFmpeg
-            let ts_data = mux_to_ts(&video_file, &audio_file)?;
-            self.pusher.write(&ts_data)?;
+    while offset + 8 <= data.len() {
+        let size32 = u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap()) as usize;
+        let box_type = &data[offset + 4..offset + 8];
+        let size = match size32 {
+            0 => data.len() - offset,
+            1 if offset + 16 <= data.len() => {
+                u64::from_be_bytes(data[offset + 8..offset + 16].try_into().unwrap()) as usize
+            }
+            _ => size32,
+        };
 
-            fs::remove_file(&video_file).ok();
-            fs::remove_file(&audio_file).ok();
+        // Malformed box: keep the rest untouched rather than guessing
+        if size < 8 || offset + size > data.len() {
+            out.extend_from_slice(&data[offset..]);
+            return out;
         }
 
-        self.last_processed_segments = (video_segments, audio_segments);
-        Ok(())
+        if box_type != b"ftyp" && box_type != b"moov" {
+            out.extend_from_slice(&data[offset..offset + size]);
+        }
+        offset += size;
     }
 
-    fn download_and_decrypt_segment(
-        &self,
-        client: &reqwest::blocking::Client,
-        url: &str,
-        kind: &str,
-    ) -> anyhow::Result<Vec<u8>> {
-        let resp = client.get(url).send()?;
-        if !resp.status().is_success() {
-            anyhow::bail!("HTTP {} on {}", resp.status(), url);
-        }
+    out.extend_from_slice(&data[offset..]);
+    out
+}
 
-        let bytes = resp.bytes()?.to_vec();
-        let combined = if let Some(init) = self.stream_info.init_segments.get(kind) {
-            let mut full = init.clone();
-            full.extend_from_slice(&bytes);
-            full
-        } else {
-            bytes
-        };
+// ffmpeg arguments muxing the video and audio FIFOs into a live HLS playlist, without video
+// for alternate audio renditions and without audio for MPDs that have none
+fn hls_args(
+    video_fifo: Option<&Path>,
+    audio_fifo: Option<&Path>,
+    output: &PusherOutput,
+    config: &PusherConfig,
+    continue_playlist: bool,
+) -> Vec<String> {
+    let mut args: Vec<String> = vec![
+        "-hide_banner".to_string(),
+        "-loglevel".to_string(),
+        "error".to_string(),
+        "-y".to_string(),
+    ];
 
-        let decrypted = self.decrypt_segment(&combined)?;
-        Ok(decrypted)
+    let inputs: Vec<(&Path, &str)> = video_fifo
+        .map(|fifo| (fifo, "v"))
+        .into_iter()
+        .chain(audio_fifo.map(|fifo| (fifo, "a")))
+        .collect();
+    for (fifo, _) in &inputs {
+        args.extend(["-i".to_string(), fifo.display().to_string()]);
     }
-
-    pub fn stop(&mut self) -> anyhow::Result<()> {
-        self.is_active = false;
-        self.pusher.kill()?;
-        Ok(())
+    for (index, (_, kind)) in inputs.iter().enumerate() {
+        args.extend(["-map".to_string(), format!("{}:{}:0", index, kind)]);
     }
 
-    pub fn run_streaming_loop(converter_arc: Arc<Mutex<Self>>) -> anyhow::Result<()> {
-        {
-            let mut converter = converter_arc.lock().unwrap();
-            converter.start()?;
-        }
+    if video_fifo.is_none() {
+        return audio_hls_args(args, output, config, continue_playlist);
+    }
 
-        loop {
-            {
-                let mut converter = converter_arc.lock().unwrap();
-                if !converter.is_active {
-                    break;
-                }
-                if let Err(e) = converter.download_and_process_segments() {
-                    error!(
-                        "Error processing segments for {}: {}",
-                        converter.stream_info.id, e
-                    );
-                    // Short pause to avoid rapid fail loops
-                    thread::sleep(Duration::from_secs(1));
-                }
+    // -maxrate/-bufsize only mean something to an encoder, copied video keeps its bitrate
+    match &config.video_codec {
+        Some(codec) => {
+            args.extend(["-c:v".to_string(), codec.clone()]);
+            // Parts are cut at any frame, low-latency segments still start on a keyframe
+            if config.parts.is_some() {
+                args.extend([
+                    "-force_key_frames".to_string(),
+                    format!("expr:gte(t,n_forced*{})", config.segment_time),
+                ]);
+            }
+            if let Some(max_bitrate) = &config.max_bitrate {
+                args.extend(["-maxrate".to_string(), max_bitrate.clone()]);
+            }
+            if let Some(bufsize) = &config.bufsize {
+                args.extend(["-bufsize".to_string(), bufsize.clone()]);
             }
-
-            // Sleep before fetching updates to MPD
-            thread::sleep(Duration::from_secs(1));
         }
-
-        Ok(())
+        None => args.extend(["-c:v".to_string(), "copy".to_string()]),
     }
+
+    audio_hls_args(args, output, config, continue_playlist)
 }
 
-fn compute_segment_times(timeline: &[S]) -> Vec<i64> {
-    let mut times = Vec::new();
-    let mut current_time = timeline.first().and_then(|s| s.t).unwrap_or(0);
+// Audio encoding and HLS output arguments, following the inputs and mappings
+fn audio_hls_args(
+    mut args: Vec<String>,
+    output: &PusherOutput,
+    config: &PusherConfig,
+    continue_playlist: bool,
+) -> Vec<String> {
+    let output_dir = &output.output_dir;
+    let segment_prefix = &output.segment_prefix;
 
-    for item in timeline {
-        let repeat = item.r.unwrap_or(0);
-        for _ in 0..=repeat {
-            times.push(current_time);
-            current_time += item.d;
-        }
+    let audio_args: &[&str] = if config.audio_copy {
+        &["-c:a", "copy"]
+    } else {
+        &[
+            "-c:a",
+            "aac",
+            "-ac",
+            "2",
+            "-channel_layout",
+            "stereo",
+            "-b:a",
+            "128k",
+            "-ar",
+            "48000",
+        ]
+    };
+    if output.has_audio {
+        args.extend(audio_args.iter().map(|arg| arg.to_string()));
+    }
+    if config.output == OutputFormat::Dash {
+        return dash_args(args, output, config);
+    }
+    args.extend(["-f".to_string(), "hls".to_string()]);
+
+    // Low-latency playlists list parts, enough of them to make up the segment window
+    let (hls_time, list_size) = match config.parts {
+        Some(parts) => (
+            parts.part_time(),
+            u64::from(config.max_segments) * parts.parts_per_segment(),
+        ),
+        None => (
+            config.segment_time.to_string(),
+            u64::from(config.max_segments),
+        ),
+    };
+    args.extend([
+        "-hls_time".to_string(),
+        hls_time,
+        "-hls_list_size".to_string(),
+        list_size.to_string(),
+        "-hls_flags".to_string(),
+        hls_flags(config, continue_playlist),
+    ]);
+
+    if let Some(key_info) = &config.key_info {
+        args.extend([
+            "-hls_key_info_file".to_string(),
+            key_info.display().to_string(),
+        ]);
+    }
+
+    match config.segment_format {
+        SegmentFormat::Ts => args.extend([
+            "-hls_segment_type".to_string(),
+            "mpegts".to_string(),
+            "-hls_segment_filename".to_string(),
+            format!("{}/{}_%03d.ts", output_dir, segment_prefix),
+        ]),
+        SegmentFormat::Fmp4 => args.extend([
+            "-hls_segment_type".to_string(),
+            "fmp4".to_string(),
+            "-hls_fmp4_init_filename".to_string(),
+            format!("{}_init.mp4", segment_prefix),
+            "-hls_segment_filename".to_string(),
+            format!("{}/{}_%03d.m4s", output_dir, segment_prefix),
+        ]),
+    }
+
+    args.push(output.playlist_path().display().to_string());
+    args
+}
+
+// DASH output arguments: a live manifest with templated fMP4 segments. Init segments are
+// rewritten when ffmpeg restarts, so they're named .mp4 to stay out of shared caches.
+fn dash_args(mut args: Vec<String>, output: &PusherOutput, config: &PusherConfig) -> Vec<String> {
+    let segment_prefix = &output.segment_prefix;
+    args.extend([
+        "-f".to_string(),
+        "dash".to_string(),
+        "-seg_duration".to_string(),
+        config.segment_time.to_string(),
+        "-window_size".to_string(),
+        config.max_segments.to_string(),
+        "-use_template".to_string(),
+        "1".to_string(),
+        "-use_timeline".to_string(),
+        "1".to_string(),
+        "-init_seg_name".to_string(),
+        format!("{}_init_$RepresentationID$.mp4", segment_prefix),
+        "-media_seg_name".to_string(),
+        format!("{}_$RepresentationID$_$Number%05d$.m4s", segment_prefix),
+    ]);
+    args.push(output.playlist_path().display().to_string());
+    args
+}
+
+fn hls_flags(config: &PusherConfig, continue_playlist: bool) -> String {
+    // Program date times are moved onto the MPD timeline when the playlist is served
+    let mut flags = "delete_segments+program_date_time".to_string();
+    if continue_playlist {
+        flags.push_str("+append_list+discont_start");
+    }
+    if config.parts.is_some() {
+        flags.push_str("+split_by_time+temp_file");
+    }
+    flags
+}
+
+// ffmpeg binary set from the config at startup
+static FFMPEG_PATH: OnceLock<String> = OnceLock::new();
+
+pub fn set_ffmpeg_path(path: &str) {
+    FFMPEG_PATH.set(path.to_string()).ok();
+}
+
+// Stands ffmpeg in for tests with a script draining the FIFOs of muxing runs, which exits
+// once they end. Other runs, such as decrypting, go to the real ffmpeg.
+#[cfg(test)]
+pub fn set_fake_ffmpeg() {
+    use std::os::unix::fs::PermissionsExt;
+
+    static SCRIPT: OnceLock<PathBuf> = OnceLock::new();
+    let script = SCRIPT.get_or_init(|| {
+        let path = std::env::temp_dir().join(format!("fake-ffmpeg-{}", std::process::id()));
+        fs::write(
+            &path,
+            "#!/bin/sh\ncase \"$*\" in *.fifo*) ;; *) exec ffmpeg \"$@\" ;; esac\n\
+             while [ $# -gt 0 ]; do\n  [ \"$1\" = -i ] && cat \"$2\" > /dev/null &\n  shift\ndone\nwait\n",
+        )
+        .unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        path
+    });
+    set_ffmpeg_path(&script.to_string_lossy());
+}
+
+// Command running the configured ffmpeg, plain `ffmpeg` from PATH until it's set
+pub fn ffmpeg_command() -> Command {
+    Command::new(FFMPEG_PATH.get().map_or("ffmpeg", String::as_str))
+}
+
+// Seconds an origin clock offset is trusted before being measured again
+const CLOCK_SYNC_SECS: u64 = 300;
+
+// Segments an ffmpeg run has to last for its restarts in a row to be forgotten
+const HEALTHY_RUN_SEGMENTS: u64 = 10;
+
+// Encoder of video transcoded because MPEG-TS can't carry its codec
+const TS_FALLBACK_ENCODER: &str = "libx264";
+
+// What a pusher has to change to output video of the given codecs, None when it can be
+// copied into its segments as is
+fn codec_strategy(
+    codecs: &str,
+    config: &PusherConfig,
+    policy: IncompatibleCodec,
+) -> Option<IncompatibleCodec> {
+    let copied_into_ts = config.output == OutputFormat::Hls
+        && config.segment_format == SegmentFormat::Ts
+        && config.video_codec.is_none();
+    (copied_into_ts && !ts_compatible(codecs)).then_some(policy)
+}
+
+// Whether MPEG-TS can carry video of the given RFC 6381 codecs, unknown codecs are assumed
+// to be fine
+fn ts_compatible(codecs: &str) -> bool {
+    const TS_INCOMPATIBLE: [&str; 4] = ["vp08", "vp09", "vp9", "av01"];
+    !codecs.split(',').any(|codec| {
+        let family = codec.trim().split('.').next().unwrap_or_default();
+        TS_INCOMPATIBLE.contains(&family.to_ascii_lowercase().as_str())
+    })
+}
+
+// Sent to origins when the channel doesn't set its own User-Agent
+const DEFAULT_USER_AGENT: &str =
+    "Mozilla/5.0 (Macintosh; Intel Mac OS X 10.15; rv:133.0) Gecko/20100101 Firefox/133.0";
+
+// Group ids of the alternate renditions in the master playlist
+const AUDIO_GROUP: &str = "audio";
+const SUBTITLES_GROUP: &str = "subs";
+
+// Init segments kept in memory before the ones no longer in use are dropped
+const MAX_CACHED_INITS: usize = 32;
+
+// A media or init segment, optionally a byte range of a larger file
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct Segment {
+    url: String,
+    range: Option<(u64, u64)>,
+}
+
+impl Segment {
+    fn whole(url: String) -> Self {
+        Self { url, range: None }
+    }
+
+    // URL of the segment, followed by its byte range if any
+    fn describe(&self) -> String {
+        match self.range {
+            Some((first, last)) => format!("{} bytes={}-{}", self.url, first, last),
+            None => self.url.clone(),
+        }
+    }
+}
+
+// Tracks selected from one MPD refresh
+struct Manifest {
+    video_tracks: Vec<Track>,
+    audio_track: Option<Track>,
+    // Languages other than audio_track's, empty unless the channel offers them
+    audio_renditions: Vec<Track>,
+    // Empty unless the channel converts subtitles
+    subtitle_tracks: Vec<Track>,
+    is_live: bool,
+    // MPD@minimumUpdatePeriod, how often the origin refreshes the manifest
+    update_period: Option<Duration>,
+}
+
+// Segment URLs of a representation and what the master playlist needs to know about it
+struct Track {
+    segments: Vec<Segment>,
+    // Wall-clock presentation time of each segment, known for live templated MPDs
+    program_date_times: Vec<Option<DateTime<Utc>>>,
+    // Presentation time of each segment in seconds, known for templated MPDs
+    start_times: Vec<Option<f64>>,
+    // Init segment of each period, with the index of the period's first segment
+    inits: Vec<(usize, Segment)>,
+    bandwidth: u64,
+    resolution: Option<(u64, u64)>,
+    // cenc:default_KID of the track, lowercase hex without dashes
+    default_kid: Option<String>,
+    // Lowercase ids of the DRM systems announced by ContentProtection
+    drm_systems: Vec<String>,
+    lang: Option<String>,
+    // Nominal segment length in seconds, when the MPD declares one
+    segment_duration: Option<f64>,
+    // RFC 6381 codecs of the representation
+    codecs: Option<String>,
+}
+
+impl Track {
+    // Init segment the segment at `index` has to be decrypted with
+    fn init_for(&self, index: usize) -> Option<&Segment> {
+        self.inits
+            .iter()
+            .rev()
+            .find(|(start, _)| *start <= index)
+            .map(|(_, init)| init)
+    }
+
+    fn program_date_time(&self, index: usize) -> Option<DateTime<Utc>> {
+        self.program_date_times.get(index).copied().flatten()
+    }
+
+    // How far the segment at `index` is behind the newest one of the MPD, when both times
+    // are known
+    fn latency_at(&self, index: usize) -> Option<Duration> {
+        let current = self.start_times.get(index).copied().flatten()?;
+        let latest = self.start_times.last().copied().flatten()?;
+        Some(segment_latency(latest, current))
+    }
+}
+
+// Time between the start of the newest segment and of the one being pushed
+fn segment_latency(latest: f64, current: f64) -> Duration {
+    Duration::from_secs_f64((latest - current).max(0.0))
+}
+
+// DASH-to-HLS converter implementation
+pub struct DashToHlsConverter {
+    stream_info: StreamInfo,
+    config: ConverterTOML,
+    // Only manifests are fetched compressed, media is already compressed and often ranged
+    downloader: Downloader,
+    manifest_client: reqwest::Client,
+    output_dir: String,
+    is_active: bool,
+    // Video segments per variant, and audio segments
+    last_processed_segments: (Vec<Vec<Segment>>, Vec<Segment>),
+    // Variant and segment, video or audio, of everything already handed to ffmpeg
+    pushed_pairs: HashSet<(usize, Segment)>,
+    // Last warning about tracks that don't line up, repeated once a minute at most
+    pairing_warned: Option<Instant>,
+    master_playlist: String,
+    pushers: Vec<LiveHlsPusher>,
+    stats: Arc<StreamStats>,
+    program_date_times: Arc<ProgramDateTimes>,
+    // Start of the current origin outage, and whether its held playlist was given up
+    outage_since: Option<Instant>,
+    held_playlist_dropped: bool,
+    // Where the errors of the stream are announced
+    events: Option<EventSender>,
+    // Segments expected after the last one listed of each live $Number$ representation,
+    // by that last segment
+    upcoming_segments: Mutex<HashMap<Segment, Vec<Segment>>>,
+    // Segments fetched before the MPD listed them, waiting to be pushed
+    prefetched: HashMap<Segment, Vec<u8>>,
+    // Offset of the origin's clock from ours per the MPD's UTCTiming, and when it was taken
+    clock_offset: Mutex<Option<(chrono::TimeDelta, Instant)>>,
+    // Configured keys, plus the bare key bound to the KIDs announced by the MPD
+    decryption_keys: HashMap<String, String>,
+    // Set once the MPD was found announcing none of the configured DRM systems
+    drm_mismatch_logged: bool,
+    decryptor: Arc<dyn decrypt::Decryptor>,
+    fallback_decryptor: Option<Arc<dyn decrypt::Decryptor>>,
+    // When each KID was last announced, rotated out ones are kept for a while
+    kid_last_seen: HashMap<String, Instant>,
+    // Whether the last MPD was dynamic, to notice live events ending
+    was_live: bool,
+    // Set once the pushers were started over without audio for an MPD that has none
+    video_only: bool,
+    // Pause between MPD fetches, following the manifest's update period
+    poll_interval: Duration,
+    // Exclusive use of output_dir, released on stop
+    output_lock: Option<lock::OutputLock>,
+    // Init segment currently held in init_segments for each kind
+    loaded_inits: HashMap<String, Segment>,
+    // Downloaded init segments by resolved URL and range, so switching back to a
+    // representation or period doesn't fetch its init again
+    init_cache: HashMap<Segment, Vec<u8>>,
+    // WebVTT playlists of the subtitle tracks, and the segments already written to them
+    subtitle_writers: Vec<subtitles::SubtitleWriter>,
+    pushed_subtitles: HashSet<(usize, Segment)>,
+    // Audio-only pushers of the alternate languages, with their language codes
+    audio_pushers: Vec<LiveHlsPusher>,
+    audio_langs: Vec<String>,
+    pushed_audio: HashSet<(usize, Segment)>,
+    fifo_dir: fifo_dir::FifoDir,
+    // HLS window and segment length, for the outputs spawned after the converter
+    max_segments: u32,
+    segment_duration: u32,
+}
+
+impl DashToHlsConverter {
+    pub fn new(
+        output_dir: &str,
+        stream_info: StreamInfo,
+        config: ConverterTOML,
+        max_segments: u32,
+        segment_duration: u32,
+    ) -> io::Result<Self> {
+        // Create output directory
+        fs::create_dir_all(output_dir)?;
+        let output_lock = if config.lock_output_dir {
+            Some(lock::OutputLock::acquire(output_dir)?)
+        } else {
+            None
+        };
+        let client = origin_client(
+            &stream_info,
+            false,
+            Duration::from_secs(config.segment_timeout_secs),
+        )
+        .map_err(io::Error::other)?;
+        let manifest_client = origin_client(
+            &stream_info,
+            true,
+            Duration::from_secs(config.mpd_timeout_secs),
+        )
+        .map_err(io::Error::other)?;
+
+        let fifo_dir = fifo_dir::FifoDir::create(output_dir)?;
+        let temp_dir = fifo_dir.path().to_path_buf();
+
+        let key_info = if stream_info.encrypt_output {
+            Some(write_output_key(Path::new(output_dir), &temp_dir)?)
+        } else {
+            None
+        };
+
+        // A single variant is written straight to master.m3u8, several ones (or one along
+        // with other renditions) get their own media playlists referenced by a generated
+        // master playlist
+        let program_date_times = Arc::new(ProgramDateTimes::default());
+        let pusher_config = |variant: usize| {
+            let rendition = stream_info.renditions.get(variant);
+            PusherConfig {
+                max_segments,
+                segment_time: segment_duration,
+                segment_format: stream_info.segment_format,
+                output: stream_info.output,
+                video_codec: stream_info.video_codec.clone(),
+                max_bitrate: rendition
+                    .and_then(|r| r.max_bitrate.clone())
+                    .or_else(|| stream_info.max_bitrate.clone()),
+                bufsize: rendition
+                    .and_then(|r| r.bufsize.clone())
+                    .or_else(|| stream_info.bufsize.clone()),
+                audio_copy: stream_info.audio_copy,
+                parts: stream_info.part_layout(),
+                key_info: key_info.clone(),
+                max_restarts: config.max_ffmpeg_restarts,
+                write_timeout: Duration::from_secs(config.mux_timeout_secs),
+                program_date_times: Arc::clone(&program_date_times),
+            }
+        };
+        let stats = Arc::new(StreamStats::default());
+        let downloader = Downloader::new(
+            client,
+            &config,
+            stream_info
+                .download_rate_limit
+                .unwrap_or(config.download_rate_limit),
+            Arc::clone(&stats),
+        );
+
+        let pushers = if !separate_master_playlist(&stream_info) {
+            vec![
+                LiveHlsPusher::spawn(
+                    output_dir,
+                    &temp_dir,
+                    stream_info.output.manifest_name(),
+                    "segment",
+                    &pusher_config(0),
+                    true,
+                    &stats,
+                )
+                .map_err(io::Error::other)?,
+            ]
+        } else {
+            (0..stream_info.variants)
+                .map(|i| {
+                    LiveHlsPusher::spawn(
+                        output_dir,
+                        &temp_dir,
+                        &format!("variant_{}.m3u8", i),
+                        &format!("variant_{}", i),
+                        &pusher_config(i as usize),
+                        true,
+                        &stats,
+                    )
+                })
+                .collect::<anyhow::Result<Vec<_>>>()
+                .map_err(io::Error::other)?
+        };
+
+        let stream_info_keys = stream_info.keys.clone();
+        let (decryptor, fallback_decryptor) =
+            decrypt::for_channel(stream_info.decryptor, !stream_info.keys.is_empty());
+        let poll_interval = Duration::from_millis(config.poll_default_ms);
+        Ok(Self {
+            stream_info,
+            config,
+            downloader,
+            manifest_client,
+            output_dir: output_dir.to_string(),
+            is_active: false,
+            last_processed_segments: (Vec::new(), Vec::new()),
+            pushed_pairs: HashSet::new(),
+            pairing_warned: None,
+            master_playlist: String::new(),
+            pushers,
+            stats,
+            program_date_times,
+            outage_since: None,
+            held_playlist_dropped: false,
+            events: None,
+            upcoming_segments: Mutex::new(HashMap::new()),
+            prefetched: HashMap::new(),
+            clock_offset: Mutex::new(None),
+            decryption_keys: stream_info_keys,
+            drm_mismatch_logged: false,
+            decryptor,
+            fallback_decryptor,
+            kid_last_seen: HashMap::new(),
+            was_live: false,
+            video_only: false,
+            poll_interval,
+            output_lock,
+            loaded_inits: HashMap::new(),
+            init_cache: HashMap::new(),
+            subtitle_writers: Vec::new(),
+            pushed_subtitles: HashSet::new(),
+            audio_pushers: Vec::new(),
+            audio_langs: Vec::new(),
+            pushed_audio: HashSet::new(),
+            fifo_dir,
+            max_segments,
+            segment_duration,
+        })
+    }
+
+    pub fn set_request_id(&mut self, header: &str, id: &str) {
+        self.downloader.set_request_id(header, id);
+    }
+
+    pub fn set_event_sender(&mut self, events: EventSender) {
+        self.events = Some(events);
+    }
+
+    pub fn stats(&self) -> Arc<StreamStats> {
+        Arc::clone(&self.stats)
+    }
+
+    pub fn program_date_times(&self) -> Arc<ProgramDateTimes> {
+        Arc::clone(&self.program_date_times)
+    }
+
+    // Upstream segments of the last MPD refresh, per video variant and for the audio
+    pub fn last_segment_urls(&self) -> (Vec<Vec<String>>, Vec<String>) {
+        describe_processed(&self.last_processed_segments)
+    }
+
+    fn start(&mut self) -> io::Result<()> {
+        if self.is_active {
+            return Ok(());
+        }
+
+        info!("Starting converter for stream: {}", self.stream_info.id);
+        self.is_active = true;
+        self.stats.mark_started();
+
+        Ok(())
+    }
+
+    // #EXT-X-MEDIA entry for a rendition, named after the channel's language map
+    fn rendition_entry(
+        &self,
+        media_type: &str,
+        group_id: &str,
+        lang: &str,
+        default: bool,
+        uri: Option<&str>,
+    ) -> String {
+        playlist::media_entry(
+            media_type,
+            group_id,
+            lang,
+            &self.stream_info.languages,
+            default,
+            uri,
+        )
+    }
+
+    async fn process_mpd(&self) -> anyhow::Result<Manifest> {
+        // Parse the MPD
+        let channel_url = Url::parse(&self.stream_info.url)?;
+
+        // Relative URLs resolve against where the MPD was served from, after redirects
+        let (mpd, mpd_url) = read_mpd(
+            self.downloader
+                .tag(self.manifest_client.get(channel_url.as_str())),
+        )
+        .await?;
+        self.sync_clock(&mpd, &mpd_url).await;
+
+        // Try to find the pinned representations first
+        // If that fails, look for highest quality video and any audio
+        let (video_tracks, audio_track) = self.extract_segments_from_mpd(&mpd, &mpd_url).await?;
+
+        let audio_renditions = match &audio_track {
+            Some(primary) if self.stream_info.audio_languages => {
+                self.extract_audio_renditions(&mpd, &mpd_url, primary)
+                    .await?
+            }
+            _ => Vec::new(),
+        };
+
+        // Broken subtitles shouldn't take the stream down with them
+        let subtitle_tracks = if self.stream_info.subtitles {
+            self.extract_subtitle_tracks(&mpd, &mpd_url)
+                .await
+                .unwrap_or_else(|e| {
+                    warn!(
+                        "Error extracting subtitles of {}: {}",
+                        self.stream_info.id, e
+                    );
+                    Vec::new()
+                })
+        } else {
+            Vec::new()
+        };
+
+        Ok(Manifest {
+            video_tracks,
+            audio_track,
+            audio_renditions,
+            subtitle_tracks,
+            is_live: mpd.mpdtype.as_deref() == Some("dynamic"),
+            update_period: mpd.minimumUpdatePeriod,
+        })
+    }
+
+    // Measures the origin's clock again once the last measure is old enough. A failed
+    // measure keeps the previous offset, or our own clock.
+    async fn sync_clock(&self, mpd: &MPD, mpd_url: &Url) {
+        if mpd.UTCTiming.is_empty() {
+            return;
+        }
+        let synced = self
+            .clock_offset
+            .lock()
+            .unwrap()
+            .is_some_and(|(_, at)| at.elapsed() < Duration::from_secs(CLOCK_SYNC_SECS));
+        if synced {
+            return;
+        }
+
+        match clock::origin_offset(&self.manifest_client, &mpd.UTCTiming, mpd_url).await {
+            Some(offset) => {
+                info!(
+                    "Clock of the origin of {} is {}ms off ours",
+                    self.stream_info.id,
+                    offset.num_milliseconds()
+                );
+                *self.clock_offset.lock().unwrap() = Some((offset, Instant::now()));
+            }
+            None => warn!(
+                "Could not get the origin's time of {}, using the local clock",
+                self.stream_info.id
+            ),
+        }
+    }
+
+    // Time elapsed on the origin's clock since a period of a live MPD started
+    fn live_edge(&self, mpd: &MPD, period: &Period) -> Option<Duration> {
+        if mpd.mpdtype.as_deref() != Some("dynamic") {
+            return None;
+        }
+        let offset = self
+            .clock_offset
+            .lock()
+            .unwrap()
+            .map_or(chrono::TimeDelta::zero(), |(offset, _)| offset);
+        let period_start = chrono::TimeDelta::from_std(period.start.unwrap_or_default()).ok()?;
+        (Utc::now() + offset - mpd.availabilityStartTime? - period_start)
+            .to_std()
+            .ok()
+    }
+
+    async fn extract_segments_from_mpd(
+        &self,
+        mpd: &MPD,
+        mpd_url: &Url,
+    ) -> anyhow::Result<(Vec<Track>, Option<Track>)> {
+        let mut video_tracks = Vec::new();
+        let mut audio_track = None;
+
+        // First try the representations pinned in the channel config
+        let video_rep_id = self.stream_info.video_rep_id.as_deref();
+        let audio_rep_id = self.stream_info.audio_rep_id.as_deref();
+
+        for period in &mpd.periods {
+            for adaptation_set in &period.adaptations {
+                for representation in &adaptation_set.representations {
+                    if is_kind(adaptation_set, "video")
+                        && video_tracks.is_empty()
+                        && video_rep_id.is_some()
+                        && representation.id.as_deref() == video_rep_id
+                    {
+                        video_tracks = vec![
+                            self.extract_track(mpd, adaptation_set, representation, mpd_url)
+                                .await?,
+                        ];
+                    } else if is_kind(adaptation_set, "audio")
+                        && audio_track.is_none()
+                        && audio_rep_id.is_some()
+                        && representation.id.as_deref() == audio_rep_id
+                    {
+                        audio_track = Some(
+                            self.extract_track(mpd, adaptation_set, representation, mpd_url)
+                                .await?,
+                        );
+                    }
+                }
+            }
+        }
+
+        // If pinned representations not found, try to use best available
+        let missing: Vec<(&str, &str, usize)> = [
+            ("video", video_rep_id, video_tracks.is_empty()),
+            ("audio", audio_rep_id, audio_track.is_none()),
+        ]
+        .into_iter()
+        .filter_map(|(kind, rep_id, not_found)| {
+            rep_id
+                .filter(|_| not_found)
+                .map(|rep_id| (kind, rep_id, count_representations(mpd, kind)))
+        })
+        .collect();
+        for (kind, rep_id, available) in &missing {
+            warn!(
+                "Configured {} representation {} of {} not found among {} available",
+                kind, rep_id, self.stream_info.id, available
+            );
+        }
+        if !missing.is_empty() && self.stream_info.rep_fallback == RepFallback::Strict {
+            anyhow::bail!("Pinned representations missing and rep_fallback is strict");
+        }
+
+        if video_tracks.is_empty() || audio_track.is_none() {
+            if !missing.is_empty() {
+                info!("Configured representation ids not found, using best available");
+            }
+
+            for period in &mpd.periods {
+                for adaptation_set in &period.adaptations {
+                    // For video, get the highest bandwidth representations, one per variant
+                    if is_kind(adaptation_set, "video") && video_tracks.is_empty() {
+                        let mut reps: Vec<&Representation> =
+                            adaptation_set.representations.iter().collect();
+                        reps.sort_by_key(|r| std::cmp::Reverse(r.bandwidth.unwrap_or(0)));
+
+                        for rep in reps.into_iter().take(self.stream_info.variants as usize) {
+                            info!(
+                                "Selected video representation with bandwidth: {}",
+                                rep.bandwidth.unwrap_or(0)
+                            );
+                            video_tracks.push(
+                                self.extract_track(mpd, adaptation_set, rep, mpd_url)
+                                    .await?,
+                            );
+                        }
+                    }
+                    // For audio, get first available representation
+                    else if is_kind(adaptation_set, "audio")
+                        && audio_track.is_none()
+                        && let Some(rep) = adaptation_set.representations.first()
+                    {
+                        info!(
+                            "Selected audio representation with bandwidth: {}",
+                            rep.bandwidth.unwrap_or(0)
+                        );
+                        audio_track = Some(
+                            self.extract_track(mpd, adaptation_set, rep, mpd_url)
+                                .await?,
+                        );
+                    }
+                }
+            }
+        }
+
+        if video_tracks.is_empty() {
+            info!("No video representation found");
+        }
+
+        if audio_track.is_none() {
+            info!("No audio representation found");
+        }
+
+        Ok((video_tracks, audio_track))
+    }
+
+    // One audio track per language besides the primary one, taken from the first period
+    // having audio
+    async fn extract_audio_renditions(
+        &self,
+        mpd: &MPD,
+        mpd_url: &Url,
+        primary: &Track,
+    ) -> anyhow::Result<Vec<Track>> {
+        let Some(period) = mpd.periods.iter().find(|period| {
+            period
+                .adaptations
+                .iter()
+                .any(|adaptation_set| is_kind(adaptation_set, "audio"))
+        }) else {
+            return Ok(Vec::new());
+        };
+
+        let mut langs = vec![primary.lang.clone()];
+        let mut tracks = Vec::new();
+        for adaptation_set in &period.adaptations {
+            if !is_kind(adaptation_set, "audio") || langs.contains(&adaptation_set.lang) {
+                continue;
+            }
+            if let Some(rep) = adaptation_set.representations.first() {
+                info!(
+                    "Selected {} audio representation with bandwidth: {}",
+                    adaptation_set.lang.as_deref().unwrap_or("und"),
+                    rep.bandwidth.unwrap_or(0)
+                );
+                tracks.push(
+                    self.extract_track(mpd, adaptation_set, rep, mpd_url)
+                        .await?,
+                );
+                langs.push(adaptation_set.lang.clone());
+            }
+        }
+
+        Ok(tracks)
+    }
+
+    // One track per subtitle adaptation set, as listed in the first period having any
+    async fn extract_subtitle_tracks(
+        &self,
+        mpd: &MPD,
+        mpd_url: &Url,
+    ) -> anyhow::Result<Vec<Track>> {
+        let Some(period) = mpd
+            .periods
+            .iter()
+            .find(|period| period.adaptations.iter().any(is_text))
+        else {
+            return Ok(Vec::new());
+        };
+
+        let mut tracks = Vec::new();
+        for adaptation_set in period.adaptations.iter().filter(|set| is_text(set)) {
+            if let Some(rep) = adaptation_set.representations.first() {
+                tracks.push(
+                    self.extract_track(mpd, adaptation_set, rep, mpd_url)
+                        .await?,
+                );
+            }
+        }
+        Ok(tracks)
+    }
+
+    fn segment_window(&self, mpd: &MPD, segment_duration: Option<f64>) -> Option<usize> {
+        start_mode_window(self.stream_info.start_mode, mpd, || {
+            self.live_window(mpd, segment_duration)
+        })
+    }
+
+    // Segments kept of a live manifest. Hours of time shift buffer would have every one of
+    // them downloaded at start, so the window is bounded by what the playlist can list.
+    fn live_window(&self, mpd: &MPD, segment_duration: Option<f64>) -> usize {
+        let bound = self.config.live_window.max(self.max_segments as usize);
+        time_shift_segments(mpd, segment_duration)
+            .map_or(self.config.live_window, |segments| segments.min(bound))
+            .max(1)
+    }
+
+    async fn extract_track(
+        &self,
+        mpd: &MPD,
+        adaptation_set: &AdaptationSet,
+        representation: &Representation,
+        base_url: &Url,
+    ) -> anyhow::Result<Track> {
+        // Periods play one after the other, the representation being picked again in each
+        let mut segments = Vec::new();
+        let mut program_date_times = Vec::new();
+        let mut presentation_times = Vec::new();
+        let mut inits: Vec<(usize, Segment)> = Vec::new();
+        for period in &mpd.periods {
+            let Some((period_set, period_rep)) =
+                period_counterpart(period, adaptation_set, representation)
+            else {
+                continue;
+            };
+
+            let (period_segments, init, start_times) = self
+                .extract_segments(mpd, period, period_set, period_rep, base_url)
+                .await?;
+
+            // Segments start at availabilityStartTime + Period@start + their own start
+            let period_start = mpd
+                .availabilityStartTime
+                .filter(|_| start_times.len() == period_segments.len())
+                .and_then(|ast| {
+                    chrono::TimeDelta::from_std(period.start.unwrap_or_default())
+                        .ok()
+                        .map(|start| ast + start)
+                });
+            program_date_times.extend((0..period_segments.len()).map(|i| {
+                period_start.and_then(|period_start| {
+                    chrono::TimeDelta::try_milliseconds((start_times[i] * 1000.0) as i64)
+                        .map(|offset| period_start + offset)
+                })
+            }));
+
+            let period_offset = period.start.unwrap_or_default().as_secs_f64();
+            let times_known = start_times.len() == period_segments.len();
+            presentation_times.extend(
+                (0..period_segments.len())
+                    .map(|i| times_known.then(|| period_offset + start_times[i])),
+            );
+
+            if let Some(init) = init
+                && inits.last().is_none_or(|(_, last)| *last != init)
+            {
+                inits.push((segments.len(), init));
+            }
+            segments.extend(period_segments);
+        }
+
+        // Only keep the last few segments, of live streams unless the channel says otherwise
+        let segment_duration = nominal_segment_duration(adaptation_set, representation);
+        if let Some(window) = self.segment_window(mpd, segment_duration)
+            && segments.len() > window
+        {
+            let skipped = segments.len() - window;
+            segments.drain(..skipped);
+            program_date_times.drain(..skipped);
+            presentation_times.drain(..skipped);
+
+            // Periods now starting before the first kept segment only matter for the last one
+            for (start, _) in &mut inits {
+                *start = start.saturating_sub(skipped);
+            }
+            let first_kept = inits.iter().rposition(|(start, _)| *start == 0);
+            if let Some(first_kept) = first_kept {
+                inits.drain(..first_kept);
+            }
+        }
+
+        Ok(Track {
+            segments,
+            program_date_times,
+            start_times: presentation_times,
+            inits,
+            bandwidth: representation.bandwidth.unwrap_or(0),
+            resolution: representation.width.zip(representation.height),
+            default_kid: extract_default_kid(adaptation_set, representation),
+            drm_systems: extract_drm_systems(adaptation_set, representation),
+            lang: adaptation_set
+                .lang
+                .clone()
+                .or_else(|| representation.lang.clone()),
+            segment_duration: nominal_segment_duration(adaptation_set, representation),
+            codecs: representation
+                .codecs
+                .clone()
+                .or_else(|| adaptation_set.codecs.clone()),
+        })
+    }
+
+    async fn extract_segments(
+        &self,
+        mpd: &MPD,
+        period: &Period,
+        adaptation_set: &AdaptationSet,
+        representation: &Representation,
+        base_url: &Url,
+    ) -> anyhow::Result<ExtractedSegments> {
+        // Each level's BaseURL is relative to the one above, down from the MPD URL. Segments
+        // are addressed from the first combination, the others are failover locations.
+        let mut base_urls = [
+            &mpd.base_url,
+            &period.BaseURL,
+            &adaptation_set.BaseURL,
+            &representation.BaseURL,
+        ]
+        .into_iter()
+        .fold(vec![base_url.to_string()], |bases, level| {
+            if level.is_empty() {
+                return bases;
+            }
+            bases
+                .iter()
+                .flat_map(|base| level.iter().map(|l| resolve_base_url(base, Some(l))))
+                .collect()
+        });
+        base_urls.dedup();
+        let base_url_str = base_urls.remove(0);
+        if !base_urls.is_empty() {
+            self.downloader
+                .set_failovers(base_url_str.clone(), base_urls);
+        }
+
+        match SegmentSource::of(period, adaptation_set, representation) {
+            Some(SegmentSource::Template(template)) => {
+                self.template_segments(mpd, period, representation, template, &base_url_str)
+            }
+            Some(SegmentSource::List(segment_list)) => list_segments(segment_list, &base_url_str),
+            Some(SegmentSource::Indexed(segment_base, index_range)) => {
+                self.indexed_segments(segment_base, index_range, &base_url_str)
+                    .await
+            }
+            Some(SegmentSource::Single) => {
+                Ok((vec![Segment::whole(base_url_str)], None, Vec::new()))
+            }
+            None => anyhow::bail!("Could not find segment information for representation"),
+        }
+    }
+
+    // Segments numbered or timed by a SegmentTemplate
+    fn template_segments(
+        &self,
+        mpd: &MPD,
+        period: &Period,
+        representation: &Representation,
+        segment_template: &SegmentTemplate,
+        base_url: &str,
+    ) -> anyhow::Result<ExtractedSegments> {
+        let mut segments = Vec::new();
+        let mut init_segment = None;
+        let mut start_times = Vec::new();
+
+        if let Some(init_template) = &segment_template.initialization {
+            let init_url = fill_template(init_template, representation);
+            init_segment = Some(Segment::whole(resolve_url(base_url, &init_url)?));
+        }
+        let duration = segment_template.duration.unwrap_or(1.0);
+        let timescale = segment_template.timescale.unwrap_or(1);
+
+        // Index of the first segment listed, numbers counting from startNumber
+        let mut first_index = 0;
+        let times = if let Some(timeline) = &segment_template.SegmentTimeline {
+            // The timeline is authoritative, the declared duration is only sanity checked
+            if let Some(period_duration) = period.duration {
+                self.check_timeline_duration(&timeline.segments, timescale, period_duration);
+            }
+
+            let start = timeline_start(
+                &timeline.segments,
+                segment_template.presentationTimeOffset,
+                self.live_edge(mpd, period),
+                timescale,
+            );
+            compute_segment_times(&timeline.segments, start)
+        } else if let Some(live_edge) = self.live_edge(mpd, period)
+            && duration > 0.0
+        {
+            // Live segments are available once fully produced, the window ends at the last
+            // one completed before the live edge
+            let available = (live_edge.as_secs_f64() * timescale as f64 / duration) as usize;
+            let segment_duration = Some(duration / timescale as f64);
+            let window = self
+                .segment_window(mpd, segment_duration)
+                .unwrap_or_else(|| self.live_window(mpd, segment_duration));
+            first_index = available.saturating_sub(window);
+            let presentation_offset = segment_template.presentationTimeOffset.unwrap_or(0) as i64;
+            (first_index..available)
+                .map(|i| presentation_offset + (i as f64 * duration).round() as i64)
+                .collect()
+        } else {
+            // Estimate number of segments from MPD duration
+            let period_duration = period.duration.unwrap_or(Duration::new(60, 0));
+            // Live representations are cut down to their window by extract_track
+            template_segment_times(
+                period_duration,
+                duration,
+                timescale,
+                segment_template.presentationTimeOffset.unwrap_or(0),
+            )
+        };
+
+        // Segments are numbered from startNumber in timeline order, repeats included, so
+        // $Number$ templates work with or without a timeline
+        let presentation_offset = segment_template.presentationTimeOffset.unwrap_or(0) as i64;
+        let start_number = segment_template.startNumber.unwrap_or(1);
+        for (index, time) in times.into_iter().enumerate() {
+            if let Some(media) = &segment_template.media {
+                let segment_url = fill_segment_template(
+                    &fill_template(media, representation),
+                    time,
+                    start_number + (first_index + index) as u64,
+                );
+                segments.push(Segment::whole(resolve_url(base_url, &segment_url)?));
+                start_times.push((time - presentation_offset) as f64 / timescale as f64);
+            }
+        }
+
+        // The next segments of a live $Number$ template are known before being listed
+        let prefetch = self.stream_info.prefetch_segments;
+        if prefetch > 0
+            && mpd.mpdtype.as_deref() == Some("dynamic")
+            && let Some(media) = &segment_template.media
+            && media.contains("$Number")
+            && !media.contains("$Time")
+            && let Some(last) = segments.last()
+        {
+            let next_number = start_number + (first_index + segments.len()) as u64;
+            let upcoming = (next_number..next_number + prefetch as u64)
+                .map(|number| {
+                    let segment_url =
+                        fill_segment_template(&fill_template(media, representation), 0, number);
+                    resolve_url(base_url, &segment_url).map(Segment::whole)
+                })
+                .collect::<anyhow::Result<Vec<Segment>>>()?;
+            self.upcoming_segments
+                .lock()
+                .unwrap()
+                .insert(last.clone(), upcoming);
+        }
+
+        Ok((segments, init_segment, start_times))
+    }
+
+    // Single file addressed by byte ranges, listed in its sidx box
+    async fn indexed_segments(
+        &self,
+        segment_base: &SegmentBase,
+        index_range: &str,
+        base_url: &str,
+    ) -> anyhow::Result<ExtractedSegments> {
+        let (index_first, index_last) = sidx::parse_byte_range(index_range)
+            .ok_or_else(|| anyhow::anyhow!("Invalid indexRange {}", index_range))?;
+        let index = self
+            .downloader
+            .fetch(&Segment {
+                url: base_url.to_string(),
+                range: Some((index_first, index_last)),
+            })
+            .await?;
+
+        // Without an explicit Initialization range the init is everything before the index
+        let init_range = segment_base
+            .initialization
+            .as_ref()
+            .and_then(|init| init.range.as_deref())
+            .and_then(sidx::parse_byte_range)
+            .or((index_first > 0).then(|| (0, index_first - 1)));
+        let init_segment = init_range.map(|range| Segment {
+            url: base_url.to_string(),
+            range: Some(range),
+        });
+
+        let segments = sidx::segment_ranges(&index, index_first)?
+            .into_iter()
+            .map(|range| Segment {
+                url: base_url.to_string(),
+                range: Some(range),
+            })
+            .collect();
+
+        Ok((segments, init_segment, Vec::new()))
+    }
+
+    fn check_timeline_duration(&self, timeline: &[S], timescale: u64, period_duration: Duration) {
+        let timeline_units: i64 = timeline.iter().map(|s| s.d * (s.r.unwrap_or(0) + 1)).sum();
+        let timeline_secs = timeline_units as f64 / timescale as f64;
+        let declared_secs = period_duration.as_secs_f64();
+
+        if (timeline_secs - declared_secs).abs() > self.config.timeline_tolerance_secs {
+            warn!(
+                "SegmentTimeline of {} lasts {:.3}s but the period declares {:.3}s, using the timeline",
+                self.stream_info.id, timeline_secs, declared_secs
+            );
+        }
+    }
+
+    // Every configured key is usable from the start. The keys of the DRM systems the MPD
+    // announces are applied over the others, so they win where systems disagree on a KID.
+    fn select_drm_keys(&mut self, tracks: &[&Track]) {
+        if self.stream_info.drm_keys.is_empty() {
+            return;
+        }
+
+        match announced_drm_keys(&self.stream_info.drm_keys, tracks) {
+            Some(selected) => {
+                for keys in selected {
+                    self.decryption_keys
+                        .extend(keys.iter().map(|(kid, key)| (kid.clone(), key.clone())));
+                }
+            }
+            None if !self.drm_mismatch_logged => {
+                warn!(
+                    "MPD of {} announces none of the configured DRM systems, trying all their keys",
+                    self.stream_info.id
+                );
+                self.drm_mismatch_logged = true;
+            }
+            None => {}
+        }
+    }
+
+    // A bare configured key (bound to track id 1) is also bound to every KID the MPD
+    // announces, so operators don't need to know the KIDs beforehand. After a rotation
+    // the previous KIDs stay bound while their segments may still be in the window.
+    fn bind_default_kids<'a>(&mut self, tracks: impl Iterator<Item = &'a Track>) {
+        let now = Instant::now();
+        let bare_key = self.stream_info.keys.get("1").cloned();
+
+        for kid in tracks.filter_map(|track| track.default_kid.as_ref()) {
+            self.kid_last_seen.insert(kid.clone(), now);
+            if let Some(key) = &bare_key
+                && !self.decryption_keys.contains_key(kid)
+            {
+                info!(
+                    "Using the configured key for KID {} of {}",
+                    kid, self.stream_info.id
+                );
+                self.decryption_keys.insert(kid.clone(), key.clone());
+            }
+        }
+
+        // Configured keys are never evicted, only the KIDs bound from the MPD
+        let retention = Duration::from_secs(self.config.key_retention_secs);
+        let expired: Vec<String> = self
+            .kid_last_seen
+            .iter()
+            .filter(|(_, seen)| now.duration_since(**seen) > retention)
+            .map(|(kid, _)| kid.clone())
+            .collect();
+        for kid in expired {
+            self.kid_last_seen.remove(&kid);
+            if !self.stream_info.keys.contains_key(&kid)
+                && self.decryption_keys.remove(&kid).is_some()
+            {
+                info!(
+                    "Dropping rotated out KID {} of {}",
+                    kid, self.stream_info.id
+                );
+            }
+        }
+    }
+
+    // Distinct keys, most recently announced first, for segments whose KID can't be matched
+    fn recent_keys(&self) -> Vec<&String> {
+        let mut entries: Vec<(&String, &String)> = self.decryption_keys.iter().collect();
+        entries.sort_by_key(|(kid, _)| std::cmp::Reverse(self.kid_last_seen.get(*kid)));
+
+        let mut keys: Vec<&String> = Vec::new();
+        for (_, key) in entries {
+            if !keys.contains(&key) {
+                keys.push(key);
+            }
+        }
+        keys
+    }
+
+    async fn download_and_process_segments(&mut self) -> anyhow::Result<()> {
+        // Parse MPD and extract segments
+        let Manifest {
+            video_tracks,
+            audio_track,
+            audio_renditions,
+            subtitle_tracks,
+            is_live,
+            update_period,
+        } = match self.process_mpd().await {
+            Ok(manifest) => manifest,
+            Err(e) => {
+                self.hold_during_outage();
+                return Err(e);
+            }
+        };
+        if let Some(outage_since) = self.outage_since.take() {
+            self.resume_after_outage(outage_since)?;
+        }
+        self.poll_interval = poll_interval(update_period, &self.config);
+
+        // A live event rewriting its MPD as static has ended, once its last segments are
+        // pushed the playlists get finalized
+        let ended = self.was_live && !is_live && self.config.finalize_on_static;
+        // A static MPD is complete once its segments are pushed, which only matters to
+        // channels keeping their output
+        let complete = !is_live && self.stream_info.persist_output;
+        self.stats.set_live(is_live);
+        let protected_tracks: Vec<&Track> = video_tracks
+            .iter()
+            .chain(audio_track.iter())
+            .chain(&audio_renditions)
+            .collect();
+        self.select_drm_keys(&protected_tracks);
+        self.bind_default_kids(protected_tracks.into_iter());
+        if audio_track.is_none() && !self.video_only {
+            // ffmpeg can only be started over without audio before its first segment
+            if !self.pushed_pairs.is_empty() || !self.last_processed_segments.0.is_empty() {
+                anyhow::bail!("No audio track available");
+            }
+            info!(
+                "{} has no audio, muxing its video alone",
+                self.stream_info.id
+            );
+            for pusher in &mut self.pushers {
+                pusher.drop_audio()?;
+            }
+            self.video_only = true;
+        }
+        // Audio showing up later has no place in pushers started without it
+        let audio_track = audio_track.filter(|_| !self.video_only);
+        // Video segments are muxed along the audio segments, or along the first variant's
+        // without audio
+        let Some(anchor) = audio_track.as_ref().or(video_tracks.first()) else {
+            anyhow::bail!("No video or audio track available");
+        };
+
+        // Skip processing if we have no new segments
+        let processed = processed_segments(&video_tracks, anchor);
+        if processed == self.last_processed_segments {
+            self.was_live = is_live;
+            if ended || complete {
+                self.finalize().await?;
+            }
+            return Ok(());
+        }
+
+        while self.subtitle_writers.len() < subtitle_tracks.len() {
+            let prefix = format!("subtitles_{}", self.subtitle_writers.len());
+            self.subtitle_writers.push(subtitles::SubtitleWriter::new(
+                &self.output_dir,
+                &prefix,
+                self.max_segments,
+                self.segment_duration,
+            ));
+        }
+        self.spawn_audio_pushers(&audio_renditions)?;
+        if separate_master_playlist(&self.stream_info) {
+            self.write_master_playlist(&video_tracks, audio_track.as_ref(), &subtitle_tracks)?;
+        }
+
+        // Video segments of each variant by the audio segment they're muxed with, audio
+        // segments without them are left for a later refresh
+        let pairings: Vec<HashMap<usize, Vec<usize>>> = video_tracks
+            .iter()
+            .take(self.pushers.len())
+            .map(|track| pair_segments(track, anchor).into_iter().collect())
+            .collect();
+        if pairings.iter().any(HashMap::is_empty)
+            && !anchor.segments.is_empty()
+            && self
+                .pairing_warned
+                .is_none_or(|warned| warned.elapsed() >= PAIRING_WARN_INTERVAL)
+        {
+            warn!(
+                "{} has no video and audio segments at the same time",
+                self.stream_info.id
+            );
+            self.pairing_warned = Some(Instant::now());
+        }
+        let pushes = pending_pushes(&video_tracks, anchor, &pairings, &self.pushed_pairs);
+        let rows: Vec<&[PendingPush]> = pushes.chunk_by(|a, b| a.audio == b.audio).collect();
+
+        // Segments are downloaded a window at a time, then decrypted and pushed in order
+        let window = self.config.download_concurrency.max(1);
+        let mut downloaded = HashMap::new();
+
+        for (row, row_pushes) in rows.iter().enumerate() {
+            if row % window == 0 {
+                let pending = download_list(
+                    rows[row..].iter().take(window).copied().flatten(),
+                    &video_tracks,
+                    audio_track.as_ref(),
+                );
+                let (ready, missing) = take_prefetched(&mut self.prefetched, pending);
+                downloaded = self.downloader.fetch_all(missing).await;
+                downloaded.extend(ready);
+            }
+
+            let i = row_pushes[0].audio;
+            let audio_segment = &anchor.segments[i];
+            let mut audio_data = None;
+
+            let backlog = rows[row..]
+                .iter()
+                .filter(|pushes| pushes.iter().any(|push| push.variant == 0))
+                .count();
+            self.stats.set_backlog(backlog as u64);
+
+            for push in row_pushes.iter() {
+                if !self.is_active {
+                    break;
+                }
+                let variant = push.variant;
+                let track = &video_tracks[variant];
+                let videos = &push.videos;
+
+                // Download and decrypt audio, shared by all variants
+                if audio_data.is_none()
+                    && let Some(audio_track) = &audio_track
+                {
+                    self.load_init_segment(audio_track.init_for(i), "audio")
+                        .await;
+                    let bytes = match take_download(&mut downloaded, audio_segment) {
+                        Ok(bytes) => bytes,
+                        Err(e) => {
+                            // The audio is muxed into every variant, so the row goes
+                            self.skip_pushes(row_pushes, &video_tracks, audio_segment, &e);
+                            break;
+                        }
+                    };
+                    self.stats.record_segment_size(false, bytes.len());
+                    audio_data = Some(self.decrypt_with_init(bytes, "audio").await?);
+                }
+                let audio = audio_data.as_deref();
+
+                // Download and decrypt the video of the audio segment's window
+                let kind = format!("video_{}", variant);
+                let mut video_data = Vec::with_capacity(videos.len());
+                let mut missing = None;
+                for &video_index in videos {
+                    self.load_init_segment(track.init_for(video_index), &kind)
+                        .await;
+                    let bytes = match take_download(&mut downloaded, &track.segments[video_index]) {
+                        Ok(bytes) => bytes,
+                        Err(e) => {
+                            missing = Some(e);
+                            break;
+                        }
+                    };
+                    self.stats.record_segment_size(true, bytes.len());
+                    video_data.push(self.decrypt_with_init(bytes, &kind).await?);
+                }
+                if let Some(e) = missing {
+                    self.skip_pushes(std::slice::from_ref(push), &video_tracks, audio_segment, &e);
+                    continue;
+                }
+                let program_date_time = videos
+                    .first()
+                    .and_then(|&video_index| track.program_date_time(video_index))
+                    .or_else(|| anchor.program_date_time(i));
+
+                // Hand both tracks to the variant's ffmpeg muxer
+                self.pushers[variant]
+                    .adapt_to_codec(track.codecs.as_deref(), self.stream_info.incompatible_codec)?;
+                self.pushers[variant]
+                    .write(&video_data, audio, program_date_time)
+                    .await?;
+                let video_bytes: usize = video_data.iter().map(Vec::len).sum();
+                self.stats
+                    .record_segment(video_bytes + audio.map_or(0, <[u8]>::len));
+                if let Some(latency) = videos.last().and_then(|&video| track.latency_at(video)) {
+                    self.stats.set_latency(latency);
+                }
+                self.pushed_pairs.extend(
+                    videos
+                        .iter()
+                        .map(|&video| (variant, track.segments[video].clone())),
+                );
+                self.pushed_pairs.insert((variant, audio_segment.clone()));
+            }
+        }
+
+        // Segments that left the manifest can't come back, forget them
+        let listed: HashSet<&Segment> = video_tracks
+            .iter()
+            .chain(std::iter::once(anchor))
+            .flat_map(|track| &track.segments)
+            .collect();
+        self.pushed_pairs
+            .retain(|(_, segment)| listed.contains(segment));
+
+        if self.is_active {
+            self.process_audio_renditions(&audio_renditions).await?;
+            self.process_subtitles(&subtitle_tracks).await?;
+            let listed_last = video_tracks
+                .iter()
+                .chain(std::iter::once(anchor))
+                .filter_map(|track| track.segments.last());
+            self.prefetch_upcoming(listed_last).await;
+        }
+
+        self.stats.set_backlog(0);
+        self.last_processed_segments = processed;
+        // Only once the segments made it out, a refresh failing before then still sees the
+        // stream as live and finalizes on the next one
+        self.was_live = is_live;
+
+        if (ended || complete) && self.is_active {
+            self.finalize().await?;
+        }
+        Ok(())
+    }
+
+    // Leaves out pushes a segment of which failed to download, so one segment doesn't fail
+    // the whole refresh. They count as pushed, a later refresh would otherwise hand them to
+    // ffmpeg after the segments that followed them.
+    fn skip_pushes(
+        &mut self,
+        pushes: &[PendingPush],
+        video_tracks: &[Track],
+        audio_segment: &Segment,
+        error: &anyhow::Error,
+    ) {
+        for push in pushes {
+            let track = &video_tracks[push.variant];
+            warn!(
+                "Skipping segment {} of variant {} of {}: {}",
+                push.audio, push.variant, self.stream_info.id, error
+            );
+            self.stats.record_skipped_segment();
+            self.pushed_pairs.extend(
+                push.videos
+                    .iter()
+                    .map(|&video| (push.variant, track.segments[video].clone())),
+            );
+            self.pushed_pairs
+                .insert((push.variant, audio_segment.clone()));
+        }
+    }
+
+    // Closes the ffmpeg inputs so each playlist gets its #EXT-X-ENDLIST, and stops polling
+    async fn finalize(&mut self) -> anyhow::Result<()> {
+        info!(
+            "MPD of {} is static and fully pushed, finalizing the HLS output",
+            self.stream_info.id
+        );
+        self.is_active = false;
+        self.stats.mark_ended();
+
+        self.finish_pushers().await?;
+        for writer in &self.subtitle_writers {
+            writer.finish()?;
+        }
+        Ok(())
+    }
+
+    // Takes the pushers out and lets their ffmpeg flush on the blocking pool, as each may
+    // take up to stop_timeout_ms
+    async fn finish_pushers(&mut self) -> anyhow::Result<()> {
+        let timeout = Duration::from_millis(self.config.stop_timeout_ms);
+        let pushers: Vec<LiveHlsPusher> = self
+            .pushers
+            .drain(..)
+            .chain(self.audio_pushers.drain(..))
+            .collect();
+        actix_web::rt::task::spawn_blocking(move || {
+            pushers
+                .into_iter()
+                .try_for_each(|pusher| pusher.finish(timeout))
+        })
+        .await?
+    }
+
+    // Starts a pusher for each audio language not seen before. Languages leaving the MPD
+    // keep theirs, idle, so the master playlist stays stable.
+    fn spawn_audio_pushers(&mut self, audio_renditions: &[Track]) -> anyhow::Result<()> {
+        for track in audio_renditions {
+            let lang = track.lang.clone().unwrap_or_else(|| "und".to_string());
+            if self.audio_langs.contains(&lang) {
+                continue;
+            }
+
+            let index = self.audio_langs.len();
+            let config = PusherConfig {
+                max_segments: self.max_segments,
+                segment_time: self.segment_duration,
+                segment_format: self.stream_info.segment_format,
+                output: self.stream_info.output,
+                video_codec: None,
+                max_bitrate: None,
+                bufsize: None,
+                audio_copy: self.stream_info.audio_copy,
+                parts: self.stream_info.part_layout(),
+                key_info: self
+                    .stream_info
+                    .encrypt_output
+                    .then(|| self.fifo_dir.join(KEY_INFO_FILE)),
+                max_restarts: self.config.max_ffmpeg_restarts,
+                write_timeout: Duration::from_secs(self.config.mux_timeout_secs),
+                program_date_times: Arc::clone(&self.program_date_times),
+            };
+            self.audio_pushers.push(LiveHlsPusher::spawn(
+                &self.output_dir,
+                self.fifo_dir.path(),
+                &format!("audio_{}.m3u8", index),
+                &format!("audio_{}", index),
+                &config,
+                false,
+                &self.stats,
+            )?);
+            self.audio_langs.push(lang);
+        }
+
+        Ok(())
+    }
+
+    // Hands the new segments of the alternate languages to their pushers. A segment that
+    // fails is tried again on the next refresh, without holding up the video.
+    async fn process_audio_renditions(&mut self, audio_renditions: &[Track]) -> anyhow::Result<()> {
+        let mut current = HashSet::new();
+
+        for track in audio_renditions {
+            let lang = track.lang.as_deref().unwrap_or("und");
+            let Some(index) = self.audio_langs.iter().position(|known| known == lang) else {
+                continue;
+            };
+            let kind = format!("audio_{}", index);
+
+            for (i, segment) in track.segments.iter().enumerate() {
+                let key = (index, segment.clone());
+                current.insert(key.clone());
+                if self.pushed_audio.contains(&key) {
+                    continue;
+                }
+
+                self.load_init_segment(track.init_for(i), &kind).await;
+                let audio_data = match self.download_and_decrypt_segment(segment, &kind).await {
+                    Ok(audio_data) => audio_data,
+                    Err(e) => {
+                        warn!("Error processing {} audio segment: {}", lang, e);
+                        break;
+                    }
+                };
+
+                self.audio_pushers[index]
+                    .write_audio(&audio_data, track.program_date_time(i))
+                    .await?;
+                self.stats.record_segment(audio_data.len());
+                self.pushed_audio.insert(key);
+            }
+        }
+
+        self.pushed_audio.retain(|key| current.contains(key));
+        Ok(())
+    }
+
+    // Converts the new subtitle segments to WebVTT. A segment that fails is logged and
+    // tried again on the next refresh, without holding up the video.
+    async fn process_subtitles(&mut self, subtitle_tracks: &[Track]) -> anyhow::Result<()> {
+        for (index, track) in subtitle_tracks.iter().enumerate() {
+            let kind = format!("subtitles_{}", index);
+
+            for (i, segment) in track.segments.iter().enumerate() {
+                let key = (index, segment.clone());
+                if self.pushed_subtitles.contains(&key) {
+                    continue;
+                }
+
+                self.load_init_segment(track.init_for(i), &kind).await;
+                let vtt = match self.download_subtitle_segment(segment, &kind).await {
+                    Ok(vtt) => vtt,
+                    Err(e) => {
+                        warn!("Error converting subtitle segment {}: {}", segment.url, e);
+                        break;
+                    }
+                };
+
+                self.subtitle_writers[index].push(&vtt, track.segment_duration)?;
+                self.pushed_subtitles.insert(key);
+            }
+        }
+
+        let current: HashSet<(usize, &Segment)> = subtitle_tracks
+            .iter()
+            .enumerate()
+            .flat_map(|(index, track)| track.segments.iter().map(move |segment| (index, segment)))
+            .collect();
+        self.pushed_subtitles
+            .retain(|(index, segment)| current.contains(&(*index, segment)));
+
+        Ok(())
+    }
+
+    async fn download_subtitle_segment(
+        &self,
+        segment: &Segment,
+        kind: &str,
+    ) -> anyhow::Result<Vec<u8>> {
+        let bytes = self.downloader.fetch(segment).await?;
+        let combined = match self.stream_info.init_segments.get(kind) {
+            Some(init) => [init.as_slice(), &bytes].concat(),
+            None => bytes,
+        };
+
+        // ffmpeg converts it, on the blocking pool
+        let timeout = Duration::from_secs(self.config.mux_timeout_secs);
+        actix_web::rt::task::spawn_blocking(move || subtitles::to_webvtt(&combined, timeout))
+            .await?
+    }
+
+    // While the origin is down ffmpeg receives nothing, so the last playlist and its segments
+    // stay untouched. Once the configured bound is exceeded the held state is given up.
+    fn hold_during_outage(&mut self) {
+        let outage_since = *self.outage_since.get_or_insert_with(Instant::now);
+        let hold_for = Duration::from_secs(self.config.hold_on_outage_secs);
+
+        if self.config.hold_on_outage_secs == 0
+            || self.held_playlist_dropped
+            || outage_since.elapsed() <= hold_for
+        {
+            return;
+        }
+
+        warn!(
+            "Origin of {} down for more than {:?}, dropping the held playlist",
+            self.stream_info.id, hold_for
+        );
+        for pusher in self.pushers.iter().chain(&self.audio_pushers) {
+            fs::remove_file(pusher.playlist_path()).ok();
+        }
+        self.held_playlist_dropped = true;
+    }
+
+    fn resume_after_outage(&mut self, outage_since: Instant) -> anyhow::Result<()> {
+        if self.config.hold_on_outage_secs == 0 {
+            return Ok(());
+        }
+
+        info!(
+            "Origin of {} recovered after {:?}",
+            self.stream_info.id,
+            outage_since.elapsed()
+        );
+
+        // Continue the held playlist after a discontinuity, or start over if it was dropped
+        let continue_playlist = !self.held_playlist_dropped;
+        for pusher in self.pushers.iter_mut().chain(&mut self.audio_pushers) {
+            pusher.respawn(continue_playlist)?;
+            self.stats.record_ffmpeg_restart();
+        }
+        self.held_playlist_dropped = false;
+
+        Ok(())
+    }
+
+    // Switches the init segment of a kind when the next segment needs another one than
+    // the one held, as at period boundaries. Each init is downloaded once.
+    async fn load_init_segment(&mut self, init: Option<&Segment>, kind: &str) {
+        let Some(init) = init else {
+            return;
+        };
+        if self.loaded_inits.get(kind) == Some(init) {
+            return;
+        }
+
+        self.stream_info.init_segments.remove(kind);
+        self.loaded_inits.remove(kind);
+
+        if !self.init_cache.contains_key(init) {
+            let bytes = match self.downloader.fetch_once(init).await {
+                Ok(bytes) => bytes,
+                Err(e) => {
+                    warn!("Skipping init segment {}: {}", init.url, e);
+                    return;
+                }
+            };
+
+            // Keep the cache bounded on long streams that keep announcing new inits
+            if self.init_cache.len() >= MAX_CACHED_INITS {
+                let loaded: HashSet<&Segment> = self.loaded_inits.values().collect();
+                self.init_cache
+                    .retain(|segment, _| loaded.contains(segment));
+            }
+            self.init_cache.insert(init.clone(), bytes);
+        }
+
+        self.stream_info
+            .init_segments
+            .insert(kind.to_string(), self.init_cache[init].clone());
+        self.loaded_inits.insert(kind.to_string(), init.clone());
+    }
+
+    // Rewrites master.m3u8 whenever the set of variants or renditions changes
+    fn write_master_playlist(
+        &mut self,
+        video_tracks: &[Track],
+        audio_track: Option<&Track>,
+        subtitle_tracks: &[Track],
+    ) -> anyhow::Result<()> {
+        // The primary language is muxed into the variants, the others have playlists of
+        // their own
+        let mut media = Vec::new();
+        if !self.audio_langs.is_empty() {
+            media.push(
+                self.rendition_entry(
+                    "AUDIO",
+                    AUDIO_GROUP,
+                    audio_track
+                        .and_then(|track| track.lang.as_deref())
+                        .unwrap_or("und"),
+                    true,
+                    None,
+                ),
+            );
+            for (index, lang) in self.audio_langs.iter().enumerate() {
+                media.push(self.rendition_entry(
+                    "AUDIO",
+                    AUDIO_GROUP,
+                    lang,
+                    false,
+                    Some(&format!("audio_{}.m3u8", index)),
+                ));
+            }
+        }
+        let audio_group = (!media.is_empty()).then(|| AUDIO_GROUP.to_string());
+
+        let subtitle_media: Vec<String> = subtitle_tracks
+            .iter()
+            .zip(&self.subtitle_writers)
+            .map(|(track, writer)| {
+                self.rendition_entry(
+                    "SUBTITLES",
+                    SUBTITLES_GROUP,
+                    track.lang.as_deref().unwrap_or("und"),
+                    false,
+                    Some(&writer.playlist_name()),
+                )
+            })
+            .collect();
+        let subtitles_group = (!subtitle_media.is_empty()).then(|| SUBTITLES_GROUP.to_string());
+        media.extend(subtitle_media);
+
+        let variants: Vec<playlist::Variant> = video_tracks
+            .iter()
+            .take(self.pushers.len())
+            .enumerate()
+            .map(|(i, track)| playlist::Variant {
+                bandwidth: track.bandwidth,
+                resolution: track.resolution,
+                uri: format!("variant_{}.m3u8", i),
+                audio: audio_group.clone(),
+                subtitles: subtitles_group.clone(),
+            })
+            .collect();
+
+        let master_playlist = playlist::master_playlist(&media, &variants);
+        if master_playlist != self.master_playlist {
+            fs::write(format!("{}/master.m3u8", self.output_dir), &master_playlist)?;
+            self.master_playlist = master_playlist;
+        }
+
+        Ok(())
+    }
+
+    async fn download_and_decrypt_segment(
+        &self,
+        segment: &Segment,
+        kind: &str,
+    ) -> anyhow::Result<Vec<u8>> {
+        let bytes = self.downloader.fetch(segment).await?;
+        self.stats
+            .record_segment_size(kind.starts_with("video"), bytes.len());
+        self.decrypt_with_init(bytes, kind).await
+    }
+
+    // Decrypts a downloaded segment after the init segment held for its kind, on the
+    // blocking pool since decryption may run ffmpeg
+    async fn decrypt_with_init(&self, bytes: Vec<u8>, kind: &str) -> anyhow::Result<Vec<u8>> {
+        let combined = if let Some(init) = self.stream_info.init_segments.get(kind) {
+            let mut full = init.clone();
+            full.extend_from_slice(&bytes);
+            full
+        } else {
+            bytes
+        };
+
+        let decryptor = Arc::clone(&self.decryptor);
+        let fallback = self.fallback_decryptor.clone();
+        let keys = self.decryption_keys.clone();
+        let recent_keys: Vec<String> = self.recent_keys().into_iter().cloned().collect();
+        let stats = Arc::clone(&self.stats);
+        actix_web::rt::task::spawn_blocking(move || {
+            decrypt_segment(
+                decryptor.as_ref(),
+                fallback.as_deref(),
+                &keys,
+                &recent_keys,
+                &stats,
+                &combined,
+            )
+        })
+        .await?
+    }
+
+    // Fetches the segments expected after the last listed ones, so they're ready when the
+    // MPD lists them. Those not published yet simply aren't kept.
+    async fn prefetch_upcoming<'a>(&mut self, listed_last: impl Iterator<Item = &'a Segment>) {
+        let mut upcoming_by_last = std::mem::take(&mut *self.upcoming_segments.lock().unwrap());
+        let upcoming: Vec<Segment> = listed_last
+            .filter_map(|last| upcoming_by_last.remove(last))
+            .flatten()
+            .collect();
+        self.prefetched
+            .retain(|segment, _| upcoming.contains(segment));
+
+        let missing: Vec<Segment> = upcoming
+            .into_iter()
+            .filter(|segment| !self.prefetched.contains_key(segment))
+            .collect();
+        let fetched = self.downloader.fetch_ahead(missing).await;
+        self.prefetched.extend(fetched);
+    }
+
+    // Gives ffmpeg a chance to complete its last segment rather than truncating it
+    pub fn stop(&mut self) -> anyhow::Result<()> {
+        self.is_active = false;
+        let timeout = Duration::from_millis(self.config.stop_timeout_ms);
+        for pusher in self.pushers.drain(..).chain(self.audio_pushers.drain(..)) {
+            pusher.finish(timeout)?;
+        }
+        self.release_output();
+        Ok(())
+    }
+
+    fn release_output(&mut self) {
+        self.fifo_dir.remove();
+        self.output_lock = None;
+    }
+
+    // Runs the stream as a task of the async runtime. Origin fetches use the async client
+    // and segments reach ffmpeg through bounded queues, so streams share the runtime's
+    // threads instead of holding one each. Decryption and the ffmpeg runs that block go to
+    // the blocking pool. The converter stays locked for the whole of a refresh.
+    pub async fn run_streaming_task(
+        converter_arc: Arc<tokio::sync::Mutex<Self>>,
+    ) -> anyhow::Result<()> {
+        converter_arc.lock().await.start()?;
+
+        loop {
+            let pause = converter_arc.lock().await.refresh().await;
+            match pause {
+                // Sleep before fetching updates to MPD
+                Some(pause) => actix_web::rt::time::sleep(pause).await,
+                None => break,
+            }
+        }
+
+        Ok(())
+    }
+
+    // Processes the current MPD once, returning how long to wait before the next refresh,
+    // or None once the stream is stopped
+    async fn refresh(&mut self) -> Option<Duration> {
+        if !self.is_active {
+            return None;
+        }
+
+        match self.download_and_process_segments().await {
+            Ok(()) => {
+                self.stats.record_success();
+                Some(self.poll_interval)
+            }
+            Err(e) => {
+                self.stats.record_failure(&e.to_string());
+                if let Some(sender) = &self.events {
+                    events::publish(
+                        sender,
+                        &self.stream_info.id,
+                        EventKind::Error,
+                        Some(&e.to_string()),
+                    );
+                }
+                error!(
+                    "Error processing segments for {}: {}",
+                    self.stream_info.id, e
+                );
+
+                let pause = pause_after_failure(&self.stats, &self.config, self.poll_interval);
+                if pause.is_none() {
+                    error!(
+                        "Giving up on {} after {} consecutive failures",
+                        self.stream_info.id,
+                        self.stats.consecutive_failures()
+                    );
+                    self.is_active = false;
+                    if let Err(e) = self.finish_pushers().await {
+                        error!("Could not stop ffmpeg process: {}", e);
+                    }
+                    self.release_output();
+                }
+                pause
+            }
+        }
+    }
+}
+
+// Short pause after a failed refresh, on top of the poll interval, to avoid rapid fail loops
+const FAILURE_PAUSE: Duration = Duration::from_secs(1);
+
+// Pause before the refresh following a failed one, counted in `stats` already. None once
+// max_consecutive_failures is reached with on_repeated_failure = "stop", which marks the
+// stream failed.
+fn pause_after_failure(
+    stats: &StreamStats,
+    config: &ConverterTOML,
+    poll_interval: Duration,
+) -> Option<Duration> {
+    let max_failures = config.max_consecutive_failures;
+    if max_failures == 0 || stats.consecutive_failures() < max_failures {
+        return Some(FAILURE_PAUSE + poll_interval);
+    }
+
+    match config.on_repeated_failure {
+        FailureAction::Stop => {
+            stats.mark_failed();
+            None
+        }
+        FailureAction::SlowRetry => Some(Duration::from_secs(config.slow_retry_secs)),
+    }
+}
+
+// $Time$ of each segment of a template without timeline. SegmentTemplate@duration and
+// @presentationTimeOffset are in timescale units, only the period duration is in seconds.
+fn template_segment_times(
+    period_duration: Duration,
+    duration: f64,
+    timescale: u64,
+    presentation_time_offset: u64,
+) -> Vec<i64> {
+    if duration <= 0.0 {
+        return Vec::new();
+    }
+
+    let period_units = period_duration.as_secs_f64() * timescale as f64;
+    let segment_count = (period_units / duration).ceil() as usize;
+
+    (0..segment_count)
+        .map(|i| presentation_time_offset as i64 + (i as f64 * duration).round() as i64)
+        .collect()
+}
+
+// Pause before the next MPD fetch: the manifest's minimumUpdatePeriod when it has one,
+// kept within the configured bounds
+fn poll_interval(update_period: Option<Duration>, config: &ConverterTOML) -> Duration {
+    let min = Duration::from_millis(config.poll_min_ms);
+    let max = Duration::from_millis(config.poll_max_ms).max(min);
+
+    update_period
+        .unwrap_or(Duration::from_millis(config.poll_default_ms))
+        .clamp(min, max)
+}
+
+// Media segments, init and start times (only known for templates) of a representation
+type ExtractedSegments = (Vec<Segment>, Option<Segment>, Vec<f64>);
+
+// How the segments of a representation are addressed, templates and lists being inherited
+// from the adaptation set (and templates from the period)
+enum SegmentSource<'a> {
+    Template(&'a SegmentTemplate),
+    List(&'a SegmentList),
+    // Single file whose segments are listed in the sidx box at the index range
+    Indexed(&'a SegmentBase, &'a str),
+    // Single file holding the whole representation
+    Single,
+}
+
+impl<'a> SegmentSource<'a> {
+    fn of(
+        period: &'a Period,
+        adaptation_set: &'a AdaptationSet,
+        representation: &'a Representation,
+    ) -> Option<Self> {
+        if let Some(template) = representation
+            .SegmentTemplate
+            .as_ref()
+            .or(adaptation_set.SegmentTemplate.as_ref())
+            .or(period.SegmentTemplate.as_ref())
+        {
+            Some(SegmentSource::Template(template))
+        } else if let Some(segment_list) = representation
+            .SegmentList
+            .as_ref()
+            .or(adaptation_set.SegmentList.as_ref())
+        {
+            Some(SegmentSource::List(segment_list))
+        } else if let Some(segment_base) = &representation.SegmentBase
+            && let Some(index_range) = &segment_base.indexRange
+        {
+            Some(SegmentSource::Indexed(segment_base, index_range))
+        } else if !representation.BaseURL.is_empty() {
+            Some(SegmentSource::Single)
+        } else {
+            None
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            SegmentSource::Template(_) => "SegmentTemplate",
+            SegmentSource::List(_) => "SegmentList",
+            SegmentSource::Indexed(..) => "SegmentBase",
+            SegmentSource::Single => "BaseURL",
+        }
+    }
+}
+
+// Segment list, whose init and media may be byte ranges of the base URL
+fn list_segments(segment_list: &SegmentList, base_url: &str) -> anyhow::Result<ExtractedSegments> {
+    let init_segment = match &segment_list.Initialization {
+        Some(init) => Some(Segment {
+            url: match &init.sourceURL {
+                Some(source) => resolve_url(base_url, source)?,
+                None => base_url.to_string(),
+            },
+            range: init.range.as_deref().and_then(sidx::parse_byte_range),
+        }),
+        None => None,
+    };
+
+    let mut segments = Vec::new();
+    for segment in &segment_list.segment_urls {
+        let url = match &segment.media {
+            Some(media) => resolve_url(base_url, media)?,
+            None if segment.mediaRange.is_some() => base_url.to_string(),
+            None => continue,
+        };
+        segments.push(Segment {
+            url,
+            range: segment
+                .mediaRange
+                .as_deref()
+                .and_then(sidx::parse_byte_range),
+        });
+    }
+
+    Ok((segments, init_segment, Vec::new()))
+}
+
+// Start times of two tracks closer than this fraction of the shorter segment length belong
+// to the same window
+const PAIRING_TOLERANCE: f64 = 0.5;
+
+// How often a stream whose tracks don't line up is warned about
+const PAIRING_WARN_INTERVAL: Duration = Duration::from_secs(60);
+
+// Video segments muxed along each audio segment, by audio index in presentation order.
+// When both tracks know their times, each audio segment takes the video segments starting
+// in its window, so tracks of different cadences are muxed whole. One whose window the
+// listed video doesn't cover yet waits for a later refresh. Otherwise segments are paired
+// by position.
+fn pair_segments(video: &Track, audio: &Track) -> Vec<(usize, Vec<usize>)> {
+    let times_known = |track: &Track| {
+        !track.start_times.is_empty() && track.start_times.iter().all(Option::is_some)
+    };
+    if !times_known(video) || !times_known(audio) {
+        let len = video.segments.len().min(audio.segments.len());
+        return (0..len).map(|i| (i, vec![i])).collect();
+    }
+
+    let video_times: Vec<f64> = video.start_times.iter().flatten().copied().collect();
+    let audio_times: Vec<f64> = audio.start_times.iter().flatten().copied().collect();
+    let segment_length = [video.segment_duration, audio.segment_duration]
+        .into_iter()
+        .flatten()
+        .fold(f64::INFINITY, f64::min);
+    let tolerance = if segment_length.is_finite() {
+        segment_length * PAIRING_TOLERANCE
+    } else {
+        PAIRING_TOLERANCE
+    };
+    // The last video segment only extends the covered time when its length is known
+    let video_start = video_times[0];
+    let video_end = video_times[video_times.len() - 1] + video.segment_duration.unwrap_or(0.0);
+
+    let mut rows = Vec::new();
+    let mut next_video = 0;
+    for (audio_index, &audio_time) in audio_times.iter().enumerate() {
+        let audio_end = match (audio_times.get(audio_index + 1), audio.segment_duration) {
+            (Some(&next), _) => next,
+            (None, Some(duration)) => audio_time + duration,
+            (None, None) => break,
+        };
+        if audio_end > video_end + tolerance {
+            break;
+        }
+        // Both lists are in presentation order, so the search resumes where it stopped.
+        // Video before the first audio window is left out, like audio before the video's.
+        while next_video < video_times.len() && video_times[next_video] < audio_time - tolerance {
+            next_video += 1;
+        }
+        if audio_time < video_start - tolerance {
+            continue;
+        }
+
+        let mut videos = Vec::new();
+        while next_video < video_times.len() && video_times[next_video] < audio_end - tolerance {
+            videos.push(next_video);
+            next_video += 1;
+        }
+        rows.push((audio_index, videos));
+    }
+    rows
+}
+
+// Substitutes the representation-level identifiers of a SegmentTemplate
+fn fill_template(template: &str, representation: &Representation) -> String {
+    template
+        .replace(
+            "$RepresentationID$",
+            representation.id.as_deref().unwrap_or_default(),
+        )
+        .replace(
+            "$Bandwidth$",
+            &representation.bandwidth.unwrap_or(0).to_string(),
+        )
+}
+
+// Substitutes the $Time$ and $Number$ of a segment, including their printf style widths
+// such as $Number%05d$
+fn fill_segment_template(template: &str, time: i64, number: u64) -> String {
+    let filled = substitute_identifier(template, "Time", &time.to_string());
+    substitute_identifier(&filled, "Number", &number.to_string())
+}
+
+fn substitute_identifier(template: &str, name: &str, value: &str) -> String {
+    let mut filled = String::with_capacity(template.len());
+    let mut rest = template;
+    let prefix = format!("${}", name);
+
+    while let Some(start) = rest.find(&prefix) {
+        let after = &rest[start + prefix.len()..];
+        let Some(end) = after.find('$') else {
+            break;
+        };
+        let width = match &after[..end] {
+            "" => Some(0),
+            format => format
+                .strip_prefix("%0")
+                .and_then(|format| format.strip_suffix('d'))
+                .and_then(|width| width.parse::<usize>().ok()),
+        };
+
+        filled.push_str(&rest[..start]);
+        match width {
+            Some(width) => filled.push_str(&format!("{:0>width$}", value, width = width)),
+            None => filled.push_str(&rest[start..start + prefix.len() + end + 1]),
+        }
+        rest = &after[end + 1..];
+    }
+
+    filled.push_str(rest);
+    filled
+}
+
+// The representation's ContentProtection wins over the one of its adaptation set
+fn extract_default_kid(
+    adaptation_set: &AdaptationSet,
+    representation: &Representation,
+) -> Option<String> {
+    representation
+        .ContentProtection
+        .iter()
+        .chain(adaptation_set.ContentProtection.iter())
+        .find_map(|cp| cp.default_KID.as_deref())
+        .map(|kid| kid.trim().replace('-', "").to_lowercase())
+}
+
+// Keys of the configured DRM systems the tracks announce, in configuration order. None
+// when the tracks announce DRM systems, but none of the configured ones.
+fn announced_drm_keys<'a>(
+    drm_keys: &'a [(DrmSystem, HashMap<String, String>)],
+    tracks: &[&Track],
+) -> Option<Vec<&'a HashMap<String, String>>> {
+    let announced: HashSet<&str> = tracks
+        .iter()
+        .flat_map(|track| track.drm_systems.iter().map(String::as_str))
+        .collect();
+
+    let selected: Vec<&HashMap<String, String>> = drm_keys
+        .iter()
+        .filter(|(system, _)| announced.contains(system.system_id()))
+        .map(|(_, keys)| keys)
+        .collect();
+    (announced.is_empty() || !selected.is_empty()).then_some(selected)
+}
+
+fn extract_drm_systems(
+    adaptation_set: &AdaptationSet,
+    representation: &Representation,
+) -> Vec<String> {
+    let mut systems: Vec<String> = representation
+        .ContentProtection
+        .iter()
+        .chain(adaptation_set.ContentProtection.iter())
+        .filter_map(|cp| cp.schemeIdUri.as_deref())
+        .filter_map(|uri| {
+            let uri = uri.trim().to_lowercase();
+            uri.strip_prefix("urn:uuid:").map(str::to_string)
+        })
+        .collect();
+    systems.sort();
+    systems.dedup();
+    systems
+}
+
+// Client for the origin, sending the channel's User-Agent and extra headers
+fn origin_client(
+    stream_info: &StreamInfo,
+    compressed: bool,
+    timeout: Duration,
+) -> anyhow::Result<reqwest::Client> {
+    let mut headers = HeaderMap::new();
+    for (name, value) in &stream_info.headers {
+        headers.insert(
+            HeaderName::from_bytes(name.as_bytes())?,
+            HeaderValue::from_str(value)?,
+        );
+    }
+
+    Ok(reqwest::Client::builder()
+        .user_agent(
+            stream_info
+                .user_agent
+                .as_deref()
+                .unwrap_or(DEFAULT_USER_AGENT),
+        )
+        .default_headers(headers)
+        .gzip(compressed)
+        .deflate(compressed)
+        .brotli(compressed)
+        .timeout(timeout)
+        .build()?)
+}
+
+// Decrypts a segment with the stream's keys. Keys are matched by KID, when that fails each
+// recent key is tried on the track, then the fallback decryptor if any.
+fn decrypt_segment(
+    decryptor: &dyn decrypt::Decryptor,
+    fallback: Option<&dyn decrypt::Decryptor>,
+    keys: &HashMap<String, String>,
+    recent_keys: &[String],
+    stats: &StreamStats,
+    data: &[u8],
+) -> anyhow::Result<Vec<u8>> {
+    let mut result = decryptor.decrypt(data, keys);
+
+    if result.is_err() && recent_keys.len() > 1 {
+        for key in recent_keys {
+            let keys = HashMap::from([("1".to_owned(), key.clone())]);
+            if let Ok(output) = decryptor.decrypt(data, &keys) {
+                result = Ok(output);
+                break;
+            }
+        }
+    }
+
+    match result {
+        Ok(output) => Ok(output),
+        Err(e) => {
+            stats.record_decrypt_failure(&e.to_string());
+            error!("Failed to decrypt segment: {}", e);
+
+            // Still encrypted bytes would only make ffmpeg write garbage segments
+            match fallback {
+                Some(fallback) => fallback.decrypt(data, keys),
+                None => Err(e),
+            }
+        }
+    }
+}
+
+// Video segments of a variant due to be muxed along an audio segment
+#[derive(Debug, PartialEq)]
+struct PendingPush {
+    audio: usize,
+    variant: usize,
+    videos: Vec<usize>,
+}
+
+// What the pushers still have to get of a refresh, in playlist order: for each audio
+// segment a variant hasn't got, the video segments of its window the variant hasn't got
+// either. Pairs pushed by an earlier refresh are left out, so nothing is muxed twice.
+fn pending_pushes(
+    video_tracks: &[Track],
+    anchor: &Track,
+    pairings: &[HashMap<usize, Vec<usize>>],
+    pushed: &HashSet<(usize, Segment)>,
+) -> Vec<PendingPush> {
+    let mut pushes = Vec::new();
+    for (audio, audio_segment) in anchor.segments.iter().enumerate() {
+        for (variant, pairing) in pairings.iter().enumerate() {
+            let Some(videos) = pairing.get(&audio) else {
+                continue;
+            };
+            if pushed.contains(&(variant, audio_segment.clone())) {
+                continue;
+            }
+            let videos = videos
+                .iter()
+                .copied()
+                .filter(|&video| {
+                    let segment = &video_tracks[variant].segments[video];
+                    !pushed.contains(&(variant, segment.clone()))
+                })
+                .collect();
+            pushes.push(PendingPush {
+                audio,
+                variant,
+                videos,
+            });
+        }
+    }
+    pushes
+}
+
+// Segments the pushes need, each once: the audio segment is shared by the variants
+fn download_list<'a>(
+    pushes: impl Iterator<Item = &'a PendingPush>,
+    video_tracks: &[Track],
+    audio_track: Option<&Track>,
+) -> Vec<Segment> {
+    let mut segments = Vec::new();
+    for push in pushes {
+        let track = &video_tracks[push.variant];
+        segments.extend(
+            push.videos
+                .iter()
+                .map(|&video| track.segments[video].clone()),
+        );
+        if let Some(audio_track) = audio_track
+            && !segments.contains(&audio_track.segments[push.audio])
+        {
+            segments.push(audio_track.segments[push.audio].clone());
+        }
+    }
+    segments
+}
+
+// Takes the segments already prefetched out of `prefetched`, as downloads, and returns
+// them along the segments still to download
+fn take_prefetched(
+    prefetched: &mut HashMap<Segment, Vec<u8>>,
+    segments: Vec<Segment>,
+) -> (HashMap<Segment, anyhow::Result<Vec<u8>>>, Vec<Segment>) {
+    let mut ready = HashMap::new();
+    let mut missing = Vec::new();
+    for segment in segments {
+        match prefetched.remove(&segment) {
+            Some(bytes) => {
+                ready.insert(segment, Ok(bytes));
+            }
+            None => missing.push(segment),
+        }
+    }
+    (ready, missing)
+}
+
+// Segments of a refresh, per video variant and for the track the video is muxed along
+fn processed_segments(video_tracks: &[Track], anchor: &Track) -> (Vec<Vec<Segment>>, Vec<Segment>) {
+    (
+        video_tracks
+            .iter()
+            .map(|track| track.segments.clone())
+            .collect(),
+        anchor.segments.clone(),
+    )
+}
+
+fn describe_processed(
+    (video, audio): &(Vec<Vec<Segment>>, Vec<Segment>),
+) -> (Vec<Vec<String>>, Vec<String>) {
+    (
+        video
+            .iter()
+            .map(|segments| segments.iter().map(Segment::describe).collect())
+            .collect(),
+        audio.iter().map(Segment::describe).collect(),
+    )
+}
+
+// Takes the result of a segment downloaded ahead of its turn
+fn take_download(
+    downloaded: &mut HashMap<Segment, anyhow::Result<Vec<u8>>>,
+    segment: &Segment,
+) -> anyhow::Result<Vec<u8>> {
+    downloaded
+        .remove(segment)
+        .unwrap_or_else(|| Err(anyhow::anyhow!("{} was not downloaded", segment.describe())))
+}
+
+// Sends the request for an MPD and parses the answer, along with the URL it was served
+// from once redirects were followed
+async fn read_mpd(request: reqwest::RequestBuilder) -> anyhow::Result<(MPD, Url)> {
+    let response = request.send().await?;
+    if !response.status().is_success() {
+        anyhow::bail!("Failed to fetch MPD: HTTP {}", response.status());
+    }
+
+    let final_url = response.url().clone();
+    Ok((dash_mpd::parse(&response.text().await?)?, final_url))
+}
+
+// MPD as served by the origin, for debugging channels
+pub struct RawMpd {
+    pub status: u16,
+    // URL the MPD was served from, after redirects
+    pub final_url: String,
+    pub body: String,
+}
+
+// Fetches the MPD of a channel the way the converter does, without interpreting it
+pub async fn fetch_raw_mpd(stream_info: &StreamInfo, timeout: Duration) -> anyhow::Result<RawMpd> {
+    let response = origin_client(stream_info, true, timeout)?
+        .get(&stream_info.url)
+        .send()
+        .await?;
+
+    Ok(RawMpd {
+        status: response.status().as_u16(),
+        final_url: response.url().to_string(),
+        body: response.text().await?,
+    })
+}
+
+// Image fetched on behalf of clients, with the type the origin gave it
+pub struct Logo {
+    pub content_type: String,
+    pub bytes: Vec<u8>,
+}
+
+// Largest logo fetched, they're served from memory
+const MAX_LOGO_BYTES: u64 = 2 * 1024 * 1024;
+
+// Fetches the logo of a channel. Logos are often hosted elsewhere than the media, so the
+// origin's headers, which may carry credentials, aren't sent along.
+pub async fn fetch_logo(url: &str, timeout: Duration) -> anyhow::Result<Logo> {
+    let response = reqwest::Client::builder()
+        .user_agent(DEFAULT_USER_AGENT)
+        .timeout(timeout)
+        .build()?
+        .get(url)
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        anyhow::bail!("HTTP {}", response.status());
+    }
+
+    let content_type = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("application/octet-stream")
+        .to_string();
+    let bytes = download::read_capped_body(
+        response.content_length(),
+        download::response_chunks(response),
+        MAX_LOGO_BYTES,
+        None,
+    )
+    .await
+    .map_err(|e| anyhow::anyhow!("{}", e))?;
+    Ok(Logo {
+        content_type,
+        bytes,
+    })
+}
+
+// Whether the pushers write media playlists referenced by a master playlist of our own,
+// rather than a single one written straight to master.m3u8
+fn separate_master_playlist(stream_info: &StreamInfo) -> bool {
+    stream_info.variants > 1 || stream_info.subtitles || stream_info.audio_languages
+}
+
+// Trailing segments a manifest is cut down to, None keeping all of them. Live manifests
+// keep what their time shift buffer holds, or live_window segments when they don't say.
+fn start_mode_window(
+    start_mode: StartMode,
+    mpd: &MPD,
+    live_window: impl FnOnce() -> usize,
+) -> Option<usize> {
+    let is_live = mpd.mpdtype.as_deref() == Some("dynamic");
+    match start_mode {
+        StartMode::LiveEdge => is_live.then(live_window),
+        StartMode::Dvr(segments) => Some(segments.max(1)),
+        StartMode::Beginning => None,
+    }
+}
+
+// Segments of the given length the time shift buffer of a live MPD holds, if it has one
+fn time_shift_segments(mpd: &MPD, segment_duration: Option<f64>) -> Option<usize> {
+    let depth = mpd.timeShiftBufferDepth?.as_secs_f64();
+    let segment_duration = segment_duration.filter(|duration| *duration > 0.0)?;
+    Some((depth / segment_duration) as usize)
+}
+
+// Segment length declared by the template of a representation or its adaptation set
+fn nominal_segment_duration(
+    adaptation_set: &AdaptationSet,
+    representation: &Representation,
+) -> Option<f64> {
+    let template = representation
+        .SegmentTemplate
+        .as_ref()
+        .or(adaptation_set.SegmentTemplate.as_ref())?;
+    let timescale = template.timescale.unwrap_or(1) as f64;
+
+    let duration = match &template.SegmentTimeline {
+        Some(timeline) => timeline.segments.first()?.d as f64,
+        None => template.duration?,
+    };
+    (duration > 0.0).then(|| duration / timescale)
+}
+
+// Resolves a segment URL from the MPD against the representation's base URL, as RFC 3986
+// does: seg/1.m4s against https://h/a/b/manifest.mpd is https://h/a/b/seg/1.m4s, and
+// ../seg/1.m4s is https://h/a/seg/1.m4s. A BaseURL without trailing slash names a file,
+// so its last component is dropped too.
+fn resolve_url(base: &str, url: &str) -> anyhow::Result<String> {
+    let base = Url::parse(base).map_err(|e| anyhow::anyhow!("Invalid base URL {}: {}", base, e))?;
+    let resolved = base
+        .join(url)
+        .map_err(|e| anyhow::anyhow!("Can't resolve {} against {}: {}", url, base, e))?;
+    Ok(resolved.to_string())
+}
+
+// Resolves a BaseURL element against the base in effect at its parent level
+fn resolve_base_url(base: &str, base_url: Option<&BaseURL>) -> String {
+    match base_url {
+        Some(base_url) => Url::parse(base)
+            .and_then(|parsed| parsed.join(&base_url.base))
+            .map(|resolved| resolved.to_string())
+            .unwrap_or_else(|_| base_url.base.clone()),
+        None => base.to_string(),
+    }
+}
+
+// The representation of a period standing in for one selected in another period: the same
+// id if present, else the closest bandwidth among adaptation sets of the same kind
+fn period_counterpart<'a>(
+    period: &'a Period,
+    adaptation_set: &AdaptationSet,
+    representation: &Representation,
+) -> Option<(&'a AdaptationSet, &'a Representation)> {
+    let kind = content_kind(adaptation_set)?;
+    let candidates = || {
+        period
+            .adaptations
+            .iter()
+            .filter(move |a| content_kind(a) == Some(kind))
+            .flat_map(|a| a.representations.iter().map(move |r| (a, r)))
+    };
+
+    if representation.id.is_some()
+        && let Some(same) = candidates().find(|(_, r)| r.id == representation.id)
+    {
+        return Some(same);
+    }
+
+    let bandwidth = representation.bandwidth.unwrap_or(0);
+    candidates().min_by_key(|(_, r)| r.bandwidth.unwrap_or(0).abs_diff(bandwidth))
+}
+
+// Subtitles come as plain WebVTT or as fragmented MP4 carrying wvtt or stpp
+fn is_text(adaptation_set: &AdaptationSet) -> bool {
+    adaptation_set.contentType.as_deref() == Some("text")
+        || matches!(
+            adaptation_set.mimeType.as_deref(),
+            Some("text/vtt" | "application/mp4")
+        )
+}
+
+fn content_kind(adaptation_set: &AdaptationSet) -> Option<&'static str> {
+    if is_kind(adaptation_set, "video") {
+        Some("video")
+    } else if is_kind(adaptation_set, "audio") {
+        Some("audio")
+    } else if is_text(adaptation_set) {
+        Some("text")
+    } else {
+        None
+    }
+}
+
+// An explicit contentType decides, otherwise the top-level type of the mime type given by
+// the adaptation set or its representations, so video/webm counts as video too
+fn is_kind(adaptation_set: &AdaptationSet, kind: &str) -> bool {
+    if let Some(content_type) = adaptation_set.contentType.as_deref() {
+        return content_type == kind;
+    }
+
+    adaptation_set
+        .mimeType
+        .as_deref()
+        .or_else(|| {
+            adaptation_set
+                .representations
+                .iter()
+                .find_map(|representation| representation.mimeType.as_deref())
+        })
+        .and_then(|mime_type| mime_type.split_once('/'))
+        .is_some_and(|(top_level, _)| top_level == kind)
+}
+
+fn count_representations(mpd: &MPD, kind: &str) -> usize {
+    mpd.periods
+        .iter()
+        .flat_map(|period| &period.adaptations)
+        .filter(|adaptation_set| is_kind(adaptation_set, kind))
+        .map(|adaptation_set| adaptation_set.representations.len())
+        .sum()
+}
+
+// Media time of the first S when it has no @t: the presentationTimeOffset, or for a live
+// MPD without one, the time making the timeline end at the live edge rather than at 0
+fn timeline_start(
+    timeline: &[S],
+    presentation_time_offset: Option<u64>,
+    live_edge: Option<Duration>,
+    timescale: u64,
+) -> u64 {
+    if let Some(offset) = presentation_time_offset {
+        return offset;
+    }
+    let Some(live_edge) = live_edge else {
+        return 0;
+    };
+    let listed: i64 = timeline
+        .iter()
+        .map(|s| s.d * (s.r.unwrap_or(0).max(0) + 1))
+        .sum();
+    ((live_edge.as_secs_f64() * timescale as f64) as i64 - listed).max(0) as u64
+}
+
+// $Time$ of each timeline entry, in timescale units. The timeline starts at `start` (see
+// timeline_start) unless its first entry says otherwise, and an explicit t restarts the
+// count, as after a gap in the timeline. A negative r repeats the entry up to the next
+// one's t, or once when the next entry doesn't give one.
+fn compute_segment_times(timeline: &[S], start: u64) -> Vec<i64> {
+    let mut times = Vec::new();
+    let mut current_time = start as i64;
+
+    for (index, item) in timeline.iter().enumerate() {
+        if let Some(t) = item.t {
+            current_time = t;
+        }
+        let repeat = match item.r.unwrap_or(0) {
+            r if r >= 0 => r,
+            _ => match timeline.get(index + 1).and_then(|next| next.t) {
+                Some(next_t) if item.d > 0 => ((next_t - current_time) / item.d - 1).max(0),
+                _ => 0,
+            },
+        };
+        for _ in 0..=repeat {
+            times.push(current_time);
+            current_time += item.d;
+        }
     }
 
     times
 }
 
-fn mux_to_ts(video_path: &Path, audio_path: &Path) -> anyhow::Result<Vec<u8>> {
-    let output = Command::new("ffmpeg")
-        .args(["-y", "-i"])
-        .arg(video_path)
-        .args(["-i"])
-        .arg(audio_path)
-        .args([
-            "-map", "0:v:0", "-map", "1:a:0", "-c:v", "copy", "-c:a", "aac", "-f", "mpegts",
-            "pipe:1",
-        ])
-        .output()?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track(count: usize, segment_duration: Option<f64>) -> Track {
+        Track {
+            segments: (0..count)
+                .map(|i| Segment::whole(format!("seg_{}.m4s", i)))
+                .collect(),
+            program_date_times: vec![None; count],
+            start_times: match segment_duration {
+                Some(duration) => (0..count).map(|i| Some(i as f64 * duration)).collect(),
+                None => vec![None; count],
+            },
+            inits: Vec::new(),
+            bandwidth: 0,
+            resolution: None,
+            default_kid: None,
+            drm_systems: Vec::new(),
+            lang: None,
+            segment_duration,
+            codecs: None,
+        }
+    }
+
+    #[test]
+    fn processed_segments_preview_every_variant_and_the_audio() {
+        let video_tracks = [track(4, Some(2.0)), track(4, Some(2.0))];
+        let audio = track(2, Some(4.0));
+
+        let (video, audio) = describe_processed(&processed_segments(&video_tracks, &audio));
+        assert_eq!(video.iter().map(Vec::len).collect::<Vec<_>>(), [4, 4]);
+        assert_eq!(audio, ["seg_0.m4s", "seg_1.m4s"]);
+    }
+
+    fn failing(failures: u32, on_repeated_failure: FailureAction) -> (StreamStats, ConverterTOML) {
+        let stats = StreamStats::default();
+        for _ in 0..failures {
+            stats.record_failure("HTTP 503");
+        }
+        let config = ConverterTOML {
+            max_consecutive_failures: 3,
+            on_repeated_failure,
+            slow_retry_secs: 60,
+            ..ConverterTOML::default()
+        };
+        (stats, config)
+    }
+
+    #[test]
+    fn failures_below_the_limit_pause_briefly() {
+        let poll = Duration::from_secs(2);
+        for action in [FailureAction::Stop, FailureAction::SlowRetry] {
+            let (stats, config) = failing(2, action);
+            assert_eq!(
+                pause_after_failure(&stats, &config, poll),
+                Some(FAILURE_PAUSE + poll)
+            );
+            assert!(!stats.has_failed());
+        }
+    }
+
+    #[test]
+    fn repeated_failures_stop_the_stream() {
+        let (stats, config) = failing(3, FailureAction::Stop);
+        assert_eq!(
+            pause_after_failure(&stats, &config, Duration::from_secs(2)),
+            None
+        );
+        assert!(stats.has_failed());
+    }
+
+    #[test]
+    fn repeated_failures_slow_the_retries_down() {
+        let (stats, config) = failing(5, FailureAction::SlowRetry);
+        assert_eq!(
+            pause_after_failure(&stats, &config, Duration::from_secs(2)),
+            Some(Duration::from_secs(60))
+        );
+        assert!(!stats.has_failed());
+    }
+
+    #[test]
+    fn no_failure_limit_retries_forever() {
+        let (stats, mut config) = failing(100, FailureAction::Stop);
+        config.max_consecutive_failures = 0;
+        let poll = Duration::from_secs(2);
+        assert_eq!(
+            pause_after_failure(&stats, &config, poll),
+            Some(FAILURE_PAUSE + poll)
+        );
+        assert!(!stats.has_failed());
+    }
+
+    #[test]
+    fn make_fifo_replaces_a_leftover_file() {
+        use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("video.pipe");
+        fs::write(&path, "left over").unwrap();
+
+        make_fifo(&path).unwrap();
+        let metadata = fs::metadata(&path).unwrap();
+        assert!(metadata.file_type().is_fifo());
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+
+        assert!(make_fifo(&dir.path().join("missing").join("video.pipe")).is_err());
+    }
+
+    #[test]
+    fn fifo_threads_give_up_when_ffmpeg_never_opens_them() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("video.pipe");
+        make_fifo(&path).unwrap();
+
+        drop(FifoWriter::spawn(path.clone(), Duration::from_secs(1)));
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while path.exists() {
+            assert!(
+                Instant::now() < deadline,
+                "the FIFO thread is still waiting"
+            );
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[actix_web::test]
+    async fn fifo_writes_time_out_when_ffmpeg_stops_reading() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("video.pipe");
+        make_fifo(&path).unwrap();
+        let timeout = Duration::from_millis(200);
+        let mut writer = FifoWriter::spawn(path.clone(), timeout);
+        // An ffmpeg that opened its input, then hung
+        let _reader = fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(&path)
+            .unwrap();
+
+        let segment = vec![0; 1 << 20];
+        let error = loop {
+            let started = Instant::now();
+            if let Err(e) = writer.write(&segment).await {
+                assert!(started.elapsed() >= timeout);
+                break e;
+            }
+        };
+        assert_eq!(
+            error.to_string(),
+            format!("ffmpeg didn't read its input within {:?}", timeout)
+        );
+    }
+
+    #[actix_web::test]
+    async fn one_ffmpeg_takes_every_segment() {
+        set_fake_ffmpeg();
+        let output_dir = tempfile::tempdir().unwrap();
+        let fifo_dir = tempfile::tempdir().unwrap();
+        let (_, config) = pusher_output(true, true);
+        let stats = Arc::new(StreamStats::default());
+        let mut pusher = LiveHlsPusher::spawn(
+            &output_dir.path().to_string_lossy(),
+            fifo_dir.path(),
+            "index.m3u8",
+            "segment",
+            &config,
+            true,
+            &stats,
+        )
+        .unwrap();
+        let ffmpeg = pusher.child.id();
+
+        for _ in 0..20 {
+            pusher
+                .write(&[b"video".to_vec()], Some(b"audio"), None)
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(pusher.child.id(), ffmpeg);
+        assert_eq!(stats.snapshot().ffmpeg_restarts, 0);
+        // Two FIFOs, for the one launch
+        assert_eq!(fs::read_dir(fifo_dir.path()).unwrap().count(), 2);
+        pusher.finish(Duration::from_secs(5)).unwrap();
+    }
+
+    #[tokio::test]
+    async fn dropping_a_pusher_kills_its_ffmpeg() {
+        set_fake_ffmpeg();
+        let output_dir = tempfile::tempdir().unwrap();
+        let fifo_dir = tempfile::tempdir().unwrap();
+        let (_, config) = pusher_output(true, true);
+        let stats = Arc::new(StreamStats::default());
+        let mut pusher = LiveHlsPusher::spawn(
+            &output_dir.path().to_string_lossy(),
+            fifo_dir.path(),
+            "index.m3u8",
+            "segment",
+            &config,
+            true,
+            &stats,
+        )
+        .unwrap();
+        pusher
+            .write(&[b"video".to_vec()], Some(b"audio"), None)
+            .await
+            .unwrap();
+        let ffmpeg = Path::new("/proc").join(pusher.child.id().to_string());
+        assert!(ffmpeg.exists());
+
+        // Dropped mid-stream, as an error path would
+        drop(pusher);
+        assert!(!ffmpeg.exists());
+    }
+
+    #[test]
+    fn stopping_a_converter_removes_its_fifo_dir() {
+        set_fake_ffmpeg();
+        let output_dir = tempfile::tempdir().unwrap();
+        let config: crate::config::channels::ChannelConfig = toml::from_str(
+            "[[channel]]\nid = \"news\"\nname = \"news\"\nurl = \"http://o/news.mpd\"\n",
+        )
+        .unwrap();
+        let info =
+            StreamInfo::from_channel(config.channel.into_iter().next().unwrap(), &HashMap::new());
+        let new_converter = || {
+            DashToHlsConverter::new(
+                &output_dir.path().to_string_lossy(),
+                info.clone(),
+                ConverterTOML::default(),
+                6,
+                4,
+            )
+            .unwrap()
+        };
+
+        let mut converter = new_converter();
+        let fifo_dir = converter.fifo_dir.path().to_path_buf();
+        assert!(fifo_dir.is_dir());
+        converter.stop().unwrap();
+        assert!(!fifo_dir.exists());
+
+        // Dropped without a stop, as when its task is aborted
+        let converter = new_converter();
+        let fifo_dir = converter.fifo_dir.path().to_path_buf();
+        drop(converter);
+        assert!(!fifo_dir.exists());
+    }
+
+    // Track of `count` two-second segments named after the track
+    fn named_track(name: &str, count: usize) -> Track {
+        let mut track = track(count, Some(2.0));
+        for (i, segment) in track.segments.iter_mut().enumerate() {
+            segment.url = format!("{}_{}.m4s", name, i);
+        }
+        track
+    }
+
+    fn pairings(video_tracks: &[Track], audio: &Track) -> Vec<HashMap<usize, Vec<usize>>> {
+        video_tracks
+            .iter()
+            .map(|track| pair_segments(track, audio).into_iter().collect())
+            .collect()
+    }
+
+    fn push(audio: usize, variant: usize, videos: &[usize]) -> PendingPush {
+        PendingPush {
+            audio,
+            variant,
+            videos: videos.to_vec(),
+        }
+    }
+
+    #[test]
+    fn pending_pushes_follow_the_playlist_order() {
+        let video_tracks = [named_track("high", 3), named_track("low", 3)];
+        let audio = named_track("audio", 3);
+
+        let pushes = pending_pushes(
+            &video_tracks,
+            &audio,
+            &pairings(&video_tracks, &audio),
+            &HashSet::new(),
+        );
+        assert_eq!(
+            pushes,
+            [
+                push(0, 0, &[0]),
+                push(0, 1, &[0]),
+                push(1, 0, &[1]),
+                push(1, 1, &[1]),
+                push(2, 0, &[2]),
+                push(2, 1, &[2]),
+            ]
+        );
+    }
+
+    #[test]
+    fn pending_pushes_leave_out_what_was_pushed() {
+        let video_tracks = [named_track("high", 3), named_track("low", 3)];
+        let audio = named_track("audio", 3);
+
+        // The first pair of both variants went out with the previous refresh, the second
+        // video segment of the first variant too before its audio failed
+        let pushed = HashSet::from([
+            (0, audio.segments[0].clone()),
+            (0, video_tracks[0].segments[0].clone()),
+            (1, audio.segments[0].clone()),
+            (1, video_tracks[1].segments[0].clone()),
+            (0, video_tracks[0].segments[1].clone()),
+        ]);
+        let pushes = pending_pushes(
+            &video_tracks,
+            &audio,
+            &pairings(&video_tracks, &audio),
+            &pushed,
+        );
+        assert_eq!(
+            pushes,
+            [
+                push(1, 0, &[]),
+                push(1, 1, &[1]),
+                push(2, 0, &[2]),
+                push(2, 1, &[2]),
+            ]
+        );
+    }
+
+    #[test]
+    fn download_list_fetches_shared_audio_once() {
+        let video_tracks = [named_track("high", 2), named_track("low", 2)];
+        let audio = named_track("audio", 2);
+        let pushes = [push(0, 0, &[0]), push(0, 1, &[0]), push(1, 0, &[1])];
+
+        let urls: Vec<String> = download_list(pushes.iter(), &video_tracks, Some(&audio))
+            .into_iter()
+            .map(|segment| segment.url)
+            .collect();
+        assert_eq!(
+            urls,
+            [
+                "high_0.m4s",
+                "audio_0.m4s",
+                "low_0.m4s",
+                "high_1.m4s",
+                "audio_1.m4s"
+            ]
+        );
+    }
+
+    #[test]
+    fn downloads_finishing_out_of_order_are_pushed_in_playlist_order() {
+        let video_tracks = [named_track("video", 4)];
+        let audio = named_track("audio", 4);
+        let pushes = pending_pushes(
+            &video_tracks,
+            &audio,
+            &pairings(&video_tracks, &audio),
+            &HashSet::new(),
+        );
+        let segments = download_list(pushes.iter(), &video_tracks, Some(&audio));
+
+        // The last segments came in first
+        let mut downloaded = HashMap::new();
+        for segment in segments.into_iter().rev() {
+            let bytes = segment.url.clone().into_bytes();
+            downloaded.insert(segment, Ok(bytes));
+        }
+
+        let mut muxed = Vec::new();
+        for push in &pushes {
+            for &video in &push.videos {
+                let segment = &video_tracks[push.variant].segments[video];
+                muxed.push(take_download(&mut downloaded, segment).unwrap());
+            }
+            muxed.push(take_download(&mut downloaded, &audio.segments[push.audio]).unwrap());
+        }
+        let muxed: Vec<String> = muxed
+            .into_iter()
+            .map(|bytes| String::from_utf8(bytes).unwrap())
+            .collect();
+        assert_eq!(
+            muxed,
+            [
+                "video_0.m4s",
+                "audio_0.m4s",
+                "video_1.m4s",
+                "audio_1.m4s",
+                "video_2.m4s",
+                "audio_2.m4s",
+                "video_3.m4s",
+                "audio_3.m4s"
+            ]
+        );
+        assert!(downloaded.is_empty());
+        assert!(take_download(&mut downloaded, &audio.segments[0]).is_err());
+    }
+
+    #[test]
+    fn take_prefetched_reuses_segments_fetched_ahead() {
+        let audio = named_track("audio", 3);
+        let mut prefetched = HashMap::from([
+            (audio.segments[1].clone(), b"ahead".to_vec()),
+            (audio.segments[2].clone(), b"later".to_vec()),
+        ]);
+
+        let (ready, missing) = take_prefetched(&mut prefetched, audio.segments[..2].to_vec());
+        assert!(missing == [audio.segments[0].clone()]);
+        assert_eq!(ready.len(), 1);
+        assert_eq!(ready[&audio.segments[1]].as_ref().unwrap(), b"ahead");
+        // Prefetches the refresh didn't need stay for the next one
+        assert_eq!(prefetched.len(), 1);
+        assert!(prefetched.contains_key(&audio.segments[2]));
+    }
+
+    #[test]
+    fn pair_segments_by_position_without_times() {
+        let pairs = pair_segments(&track(5, None), &track(3, None));
+        assert_eq!(pairs, [(0, vec![0]), (1, vec![1]), (2, vec![2])]);
+    }
+
+    #[test]
+    fn pair_segments_of_equal_cadence() {
+        let pairs = pair_segments(&track(3, Some(2.0)), &track(3, Some(2.0)));
+        assert_eq!(pairs, [(0, vec![0]), (1, vec![1]), (2, vec![2])]);
+    }
+
+    #[test]
+    fn pair_segments_keeps_every_shorter_video_segment() {
+        // 2s video against 4s audio, each audio segment takes two video segments
+        let pairs = pair_segments(&track(6, Some(2.0)), &track(3, Some(4.0)));
+        assert_eq!(pairs, [(0, vec![0, 1]), (1, vec![2, 3]), (2, vec![4, 5])]);
+    }
+
+    #[test]
+    fn pair_segments_keeps_every_shorter_audio_segment() {
+        // 4s video against 2s audio, every other audio segment goes without video
+        let pairs = pair_segments(&track(2, Some(4.0)), &track(4, Some(2.0)));
+        assert_eq!(
+            pairs,
+            [(0, vec![0]), (1, vec![]), (2, vec![1]), (3, vec![])]
+        );
+    }
+
+    #[test]
+    fn pair_segments_leaves_audio_past_the_video_for_later() {
+        let pairs = pair_segments(&track(2, Some(2.0)), &track(4, Some(2.0)));
+        assert_eq!(pairs, [(0, vec![0]), (1, vec![1])]);
+    }
+
+    fn pusher_output(has_video: bool, has_audio: bool) -> (PusherOutput, PusherConfig) {
+        let output = PusherOutput {
+            output_dir: "/out".to_string(),
+            fifo_dir: PathBuf::from("/fifo"),
+            playlist_name: "index.m3u8".to_string(),
+            segment_prefix: "segment".to_string(),
+            has_video,
+            has_audio,
+        };
+        let config = PusherConfig {
+            max_segments: 5,
+            segment_time: 4,
+            segment_format: SegmentFormat::Ts,
+            output: OutputFormat::Hls,
+            video_codec: None,
+            max_bitrate: None,
+            bufsize: None,
+            audio_copy: true,
+            parts: None,
+            key_info: None,
+            max_restarts: 0,
+            write_timeout: Duration::from_secs(1),
+            program_date_times: Arc::default(),
+        };
+        (output, config)
+    }
+
+    fn maps(args: &[String]) -> Vec<&str> {
+        args.windows(2)
+            .filter(|pair| pair[0] == "-map" || pair[0] == "-i" || pair[0] == "-c:a")
+            .map(|pair| pair[1].as_str())
+            .collect()
+    }
+
+    #[test]
+    fn hls_args_map_whichever_tracks_the_pusher_takes() {
+        let video = Path::new("/fifo/v");
+        let audio = Path::new("/fifo/a");
+
+        let (output, config) = pusher_output(true, true);
+        let args = hls_args(Some(video), Some(audio), &output, &config, false);
+        assert_eq!(
+            maps(&args),
+            ["/fifo/v", "/fifo/a", "0:v:0", "1:a:0", "copy"]
+        );
+
+        let (output, config) = pusher_output(false, true);
+        let args = hls_args(None, Some(audio), &output, &config, false);
+        assert_eq!(maps(&args), ["/fifo/a", "0:a:0", "copy"]);
+
+        let (output, config) = pusher_output(true, false);
+        let args = hls_args(Some(video), None, &output, &config, false);
+        assert_eq!(maps(&args), ["/fifo/v", "0:v:0"]);
+    }
+
+    fn arg<'a>(args: &'a [String], name: &str) -> Option<&'a str> {
+        args.windows(2)
+            .find(|pair| pair[0] == name)
+            .map(|pair| pair[1].as_str())
+    }
+
+    #[test]
+    fn low_latency_cuts_parts_of_the_part_duration() {
+        let video = Path::new("/fifo/v");
+        let (output, mut config) = pusher_output(true, true);
+        let args = hls_args(Some(video), None, &output, &config, false);
+        assert_eq!(arg(&args, "-hls_time"), Some("4"));
+        assert_eq!(arg(&args, "-hls_list_size"), Some("5"));
+        assert_eq!(
+            arg(&args, "-hls_flags"),
+            Some("delete_segments+program_date_time")
+        );
+
+        config.parts = Some(PartLayout {
+            segment_time: 4,
+            part_duration_ms: 500,
+        });
+        config.video_codec = Some("libx264".to_string());
+        let args = hls_args(Some(video), None, &output, &config, false);
+        assert_eq!(arg(&args, "-hls_time"), Some("0.5"));
+        assert_eq!(arg(&args, "-hls_list_size"), Some("40"));
+        assert_eq!(
+            arg(&args, "-hls_flags"),
+            Some("delete_segments+program_date_time+split_by_time+temp_file")
+        );
+        assert_eq!(
+            arg(&args, "-force_key_frames"),
+            Some("expr:gte(t,n_forced*4)")
+        );
+    }
+
+    #[test]
+    fn dash_output_writes_a_manifest_with_templated_segments() {
+        let (mut output, mut config) = pusher_output(true, true);
+        output.playlist_name = "manifest.mpd".to_string();
+        config.output = OutputFormat::Dash;
+        let args = hls_args(
+            Some(Path::new("/fifo/v")),
+            Some(Path::new("/fifo/a")),
+            &output,
+            &config,
+            false,
+        );
+
+        assert_eq!(arg(&args, "-f"), Some("dash"));
+        assert_eq!(arg(&args, "-seg_duration"), Some("4"));
+        assert_eq!(arg(&args, "-window_size"), Some("5"));
+        assert_eq!(
+            arg(&args, "-init_seg_name"),
+            Some("segment_init_$RepresentationID$.mp4")
+        );
+        assert_eq!(
+            arg(&args, "-media_seg_name"),
+            Some("segment_$RepresentationID$_$Number%05d$.m4s")
+        );
+        assert_eq!(arg(&args, "-hls_time"), None);
+        assert_eq!(args.last().unwrap(), "/out/manifest.mpd");
+    }
+
+    #[test]
+    fn announced_drm_systems_pick_their_keys() {
+        let widevine = HashMap::from([("kid".to_string(), "widevine key".to_string())]);
+        let playready = HashMap::from([("kid".to_string(), "playready key".to_string())]);
+        let drm_keys = [
+            (DrmSystem::Widevine, widevine.clone()),
+            (DrmSystem::PlayReady, playready.clone()),
+        ];
+        let announcing = |systems: &[DrmSystem]| {
+            let mut track = track(1, None);
+            track.drm_systems = systems.iter().map(|s| s.system_id().to_string()).collect();
+            track
+        };
+
+        let track = announcing(&[DrmSystem::PlayReady]);
+        assert_eq!(
+            announced_drm_keys(&drm_keys, &[&track]),
+            Some(vec![&playready])
+        );
+        let track = announcing(&[DrmSystem::PlayReady, DrmSystem::Widevine]);
+        assert_eq!(
+            announced_drm_keys(&drm_keys, &[&track]),
+            Some(vec![&widevine, &playready])
+        );
+
+        // Nothing announced leaves the keys as they are, other systems are a mismatch
+        let track = announcing(&[]);
+        assert_eq!(announced_drm_keys(&drm_keys, &[&track]), Some(vec![]));
+        let track = announcing(&[DrmSystem::ClearKey]);
+        assert_eq!(announced_drm_keys(&drm_keys, &[&track]), None);
+    }
+
+    #[test]
+    fn start_modes_pick_their_segments() {
+        let live = MPD {
+            mpdtype: Some("dynamic".to_string()),
+            ..Default::default()
+        };
+        let vod = MPD {
+            mpdtype: Some("static".to_string()),
+            ..Default::default()
+        };
+        let kept = |start_mode: StartMode, mpd: &MPD| {
+            let segments: Vec<usize> = (0..10).collect();
+            let window = start_mode_window(start_mode, mpd, || 3).unwrap_or(segments.len());
+            segments[segments.len().saturating_sub(window)..].to_vec()
+        };
+
+        assert_eq!(kept(StartMode::LiveEdge, &live), [7, 8, 9]);
+        assert_eq!(kept(StartMode::LiveEdge, &vod), (0..10).collect::<Vec<_>>());
+        for mpd in [&live, &vod] {
+            assert_eq!(kept(StartMode::Dvr(5), mpd), [5, 6, 7, 8, 9]);
+            assert_eq!(kept(StartMode::Dvr(0), mpd), [9]);
+            assert_eq!(kept(StartMode::Dvr(50), mpd).len(), 10);
+            assert_eq!(kept(StartMode::Beginning, mpd).len(), 10);
+        }
+    }
+
+    #[test]
+    fn encrypted_output_gets_a_fresh_key_and_its_key_info() {
+        let output_dir = tempfile::tempdir().unwrap();
+        let fifo_dir = tempfile::tempdir().unwrap();
+        let key_info = write_output_key(output_dir.path(), fifo_dir.path()).unwrap();
+
+        let key_path = output_dir.path().join(OUTPUT_KEY_FILE);
+        assert_eq!(fs::read(&key_path).unwrap().len(), 16);
+        assert_eq!(key_info, fifo_dir.path().join(KEY_INFO_FILE));
+        assert_eq!(
+            fs::read_to_string(&key_info).unwrap(),
+            format!("stream.key\n{}\n", key_path.display())
+        );
+
+        let (output, mut config) = pusher_output(true, true);
+        let args = hls_args(Some(Path::new("/fifo/v")), None, &output, &config, false);
+        assert_eq!(arg(&args, "-hls_key_info_file"), None);
+        config.key_info = Some(key_info.clone());
+        let args = hls_args(Some(Path::new("/fifo/v")), None, &output, &config, false);
+        assert_eq!(
+            arg(&args, "-hls_key_info_file"),
+            Some(key_info.to_str().unwrap())
+        );
+    }
+
+    #[test]
+    fn fill_segment_template_pads_to_the_given_width() {
+        assert_eq!(
+            fill_segment_template("seg_$Number%05d$.m4s", 0, 42),
+            "seg_00042.m4s"
+        );
+        assert_eq!(
+            fill_segment_template("$RepresentationID$/$Time$_$Number$.m4s", 9000, 7),
+            "$RepresentationID$/9000_7.m4s"
+        );
+        // Too narrow a width doesn't cut the value, an unknown format is left alone
+        assert_eq!(fill_segment_template("$Number%02d$", 0, 12345), "12345");
+        assert_eq!(fill_segment_template("$Number%x$", 0, 1), "$Number%x$");
+    }
+
+    #[test]
+    fn templates_resolve_the_representation_id_bandwidth_and_number() {
+        let representation = Representation {
+            id: Some("video=1500000".to_string()),
+            bandwidth: Some(1500000),
+            ..Default::default()
+        };
+        let media = fill_template(
+            "chunk-$RepresentationID$-$Bandwidth$-$Number$.m4s",
+            &representation,
+        );
+        assert_eq!(
+            fill_segment_template(&media, 0, 17),
+            "chunk-video=1500000-1500000-17.m4s"
+        );
+        assert_eq!(
+            fill_template("init-$Bandwidth$.mp4", &Representation::default()),
+            "init-0.mp4"
+        );
+    }
+
+    fn s(t: Option<i64>, d: i64, r: Option<i64>) -> S {
+        S { t, d, r }
+    }
+
+    #[test]
+    fn compute_segment_times_restarts_at_each_t() {
+        // A gap between 400 and 1000
+        let timeline = [s(Some(0), 100, Some(3)), s(Some(1000), 50, None)];
+        assert_eq!(
+            compute_segment_times(&timeline, 0),
+            [0, 100, 200, 300, 1000]
+        );
+    }
+
+    #[test]
+    fn compute_segment_times_repeats_a_negative_r_up_to_the_next_t() {
+        let timeline = [s(Some(0), 100, Some(-1)), s(Some(400), 100, None)];
+        assert_eq!(compute_segment_times(&timeline, 0), [0, 100, 200, 300, 400]);
+        // Without a next t, once
+        assert_eq!(compute_segment_times(&[s(None, 100, Some(-1))], 50), [50]);
+    }
+
+    #[test]
+    fn timeline_start_follows_the_offset_or_the_live_edge() {
+        let timeline = [s(None, 2, Some(2))];
+        assert_eq!(timeline_start(&timeline, Some(500), None, 1), 500);
+        assert_eq!(timeline_start(&timeline, None, None, 1), 0);
+        // Six seconds listed, ending at the live edge of 100 s
+        assert_eq!(
+            timeline_start(&timeline, None, Some(Duration::from_secs(100)), 1),
+            94
+        );
+        assert_eq!(compute_segment_times(&timeline, 94), [94, 96, 98]);
+    }
+
+    #[test]
+    fn resolve_url_against_the_manifest_directory() {
+        let base = "https://h/a/b/manifest.mpd";
+        assert_eq!(
+            resolve_url(base, "seg/1.m4s").unwrap(),
+            "https://h/a/b/seg/1.m4s"
+        );
+        assert_eq!(
+            resolve_url(base, "../seg/1.m4s").unwrap(),
+            "https://h/a/seg/1.m4s"
+        );
+        assert_eq!(
+            resolve_url(base, "https://cdn/seg/1.m4s").unwrap(),
+            "https://cdn/seg/1.m4s"
+        );
+        assert!(resolve_url("not a url", "seg/1.m4s").is_err());
+    }
+
+    fn live_mpd(time_shift: &str) -> MPD {
+        dash_mpd::parse(&format!(
+            r#"<MPD xmlns="urn:mpeg:dash:schema:mpd:2011" type="dynamic" timeShiftBufferDepth="{time_shift}"><Period/></MPD>"#
+        ))
+        .unwrap()
+    }
 
-    if !output.status.success() {
-        let err = String::from_utf8_lossy(&output.stderr);
-        anyhow::bail!("ffmpeg muxing failed: {}", err);
+    #[test]
+    fn time_shift_segments_divides_buffer_by_segment_length() {
+        let mpd = live_mpd("PT30S");
+        assert_eq!(time_shift_segments(&mpd, Some(4.0)), Some(7));
+        assert_eq!(time_shift_segments(&mpd, Some(0.0)), None);
+        assert_eq!(time_shift_segments(&mpd, None), None);
     }
 
-    Ok(output.stdout)
+    #[test]
+    fn time_shift_segments_needs_a_buffer_depth() {
+        let mpd = dash_mpd::parse(
+            r#"<MPD xmlns="urn:mpeg:dash:schema:mpd:2011" type="dynamic"><Period/></MPD>"#,
+        )
+        .unwrap();
+        assert_eq!(time_shift_segments(&mpd, Some(2.0)), None);
+    }
 }
@@ -0,0 +1,28 @@
+use super::{StreamInfo, count_representations, origin_client, read_mpd};
+use std::time::Duration;
+
+// Representations of a channel's MPD the converter could convert
+pub struct ChannelCheck {
+    pub video: usize,
+    pub audio: usize,
+}
+
+impl ChannelCheck {
+    pub fn usable(&self) -> bool {
+        self.video > 0 || self.audio > 0
+    }
+}
+
+// Fetches and parses the MPD of a channel the way the converter does, without starting it
+pub async fn check_channel(
+    stream_info: &StreamInfo,
+    timeout: Duration,
+) -> anyhow::Result<ChannelCheck> {
+    let client = origin_client(stream_info, true, timeout)?;
+    let (mpd, _) = read_mpd(client.get(&stream_info.url)).await?;
+
+    Ok(ChannelCheck {
+        video: count_representations(&mpd, "video"),
+        audio: count_representations(&mpd, "audio"),
+    })
+}
@@ -0,0 +1,76 @@
+// Live MPDs may announce how clients get the origin's clock (UTCTiming), so the live edge
+// is computed on the origin's time rather than ours
+
+use chrono::{DateTime, NaiveDateTime, TimeDelta, Utc};
+use dash_mpd::UTCTiming;
+use url::Url;
+
+// Offset to add to the local clock to get the origin's, from the first UTCTiming scheme we
+// support. None when there's none or every one of them failed.
+pub async fn origin_offset(
+    client: &reqwest::Client,
+    timings: &[UTCTiming],
+    mpd_url: &Url,
+) -> Option<TimeDelta> {
+    for timing in timings {
+        if let Some(offset) = timing_offset(client, timing, mpd_url).await {
+            return Some(offset);
+        }
+    }
+    None
+}
+
+async fn timing_offset(
+    client: &reqwest::Client,
+    timing: &UTCTiming,
+    mpd_url: &Url,
+) -> Option<TimeDelta> {
+    let scheme = timing.schemeIdUri.as_deref()?;
+    let value = timing.value.as_deref()?;
+    let scheme = scheme.strip_prefix("urn:mpeg:dash:utc:")?;
+
+    // The request takes time too, the origin's answer is matched with its midpoint
+    let sent = Utc::now();
+    let origin_time = match scheme {
+        "direct:2014" => parse_utc_time(value)?,
+        "http-iso:2014" | "http-xsdate:2014" => {
+            let url = mpd_url.join(value).ok()?;
+            let response = client.get(url).send().await.ok()?.error_for_status().ok()?;
+            parse_utc_time(&response.text().await.ok()?)?
+        }
+        "http-head:2014" => {
+            let url = mpd_url.join(value).ok()?;
+            let response = client
+                .head(url)
+                .send()
+                .await
+                .ok()?
+                .error_for_status()
+                .ok()?;
+            let date = response
+                .headers()
+                .get(reqwest::header::DATE)?
+                .to_str()
+                .ok()?;
+            DateTime::parse_from_rfc2822(date).ok()?.with_timezone(&Utc)
+        }
+        _ => return None,
+    };
+    let received = Utc::now();
+
+    if scheme == "direct:2014" {
+        return Some(origin_time - sent);
+    }
+    Some(origin_time - (sent + (received - sent) / 2))
+}
+
+// ISO 8601 / xs:dateTime, taken as UTC when it has no offset
+pub fn parse_utc_time(text: &str) -> Option<DateTime<Utc>> {
+    let text = text.trim();
+    DateTime::parse_from_rfc3339(text)
+        .map(|time| time.with_timezone(&Utc))
+        .or_else(|_| {
+            NaiveDateTime::parse_from_str(text, "%Y-%m-%dT%H:%M:%S%.f").map(|time| time.and_utc())
+        })
+        .ok()
+}
@@ -0,0 +1,273 @@
+use super::ffmpeg_command;
+use crate::config::channels::DecryptorKind;
+use std::collections::HashMap;
+use std::io::Write;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::thread;
+
+// Turns a CENC protected fragment, init segment included, into a clear one
+pub trait Decryptor: Send + Sync {
+    fn decrypt(&self, data: &[u8], keys: &HashMap<String, String>) -> anyhow::Result<Vec<u8>>;
+}
+
+// Backend of a channel, and the one to try when it fails. Channels without keys are passed
+// through untouched. Shared so segments are decrypted on the blocking pool.
+pub fn for_channel(
+    kind: DecryptorKind,
+    has_keys: bool,
+) -> (Arc<dyn Decryptor>, Option<Arc<dyn Decryptor>>) {
+    if !has_keys {
+        return (Arc::new(NoOp), None);
+    }
+
+    match kind {
+        DecryptorKind::Mp4decrypt => (Arc::new(Mp4Decrypt), Some(Arc::new(Ffmpeg))),
+        DecryptorKind::Ffmpeg => (Arc::new(Ffmpeg), None),
+    }
+}
+
+pub struct NoOp;
+
+impl Decryptor for NoOp {
+    fn decrypt(&self, data: &[u8], _keys: &HashMap<String, String>) -> anyhow::Result<Vec<u8>> {
+        Ok(data.to_vec())
+    }
+}
+
+// Bento4's mp4decrypt, matching keys by KID
+pub struct Mp4Decrypt;
+
+impl Decryptor for Mp4Decrypt {
+    fn decrypt(&self, data: &[u8], keys: &HashMap<String, String>) -> anyhow::Result<Vec<u8>> {
+        mp4decrypt::mp4decrypt(data, keys.clone(), None)
+            .map_err(|e| anyhow::anyhow!("mp4decrypt failed: {}", e))
+    }
+}
+
+// ffmpeg's CENC decryption, which only takes a single key
+pub struct Ffmpeg;
+
+impl Decryptor for Ffmpeg {
+    fn decrypt(&self, data: &[u8], keys: &HashMap<String, String>) -> anyhow::Result<Vec<u8>> {
+        let Some(key) = keys.values().next() else {
+            anyhow::bail!("no key to decrypt the segment with");
+        };
+        let mut child = ffmpeg_command()
+            .args([
+                "-hide_banner",
+                "-loglevel",
+                "error",
+                "-decryption_key",
+                key,
+                "-i",
+                "pipe:0",
+                "-c",
+                "copy",
+                "-f",
+                "mp4",
+                "-movflags",
+                "frag_keyframe+empty_moov+default_base_moof",
+                "pipe:1",
+            ])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        // Fed from another thread so a full stdout pipe can't block the input, stdin is
+        // closed once written so ffmpeg sees the end of the segment
+        let mut stdin = child
+            .stdin
+            .take()
+            .ok_or_else(|| anyhow::anyhow!("ffmpeg stdin unavailable"))?;
+        let input = data.to_vec();
+        let feeder = thread::spawn(move || stdin.write_all(&input));
+
+        let output = child.wait_with_output()?;
+        feeder.join().ok();
+
+        if !output.status.success() || output.stdout.is_empty() {
+            anyhow::bail!(
+                "ffmpeg could not decrypt the segment: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        Ok(output.stdout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dash_to_hls::{StreamStats, decrypt_segment};
+    use std::sync::Mutex;
+
+    // Records the keys of each call, failing unless given one of the `working` keys
+    struct Recording {
+        working: Vec<&'static str>,
+        calls: Mutex<Vec<Vec<String>>>,
+    }
+
+    impl Recording {
+        fn new(working: &[&'static str]) -> Self {
+            Recording {
+                working: working.to_vec(),
+                calls: Mutex::new(Vec::new()),
+            }
+        }
+
+        fn calls(&self) -> Vec<Vec<String>> {
+            self.calls.lock().unwrap().clone()
+        }
+    }
+
+    impl Decryptor for Recording {
+        fn decrypt(&self, data: &[u8], keys: &HashMap<String, String>) -> anyhow::Result<Vec<u8>> {
+            let mut keys: Vec<String> = keys.values().cloned().collect();
+            keys.sort();
+            let works = keys.iter().any(|key| self.working.contains(&key.as_str()));
+            self.calls.lock().unwrap().push(keys);
+            if !works {
+                anyhow::bail!("wrong key");
+            }
+            Ok(data.iter().rev().copied().collect())
+        }
+    }
+
+    fn keys(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(kid, key)| (kid.to_string(), key.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn noop_passes_segments_through() {
+        let data = b"clear segment".to_vec();
+        assert_eq!(NoOp.decrypt(&data, &HashMap::new()).unwrap(), data);
+        assert_eq!(NoOp.decrypt(&data, &keys(&[("kid", "key")])).unwrap(), data);
+    }
+
+    #[test]
+    fn channels_without_keys_are_passed_through() {
+        let (decryptor, fallback) = for_channel(DecryptorKind::Mp4decrypt, false);
+        assert_eq!(
+            decryptor.decrypt(b"data", &HashMap::new()).unwrap(),
+            b"data"
+        );
+        assert!(fallback.is_none());
+
+        let (_, fallback) = for_channel(DecryptorKind::Mp4decrypt, true);
+        assert!(fallback.is_some());
+        let (_, fallback) = for_channel(DecryptorKind::Ffmpeg, true);
+        assert!(fallback.is_none());
+    }
+
+    #[test]
+    fn ffmpeg_refuses_segments_without_a_key() {
+        assert!(Ffmpeg.decrypt(b"data", &HashMap::new()).is_err());
+    }
+
+    // One fragment of four mono AAC frames, CENC encrypted with KEY under KID
+    const CENC_FIXTURE: &[u8] = include_bytes!("../../tests/fixtures/cenc_audio.mp4");
+    const KID: &str = "eb676abbcb345e96bbcf616630f1a3da";
+    const KEY: &str = "00112233445566778899aabbccddeeff";
+    const CLEAR_FRAME: [u8; 4] = [0x01, 0x40, 0x20, 0x07];
+
+    fn contains(data: &[u8], part: &[u8]) -> bool {
+        data.windows(part.len()).any(|window| window == part)
+    }
+
+    #[test]
+    fn ffmpeg_fallback_decrypts_the_cenc_fixture() {
+        assert!(!contains(CENC_FIXTURE, &CLEAR_FRAME));
+
+        let found = std::process::Command::new("ffmpeg")
+            .arg("-version")
+            .stdout(Stdio::null())
+            .status()
+            .is_ok_and(|status| status.success());
+        if !found {
+            eprintln!("ffmpeg not found, skipping");
+            return;
+        }
+
+        let clear = Ffmpeg.decrypt(CENC_FIXTURE, &keys(&[(KID, KEY)])).unwrap();
+        assert!(!clear.is_empty());
+        assert!(contains(&clear, &CLEAR_FRAME));
+    }
+
+    #[test]
+    fn decrypt_segment_uses_the_stream_keys() {
+        let decryptor = Recording::new(&["key"]);
+        let stats = StreamStats::default();
+
+        let output = decrypt_segment(
+            &decryptor,
+            None,
+            &keys(&[("kid", "key")]),
+            &[],
+            &stats,
+            b"abc",
+        );
+        assert_eq!(output.unwrap(), b"cba");
+        assert_eq!(decryptor.calls(), [["key"]]);
+        assert_eq!(stats.snapshot().decrypt_failures, 0);
+    }
+
+    #[test]
+    fn decrypt_segment_tries_each_recent_key() {
+        let decryptor = Recording::new(&["old"]);
+        let stats = StreamStats::default();
+        let recent = ["new".to_string(), "old".to_string()];
+
+        let output = decrypt_segment(
+            &decryptor,
+            None,
+            &keys(&[("kid", "new")]),
+            &recent,
+            &stats,
+            b"abc",
+        );
+        assert_eq!(output.unwrap(), b"cba");
+        assert_eq!(decryptor.calls(), [["new"], ["new"], ["old"]]);
+    }
+
+    #[test]
+    fn decrypt_segment_falls_back_to_the_other_decryptor() {
+        let decryptor = Recording::new(&[]);
+        let fallback = Recording::new(&["key"]);
+        let stats = StreamStats::default();
+
+        let output = decrypt_segment(
+            &decryptor,
+            Some(&fallback),
+            &keys(&[("kid", "key")]),
+            &[],
+            &stats,
+            b"abc",
+        );
+        assert_eq!(output.unwrap(), b"cba");
+        assert_eq!(decryptor.calls(), [["key"]]);
+        assert_eq!(fallback.calls(), [["key"]]);
+        assert_eq!(stats.snapshot().decrypt_failures, 1);
+    }
+
+    #[test]
+    fn decrypt_segment_fails_without_a_fallback() {
+        let decryptor = Recording::new(&[]);
+        let stats = StreamStats::default();
+
+        let output = decrypt_segment(
+            &decryptor,
+            None,
+            &keys(&[("kid", "key")]),
+            &[],
+            &stats,
+            b"abc",
+        );
+        assert!(output.is_err());
+        assert_eq!(stats.snapshot().decrypt_failures, 1);
+    }
+}
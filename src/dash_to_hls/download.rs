@@ -0,0 +1,552 @@
+// Segment downloads of a stream, made with the async client so the downloads of every
+// stream share the runtime's threads
+
+use super::{Segment, StreamStats, throttle};
+use crate::config::settings::ConverterTOML;
+use futures_util::{Stream, StreamExt, stream};
+use log::{debug, warn};
+use reqwest::StatusCode;
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+pub struct Downloader {
+    // Built once so keep-alive connections to the origin survive between refreshes
+    client: reqwest::Client,
+    // Header name and id of the request that started the stream, sent along origin fetches
+    request_id: Option<(String, String)>,
+    // Attempts per segment and the delay before the first retry, doubled on each one
+    attempts: u32,
+    retry_backoff: Duration,
+    // Segments downloaded at the same time
+    concurrency: usize,
+    segment_timeout: Duration,
+    max_segment_bytes: u64,
+    // Shared by every download of the stream, None when unlimited
+    throttle: Option<throttle::TokenBucket>,
+    stats: Arc<StreamStats>,
+    // Alternative BaseURLs (e.g. other @serviceLocation CDNs) of each base segments are
+    // addressed from, tried in turn when a download fails
+    base_url_failovers: Mutex<HashMap<String, Vec<String>>>,
+}
+
+impl Downloader {
+    pub fn new(
+        client: reqwest::Client,
+        config: &ConverterTOML,
+        rate_limit: u64,
+        stats: Arc<StreamStats>,
+    ) -> Self {
+        Self {
+            client,
+            request_id: None,
+            attempts: config.segment_attempts,
+            retry_backoff: Duration::from_millis(config.retry_backoff_ms),
+            concurrency: config.download_concurrency.max(1),
+            segment_timeout: Duration::from_secs(config.segment_timeout_secs),
+            max_segment_bytes: config.max_segment_bytes,
+            throttle: (rate_limit > 0).then(|| throttle::TokenBucket::new(rate_limit)),
+            stats,
+            base_url_failovers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn set_request_id(&mut self, header: &str, id: &str) {
+        self.request_id = Some((header.to_string(), id.to_string()));
+    }
+
+    // Tags a request to the origin with the originating request id
+    pub fn tag(&self, request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        match &self.request_id {
+            Some((header, id)) => request.header(header.as_str(), id.as_str()),
+            None => request,
+        }
+    }
+
+    pub fn set_failovers(&self, base: String, alternatives: Vec<String>) {
+        self.base_url_failovers
+            .lock()
+            .unwrap()
+            .insert(base, alternatives);
+    }
+
+    // GET of a segment, limited to its byte range when it has one
+    fn segment_get(&self, segment: &Segment) -> reqwest::RequestBuilder {
+        let request = self.tag(self.client.get(&segment.url));
+        match segment.range {
+            Some((first, last)) => {
+                request.header(reqwest::header::RANGE, format!("bytes={}-{}", first, last))
+            }
+            None => request,
+        }
+    }
+
+    // Body of a segment response, within max_segment_bytes and the download rate limit.
+    // A throttled body has as long as a segment request to arrive.
+    async fn read_body(&self, resp: reqwest::Response) -> Result<Vec<u8>, BodyError> {
+        let deadline = Instant::now() + self.segment_timeout;
+        read_capped_body(
+            resp.content_length(),
+            response_chunks(resp),
+            self.max_segment_bytes,
+            self.throttle.as_ref().map(|bucket| (bucket, deadline)),
+        )
+        .await
+    }
+
+    // The segment itself, then the same segment under each failover BaseURL of its base
+    pub fn locations(&self, segment: &Segment) -> Vec<Segment> {
+        let mut locations = vec![segment.clone()];
+        let failovers = self.base_url_failovers.lock().unwrap();
+
+        for (base, alternatives) in failovers.iter() {
+            if let Some(path) = segment.url.strip_prefix(base.as_str()) {
+                locations.extend(alternatives.iter().map(|alternative| Segment {
+                    url: format!("{}{}", alternative, path),
+                    range: segment.range,
+                }));
+                break;
+            }
+        }
+
+        locations
+    }
+
+    // Fetches a segment, retrying network errors, 5xx and 429 with exponential backoff. Each
+    // retry moves on to the next location of the segment.
+    pub async fn fetch(&self, segment: &Segment) -> anyhow::Result<Vec<u8>> {
+        let locations = self.locations(segment);
+        let max_attempts = self.attempts.max(locations.len() as u32);
+        let mut backoff = self.retry_backoff;
+        let mut attempt = 1;
+
+        loop {
+            let location = &locations[(attempt as usize - 1) % locations.len()];
+            let url = &location.url;
+            let started = Instant::now();
+            let error = match self.segment_get(location).send().await {
+                Ok(resp) if resp.status().is_success() => {
+                    match self.read_body(resp).await {
+                        Ok(bytes) => {
+                            self.stats.record_download(started.elapsed(), bytes.len());
+                            return Ok(bytes);
+                        }
+                        // Another attempt would get the same body
+                        Err(e @ BodyError::TooLarge { .. }) => {
+                            let error = format!("{} on {}", e, url);
+                            self.stats.record_download_failure(url, &error);
+                            anyhow::bail!(error);
+                        }
+                        Err(BodyError::Read(e)) => e.into(),
+                    }
+                }
+                Ok(resp) => {
+                    let status = resp.status();
+                    if !is_retried(status) {
+                        let error = format!("HTTP {} on {}", status, url);
+                        self.stats.record_download_failure(url, &error);
+                        anyhow::bail!(error);
+                    }
+                    anyhow::anyhow!("HTTP {} on {}", status, url)
+                }
+                Err(e) => e.into(),
+            };
+
+            if attempt >= max_attempts {
+                self.stats
+                    .record_download_failure(url, &format!("{} after {} attempts", error, attempt));
+                return Err(error);
+            }
+
+            warn!(
+                "Attempt {}/{} failed for {}: {}, retrying in {:?}",
+                attempt, max_attempts, url, error, backoff
+            );
+            actix_web::rt::time::sleep(backoff).await;
+            backoff *= 2;
+            attempt += 1;
+        }
+    }
+
+    // A single attempt at the segment's own location, as for init segments and prefetches
+    pub async fn fetch_once(&self, segment: &Segment) -> anyhow::Result<Vec<u8>> {
+        let started = Instant::now();
+        let resp = self.segment_get(segment).send().await?.error_for_status()?;
+        let bytes = self
+            .read_body(resp)
+            .await
+            .map_err(|e| anyhow::anyhow!("{}", e))?;
+        self.stats.record_download(started.elapsed(), bytes.len());
+        Ok(bytes)
+    }
+
+    // Fetches segments up to download_concurrency at once
+    pub async fn fetch_all(
+        &self,
+        segments: Vec<Segment>,
+    ) -> HashMap<Segment, anyhow::Result<Vec<u8>>> {
+        stream::iter(segments)
+            .map(|segment| async move {
+                let result = self.fetch(&segment).await;
+                (segment, result)
+            })
+            .buffer_unordered(self.concurrency)
+            .collect()
+            .await
+    }
+
+    // Fetches segments that may not be published yet, keeping those that were. A 404 only
+    // means the segment isn't out yet.
+    pub async fn fetch_ahead(&self, segments: Vec<Segment>) -> Vec<(Segment, Vec<u8>)> {
+        stream::iter(segments)
+            .map(|segment| async move {
+                match self.fetch_once(&segment).await {
+                    Ok(bytes) => Some((segment, bytes)),
+                    Err(e) => {
+                        debug!("Prefetch of {} failed: {}", segment.url, e);
+                        None
+                    }
+                }
+            })
+            .buffer_unordered(self.concurrency)
+            .filter_map(|fetched| async move { fetched })
+            .collect()
+            .await
+    }
+}
+
+// Network errors, 5xx and 429 may go away on their own, other answers won't
+fn is_retried(status: StatusCode) -> bool {
+    status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
+}
+
+#[derive(Debug)]
+pub enum BodyError {
+    TooLarge { length: u64, max: u64 },
+    Read(io::Error),
+}
+
+impl std::fmt::Display for BodyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BodyError::TooLarge { length, max } => write!(
+                f,
+                "body of {} bytes exceeds max_segment_bytes ({})",
+                length, max
+            ),
+            BodyError::Read(e) => write!(f, "error reading body: {}", e),
+        }
+    }
+}
+
+impl From<io::Error> for BodyError {
+    fn from(e: io::Error) -> Self {
+        BodyError::Read(e)
+    }
+}
+
+// Body of a response, chunk by chunk as it arrives
+pub fn response_chunks(
+    resp: reqwest::Response,
+) -> impl Stream<Item = reqwest::Result<impl AsRef<[u8]>>> {
+    stream::unfold(resp, |mut resp| async move {
+        resp.chunk().await.transpose().map(|chunk| (chunk, resp))
+    })
+}
+
+// Reads a response body of at most `max` bytes. An announced Content-Length above it is
+// refused before reading anything, otherwise reading stops at the first chunk past the
+// limit. With a throttle, the body is read no faster than its rate, and given up on when
+// that would take it past the deadline.
+pub async fn read_capped_body<B, E>(
+    content_length: Option<u64>,
+    body: impl Stream<Item = Result<B, E>>,
+    max: u64,
+    throttle: Option<(&throttle::TokenBucket, Instant)>,
+) -> Result<Vec<u8>, BodyError>
+where
+    B: AsRef<[u8]>,
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    if let Some(length) = content_length
+        && length > max
+    {
+        return Err(BodyError::TooLarge { length, max });
+    }
+
+    let mut bytes = Vec::new();
+    let mut body = std::pin::pin!(body);
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk.map_err(io::Error::other)?;
+        let chunk = chunk.as_ref();
+        if let Some((bucket, deadline)) = throttle {
+            bucket.take(chunk.len(), deadline).await?;
+        }
+        bytes.extend_from_slice(chunk);
+        if bytes.len() as u64 > max {
+            return Err(BodyError::TooLarge {
+                length: bytes.len() as u64,
+                max,
+            });
+        }
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    // Origin answering each request from a thread of its own, with the status and body
+    // `respond` gives for the request's path
+    fn mock_origin(respond: impl Fn(&str) -> (u16, Vec<u8>) + Send + Sync + 'static) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let respond = Arc::new(respond);
+        thread::spawn(move || {
+            for connection in listener.incoming() {
+                let Ok(mut connection) = connection else {
+                    continue;
+                };
+                let respond = Arc::clone(&respond);
+                thread::spawn(move || {
+                    let mut request = Vec::new();
+                    let mut buf = [0; 1024];
+                    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+                        match connection.read(&mut buf) {
+                            Ok(0) | Err(_) => return,
+                            Ok(read) => request.extend_from_slice(&buf[..read]),
+                        }
+                    }
+                    let request = String::from_utf8_lossy(&request);
+                    let path = request.split_whitespace().nth(1).unwrap_or("/");
+                    let (status, body) = respond(path);
+                    let head = format!(
+                        "HTTP/1.1 {} Mock\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                        status,
+                        body.len()
+                    );
+                    connection.write_all(head.as_bytes()).ok();
+                    connection.write_all(&body).ok();
+                });
+            }
+        });
+        format!("http://{}", address)
+    }
+
+    fn downloader(config: &ConverterTOML) -> Downloader {
+        Downloader::new(
+            reqwest::Client::new(),
+            config,
+            0,
+            Arc::new(StreamStats::default()),
+        )
+    }
+
+    #[actix_web::test]
+    async fn streams_download_at_the_same_time_on_a_single_thread() {
+        let origin = mock_origin(|_| {
+            thread::sleep(Duration::from_millis(200));
+            (200, b"segment".to_vec())
+        });
+        let streams: Vec<Downloader> = (0..8)
+            .map(|_| downloader(&ConverterTOML::default()))
+            .collect();
+
+        // The test runtime runs every task on this thread
+        let runtime_thread = thread::current().id();
+        let started = Instant::now();
+        let fetches = streams.iter().enumerate().map(|(stream, downloader)| {
+            let segment = Segment::whole(format!("{}/stream_{}/segment.m4s", origin, stream));
+            async move {
+                let bytes = downloader.fetch(&segment).await.unwrap();
+                (bytes, thread::current().id())
+            }
+        });
+        let fetched = futures_util::future::join_all(fetches).await;
+
+        for (bytes, thread) in fetched {
+            assert_eq!(bytes, b"segment");
+            assert_eq!(thread, runtime_thread);
+        }
+        // One stream after the other would take 1.6s
+        assert!(started.elapsed() < Duration::from_millis(1000));
+    }
+
+    fn quick_retries() -> ConverterTOML {
+        ConverterTOML {
+            segment_attempts: 3,
+            retry_backoff_ms: 1,
+            ..ConverterTOML::default()
+        }
+    }
+
+    // Origin answering with `statuses` in turn, then with 200, counting the requests
+    fn flaky_origin(statuses: &'static [u16]) -> (String, Arc<AtomicUsize>) {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counted = Arc::clone(&requests);
+        let origin = mock_origin(move |_| {
+            let request = counted.fetch_add(1, Ordering::SeqCst);
+            match statuses.get(request) {
+                Some(&status) => (status, Vec::new()),
+                None => (200, b"segment".to_vec()),
+            }
+        });
+        (origin, requests)
+    }
+
+    #[test]
+    fn server_errors_and_throttling_are_retried() {
+        assert!(is_retried(StatusCode::INTERNAL_SERVER_ERROR));
+        assert!(is_retried(StatusCode::SERVICE_UNAVAILABLE));
+        assert!(is_retried(StatusCode::TOO_MANY_REQUESTS));
+        assert!(!is_retried(StatusCode::NOT_FOUND));
+        assert!(!is_retried(StatusCode::FORBIDDEN));
+        assert!(!is_retried(StatusCode::RANGE_NOT_SATISFIABLE));
+    }
+
+    #[actix_web::test]
+    async fn fetch_retries_until_the_origin_recovers() {
+        let (origin, requests) = flaky_origin(&[503, 429]);
+        let segment = Segment::whole(format!("{}/segment_1.m4s", origin));
+
+        let bytes = downloader(&quick_retries()).fetch(&segment).await.unwrap();
+        assert_eq!(bytes, b"segment");
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+    }
+
+    #[actix_web::test]
+    async fn fetch_gives_up_after_the_last_attempt() {
+        let (origin, requests) = flaky_origin(&[500, 500, 500, 500]);
+        let segment = Segment::whole(format!("{}/segment_1.m4s", origin));
+
+        let error = downloader(&quick_retries())
+            .fetch(&segment)
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("HTTP 500"), "{}", error);
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+    }
+
+    #[actix_web::test]
+    async fn fetch_does_not_retry_client_errors() {
+        for status in [&[404], &[403]] {
+            let (origin, requests) = flaky_origin(status);
+            let segment = Segment::whole(format!("{}/segment_1.m4s", origin));
+            let downloader = downloader(&quick_retries());
+
+            let error = downloader.fetch(&segment).await.unwrap_err();
+            assert!(
+                error.to_string().contains(&format!("HTTP {}", status[0])),
+                "{}",
+                error
+            );
+            assert_eq!(requests.load(Ordering::SeqCst), 1);
+            assert_eq!(downloader.stats.snapshot().download_failures, 1);
+        }
+    }
+
+    #[test]
+    fn locations_put_the_failovers_after_the_segment() {
+        let downloader = downloader(&ConverterTOML::default());
+        downloader.set_failovers(
+            "https://a.cdn/live/".to_string(),
+            vec!["https://b.cdn/live/".to_string()],
+        );
+
+        let segment = Segment {
+            url: "https://a.cdn/live/video/1.m4s".to_string(),
+            range: Some((0, 99)),
+        };
+        let urls: Vec<(String, Option<(u64, u64)>)> = downloader
+            .locations(&segment)
+            .into_iter()
+            .map(|location| (location.url, location.range))
+            .collect();
+        assert_eq!(
+            urls,
+            [
+                ("https://a.cdn/live/video/1.m4s".to_string(), Some((0, 99))),
+                ("https://b.cdn/live/video/1.m4s".to_string(), Some((0, 99))),
+            ]
+        );
+
+        let elsewhere = Segment::whole("https://c.cdn/live/1.m4s".to_string());
+        assert_eq!(downloader.locations(&elsewhere).len(), 1);
+    }
+
+    #[actix_web::test]
+    async fn fetch_fails_over_to_the_next_base_url() {
+        let (down, down_requests) = flaky_origin(&[503; 8]);
+        let (up, up_requests) = flaky_origin(&[]);
+        let downloader = downloader(&ConverterTOML {
+            segment_attempts: 1,
+            ..quick_retries()
+        });
+        downloader.set_failovers(format!("{}/", down), vec![format!("{}/", up)]);
+
+        let segment = Segment::whole(format!("{}/live/1.m4s", down));
+        let bytes = downloader.fetch(&segment).await.unwrap();
+        assert_eq!(bytes, b"segment");
+        assert_eq!(down_requests.load(Ordering::SeqCst), 1);
+        assert_eq!(up_requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[actix_web::test]
+    async fn read_capped_body_refuses_an_announced_length_over_the_limit() {
+        // Nothing is read from a body announced too large
+        let body = stream::iter([Ok::<_, io::Error>(vec![0u8; 10])])
+            .map(|_| -> io::Result<Vec<u8>> { panic!("body read despite its Content-Length") });
+
+        match read_capped_body(Some(2_000), body, 1_000, None).await {
+            Err(BodyError::TooLarge { length, max }) => assert_eq!((length, max), (2_000, 1_000)),
+            other => panic!("expected TooLarge, got {:?}", other),
+        }
+    }
+
+    #[actix_web::test]
+    async fn read_capped_body_stops_a_streamed_body_over_the_limit() {
+        let chunks = (0..4).map(|_| Ok::<_, io::Error>(vec![1u8; 400]));
+
+        match read_capped_body(None, stream::iter(chunks), 1_000, None).await {
+            Err(BodyError::TooLarge { length, max }) => assert_eq!((length, max), (1_200, 1_000)),
+            other => panic!("expected TooLarge, got {:?}", other),
+        }
+
+        let chunks = (0..2).map(|_| Ok::<_, io::Error>(vec![1u8; 500]));
+        let body = read_capped_body(None, stream::iter(chunks), 1_000, None).await;
+        assert_eq!(body.unwrap().len(), 1_000);
+    }
+
+    #[actix_web::test]
+    async fn read_capped_body_passes_read_errors_on() {
+        let chunks = [Ok(vec![1u8; 10]), Err(io::Error::other("connection reset"))];
+
+        match read_capped_body(None, stream::iter(chunks), 1_000, None).await {
+            Err(BodyError::Read(e)) => assert!(e.to_string().contains("connection reset")),
+            other => panic!("expected Read, got {:?}", other),
+        }
+    }
+
+    #[actix_web::test]
+    async fn read_capped_body_reads_a_throttled_body_whole() {
+        let bucket = throttle::TokenBucket::new(1_000_000);
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let chunks = (0..4).map(|_| Ok::<_, io::Error>(vec![7u8; 10_000]));
+
+        let body = read_capped_body(
+            None,
+            stream::iter(chunks),
+            1_000_000,
+            Some((&bucket, deadline)),
+        )
+        .await
+        .unwrap();
+        assert_eq!(body, vec![7u8; 40_000]);
+    }
+}
@@ -0,0 +1,66 @@
+use log::{error, warn};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use tempfile::tempdir;
+
+// Directory of a converter's named pipes and key info, removed along with the converter
+// whether it's a fresh temp directory or the fallback inside the output directory
+pub struct FifoDir {
+    path: PathBuf,
+    // Set when no temp directory could be created
+    fallback: bool,
+}
+
+impl FifoDir {
+    pub fn create(output_dir: &str) -> io::Result<Self> {
+        match tempdir() {
+            Ok(dir) => Ok(Self {
+                path: dir.keep(),
+                fallback: false,
+            }),
+            Err(e) => {
+                let path = Path::new(output_dir).join("temp");
+                warn!(
+                    "Error creating a temp directory ({}), using {}",
+                    e,
+                    path.display()
+                );
+                fs::create_dir_all(&path)?;
+                Ok(Self {
+                    path,
+                    fallback: true,
+                })
+            }
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn join(&self, name: &str) -> PathBuf {
+        self.path.join(name)
+    }
+
+    // Safe to call more than once, the converter does on stop and again when dropped
+    pub fn remove(&self) {
+        if let Err(e) = fs::remove_dir_all(&self.path)
+            && e.kind() != io::ErrorKind::NotFound
+        {
+            let kind = if self.fallback { "fallback " } else { "" };
+            error!(
+                "Error removing {}fifo directory {}: {}",
+                kind,
+                self.path.display(),
+                e
+            );
+        }
+    }
+}
+
+impl Drop for FifoDir {
+    fn drop(&mut self) {
+        self.remove();
+    }
+}
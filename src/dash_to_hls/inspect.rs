@@ -0,0 +1,87 @@
+use super::{DEFAULT_USER_AGENT, SegmentSource, content_kind};
+use dash_mpd::{AdaptationSet, MPD, Period, Representation};
+
+// What a representation of an MPD looks like to the converter
+struct RepresentationSummary {
+    period: usize,
+    adaptation_set: usize,
+    id: Option<String>,
+    kind: Option<&'static str>,
+    mime_type: Option<String>,
+    bandwidth: Option<u64>,
+    addressing: &'static str,
+}
+
+// Fetches an MPD and describes its representations, for checking a source before
+// adding it as a channel
+pub fn inspect_mpd(url: &str) -> anyhow::Result<String> {
+    let client = reqwest::blocking::Client::builder()
+        .user_agent(DEFAULT_USER_AGENT)
+        .build()?;
+    let response = client.get(url).send()?;
+    if !response.status().is_success() {
+        anyhow::bail!("Failed to fetch MPD: HTTP {}", response.status());
+    }
+
+    let mpd = dash_mpd::parse(&response.text()?)?;
+    Ok(format_table(&analyze(&mpd)))
+}
+
+// Lists every representation along with how its segments are addressed, following the
+// same inheritance as segment extraction
+fn analyze(mpd: &MPD) -> Vec<RepresentationSummary> {
+    let mut summaries = Vec::new();
+
+    for (period_index, period) in mpd.periods.iter().enumerate() {
+        for (set_index, adaptation_set) in period.adaptations.iter().enumerate() {
+            for representation in &adaptation_set.representations {
+                summaries.push(RepresentationSummary {
+                    period: period_index,
+                    adaptation_set: set_index,
+                    id: representation.id.clone(),
+                    kind: content_kind(adaptation_set),
+                    mime_type: representation
+                        .mimeType
+                        .clone()
+                        .or_else(|| adaptation_set.mimeType.clone()),
+                    bandwidth: representation.bandwidth,
+                    addressing: addressing(period, adaptation_set, representation),
+                });
+            }
+        }
+    }
+
+    summaries
+}
+
+fn addressing(
+    period: &Period,
+    adaptation_set: &AdaptationSet,
+    representation: &Representation,
+) -> &'static str {
+    SegmentSource::of(period, adaptation_set, representation).map_or("none", |source| source.name())
+}
+
+fn format_table(summaries: &[RepresentationSummary]) -> String {
+    let mut table = format!(
+        "{:<7} {:<4} {:<24} {:<6} {:<16} {:>10}  {}\n",
+        "PERIOD", "SET", "REPRESENTATION", "KIND", "MIME TYPE", "BANDWIDTH", "ADDRESSING"
+    );
+
+    for summary in summaries {
+        table.push_str(&format!(
+            "{:<7} {:<4} {:<24} {:<6} {:<16} {:>10}  {}\n",
+            summary.period,
+            summary.adaptation_set,
+            summary.id.as_deref().unwrap_or("-"),
+            summary.kind.unwrap_or("-"),
+            summary.mime_type.as_deref().unwrap_or("-"),
+            summary
+                .bandwidth
+                .map_or_else(|| "-".to_string(), |bandwidth| bandwidth.to_string()),
+            summary.addressing,
+        ));
+    }
+
+    table
+}
@@ -0,0 +1,65 @@
+use std::fs::{self, File, TryLockError};
+use std::io::{self, Write};
+use std::path::Path;
+
+// Marks an output directory as owned by a converter, so two ffmpeg processes never write
+// the same playlist. Holds an exclusive flock on the .lock file, which the kernel releases
+// when the owner closes it or dies, so there's no stale lock to take over. The file stays
+// behind: removing it would let a converter lock the old file while another creates a new one.
+pub struct OutputLock {
+    _file: File,
+}
+
+impl OutputLock {
+    pub fn acquire(output_dir: &str) -> io::Result<Self> {
+        let path = Path::new(output_dir).join(".lock");
+        let mut file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} is already in use by another converter", output_dir),
+                ));
+            }
+            Err(TryLockError::Error(e)) => return Err(e),
+        }
+
+        // The pid is only there for whoever looks into the directory
+        file.set_len(0)?;
+        write!(file, "{}", std::process::id())?;
+        Ok(Self { _file: file })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn second_lock_is_refused_until_the_first_is_dropped() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path().to_str().unwrap();
+
+        let first = OutputLock::acquire(dir).unwrap();
+        let refused = OutputLock::acquire(dir).err().unwrap();
+        assert_eq!(refused.kind(), io::ErrorKind::AlreadyExists);
+
+        drop(first);
+        assert!(OutputLock::acquire(dir).is_ok());
+    }
+
+    #[test]
+    fn lock_file_holds_the_owner_pid() {
+        let dir = tempfile::tempdir().unwrap();
+        let _lock = OutputLock::acquire(dir.path().to_str().unwrap()).unwrap();
+        let owner = fs::read_to_string(dir.path().join(".lock")).unwrap();
+        assert_eq!(owner, std::process::id().to_string());
+    }
+}
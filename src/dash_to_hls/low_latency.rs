@@ -0,0 +1,453 @@
+// Low-latency HLS out of ffmpeg's HLS muxer, which can't write partial segments itself.
+// ffmpeg cuts parts of the configured duration, at any frame, into a playlist of its own.
+// Served playlists group every `parts_per_segment` parts into a segment listed along its
+// parts (EXT-X-PART), and hint the part ffmpeg writes next (EXT-X-PRELOAD-HINT). Segments
+// aren't written to disk, they're assembled from their parts when requested.
+
+// How the segments of a low-latency stream are split into parts
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PartLayout {
+    pub segment_time: u32,
+    pub part_duration_ms: u32,
+}
+
+impl PartLayout {
+    pub fn parts_per_segment(&self) -> u64 {
+        (u64::from(self.segment_time) * 1000 / u64::from(self.part_duration_ms.max(1))).max(1)
+    }
+
+    // Part duration in seconds, as ffmpeg's -hls_time takes it
+    pub fn part_time(&self) -> String {
+        (f64::from(self.part_duration_ms) / 1000.0).to_string()
+    }
+
+    fn part_target(&self) -> f64 {
+        f64::from(self.part_duration_ms) / 1000.0
+    }
+}
+
+// Complete segments still listed with their parts, counted from the end of the playlist.
+// The LL-HLS spec asks for parts of at least the last three target durations.
+const SEGMENTS_WITH_PARTS: usize = 3;
+
+// Appended to the name of a segment's first part to name the segment
+const SEGMENT_SUFFIX: &str = "_full";
+
+// A part listed by ffmpeg
+struct Part {
+    sequence: u64,
+    duration: f64,
+    uri: String,
+    // Follows an ffmpeg restart
+    discontinuity: bool,
+    // Other tags ffmpeg wrote for the part, such as its program date time
+    tags: Vec<String>,
+}
+
+// A segment of the served playlist, complete once its last part is written or ffmpeg moved
+// on to another segment
+struct PartedSegment {
+    sequence: u64,
+    parts: Vec<Part>,
+    complete: bool,
+}
+
+impl PartedSegment {
+    fn uri(&self) -> String {
+        segment_uri(&self.parts[0].uri)
+    }
+}
+
+// ffmpeg's playlist of parts seen as the segments they make up
+pub struct PartedPlaylist {
+    version: u32,
+    // Tags ahead of the first part that apply to the whole playlist, such as EXT-X-MAP
+    header: Vec<String>,
+    segments: Vec<PartedSegment>,
+    // Part ffmpeg writes after the last listed one
+    next_part: Option<String>,
+    ended: bool,
+}
+
+impl PartedPlaylist {
+    // Parts numbered N * parts_per_segment start the segment N. A part whose segment
+    // started before the playlist window, or follows an ffmpeg restart in the middle of a
+    // segment, is left out along with the rest of its segment. The restart then ends the
+    // segment it interrupted, and its discontinuity moves to the next segment.
+    pub fn parse(playlist: &str, layout: PartLayout) -> Self {
+        let (version, header, parts, ended) = parse_parts(playlist);
+        let per_segment = layout.parts_per_segment();
+        let next_part = parts
+            .last()
+            .filter(|_| !ended)
+            .and_then(|part| next_part_uri(&part.uri));
+
+        let mut segments: Vec<PartedSegment> = Vec::new();
+        let mut carried_discontinuity = false;
+        for mut part in parts {
+            let sequence = part.sequence / per_segment;
+            if part.sequence % per_segment == 0 {
+                if let Some(previous) = segments.last_mut() {
+                    previous.complete = true;
+                }
+                part.discontinuity |= std::mem::take(&mut carried_discontinuity);
+                segments.push(PartedSegment {
+                    sequence,
+                    parts: vec![part],
+                    complete: false,
+                });
+                continue;
+            }
+
+            match segments.last_mut() {
+                Some(current)
+                    if current.sequence == sequence && !current.complete && !part.discontinuity =>
+                {
+                    current.parts.push(part);
+                }
+                current => {
+                    if part.discontinuity {
+                        carried_discontinuity = true;
+                        if let Some(current) = current {
+                            current.complete = true;
+                        }
+                    }
+                }
+            }
+        }
+
+        for segment in &mut segments {
+            let last = segment.parts.last().map_or(0, |part| part.sequence);
+            segment.complete |= ended || last % per_segment == per_segment - 1;
+        }
+
+        PartedPlaylist {
+            version,
+            header,
+            segments,
+            next_part,
+            ended,
+        }
+    }
+
+    // Whether the playlist got to the segment `msn`, or to its part `part` when given
+    pub fn reached(&self, msn: u64, part: Option<u64>) -> bool {
+        self.ended
+            || self.segments.iter().any(|segment| {
+                segment.sequence > msn
+                    || (segment.sequence == msn
+                        && (segment.complete
+                            || part.is_some_and(|part| (segment.parts.len() as u64) > part)))
+            })
+    }
+
+    // Media sequence number of the last complete segment
+    pub fn last_complete(&self) -> Option<u64> {
+        self.segments
+            .iter()
+            .rev()
+            .find(|segment| segment.complete)
+            .map(|segment| segment.sequence)
+    }
+
+    // Parts to concatenate into the complete segment named `uri`
+    pub fn segment_parts(&self, uri: &str) -> Option<Vec<&str>> {
+        self.segments
+            .iter()
+            .find(|segment| segment.complete && segment.uri() == uri)
+            .map(|segment| segment.parts.iter().map(|part| part.uri.as_str()).collect())
+    }
+
+    // Whether `uri` is the part hinted as coming next
+    pub fn is_next_part(&self, uri: &str) -> bool {
+        self.next_part.as_deref() == Some(uri)
+    }
+
+    // The LL-HLS media playlist. The target durations are the configured ones, so they
+    // don't change from one reload to the next.
+    pub fn render(&self, layout: PartLayout) -> String {
+        let part_target = layout.part_target();
+        let mut playlist = format!(
+            "#EXTM3U\n\
+             #EXT-X-VERSION:{}\n\
+             #EXT-X-TARGETDURATION:{}\n\
+             #EXT-X-SERVER-CONTROL:CAN-BLOCK-RELOAD=YES,PART-HOLD-BACK={:.3}\n\
+             #EXT-X-PART-INF:PART-TARGET={:.3}\n\
+             #EXT-X-MEDIA-SEQUENCE:{}\n",
+            self.version.max(6),
+            layout.segment_time,
+            3.0 * part_target,
+            part_target,
+            self.segments.first().map_or(0, |segment| segment.sequence),
+        );
+        for tag in &self.header {
+            playlist.push_str(tag);
+            playlist.push('\n');
+        }
+
+        let complete = self.segments.iter().filter(|s| s.complete).count();
+        let parts_from = complete.saturating_sub(SEGMENTS_WITH_PARTS);
+        for (index, segment) in self.segments.iter().enumerate() {
+            let first = &segment.parts[0];
+            if first.discontinuity {
+                playlist.push_str("#EXT-X-DISCONTINUITY\n");
+            }
+            for tag in &first.tags {
+                playlist.push_str(tag);
+                playlist.push('\n');
+            }
+            if !segment.complete || index >= parts_from {
+                for part in &segment.parts {
+                    playlist.push_str(&format!(
+                        "#EXT-X-PART:DURATION={:.5},URI=\"{}\"\n",
+                        part.duration, part.uri
+                    ));
+                }
+            }
+            if segment.complete {
+                let duration: f64 = segment.parts.iter().map(|part| part.duration).sum();
+                playlist.push_str(&format!("#EXTINF:{:.6},\n{}\n", duration, segment.uri()));
+            }
+        }
+
+        if let Some(next_part) = &self.next_part {
+            playlist.push_str(&format!(
+                "#EXT-X-PRELOAD-HINT:TYPE=PART,URI=\"{}\"\n",
+                next_part
+            ));
+        }
+        if self.ended {
+            playlist.push_str("#EXT-X-ENDLIST\n");
+        }
+        playlist
+    }
+}
+
+// Version, playlist-wide tags and parts of an ffmpeg media playlist, and whether it ended
+fn parse_parts(playlist: &str) -> (u32, Vec<String>, Vec<Part>, bool) {
+    let mut version = 3;
+    let mut media_sequence = 0;
+    let mut header = Vec::new();
+    let mut parts: Vec<Part> = Vec::new();
+    let mut ended = false;
+
+    let mut duration = 0.0;
+    let mut discontinuity = false;
+    let mut tags = Vec::new();
+    for line in playlist.lines().map(str::trim) {
+        if line.is_empty() || line == "#EXTM3U" || line.starts_with("#EXT-X-TARGETDURATION") {
+            continue;
+        } else if let Some(value) = line.strip_prefix("#EXT-X-VERSION:") {
+            version = value.parse().unwrap_or(version);
+        } else if let Some(value) = line.strip_prefix("#EXT-X-MEDIA-SEQUENCE:") {
+            media_sequence = value.parse().unwrap_or(0);
+        } else if let Some(value) = line.strip_prefix("#EXTINF:") {
+            let value = value.split(',').next().unwrap_or_default();
+            duration = value.parse().unwrap_or(0.0);
+        } else if line == "#EXT-X-DISCONTINUITY" {
+            discontinuity = true;
+        } else if line == "#EXT-X-ENDLIST" {
+            ended = true;
+        } else if line.starts_with('#') {
+            // ffmpeg writes the playlist-wide tags ahead of the first part
+            if parts.is_empty() && !line.starts_with("#EXT-X-PROGRAM-DATE-TIME") {
+                header.push(line.to_string());
+            } else {
+                tags.push(line.to_string());
+            }
+        } else {
+            parts.push(Part {
+                sequence: media_sequence + parts.len() as u64,
+                duration: std::mem::take(&mut duration),
+                uri: line.to_string(),
+                discontinuity: std::mem::take(&mut discontinuity),
+                tags: std::mem::take(&mut tags),
+            });
+        }
+    }
+    (version, header, parts, ended)
+}
+
+// Name of the segment whose first part is `part_uri`
+fn segment_uri(part_uri: &str) -> String {
+    match part_uri.rsplit_once('.') {
+        Some((stem, extension)) => format!("{}{}.{}", stem, SEGMENT_SUFFIX, extension),
+        None => format!("{}{}", part_uri, SEGMENT_SUFFIX),
+    }
+}
+
+// Name ffmpeg gives the part after `part_uri`, whose number ends its stem
+fn next_part_uri(part_uri: &str) -> Option<String> {
+    let (stem, extension) = part_uri.rsplit_once('.')?;
+    let prefix = stem.trim_end_matches(|c: char| c.is_ascii_digit());
+    let digits = &stem[prefix.len()..];
+    let number: u64 = digits.parse().ok()?;
+    Some(format!(
+        "{}{:0width$}.{}",
+        prefix,
+        number + 1,
+        extension,
+        width = digits.len()
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LAYOUT: PartLayout = PartLayout {
+        segment_time: 2,
+        part_duration_ms: 500,
+    };
+
+    // ffmpeg's playlist of the parts `first..first + count` of 0.5 s
+    fn ffmpeg_playlist(first: u64, count: u64, extra: &[(u64, &str)]) -> String {
+        let mut playlist = format!(
+            "#EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-TARGETDURATION:1\n#EXT-X-MEDIA-SEQUENCE:{}\n",
+            first
+        );
+        for sequence in first..first + count {
+            for (at, tag) in extra {
+                if *at == sequence {
+                    playlist.push_str(tag);
+                    playlist.push('\n');
+                }
+            }
+            playlist.push_str(&format!("#EXTINF:0.500000,\nvideo_0_{:03}.ts\n", sequence));
+        }
+        playlist
+    }
+
+    #[test]
+    fn layout_splits_segments_into_parts() {
+        assert_eq!(LAYOUT.parts_per_segment(), 4);
+        assert_eq!(LAYOUT.part_time(), "0.5");
+        let whole = PartLayout {
+            segment_time: 4,
+            part_duration_ms: 1000,
+        };
+        assert_eq!(whole.part_time(), "1");
+    }
+
+    #[test]
+    fn render_groups_parts_into_segments() {
+        // Parts 2 and 3 belong to a segment that started before the window
+        let playlist = PartedPlaylist::parse(&ffmpeg_playlist(2, 11, &[]), LAYOUT);
+
+        assert_eq!(
+            playlist.render(LAYOUT),
+            "#EXTM3U\n\
+             #EXT-X-VERSION:6\n\
+             #EXT-X-TARGETDURATION:2\n\
+             #EXT-X-SERVER-CONTROL:CAN-BLOCK-RELOAD=YES,PART-HOLD-BACK=1.500\n\
+             #EXT-X-PART-INF:PART-TARGET=0.500\n\
+             #EXT-X-MEDIA-SEQUENCE:1\n\
+             #EXT-X-PART:DURATION=0.50000,URI=\"video_0_004.ts\"\n\
+             #EXT-X-PART:DURATION=0.50000,URI=\"video_0_005.ts\"\n\
+             #EXT-X-PART:DURATION=0.50000,URI=\"video_0_006.ts\"\n\
+             #EXT-X-PART:DURATION=0.50000,URI=\"video_0_007.ts\"\n\
+             #EXTINF:2.000000,\n\
+             video_0_004_full.ts\n\
+             #EXT-X-PART:DURATION=0.50000,URI=\"video_0_008.ts\"\n\
+             #EXT-X-PART:DURATION=0.50000,URI=\"video_0_009.ts\"\n\
+             #EXT-X-PART:DURATION=0.50000,URI=\"video_0_010.ts\"\n\
+             #EXT-X-PART:DURATION=0.50000,URI=\"video_0_011.ts\"\n\
+             #EXTINF:2.000000,\n\
+             video_0_008_full.ts\n\
+             #EXT-X-PART:DURATION=0.50000,URI=\"video_0_012.ts\"\n\
+             #EXT-X-PRELOAD-HINT:TYPE=PART,URI=\"video_0_013.ts\"\n"
+        );
+    }
+
+    #[test]
+    fn render_keeps_parts_of_the_last_segments_only() {
+        let playlist = PartedPlaylist::parse(&ffmpeg_playlist(0, 20, &[]), LAYOUT);
+        let rendered = playlist.render(LAYOUT);
+
+        assert_eq!(rendered.matches("#EXTINF").count(), 5);
+        assert_eq!(rendered.matches("#EXT-X-PART:").count(), 12);
+        assert!(!rendered.contains("URI=\"video_0_007.ts\""));
+        assert!(rendered.contains("URI=\"video_0_008.ts\""));
+    }
+
+    #[test]
+    fn restarts_end_the_interrupted_segment() {
+        let tags = [(6, "#EXT-X-DISCONTINUITY")];
+        let playlist = PartedPlaylist::parse(&ffmpeg_playlist(0, 10, &tags), LAYOUT);
+        let rendered = playlist.render(LAYOUT);
+
+        // Parts 6 and 7 are left out, the discontinuity starts the next segment
+        assert!(rendered.contains(
+            "URI=\"video_0_005.ts\"\n\
+             #EXTINF:1.000000,\n\
+             video_0_004_full.ts\n\
+             #EXT-X-DISCONTINUITY\n\
+             #EXT-X-PART:DURATION=0.50000,URI=\"video_0_008.ts\""
+        ));
+        assert!(!rendered.contains("video_0_006.ts"));
+        assert_eq!(
+            playlist.segment_parts("video_0_004_full.ts").unwrap(),
+            ["video_0_004.ts", "video_0_005.ts"]
+        );
+    }
+
+    #[test]
+    fn segment_parts_only_of_complete_segments() {
+        let playlist = PartedPlaylist::parse(&ffmpeg_playlist(0, 6, &[]), LAYOUT);
+        assert_eq!(
+            playlist.segment_parts("video_0_000_full.ts").unwrap(),
+            [
+                "video_0_000.ts",
+                "video_0_001.ts",
+                "video_0_002.ts",
+                "video_0_003.ts"
+            ]
+        );
+        assert!(playlist.segment_parts("video_0_004_full.ts").is_none());
+        assert!(playlist.is_next_part("video_0_006.ts"));
+    }
+
+    #[test]
+    fn reached_follows_segments_and_parts() {
+        let playlist = PartedPlaylist::parse(&ffmpeg_playlist(0, 6, &[]), LAYOUT);
+        assert_eq!(playlist.last_complete(), Some(0));
+        assert!(playlist.reached(0, None));
+        assert!(playlist.reached(1, Some(1)));
+        assert!(!playlist.reached(1, Some(2)));
+        assert!(!playlist.reached(1, None));
+
+        let ended = format!("{}#EXT-X-ENDLIST\n", ffmpeg_playlist(0, 6, &[]));
+        let playlist = PartedPlaylist::parse(&ended, LAYOUT);
+        assert!(playlist.reached(1, None));
+        assert!(playlist.segment_parts("video_0_004_full.ts").is_some());
+        assert!(!playlist.render(LAYOUT).contains("PRELOAD-HINT"));
+    }
+
+    #[test]
+    fn header_and_program_date_times_stay_with_their_segment() {
+        let tags = [
+            (0, "#EXT-X-PROGRAM-DATE-TIME:2024-01-01T00:00:00.000+0000"),
+            (1, "#EXT-X-PROGRAM-DATE-TIME:2024-01-01T00:00:00.500+0000"),
+        ];
+        let ffmpeg = ffmpeg_playlist(0, 4, &tags).replace(
+            "#EXT-X-MEDIA-SEQUENCE:0\n",
+            "#EXT-X-MEDIA-SEQUENCE:0\n#EXT-X-MAP:URI=\"video_0_init.mp4\"\n",
+        );
+        let rendered = PartedPlaylist::parse(&ffmpeg, LAYOUT).render(LAYOUT);
+
+        assert!(rendered.contains(
+            "#EXT-X-MEDIA-SEQUENCE:0\n\
+             #EXT-X-MAP:URI=\"video_0_init.mp4\"\n\
+             #EXT-X-PROGRAM-DATE-TIME:2024-01-01T00:00:00.000+0000\n\
+             #EXT-X-PART:"
+        ));
+        assert!(!rendered.contains("00:00:00.500"));
+    }
+
+    #[test]
+    fn next_part_uri_keeps_the_number_width() {
+        assert_eq!(next_part_uri("audio_1_009.m4s").unwrap(), "audio_1_010.m4s");
+        assert_eq!(next_part_uri("video_0_999.ts").unwrap(), "video_0_1000.ts");
+        assert!(next_part_uri("video.ts").is_none());
+    }
+}
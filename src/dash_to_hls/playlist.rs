@@ -0,0 +1,277 @@
+use chrono::{DateTime, TimeDelta, Utc};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+const PROGRAM_DATE_TIME_TAG: &str = "#EXT-X-PROGRAM-DATE-TIME:";
+
+// Human readable name of a language code, falling back to the code itself
+pub fn rendition_name<'a>(lang: &'a str, languages: &'a HashMap<String, String>) -> &'a str {
+    languages.get(lang).map(String::as_str).unwrap_or(lang)
+}
+
+// Builds an #EXT-X-MEDIA entry for an audio or subtitle rendition
+pub fn media_entry(
+    media_type: &str,
+    group_id: &str,
+    lang: &str,
+    languages: &HashMap<String, String>,
+    default: bool,
+    uri: Option<&str>,
+) -> String {
+    let mut entry = format!(
+        "#EXT-X-MEDIA:TYPE={},GROUP-ID=\"{}\",LANGUAGE=\"{}\",NAME=\"{}\",DEFAULT={},AUTOSELECT=YES",
+        media_type,
+        group_id,
+        lang,
+        rendition_name(lang, languages),
+        if default { "YES" } else { "NO" },
+    );
+
+    if let Some(uri) = uri {
+        entry.push_str(&format!(",URI=\"{}\"", uri));
+    }
+
+    entry
+}
+
+// A variant stream listed in the master playlist
+pub struct Variant {
+    pub bandwidth: u64,
+    pub resolution: Option<(u64, u64)>,
+    pub uri: String,
+    // Group ids of the audio and subtitle renditions offered with the variant
+    pub audio: Option<String>,
+    pub subtitles: Option<String>,
+}
+
+// Builds a master playlist referencing one media playlist per variant, after the
+// #EXT-X-MEDIA entries of its renditions
+pub fn master_playlist(media: &[String], variants: &[Variant]) -> String {
+    let mut playlist = String::from("#EXTM3U\n#EXT-X-VERSION:3\n");
+
+    for entry in media {
+        playlist.push_str(entry);
+        playlist.push('\n');
+    }
+
+    for variant in variants {
+        playlist.push_str(&format!(
+            "#EXT-X-STREAM-INF:BANDWIDTH={}",
+            variant.bandwidth
+        ));
+        if let Some((width, height)) = variant.resolution {
+            playlist.push_str(&format!(",RESOLUTION={}x{}", width, height));
+        }
+        if let Some(group) = &variant.audio {
+            playlist.push_str(&format!(",AUDIO=\"{}\"", group));
+        }
+        if let Some(group) = &variant.subtitles {
+            playlist.push_str(&format!(",SUBTITLES=\"{}\"", group));
+        }
+        playlist.push('\n');
+        playlist.push_str(&variant.uri);
+        playlist.push('\n');
+    }
+
+    playlist
+}
+
+// Offsets moving the #EXT-X-PROGRAM-DATE-TIME tags ffmpeg writes onto the MPD timeline.
+// ffmpeg stamps segments from its own clock, so each of its runs is anchored on its first
+// segment: the time ffmpeg listed it at against the MPD time of the first segment it got.
+#[derive(Default)]
+pub struct ProgramDateTimes {
+    // ffmpeg runs of each playlist, oldest first
+    anchors: Mutex<HashMap<String, Vec<Anchor>>>,
+}
+
+// ffmpeg's time of the first segment of a run, and how far its times are from the MPD's
+type Anchor = (DateTime<Utc>, TimeDelta);
+
+// ffmpeg runs remembered per playlist, enough to cover its window across restarts
+const MAX_ANCHORS: usize = 8;
+
+impl ProgramDateTimes {
+    pub fn anchor(&self, playlist_name: &str, listed: DateTime<Utc>, presented: DateTime<Utc>) {
+        let mut anchors = self.anchors.lock().unwrap();
+        let anchors = anchors.entry(playlist_name.to_string()).or_default();
+        anchors.push((listed, presented - listed));
+        if anchors.len() > MAX_ANCHORS {
+            anchors.remove(0);
+        }
+    }
+
+    // Moves each tag of the playlist by the offset of the ffmpeg run that wrote it
+    pub fn correct(&self, playlist_name: &str, content: &str) -> String {
+        let anchors = self.anchors.lock().unwrap();
+        let Some(anchors) = anchors.get(playlist_name) else {
+            return content.to_string();
+        };
+
+        shift_program_date_times(content, |time| {
+            anchors
+                .iter()
+                .rev()
+                .find(|(first, _)| *first <= time)
+                .map(|(_, offset)| *offset)
+        })
+    }
+}
+
+fn parse_program_date_time(line: &str) -> Option<DateTime<Utc>> {
+    line.strip_prefix(PROGRAM_DATE_TIME_TAG)
+        .and_then(|value| DateTime::parse_from_str(value, "%Y-%m-%dT%H:%M:%S%.f%z").ok())
+        .map(|time| time.with_timezone(&Utc))
+}
+
+// URI of the last segment listed in the playlist
+pub fn last_segment(playlist: &str) -> Option<&str> {
+    playlist
+        .lines()
+        .rev()
+        .find(|line| !line.is_empty() && !line.starts_with('#'))
+}
+
+// Program date time of the first segment listed after the segment `after`, or of the first
+// segment when `after` is None or no longer listed
+pub fn first_program_date_time(playlist: &str, after: Option<&str>) -> Option<DateTime<Utc>> {
+    let lines: Vec<&str> = playlist.lines().collect();
+    let start = after
+        .and_then(|after| lines.iter().position(|line| *line == after))
+        .map_or(0, |position| position + 1);
+
+    let mut time = None;
+    for line in &lines[start..] {
+        if let Some(parsed) = parse_program_date_time(line) {
+            time = Some(parsed);
+        } else if !line.is_empty() && !line.starts_with('#') {
+            return time;
+        }
+    }
+    None
+}
+
+// Rewrites each #EXT-X-PROGRAM-DATE-TIME tag by the offset returned for its time, leaving
+// tags without one or that don't parse untouched
+fn shift_program_date_times(
+    playlist: &str,
+    offset_for: impl Fn(DateTime<Utc>) -> Option<TimeDelta>,
+) -> String {
+    let mut shifted = String::with_capacity(playlist.len());
+
+    for line in playlist.lines() {
+        let time = parse_program_date_time(line);
+
+        match time.and_then(|time| offset_for(time).map(|offset| time + offset)) {
+            Some(time) => shifted.push_str(&format!(
+                "{}{}",
+                PROGRAM_DATE_TIME_TAG,
+                time.format("%Y-%m-%dT%H:%M:%S%.3f%z")
+            )),
+            None => shifted.push_str(line),
+        }
+        shifted.push('\n');
+    }
+
+    shifted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PLAYLIST: &str = "#EXTM3U\n\
+        #EXT-X-PROGRAM-DATE-TIME:2024-01-01T10:00:00.000+0000\n\
+        #EXTINF:4.000000,\n\
+        segment_1.ts\n\
+        #EXT-X-PROGRAM-DATE-TIME:2024-01-01T10:00:04.000+0000\n\
+        #EXTINF:4.000000,\n\
+        segment_2.ts\n\
+        #EXT-X-DISCONTINUITY\n\
+        #EXT-X-PROGRAM-DATE-TIME:2024-01-01T10:05:00.000+0000\n\
+        #EXTINF:4.000000,\n\
+        segment_3.ts\n";
+
+    #[test]
+    fn media_entry_names_the_language_and_keeps_its_code() {
+        let languages = HashMap::from([("spa".to_string(), "Español".to_string())]);
+
+        assert_eq!(
+            media_entry(
+                "AUDIO",
+                "audio",
+                "spa",
+                &languages,
+                true,
+                Some("audio_spa.m3u8")
+            ),
+            "#EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID=\"audio\",LANGUAGE=\"spa\",NAME=\"Español\",\
+             DEFAULT=YES,AUTOSELECT=YES,URI=\"audio_spa.m3u8\""
+        );
+        // Codes without a name are shown as is
+        assert_eq!(
+            media_entry("SUBTITLES", "subs", "deu", &languages, false, None),
+            "#EXT-X-MEDIA:TYPE=SUBTITLES,GROUP-ID=\"subs\",LANGUAGE=\"deu\",NAME=\"deu\",\
+             DEFAULT=NO,AUTOSELECT=YES"
+        );
+    }
+
+    fn time(value: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(value)
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    #[test]
+    fn first_program_date_time_of_a_run_follows_the_last_segment_before_it() {
+        assert_eq!(last_segment(PLAYLIST), Some("segment_3.ts"));
+        assert_eq!(
+            first_program_date_time(PLAYLIST, None),
+            Some(time("2024-01-01T10:00:00Z"))
+        );
+        assert_eq!(
+            first_program_date_time(PLAYLIST, Some("segment_2.ts")),
+            Some(time("2024-01-01T10:05:00Z"))
+        );
+        // The run hasn't listed a segment yet
+        assert_eq!(
+            first_program_date_time(PLAYLIST, Some("segment_3.ts")),
+            None
+        );
+        // Slid out of the window, everything listed came after it
+        assert_eq!(
+            first_program_date_time(PLAYLIST, Some("segment_0.ts")),
+            Some(time("2024-01-01T10:00:00Z"))
+        );
+    }
+
+    #[test]
+    fn each_run_is_moved_by_its_own_offset() {
+        let pdts = ProgramDateTimes::default();
+        pdts.anchor(
+            "index.m3u8",
+            time("2024-01-01T10:00:00Z"),
+            time("2024-01-01T09:00:00Z"),
+        );
+        pdts.anchor(
+            "index.m3u8",
+            time("2024-01-01T10:05:00Z"),
+            time("2024-01-01T09:00:08Z"),
+        );
+
+        let corrected = pdts.correct("index.m3u8", PLAYLIST);
+        let tags: Vec<&str> = corrected
+            .lines()
+            .filter(|line| line.starts_with(PROGRAM_DATE_TIME_TAG))
+            .collect();
+        assert_eq!(
+            tags,
+            [
+                "#EXT-X-PROGRAM-DATE-TIME:2024-01-01T09:00:00.000+0000",
+                "#EXT-X-PROGRAM-DATE-TIME:2024-01-01T09:00:04.000+0000",
+                "#EXT-X-PROGRAM-DATE-TIME:2024-01-01T09:00:08.000+0000",
+            ]
+        );
+        assert_eq!(pdts.correct("other.m3u8", PLAYLIST), PLAYLIST);
+    }
+}
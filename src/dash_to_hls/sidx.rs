@@ -0,0 +1,216 @@
+// Single-file representations (SegmentBase) are addressed by byte ranges, listed in
+// the sidx box found at the representation's indexRange
+
+// Parses a DASH byte range ("first-last", both inclusive)
+pub fn parse_byte_range(range: &str) -> Option<(u64, u64)> {
+    let (first, last) = range.trim().split_once('-')?;
+    let first = first.trim().parse().ok()?;
+    let last = last.trim().parse().ok()?;
+    (first <= last).then_some((first, last))
+}
+
+// A media segment referenced by a sidx box, `start` counting from the first byte of the
+// data the box was parsed from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SegmentRange {
+    pub start: u64,
+    pub length: u64,
+    // Seconds, from the box's timescale
+    pub duration: f64,
+}
+
+// Byte ranges of the media segments referenced by a sidx box. `data` starts at the
+// beginning of the box and `index_offset` is where it sits in the file.
+pub fn segment_ranges(data: &[u8], index_offset: u64) -> anyhow::Result<Vec<(u64, u64)>> {
+    Ok(parse_sidx(data)?
+        .into_iter()
+        .map(|range| {
+            let start = index_offset + range.start;
+            (start, start + range.length - 1)
+        })
+        .collect())
+}
+
+// Parses the first sidx box of `data`, either version. Offsets in the box are relative to
+// the first byte after it, the box must be whole.
+pub fn parse_sidx(data: &[u8]) -> anyhow::Result<Vec<SegmentRange>> {
+    let mut reader = BoxReader { data, pos: 0 };
+
+    // Skip anything before the sidx box (e.g. a styp) within the index range
+    let box_size = loop {
+        let size = reader.u32()? as u64;
+        let kind = reader.bytes(4)?;
+        if kind == b"sidx" {
+            break size;
+        }
+        if size < 8 {
+            anyhow::bail!("Malformed box in index range");
+        }
+        reader.skip(size as usize - 8)?;
+    };
+    let box_start = reader.pos as u64 - 8;
+    let box_end = box_start + box_size;
+    if box_size < 8 || box_end > data.len() as u64 {
+        anyhow::bail!("Truncated sidx box");
+    }
+    // Fields past the declared size belong to whatever follows the box
+    let mut reader = BoxReader {
+        data: &data[..box_end as usize],
+        pos: reader.pos,
+    };
+
+    let version = reader.bytes(1)?[0];
+    reader.skip(3)?; // flags
+    reader.skip(4)?; // reference_ID
+    let timescale = reader.u32()?;
+    let first_offset = match version {
+        0 => {
+            reader.skip(4)?; // earliest_presentation_time
+            reader.u32()? as u64
+        }
+        1 => {
+            reader.skip(8)?;
+            reader.u64()?
+        }
+        _ => anyhow::bail!("Unsupported sidx version {}", version),
+    };
+    reader.skip(2)?; // reserved
+    let reference_count = reader.u16()?;
+
+    let mut start = box_end + first_offset;
+    let mut ranges = Vec::with_capacity(reference_count as usize);
+    for _ in 0..reference_count {
+        let reference = reader.u32()?;
+        let duration = reader.u32()?;
+        reader.skip(4)?; // SAP fields
+
+        // Hierarchical indexes point at further sidx boxes, not media
+        if reference & 0x8000_0000 != 0 {
+            anyhow::bail!("Hierarchical sidx indexes are not supported");
+        }
+
+        let length = (reference & 0x7fff_ffff) as u64;
+        if length == 0 {
+            anyhow::bail!("Empty segment reference in sidx");
+        }
+        ranges.push(SegmentRange {
+            start,
+            length,
+            duration: if timescale > 0 {
+                duration as f64 / timescale as f64
+            } else {
+                0.0
+            },
+        });
+        start += length;
+    }
+
+    Ok(ranges)
+}
+
+struct BoxReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BoxReader<'a> {
+    fn bytes(&mut self, len: usize) -> anyhow::Result<&'a [u8]> {
+        let end = self.pos + len;
+        if end > self.data.len() {
+            anyhow::bail!("Truncated sidx box");
+        }
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn skip(&mut self, len: usize) -> anyhow::Result<()> {
+        self.bytes(len).map(|_| ())
+    }
+
+    fn u16(&mut self) -> anyhow::Result<u16> {
+        Ok(u16::from_be_bytes(self.bytes(2)?.try_into()?))
+    }
+
+    fn u32(&mut self) -> anyhow::Result<u32> {
+        Ok(u32::from_be_bytes(self.bytes(4)?.try_into()?))
+    }
+
+    fn u64(&mut self) -> anyhow::Result<u64> {
+        Ok(u64::from_be_bytes(self.bytes(8)?.try_into()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // sidx box of the given version with a timescale of 1000, referencing media segments
+    // of (size, duration) each
+    fn sidx(version: u8, first_offset: u64, references: &[(u32, u32)]) -> Vec<u8> {
+        let mut body = vec![version, 0, 0, 0];
+        body.extend(1u32.to_be_bytes()); // reference_ID
+        body.extend(1000u32.to_be_bytes());
+        if version == 0 {
+            body.extend(0u32.to_be_bytes());
+            body.extend((first_offset as u32).to_be_bytes());
+        } else {
+            body.extend(0u64.to_be_bytes());
+            body.extend(first_offset.to_be_bytes());
+        }
+        body.extend(0u16.to_be_bytes());
+        body.extend((references.len() as u16).to_be_bytes());
+        for &(size, duration) in references {
+            body.extend(size.to_be_bytes());
+            body.extend(duration.to_be_bytes());
+            body.extend(0x9000_0000u32.to_be_bytes());
+        }
+
+        let mut data = ((body.len() + 8) as u32).to_be_bytes().to_vec();
+        data.extend(b"sidx");
+        data.extend(body);
+        data
+    }
+
+    #[test]
+    fn parse_sidx_of_either_version() {
+        for version in [0, 1] {
+            let data = sidx(version, 10, &[(500, 2000), (600, 1500)]);
+            let end = data.len() as u64;
+            assert_eq!(
+                parse_sidx(&data).unwrap(),
+                [
+                    SegmentRange {
+                        start: end + 10,
+                        length: 500,
+                        duration: 2.0,
+                    },
+                    SegmentRange {
+                        start: end + 510,
+                        length: 600,
+                        duration: 1.5,
+                    },
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn parse_sidx_skips_boxes_before_it() {
+        let mut data = vec![0, 0, 0, 12];
+        data.extend(b"styp");
+        data.extend(b"msdh");
+        data.extend(sidx(0, 0, &[(100, 1000)]));
+
+        let ranges = segment_ranges(&data, 1000).unwrap();
+        let first = 1000 + data.len() as u64;
+        assert_eq!(ranges, [(first, first + 99)]);
+    }
+
+    #[test]
+    fn truncated_sidx_is_rejected() {
+        let data = sidx(1, 0, &[(100, 1000), (100, 1000)]);
+        assert!(parse_sidx(&data[..data.len() - 4]).is_err());
+        assert!(parse_sidx(&data[..6]).is_err());
+    }
+}
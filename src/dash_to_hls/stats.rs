@@ -0,0 +1,342 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Live counters of a converter, shared with the web handlers without locking the converter
+#[derive(Default)]
+pub struct StreamStats {
+    consecutive_failures: AtomicU32,
+    errors: AtomicU64,
+    segments_processed: AtomicU64,
+    // Segments of the current MPD refresh not handed to ffmpeg yet
+    backlog: AtomicU64,
+    // How far the last pushed segment was behind the live edge of the MPD
+    latency_ms: AtomicU64,
+    downloads: AtomicU64,
+    download_ms_total: AtomicU64,
+    last_download_ms: AtomicU64,
+    bytes_downloaded: AtomicU64,
+    download_failures: AtomicU64,
+    decrypt_failures: AtomicU64,
+    // Segments left out of the output because their download failed
+    segments_skipped: AtomicU64,
+    bytes_pushed: AtomicU64,
+    ffmpeg_restarts: AtomicU64,
+    // Set once a live event ended and its output was finalized
+    ended: AtomicBool,
+    // Set once the converter gave up after repeated failures
+    failed: AtomicBool,
+    // Sizes of the downloaded segments, audio renditions included in audio
+    video_segment_sizes: SizeHistogram,
+    audio_segment_sizes: SizeHistogram,
+    // Unix time the streaming loop started at, 0 before
+    started_at: AtomicU64,
+    // Unix time of the last segment pushed, or of the start before any
+    last_progress_at: AtomicU64,
+    // Whether the last MPD fetched was dynamic
+    live: AtomicBool,
+    last_error: Mutex<Option<String>>,
+    // Latest segment failures, oldest first
+    segment_errors: Mutex<VecDeque<SegmentError>>,
+}
+
+// Segment failures remembered per stream
+const MAX_SEGMENT_ERRORS: usize = 50;
+
+// Upper bounds of the segment size buckets in bytes, from 64 KiB to 8 MiB
+pub const SEGMENT_SIZE_BUCKETS: [u64; 8] = [
+    64 << 10,
+    128 << 10,
+    256 << 10,
+    512 << 10,
+    1 << 20,
+    2 << 20,
+    4 << 20,
+    8 << 20,
+];
+
+// Counts of sizes per bucket, larger sizes only go into the count and sum
+#[derive(Default)]
+struct SizeHistogram {
+    buckets: [AtomicU64; SEGMENT_SIZE_BUCKETS.len()],
+    count: AtomicU64,
+    sum: AtomicU64,
+}
+
+impl SizeHistogram {
+    fn record(&self, bytes: u64) {
+        if let Some(bucket) = SEGMENT_SIZE_BUCKETS
+            .iter()
+            .position(|&bound| bytes <= bound)
+        {
+            self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    // Buckets are cumulative, as Prometheus expects
+    fn snapshot(&self) -> HistogramSnapshot {
+        let buckets = self
+            .buckets
+            .iter()
+            .scan(0, |total, bucket| {
+                *total += bucket.load(Ordering::Relaxed);
+                Some(*total)
+            })
+            .collect();
+        HistogramSnapshot {
+            buckets,
+            count: self.count.load(Ordering::Relaxed),
+            sum: self.sum.load(Ordering::Relaxed),
+        }
+    }
+}
+
+// Cumulative count for each bound of SEGMENT_SIZE_BUCKETS, with the overall count and sum
+#[derive(Serialize)]
+pub struct HistogramSnapshot {
+    pub buckets: Vec<u64>,
+    pub count: u64,
+    pub sum: u64,
+}
+
+// A segment that couldn't be downloaded or decrypted
+#[derive(Serialize, Clone)]
+pub struct SegmentError {
+    pub at: DateTime<Utc>,
+    // Unknown for decryption failures
+    pub segment_url: Option<String>,
+    pub error: String,
+}
+
+// Point in time copy of the counters, as served to operators
+#[derive(Serialize)]
+pub struct StatsSnapshot {
+    pub segments_processed: u64,
+    pub errors: u64,
+    pub consecutive_failures: u32,
+    pub backlog: u64,
+    pub latency_ms: u64,
+    pub downloads: u64,
+    pub avg_download_ms: u64,
+    pub last_download_ms: u64,
+    pub bytes_downloaded: u64,
+    pub download_failures: u64,
+    pub decrypt_failures: u64,
+    pub segments_skipped: u64,
+    pub bytes_pushed: u64,
+    pub ffmpeg_restarts: u64,
+    pub ended: bool,
+    pub failed: bool,
+    pub video_segment_sizes: HistogramSnapshot,
+    pub audio_segment_sizes: HistogramSnapshot,
+    pub started_at: u64,
+    pub uptime_secs: u64,
+    pub last_error: Option<String>,
+}
+
+impl StreamStats {
+    pub fn record_success(&self) {
+        self.consecutive_failures.store(0, Ordering::Relaxed);
+    }
+
+    pub fn record_failure(&self, error: &str) {
+        self.consecutive_failures.fetch_add(1, Ordering::Relaxed);
+        self.errors.fetch_add(1, Ordering::Relaxed);
+        *self.last_error.lock().unwrap() = Some(error.to_string());
+    }
+
+    pub fn consecutive_failures(&self) -> u32 {
+        self.consecutive_failures.load(Ordering::Relaxed)
+    }
+
+    pub fn record_download(&self, elapsed: Duration, bytes: usize) {
+        let ms = elapsed.as_millis() as u64;
+        self.downloads.fetch_add(1, Ordering::Relaxed);
+        self.download_ms_total.fetch_add(ms, Ordering::Relaxed);
+        self.last_download_ms.store(ms, Ordering::Relaxed);
+        self.bytes_downloaded
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_segment_size(&self, video: bool, bytes: usize) {
+        let histogram = if video {
+            &self.video_segment_sizes
+        } else {
+            &self.audio_segment_sizes
+        };
+        histogram.record(bytes as u64);
+    }
+
+    pub fn record_download_failure(&self, url: &str, error: &str) {
+        self.download_failures.fetch_add(1, Ordering::Relaxed);
+        self.push_segment_error(Some(url), error);
+    }
+
+    pub fn record_decrypt_failure(&self, error: &str) {
+        self.decrypt_failures.fetch_add(1, Ordering::Relaxed);
+        self.push_segment_error(None, error);
+    }
+
+    pub fn record_skipped_segment(&self) {
+        self.segments_skipped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn push_segment_error(&self, url: Option<&str>, error: &str) {
+        let mut errors = self.segment_errors.lock().unwrap();
+        if errors.len() >= MAX_SEGMENT_ERRORS {
+            errors.pop_front();
+        }
+        errors.push_back(SegmentError {
+            at: Utc::now(),
+            segment_url: url.map(str::to_string),
+            error: error.to_string(),
+        });
+    }
+
+    pub fn segment_errors(&self) -> Vec<SegmentError> {
+        self.segment_errors
+            .lock()
+            .unwrap()
+            .iter()
+            .cloned()
+            .collect()
+    }
+
+    // A segment handed to ffmpeg, with the bytes written to its inputs
+    pub fn record_segment(&self, bytes: usize) {
+        self.segments_processed.fetch_add(1, Ordering::Relaxed);
+        self.bytes_pushed.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.last_progress_at.store(unix_time(), Ordering::Relaxed);
+    }
+
+    pub fn set_live(&self, live: bool) {
+        self.live.store(live, Ordering::Relaxed);
+    }
+
+    // How long a running live stream has gone without pushing a segment. Static and
+    // finalized streams have nothing left to push, so they never stall.
+    pub fn stalled_for(&self) -> Option<Duration> {
+        if !self.live.load(Ordering::Relaxed) || self.ended.load(Ordering::Relaxed) {
+            return None;
+        }
+        match self.last_progress_at.load(Ordering::Relaxed) {
+            0 => None,
+            last => Some(Duration::from_secs(unix_time().saturating_sub(last))),
+        }
+    }
+
+    pub fn set_backlog(&self, segments: u64) {
+        self.backlog.store(segments, Ordering::Relaxed);
+    }
+
+    pub fn set_latency(&self, latency: Duration) {
+        self.latency_ms
+            .store(latency.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    pub fn latency(&self) -> Duration {
+        Duration::from_millis(self.latency_ms.load(Ordering::Relaxed))
+    }
+
+    pub fn record_ffmpeg_restart(&self) {
+        self.ffmpeg_restarts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn mark_started(&self) {
+        let now = unix_time();
+        self.started_at.store(now, Ordering::Relaxed);
+        self.last_progress_at.store(now, Ordering::Relaxed);
+    }
+
+    pub fn mark_ended(&self) {
+        self.ended.store(true, Ordering::Relaxed);
+    }
+
+    pub fn has_ended(&self) -> bool {
+        self.ended.load(Ordering::Relaxed)
+    }
+
+    pub fn mark_failed(&self) {
+        self.failed.store(true, Ordering::Relaxed);
+    }
+
+    pub fn has_failed(&self) -> bool {
+        self.failed.load(Ordering::Relaxed)
+    }
+
+    pub fn last_error(&self) -> Option<String> {
+        self.last_error.lock().unwrap().clone()
+    }
+
+    pub fn snapshot(&self) -> StatsSnapshot {
+        let downloads = self.downloads.load(Ordering::Relaxed);
+        let download_ms_total = self.download_ms_total.load(Ordering::Relaxed);
+        let started_at = self.started_at.load(Ordering::Relaxed);
+        let uptime_secs = if started_at == 0 {
+            0
+        } else {
+            unix_time().saturating_sub(started_at)
+        };
+
+        StatsSnapshot {
+            segments_processed: self.segments_processed.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            consecutive_failures: self.consecutive_failures(),
+            backlog: self.backlog.load(Ordering::Relaxed),
+            latency_ms: self.latency_ms.load(Ordering::Relaxed),
+            downloads,
+            avg_download_ms: download_ms_total.checked_div(downloads).unwrap_or(0),
+            last_download_ms: self.last_download_ms.load(Ordering::Relaxed),
+            bytes_downloaded: self.bytes_downloaded.load(Ordering::Relaxed),
+            download_failures: self.download_failures.load(Ordering::Relaxed),
+            decrypt_failures: self.decrypt_failures.load(Ordering::Relaxed),
+            segments_skipped: self.segments_skipped.load(Ordering::Relaxed),
+            bytes_pushed: self.bytes_pushed.load(Ordering::Relaxed),
+            ffmpeg_restarts: self.ffmpeg_restarts.load(Ordering::Relaxed),
+            ended: self.ended.load(Ordering::Relaxed),
+            failed: self.has_failed(),
+            video_segment_sizes: self.video_segment_sizes.snapshot(),
+            audio_segment_sizes: self.audio_segment_sizes.snapshot(),
+            started_at,
+            uptime_secs,
+            last_error: self.last_error.lock().unwrap().clone(),
+        }
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn segment_sizes_fall_in_cumulative_buckets() {
+        let stats = StreamStats::default();
+        for bytes in [1, 64 << 10, (64 << 10) + 1, 3 << 20, 9 << 20] {
+            stats.record_segment_size(true, bytes);
+        }
+        stats.record_segment_size(false, 100);
+
+        let video = stats.snapshot().video_segment_sizes;
+        assert_eq!(video.buckets, [2, 3, 3, 3, 3, 3, 4, 4]);
+        assert_eq!(video.count, 5);
+        assert_eq!(
+            video.sum,
+            1 + (64 << 10) + (64 << 10) + 1 + (3 << 20) + (9 << 20)
+        );
+
+        let audio = stats.snapshot().audio_segment_sizes;
+        assert_eq!(audio.buckets, [1; SEGMENT_SIZE_BUCKETS.len()]);
+        assert_eq!((audio.count, audio.sum), (1, 100));
+    }
+}
@@ -0,0 +1,237 @@
+use super::ffmpeg_command;
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// MPEG-TS timestamp ffmpeg starts its HLS output at, 1.4s with its default mux delay
+const TS_START_PTS: f64 = 126_000.0;
+// Wrap-around of 33-bit MPEG-TS timestamps
+const TS_PTS_WRAP: u64 = 1 << 33;
+
+// Sliding WebVTT media playlist of one subtitle track. Subtitles don't go through the
+// ffmpeg pushers, so segments and playlist are written here as they come.
+pub struct SubtitleWriter {
+    output_dir: PathBuf,
+    prefix: String,
+    max_segments: usize,
+    default_duration: f64,
+    // Media sequence number of the first listed segment
+    sequence: u64,
+    // File name and duration of the listed segments
+    segments: VecDeque<(String, f64)>,
+    // Time from the start of the output to the next segment
+    elapsed: f64,
+}
+
+impl SubtitleWriter {
+    pub fn new(output_dir: &str, prefix: &str, max_segments: u32, default_duration: u32) -> Self {
+        Self {
+            output_dir: PathBuf::from(output_dir),
+            prefix: prefix.to_string(),
+            max_segments: max_segments.max(1) as usize,
+            default_duration: default_duration as f64,
+            sequence: 0,
+            segments: VecDeque::new(),
+            elapsed: 0.0,
+        }
+    }
+
+    pub fn playlist_name(&self) -> String {
+        format!("{}.m3u8", self.prefix)
+    }
+
+    // Adds a WebVTT segment, dropping the oldest one once the window is full. Without a
+    // known duration the segment counts as long as the HLS segments.
+    pub fn push(&mut self, vtt: &[u8], duration: Option<f64>) -> io::Result<()> {
+        let name = format!(
+            "{}_{:03}.vtt",
+            self.prefix,
+            self.sequence + self.segments.len() as u64
+        );
+        fs::write(
+            self.output_dir.join(&name),
+            with_timestamp_map(vtt, self.elapsed),
+        )?;
+        let duration = duration.unwrap_or(self.default_duration);
+        self.segments.push_back((name, duration));
+        self.elapsed += duration;
+
+        while self.segments.len() > self.max_segments {
+            if let Some((oldest, _)) = self.segments.pop_front() {
+                fs::remove_file(self.output_dir.join(oldest)).ok();
+            }
+            self.sequence += 1;
+        }
+
+        self.write_playlist(false)
+    }
+
+    // Ends the playlist, as the pushers do when the live event is over
+    pub fn finish(&self) -> io::Result<()> {
+        self.write_playlist(true)
+    }
+
+    fn write_playlist(&self, ended: bool) -> io::Result<()> {
+        let target_duration = self
+            .segments
+            .iter()
+            .map(|(_, duration)| duration.ceil() as u64)
+            .max()
+            .unwrap_or(1);
+
+        let mut playlist = format!(
+            "#EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-TARGETDURATION:{}\n#EXT-X-MEDIA-SEQUENCE:{}\n",
+            target_duration, self.sequence
+        );
+        for (name, duration) in &self.segments {
+            playlist.push_str(&format!("#EXTINF:{:.3},\n{}\n", duration, name));
+        }
+        if ended {
+            playlist.push_str("#EXT-X-ENDLIST\n");
+        }
+
+        // Players must never read a half written playlist
+        let path = self.output_dir.join(self.playlist_name());
+        let temp_path = path.with_extension("m3u8.tmp");
+        fs::write(&temp_path, playlist)?;
+        fs::rename(temp_path, path)
+    }
+}
+
+// Ties the cues of a segment to the timestamps of the HLS media with an X-TIMESTAMP-MAP,
+// unless it has one. Cue times count from the start of the segment, as ffmpeg writes
+// them, which plays `offset` seconds into the output.
+fn with_timestamp_map(vtt: &[u8], offset: f64) -> Vec<u8> {
+    let text = String::from_utf8_lossy(vtt);
+    if text.contains("X-TIMESTAMP-MAP") {
+        return vtt.to_vec();
+    }
+    let (header, rest) = text.split_once('\n').unwrap_or((&text, ""));
+    let pts = (TS_START_PTS + offset * 90_000.0).round() as u64 % TS_PTS_WRAP;
+    format!(
+        "{}\nX-TIMESTAMP-MAP=MPEGTS:{},LOCAL:00:00:00.000\n{}",
+        header.trim_end_matches('\r'),
+        pts,
+        rest
+    )
+    .into_bytes()
+}
+
+// Converts a subtitle segment to WebVTT. Plain WebVTT (text/vtt) passes through, subtitles
+// in fragmented MP4 (wvtt, stpp) go through ffmpeg along with their init segment. An
+// ffmpeg still running after `timeout` is killed.
+pub fn to_webvtt(data: &[u8], timeout: Duration) -> anyhow::Result<Vec<u8>> {
+    let text = data.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(data);
+    if text.starts_with(b"WEBVTT") {
+        return Ok(data.to_vec());
+    }
+
+    let mut child = ffmpeg_command()
+        .args([
+            "-hide_banner",
+            "-loglevel",
+            "error",
+            "-i",
+            "pipe:0",
+            "-f",
+            "webvtt",
+            "pipe:1",
+        ])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // Fed from another thread so a full stdout pipe can't block the input
+    let mut stdin = child
+        .stdin
+        .take()
+        .ok_or_else(|| anyhow::anyhow!("ffmpeg stdin unavailable"))?;
+    let input = data.to_vec();
+    let feeder = thread::spawn(move || stdin.write_all(&input));
+    // Drained by threads as well, so ffmpeg can be waited on with a deadline
+    let stdout = child.stdout.take();
+    let stderr = child.stderr.take();
+    let output = thread::spawn(move || {
+        let mut output = Vec::new();
+        if let Some(mut stdout) = stdout {
+            stdout.read_to_end(&mut output).ok();
+        }
+        output
+    });
+    let errors = thread::spawn(move || {
+        let mut errors = String::new();
+        if let Some(mut stderr) = stderr {
+            stderr.read_to_string(&mut errors).ok();
+        }
+        errors
+    });
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            child.kill().ok();
+            child.wait().ok();
+            anyhow::bail!("ffmpeg didn't convert subtitles within {:?}", timeout);
+        }
+        thread::sleep(Duration::from_millis(10));
+    };
+    feeder.join().ok();
+    let output = output.join().unwrap_or_default();
+    let errors = errors.join().unwrap_or_default();
+
+    if !status.success() {
+        anyhow::bail!(
+            "ffmpeg could not convert subtitles to WebVTT: {}",
+            errors.trim()
+        );
+    }
+
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn to_webvtt_passes_plain_webvtt_through() {
+        let vtt = b"\xEF\xBB\xBFWEBVTT\n\n00:00.000 --> 00:01.000\nHello\n";
+        assert_eq!(to_webvtt(vtt, Duration::from_secs(1)).unwrap(), vtt);
+    }
+
+    #[test]
+    fn timestamp_map_follows_the_header() {
+        let vtt = with_timestamp_map(b"WEBVTT\r\n\n00:00.000 --> 00:01.000\nHi\n", 4.0);
+        assert_eq!(
+            String::from_utf8(vtt).unwrap(),
+            "WEBVTT\nX-TIMESTAMP-MAP=MPEGTS:486000,LOCAL:00:00:00.000\n\n00:00.000 --> 00:01.000\nHi\n"
+        );
+    }
+
+    #[test]
+    fn existing_timestamp_map_is_kept() {
+        let vtt = b"WEBVTT\nX-TIMESTAMP-MAP=MPEGTS:900000,LOCAL:00:00:00.000\n";
+        assert_eq!(with_timestamp_map(vtt, 10.0), vtt);
+    }
+
+    #[test]
+    fn segments_are_mapped_at_their_offset() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut writer = SubtitleWriter::new(dir.path().to_str().unwrap(), "subtitles_0", 5, 6);
+        writer.push(b"WEBVTT\n", Some(4.0)).unwrap();
+        writer.push(b"WEBVTT\n", None).unwrap();
+
+        let second = fs::read_to_string(dir.path().join("subtitles_0_001.vtt")).unwrap();
+        assert!(second.contains("MPEGTS:486000,"));
+        let playlist = fs::read_to_string(dir.path().join("subtitles_0.m3u8")).unwrap();
+        assert!(playlist.contains("#EXTINF:6.000,\nsubtitles_0_001.vtt"));
+    }
+}
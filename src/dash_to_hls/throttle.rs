@@ -0,0 +1,77 @@
+use std::io;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Token bucket shared by every download of a stream, holding up to a second of traffic.
+// Reads spend tokens, going into debt when they exceed what's available, and the reader
+// then sleeps until the debt is paid back. A reader that would sleep past its deadline
+// gives up instead, as it holds the converter while sleeping.
+pub struct TokenBucket {
+    bytes_per_sec: f64,
+    // Available tokens, negative while in debt, as of the instant they were counted
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        let bytes_per_sec = bytes_per_sec as f64;
+        Self {
+            bytes_per_sec,
+            state: Mutex::new((bytes_per_sec, Instant::now())),
+        }
+    }
+
+    pub async fn take(&self, bytes: usize, deadline: Instant) -> io::Result<()> {
+        let wait = {
+            let mut state = self.state.lock().unwrap();
+            let (tokens, counted_at) = &mut *state;
+            let now = Instant::now();
+            let refilled = now.duration_since(*counted_at).as_secs_f64() * self.bytes_per_sec;
+            *tokens = (*tokens + refilled).min(self.bytes_per_sec) - bytes as f64;
+            *counted_at = now;
+            (*tokens < 0.0).then(|| Duration::from_secs_f64(-*tokens / self.bytes_per_sec))
+        };
+
+        let Some(wait) = wait else {
+            return Ok(());
+        };
+        if Instant::now() + wait > deadline {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "download rate limit doesn't let the body arrive in time",
+            ));
+        }
+        actix_web::rt::time::sleep(wait).await;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_web::test]
+    async fn take_waits_for_the_debt_to_be_paid() {
+        let bucket = TokenBucket::new(10_000);
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let start = Instant::now();
+
+        // A full second of traffic is available up front
+        bucket.take(10_000, deadline).await.unwrap();
+        assert!(start.elapsed() < Duration::from_millis(100));
+
+        bucket.take(2_000, deadline).await.unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(190));
+    }
+
+    #[actix_web::test]
+    async fn take_gives_up_past_the_deadline() {
+        let bucket = TokenBucket::new(1_000);
+        let deadline = Instant::now() + Duration::from_millis(50);
+        let start = Instant::now();
+
+        let error = bucket.take(5_000, deadline).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+        assert!(start.elapsed() < Duration::from_millis(50));
+    }
+}
@@ -0,0 +1,238 @@
+use log::{info, warn};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Media segments, the only files eviction may remove. Playlists, init segments and keys
+// are small and needed for as long as the stream runs.
+const SEGMENT_EXTENSIONS: [&str; 4] = ["ts", "m4s", "vtt", "webvtt"];
+
+// Bounds the disk used by the outputs of all streams. Above the cap, segments are removed
+// least recently served first. Those a live playlist still lists are its window and are
+// never removed, nor are the segments of DASH outputs, whose manifest doesn't list them.
+// Finished playlists, ending with #EXT-X-ENDLIST, protect none of theirs.
+#[derive(Clone)]
+pub struct DiskCache {
+    // 0 means unbounded
+    max_bytes: u64,
+    // When each segment file was last served to a client
+    served_at: HashMap<PathBuf, SystemTime>,
+}
+
+impl DiskCache {
+    pub fn new(max_bytes: u64) -> Self {
+        Self {
+            max_bytes,
+            served_at: HashMap::new(),
+        }
+    }
+
+    pub fn touch(&mut self, path: PathBuf) {
+        if self.max_bytes > 0 {
+            self.served_at.insert(path, SystemTime::now());
+        }
+    }
+
+    // Copy to enforce the cap with, so the files are read and removed without holding the
+    // stream manager's lock. None when the outputs are unbounded.
+    pub fn snapshot(&self) -> Option<DiskCache> {
+        (self.max_bytes > 0).then(|| self.clone())
+    }
+
+    // Drops the segments enforce() found gone, those served since are gone too
+    pub fn forget(&mut self, gone: Vec<PathBuf>) {
+        for path in gone {
+            self.served_at.remove(&path);
+        }
+    }
+
+    // Removes segments until the stream directories under the root fit in the cap,
+    // returning the bytes freed and the served segments that are gone, evicted or removed
+    // by ffmpeg or stopped streams. Segments never served count from their last write.
+    pub fn enforce(&self, output_root: &str) -> (u64, Vec<PathBuf>) {
+        let mut gone: Vec<PathBuf> = self
+            .served_at
+            .keys()
+            .filter(|path| !path.exists())
+            .cloned()
+            .collect();
+        if self.max_bytes == 0 {
+            return (0, gone);
+        }
+        let Ok(stream_dirs) = fs::read_dir(output_root) else {
+            return (0, gone);
+        };
+
+        let mut total = 0;
+        let mut candidates: Vec<(SystemTime, u64, PathBuf)> = Vec::new();
+        for stream_dir in stream_dirs.flatten().map(|entry| entry.path()) {
+            let Ok(entries) = fs::read_dir(&stream_dir) else {
+                continue;
+            };
+            let files: Vec<(PathBuf, fs::Metadata)> = entries
+                .flatten()
+                .filter_map(|entry| Some((entry.path(), entry.metadata().ok()?)))
+                .filter(|(_, metadata)| metadata.is_file())
+                .collect();
+            total += files
+                .iter()
+                .map(|(_, metadata)| metadata.len())
+                .sum::<u64>();
+
+            if files.iter().any(|(path, _)| has_extension(path, &["mpd"])) {
+                continue;
+            }
+            let listed = listed_files(&files);
+            for (path, metadata) in files {
+                let name = path.file_name().and_then(|name| name.to_str());
+                if !has_extension(&path, &SEGMENT_EXTENSIONS)
+                    || name.is_none_or(|name| listed.contains(name))
+                {
+                    continue;
+                }
+                let used = self
+                    .served_at
+                    .get(&path)
+                    .copied()
+                    .or_else(|| metadata.modified().ok())
+                    .unwrap_or(UNIX_EPOCH);
+                candidates.push((used, metadata.len(), path));
+            }
+        }
+        if total <= self.max_bytes {
+            return (0, gone);
+        }
+        candidates.sort_by_key(|(used, _, _)| *used);
+        let mut freed = 0;
+        for (_, size, path) in candidates {
+            if total - freed <= self.max_bytes {
+                break;
+            }
+            match fs::remove_file(&path) {
+                Ok(()) => {
+                    freed += size;
+                    gone.push(path);
+                }
+                Err(e) => warn!("Could not evict {}: {}", path.display(), e),
+            }
+        }
+
+        info!(
+            "Evicted {} bytes of segments, outputs now use {} of at most {} bytes",
+            freed,
+            total - freed,
+            self.max_bytes
+        );
+        if total - freed > self.max_bytes {
+            warn!("Stream outputs stay above max_output_bytes, only live segments are left");
+        }
+        (freed, gone)
+    }
+}
+
+fn has_extension(path: &Path, extensions: &[&str]) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| extensions.contains(&ext))
+}
+
+// Names of the files the directory's live playlists point at, as URI lines or URI attributes
+fn listed_files(files: &[(PathBuf, fs::Metadata)]) -> HashSet<String> {
+    let mut listed = HashSet::new();
+    for (path, _) in files
+        .iter()
+        .filter(|(path, _)| has_extension(path, &["m3u8"]))
+    {
+        let Ok(playlist) = fs::read_to_string(path) else {
+            continue;
+        };
+        if playlist.contains("#EXT-X-ENDLIST") {
+            continue;
+        }
+        for line in playlist.lines().map(str::trim) {
+            if !line.starts_with('#') {
+                listed.insert(line.to_string());
+            } else if let Some((_, rest)) = line.split_once("URI=\"")
+                && let Some((uri, _)) = rest.split_once('"')
+            {
+                listed.insert(uri.to_string());
+            }
+        }
+    }
+    listed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn write_segment(dir: &Path, name: &str, size: usize) -> PathBuf {
+        let path = dir.join(name);
+        fs::write(&path, vec![0u8; size]).unwrap();
+        path
+    }
+
+    #[test]
+    fn enforce_evicts_least_recently_served_first() {
+        let root = tempfile::tempdir().unwrap();
+        let stream = root.path().join("stream");
+        fs::create_dir(&stream).unwrap();
+        let old = write_segment(&stream, "old.ts", 100);
+        let recent = write_segment(&stream, "recent.ts", 100);
+        let newest = write_segment(&stream, "newest.ts", 100);
+
+        let mut cache = DiskCache::new(250);
+        let now = SystemTime::now();
+        cache
+            .served_at
+            .insert(old.clone(), now - Duration::from_secs(30));
+        cache
+            .served_at
+            .insert(recent.clone(), now - Duration::from_secs(20));
+        cache
+            .served_at
+            .insert(newest.clone(), now - Duration::from_secs(10));
+
+        let (freed, gone) = cache.enforce(root.path().to_str().unwrap());
+        assert_eq!(freed, 100);
+        assert_eq!(gone, std::slice::from_ref(&old));
+        assert!(!old.exists() && recent.exists() && newest.exists());
+
+        cache.forget(gone);
+        assert!(!cache.served_at.contains_key(&old));
+    }
+
+    #[test]
+    fn enforce_keeps_the_window_of_live_playlists_only() {
+        let root = tempfile::tempdir().unwrap();
+        let live = root.path().join("live");
+        let finished = root.path().join("finished");
+        fs::create_dir(&live).unwrap();
+        fs::create_dir(&finished).unwrap();
+        let listed = write_segment(&live, "segment_1.ts", 100);
+        fs::write(
+            live.join("master.m3u8"),
+            "#EXTM3U\n#EXTINF:4,\nsegment_1.ts\n",
+        )
+        .unwrap();
+        let ended = write_segment(&finished, "segment_1.ts", 100);
+        fs::write(
+            finished.join("master.m3u8"),
+            "#EXTM3U\n#EXTINF:4,\nsegment_1.ts\n#EXT-X-ENDLIST\n",
+        )
+        .unwrap();
+
+        let cache = DiskCache::new(1);
+        cache.enforce(root.path().to_str().unwrap());
+        assert!(listed.exists());
+        assert!(!ended.exists());
+    }
+
+    #[test]
+    fn unbounded_cache_has_no_snapshot() {
+        assert!(DiskCache::new(0).snapshot().is_none());
+        assert!(DiskCache::new(1).snapshot().is_some());
+    }
+}
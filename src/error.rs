@@ -0,0 +1,198 @@
+use crate::request_id;
+use actix_web::body::BoxBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::{StatusCode, header};
+use actix_web::middleware::Next;
+use actix_web::{HttpRequest, HttpResponse, HttpResponseBuilder, ResponseError};
+use std::fmt;
+
+// Errors answered by the handlers, as a `{ "error": ..., "code": ... }` JSON body, or as
+// plain text on the routes negotiating it
+#[derive(Debug)]
+pub enum AppError {
+    BadRequest(String),
+    // No credentials were sent
+    Unauthorized(String),
+    // A token was sent but can't be used, expired ones may be worth a refresh
+    InvalidToken(String),
+    TokenExpired(String),
+    Forbidden(String),
+    NotFound(String),
+    Conflict(String),
+    // Asks the client to come back after the given number of seconds
+    Unavailable { message: String, retry_after: u64 },
+    // The origin couldn't be reached
+    BadGateway(String),
+    // Reading the stream's files took too long
+    GatewayTimeout(String),
+    Internal(String),
+}
+
+impl AppError {
+    // Machine readable counterpart of the message
+    pub fn code(&self) -> &'static str {
+        match self {
+            AppError::BadRequest(_) => "bad_request",
+            AppError::Unauthorized(_) => "unauthorized",
+            AppError::InvalidToken(_) => "invalid_token",
+            AppError::TokenExpired(_) => "token_expired",
+            AppError::Forbidden(_) => "forbidden",
+            AppError::NotFound(_) => "not_found",
+            AppError::Conflict(_) => "conflict",
+            AppError::Unavailable { .. } => "unavailable",
+            AppError::BadGateway(_) => "bad_gateway",
+            AppError::GatewayTimeout(_) => "gateway_timeout",
+            AppError::Internal(_) => "internal",
+        }
+    }
+
+    fn message(&self) -> &str {
+        match self {
+            AppError::BadRequest(message)
+            | AppError::Unauthorized(message)
+            | AppError::InvalidToken(message)
+            | AppError::TokenExpired(message)
+            | AppError::Forbidden(message)
+            | AppError::NotFound(message)
+            | AppError::Conflict(message)
+            | AppError::Unavailable { message, .. }
+            | AppError::BadGateway(message)
+            | AppError::GatewayTimeout(message)
+            | AppError::Internal(message) => message,
+        }
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.message())
+    }
+}
+
+impl ResponseError for AppError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AppError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            AppError::Unauthorized(_) | AppError::InvalidToken(_) | AppError::TokenExpired(_) => {
+                StatusCode::UNAUTHORIZED
+            }
+            AppError::Forbidden(_) => StatusCode::FORBIDDEN,
+            AppError::NotFound(_) => StatusCode::NOT_FOUND,
+            AppError::Conflict(_) => StatusCode::CONFLICT,
+            AppError::Unavailable { .. } => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::BadGateway(_) => StatusCode::BAD_GATEWAY,
+            AppError::GatewayTimeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            AppError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut body = serde_json::json!({
+            "error": self.message(),
+            "code": self.code(),
+        });
+        if let Some(request_id) = request_id::current() {
+            body["request_id"] = request_id.into();
+        }
+        self.response_builder().json(body)
+    }
+}
+
+impl AppError {
+    fn text_response(&self) -> HttpResponse {
+        self.response_builder()
+            .content_type("text/plain; charset=utf-8")
+            .body(self.message().to_string())
+    }
+
+    // Status and headers shared by both bodies
+    fn response_builder(&self) -> HttpResponseBuilder {
+        let mut response = HttpResponse::build(self.status_code());
+        match self {
+            AppError::Unavailable { retry_after, .. } => {
+                response.insert_header(("Retry-After", retry_after.to_string()));
+            }
+            AppError::Unauthorized(_) => {
+                response.insert_header(("WWW-Authenticate", "Bearer realm=\"dash-to-hls\""));
+            }
+            AppError::InvalidToken(_) | AppError::TokenExpired(_) => {
+                response.insert_header((
+                    "WWW-Authenticate",
+                    "Bearer realm=\"dash-to-hls\", error=\"invalid_token\"",
+                ));
+            }
+            _ => {}
+        }
+        response
+    }
+}
+
+// Whether the client's preferred type in Accept is JSON. Without Accept, or preferring
+// text/* or anything, the error is answered as plain text.
+fn prefers_json(req: &HttpRequest) -> bool {
+    let Some(accept) = req
+        .headers()
+        .get(header::ACCEPT)
+        .and_then(|value| value.to_str().ok())
+    else {
+        return false;
+    };
+
+    accept
+        .split(',')
+        .map(|range| range.split(';').next().unwrap_or_default().trim())
+        .find(|media_type| !media_type.is_empty())
+        .is_some_and(|media_type| media_type == "application/json" || media_type.ends_with("+json"))
+}
+
+// Middleware answering AppErrors as JSON to clients preferring it and as plain text to
+// the others, such as browsers
+pub async fn negotiate_errors(
+    req: ServiceRequest,
+    next: Next<BoxBody>,
+) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    let json = prefers_json(req.request());
+    let response = next.call(req).await?;
+    if json {
+        return Ok(response);
+    }
+
+    let text = response
+        .response()
+        .error()
+        .and_then(|e| e.as_error::<AppError>())
+        .map(AppError::text_response);
+    Ok(match text {
+        Some(text) => response.into_response(text),
+        None => response,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::body::MessageBody;
+    use request_id::RequestId;
+
+    fn body(error: &AppError) -> serde_json::Value {
+        let bytes = error.error_response().into_body().try_into_bytes().unwrap();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    #[actix_web::test]
+    async fn error_bodies_carry_the_request_id() {
+        let error = AppError::NotFound("Stream not found".to_string());
+        assert_eq!(body(&error).get("request_id"), None);
+
+        let request_id = RequestId("abc-1".to_string());
+        let body = request_id::scope(&request_id, async { body(&error) }).await;
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "error": "Stream not found",
+                "code": "not_found",
+                "request_id": "abc-1",
+            })
+        );
+    }
+}
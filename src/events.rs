@@ -0,0 +1,45 @@
+use serde::Serialize;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+
+// Events kept for subscribers that fall behind, older ones are skipped
+const EVENT_BUFFER: usize = 64;
+
+pub type EventSender = broadcast::Sender<StreamEvent>;
+
+#[derive(Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    Active,
+    Inactive,
+    Error,
+}
+
+// A change in a stream's state, pushed to the /ws/status subscribers
+#[derive(Clone, Serialize)]
+pub struct StreamEvent {
+    pub stream: String,
+    pub event: EventKind,
+    // Why a stream went inactive, or the error it ran into
+    pub message: Option<String>,
+    // Unix time of the event
+    pub at: u64,
+}
+
+pub fn channel() -> EventSender {
+    broadcast::channel(EVENT_BUFFER).0
+}
+
+// Publishes an event, nobody listening isn't an error
+pub fn publish(sender: &EventSender, stream: &str, event: EventKind, message: Option<&str>) {
+    sender
+        .send(StreamEvent {
+            stream: stream.to_string(),
+            event,
+            message: message.map(str::to_string),
+            at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+        })
+        .ok();
+}
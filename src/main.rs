@@ -1,47 +1,467 @@
-use actix_files::Files;
+use actix_cors::Cors;
+use actix_files::{Files, NamedFile};
 use actix_web::{
-    App, HttpResponse, HttpServer, Responder,
+    App, HttpRequest, HttpResponse, HttpServer, Responder,
     cookie::{Cookie, SameSite},
+    dev::Service,
+    http::header::{CACHE_CONTROL, HeaderName, HeaderValue},
+    middleware::from_fn,
     web,
 };
-use config::deserializer::Deserializer;
-use dash_to_hls::DashToHlsConverter;
-use log::{error, info};
+use actix_ws::Message;
+use audit::AuditLog;
+use config::channels::ChannelConfig;
+use config::channels::{
+    ChannelTOML, DecryptorKind, DrmSystem, IncompatibleCodec, OutputFormat, RenditionTOML,
+    RepFallback, SegmentFormat, StartMode,
+};
+use config::deserializer::{self, Deserializer};
+use config::settings::{
+    AuthTOML, CleanupTOML, ConverterTOML, CorsTOML, HealthTOML, MetricsTOML, RequestIdTOML,
+    SegmentsTOML, StallAction, UnlistedSegments,
+};
+use config::users::UserConfig;
+use dash_to_hls::{
+    DashToHlsConverter, Logo, PartLayout, PartedPlaylist, ProgramDateTimes, SEGMENT_SIZE_BUCKETS,
+    StatsSnapshot, StreamStats, check_channel, fetch_logo, fetch_raw_mpd, ffmpeg_command,
+    inspect_mpd, set_ffmpeg_path,
+};
+use disk_cache::DiskCache;
+use error::{AppError, negotiate_errors};
+use events::{EventKind, EventSender};
+use futures_util::StreamExt;
+use futures_util::future::{Either, select};
+use log::{error, info, warn};
+use notify::{RecursiveMode, Watcher};
+use request_id::RequestId;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs;
+use std::path::{Component, Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::{Duration, Instant};
+use tokio::sync::broadcast::error::RecvError;
 
+mod access_log;
+mod audit;
 mod auth;
 mod config;
 mod dash_to_hls;
+mod disk_cache;
+mod error;
+mod events;
+mod request_id;
 
 // Stream management structures
 struct StreamManager {
     streams: HashMap<String, StreamInfo>,
-    active_streams: HashMap<String, Arc<Mutex<DashToHlsConverter>>>,
+    // Global language names, for channels added at runtime
+    languages: HashMap<String, String>,
+    // Channels added through POST /channels, which reloading channels.toml keeps
+    runtime_channels: HashSet<String>,
+    active_streams: HashMap<String, Arc<tokio::sync::Mutex<DashToHlsConverter>>>,
+    stream_stats: HashMap<String, Arc<StreamStats>>,
+    program_date_times: HashMap<String, Arc<ProgramDateTimes>>,
     last_access: HashMap<String, Instant>,
+    // Streams served from the kept output of a finished conversion, with no converter
+    kept_outputs: HashSet<String>,
+    // Last playlist fetch of each client, per stream
+    viewers: HashMap<String, HashMap<String, Instant>>,
+    viewer_window: Duration,
+    converter_config: ConverterTOML,
+    request_id_config: RequestIdTOML,
+    // Whether the last load of the channels config succeeded
+    config_loaded: bool,
+    events: EventSender,
+    // Directory holding the output directory of each stream
+    output_root: String,
+    // Last serving of each segment, for evicting when the outputs grow too big
+    disk_cache: DiskCache,
+    // Why each stream released after repeated failures gave up, until it's started again
+    failures: HashMap<String, String>,
+    // Streams taken out whose converter is still being stopped, they can't start meanwhile
+    halting: HashSet<String>,
+}
+
+// A stream taken out of the manager, whose converter still has to be stopped and output
+// removed or kept. Stopping waits for a refresh in progress and for ffmpeg to flush, so it
+// happens through finish_halted once the manager's lock is released.
+#[must_use]
+struct Halted {
+    stream_id: String,
+    stream_dir: String,
+    converter: Option<Arc<tokio::sync::Mutex<DashToHlsConverter>>>,
+    stats: Option<Arc<StreamStats>>,
+    // Keep the output if the conversion finished, marked so that /init serves it again
+    keep_finished: bool,
+}
+
+impl Halted {
+    fn finish(self) {
+        if let Some(converter) = &self.converter
+            && let Err(e) = converter.blocking_lock().stop()
+        {
+            error!("Could not stop ffmpeg process: {}", e);
+        }
+
+        let finished = self.stats.is_some_and(|stats| stats.has_ended());
+        if self.keep_finished && finished {
+            let marker = Path::new(&self.stream_dir).join(COMPLETE_MARKER);
+            match fs::write(&marker, b"") {
+                Ok(()) => {
+                    info!("Keeping the finished output of {}", self.stream_id);
+                    return;
+                }
+                Err(e) => error!("Could not mark {} as complete: {}", marker.display(), e),
+            }
+        }
+
+        info!("Removing folder: {}", self.stream_dir);
+        if let Err(e) = fs::remove_dir_all(&self.stream_dir) {
+            error!("Error deleting folder: {}: {}", self.stream_dir, e);
+        }
+    }
+}
+
+// Stops the converters of halted streams and removes or keeps their outputs, then lets the
+// streams start again. Called without holding the manager's lock.
+fn finish_halted(stream_manager: &Mutex<StreamManager>, halted: Vec<Halted>) {
+    for halted in halted {
+        let stream_id = halted.stream_id.clone();
+        halted.finish();
+        stream_manager.lock().unwrap().halting.remove(&stream_id);
+    }
+}
+
+// finish_halted on the blocking pool, for handlers
+async fn finish_halted_blocking(stream_manager: Arc<Mutex<StreamManager>>, halted: Vec<Halted>) {
+    if let Err(e) = web::block(move || finish_halted(&stream_manager, halted)).await {
+        error!("Could not finish stopping streams: {}", e);
+    }
+}
+
+impl StreamManager {
+    fn stream_dir(&self, stream_id: &str) -> String {
+        format!("{}/{}", self.output_root.trim_end_matches('/'), stream_id)
+    }
+
+    // Clients that fetched a playlist of the stream within the viewer window
+    fn viewer_count(&self, stream_id: &str) -> usize {
+        self.viewers.get(stream_id).map_or(0, |clients| {
+            clients
+                .values()
+                .filter(|seen| seen.elapsed() <= self.viewer_window)
+                .count()
+        })
+    }
+
+    // Takes out the stream's converter if running, to be stopped and have its output removed
+    fn stop_stream(&mut self, stream_id: &str, reason: &str) -> Option<Halted> {
+        let was_kept = self.kept_outputs.remove(stream_id);
+        match self.halt_converter(stream_id, reason, false) {
+            Some(halted) => Some(halted),
+            None if was_kept => {
+                self.halting.insert(stream_id.to_string());
+                Some(Halted {
+                    stream_id: stream_id.to_string(),
+                    stream_dir: self.stream_dir(stream_id),
+                    converter: None,
+                    stats: None,
+                    keep_finished: false,
+                })
+            }
+            None => None,
+        }
+    }
+
+    // Takes out an idle stream. The output of a channel with persist_output is kept once
+    // its conversion finished.
+    fn stop_idle_stream(&mut self, stream_id: &str) -> Option<Halted> {
+        let persist = self
+            .streams
+            .get(stream_id)
+            .is_some_and(|info| info.persist_output);
+        self.halt_converter(stream_id, "idle", persist)
+    }
+
+    // Takes out the stream's converter and forgets its state, or None when it wasn't running
+    fn halt_converter(
+        &mut self,
+        stream_id: &str,
+        reason: &str,
+        keep_finished: bool,
+    ) -> Option<Halted> {
+        let converter = self.active_streams.remove(stream_id)?;
+        events::publish(&self.events, stream_id, EventKind::Inactive, Some(reason));
+        self.last_access.remove(stream_id);
+        self.viewers.remove(stream_id);
+        self.program_date_times.remove(stream_id);
+        self.halting.insert(stream_id.to_string());
+        Some(Halted {
+            stream_id: stream_id.to_string(),
+            stream_dir: self.stream_dir(stream_id),
+            converter: Some(converter),
+            stats: self.stream_stats.remove(stream_id),
+            keep_finished,
+        })
+    }
+
+    // Creates and registers the stream's converter, which the caller then runs. The request
+    // starting it, if any, is traced in the origin fetches.
+    fn start_converter(
+        &mut self,
+        stream_info: StreamInfo,
+        request_id: Option<&str>,
+    ) -> Result<Arc<tokio::sync::Mutex<DashToHlsConverter>>, AppError> {
+        let stream_name = stream_info.id.clone();
+        if self.halting.contains(&stream_name) {
+            return Err(AppError::Unavailable {
+                message: "Stream is stopping".to_string(),
+                retry_after: 1,
+            });
+        }
+        let output_dir = self.stream_dir(&stream_name);
+        fs::create_dir_all(&output_dir).unwrap_or(());
+        self.failures.remove(&stream_name);
+
+        let converter = match DashToHlsConverter::new(
+            &output_dir,
+            stream_info.clone(),
+            self.converter_config.clone(),
+            stream_info.hls_list_size,
+            stream_info.hls_segment_time,
+        ) {
+            Ok(mut conv) => {
+                if self.request_id_config.forward_to_origin
+                    && let Some(request_id) = request_id
+                {
+                    conv.set_request_id(&self.request_id_config.header, request_id);
+                }
+                conv.set_event_sender(self.events.clone());
+                self.stream_stats.insert(stream_name.clone(), conv.stats());
+                self.program_date_times
+                    .insert(stream_name.clone(), conv.program_date_times());
+                Arc::new(tokio::sync::Mutex::new(conv))
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                error!("Output directory collision for {}: {}", stream_name, e);
+                return Err(AppError::Conflict(format!(
+                    "Failed to create converter: {}",
+                    e
+                )));
+            }
+            Err(e) => {
+                return Err(AppError::Internal(format!(
+                    "Failed to create converter: {}",
+                    e
+                )));
+            }
+        };
+
+        events::publish(&self.events, &stream_name, EventKind::Active, None);
+        self.active_streams
+            .insert(stream_name, Arc::clone(&converter));
+        Ok(converter)
+    }
+}
+
+async fn run_converter(converter: Arc<tokio::sync::Mutex<DashToHlsConverter>>) {
+    if let Err(e) = DashToHlsConverter::run_streaming_task(converter).await {
+        error!("Streaming loop error: {}", e);
+    }
 }
 
+// Left in a kept output directory once its conversion finished
+const COMPLETE_MARKER: &str = ".complete";
+
 #[derive(Clone)]
 struct StreamInfo {
     id: String,
     name: String,
     url: String,
-    key: String,
+    logo_url: Option<String>,
+    // Plain and DRM keys together
+    keys: HashMap<String, String>,
+    // Keys of each DRM system, preferred when the MPD announces the system
+    drm_keys: Vec<(DrmSystem, HashMap<String, String>)>,
+    video_rep_id: Option<String>,
+    audio_rep_id: Option<String>,
+    rep_fallback: RepFallback,
+    variants: u32,
+    segment_format: SegmentFormat,
+    output: OutputFormat,
+    decryptor: DecryptorKind,
+    incompatible_codec: IncompatibleCodec,
+    start_mode: StartMode,
+    hls_segment_time: u32,
+    hls_list_size: u32,
+    video_codec: Option<String>,
+    max_bitrate: Option<String>,
+    bufsize: Option<String>,
+    renditions: Vec<RenditionTOML>,
+    audio_copy: bool,
+    low_latency: bool,
+    part_duration_ms: u32,
+    prefetch_segments: usize,
+    download_rate_limit: Option<u64>,
+    persist_output: bool,
+    encrypt_output: bool,
+    audio_languages: bool,
+    subtitles: bool,
+    user_agent: Option<String>,
+    headers: HashMap<String, String>,
+    languages: HashMap<String, String>,
     init_segments: HashMap<String, Vec<u8>>,
 }
 
+impl StreamInfo {
+    // Channel language names take precedence over the global ones. The keys must have been
+    // validated already.
+    fn from_channel(channel: ChannelTOML, languages: &HashMap<String, String>) -> Self {
+        let keys = channel.all_keys().unwrap_or_default();
+        let drm_keys = channel
+            .drm
+            .iter()
+            .filter_map(|(system, key)| Some((*system, key.keys().ok()?)))
+            .collect();
+        let mut all_languages = languages.clone();
+        all_languages.extend(channel.languages);
+
+        StreamInfo {
+            id: channel.id,
+            name: channel.name,
+            url: channel.url,
+            logo_url: channel.logo_url,
+            keys,
+            drm_keys,
+            video_rep_id: channel.video_rep_id,
+            audio_rep_id: channel.audio_rep_id,
+            rep_fallback: channel.rep_fallback,
+            variants: channel.variants.unwrap_or(1).max(1),
+            segment_format: channel.segment_format,
+            output: channel.output,
+            decryptor: channel.decryptor,
+            incompatible_codec: channel.incompatible_codec,
+            start_mode: channel.start_mode,
+            hls_segment_time: channel.hls_segment_time.unwrap_or(4),
+            hls_list_size: channel.hls_list_size.unwrap_or(40),
+            video_codec: channel.video_codec,
+            max_bitrate: channel.max_bitrate,
+            bufsize: channel.bufsize,
+            renditions: channel.rendition,
+            audio_copy: channel.audio_copy,
+            low_latency: channel.low_latency,
+            part_duration_ms: channel.part_duration_ms.unwrap_or(1000),
+            prefetch_segments: channel.prefetch_segments,
+            download_rate_limit: channel.download_rate_limit,
+            persist_output: channel.persist_output,
+            encrypt_output: channel.encrypt_output,
+            audio_languages: channel.audio_languages,
+            subtitles: channel.subtitles,
+            user_agent: channel.user_agent,
+            headers: channel.headers,
+            languages: all_languages,
+            init_segments: HashMap::new(),
+        }
+    }
+}
+
+impl StreamInfo {
+    // How segments are split into parts, for low-latency streams
+    fn part_layout(&self) -> Option<PartLayout> {
+        self.low_latency.then_some(PartLayout {
+            segment_time: self.hls_segment_time,
+            part_duration_ms: self.part_duration_ms,
+        })
+    }
+}
+
 #[derive(Serialize)]
 struct ChannelInfo {
     id: String,
     name: String,
+    // Our own logo endpoint, so clients never reach the artwork host
+    logo_url: Option<String>,
+}
+
+impl ChannelInfo {
+    fn from_stream(info: &StreamInfo) -> Self {
+        ChannelInfo {
+            id: info.id.clone(),
+            name: info.name.clone(),
+            logo_url: logo_path(info),
+        }
+    }
 }
 
+fn logo_path(info: &StreamInfo) -> Option<String> {
+    info.logo_url
+        .as_ref()
+        .map(|_| format!("/channels/{}/logo", info.id))
+}
+
+// Seconds a fetched logo is served from memory before being fetched again
+const LOGO_CACHE_SECS: u64 = 3600;
+
+// Logo of a channel along with the URL it came from, refetched when the URL changes
+struct CachedLogo {
+    url: String,
+    logo: Arc<Logo>,
+    fetched: Instant,
+}
+
+type LogoCache = Mutex<HashMap<String, CachedLogo>>;
+
 struct UserManager {
     users: HashMap<String, String>,
+    roles: HashMap<String, String>,
+    // Channel allow-lists, users without one may access every channel
+    channels: HashMap<String, Vec<String>>,
+}
+
+impl UserManager {
+    fn from_config(users_config: UserConfig) -> Self {
+        UserManager {
+            users: users_config
+                .user
+                .iter()
+                .map(|user| (user.username.clone(), user.password.clone()))
+                .collect(),
+            roles: users_config
+                .user
+                .iter()
+                .map(|user| {
+                    let role = user.role.clone().unwrap_or_else(auth::default_role);
+                    (user.username.clone(), role)
+                })
+                .collect(),
+            channels: users_config
+                .user
+                .into_iter()
+                .map(|user| (user.username, user.channels))
+                .collect(),
+        }
+    }
+
+    // Role of the user when the password matches
+    fn verify_password(&self, username: &str, password: &str) -> Option<String> {
+        if self.users.get(username)? != password {
+            return None;
+        }
+        Some(
+            self.roles
+                .get(username)
+                .cloned()
+                .unwrap_or_else(auth::default_role),
+        )
+    }
+
+    fn can_access(&self, username: &str, channel_id: &str) -> bool {
+        self.channels
+            .get(username)
+            .is_none_or(|channels| channels.is_empty() || channels.iter().any(|c| c == channel_id))
+    }
 }
 
 #[derive(Deserialize)]
@@ -53,303 +473,3041 @@ struct LoginRequest {
 async fn login(
     req: web::Json<LoginRequest>,
     user_manager: web::Data<Arc<Mutex<UserManager>>>,
-) -> impl Responder {
-    let user_manager = user_manager.lock().unwrap();
+    auth_config: web::Data<AuthTOML>,
+) -> Result<HttpResponse, AppError> {
+    let role = user_manager
+        .lock()
+        .unwrap()
+        .verify_password(&req.username, &req.password);
 
-    if let Some(pass) = user_manager.users.get(&req.username) {
-        if req.password == *pass {
-            match auth::create_token(&req.username) {
-                Ok(token) => {
-                    let cookie = Cookie::build("auth", token)
-                        .http_only(true)
-                        .same_site(SameSite::Lax)
-                        .secure(false) // Set to true in production with HTTPS!
-                        .path("/")
-                        .finish();
-
-                    return HttpResponse::Ok()
-                        .cookie(cookie)
-                        .json(serde_json::json!({ "message": "Logged in" }));
-                }
-                Err(_) => return HttpResponse::InternalServerError().finish(),
-            }
-        }
+    if let Some(role) = role {
+        let token = auth::create_token(&req.username, &role, token_ttl(&auth_config)?)
+            .map_err(|e| AppError::Internal(format!("Failed to create token: {}", e)))?;
+        return Ok(HttpResponse::Ok()
+            .cookie(auth_cookie(token))
+            .json(serde_json::json!({ "message": "Logged in" })));
+    }
+
+    Err(AppError::Unauthorized("Invalid credentials".to_string()))
+}
+
+// Extends a still valid token, up to the configured maximum session age
+async fn refresh(
+    req: HttpRequest,
+    auth_config: web::Data<AuthTOML>,
+) -> Result<HttpResponse, AppError> {
+    let Some(claims) = auth::request_claims(&req) else {
+        return Err(AppError::Unauthorized(
+            "Invalid or missing token".to_string(),
+        ));
+    };
+
+    if session_expired(&claims, &auth_config) {
+        return Err(AppError::Unauthorized(
+            "Session expired, log in again".to_string(),
+        ));
+    }
+
+    let token = auth::refresh_token(&claims, token_ttl(&auth_config)?)
+        .map_err(|e| AppError::Internal(format!("Failed to refresh token: {}", e)))?;
+    Ok(HttpResponse::Ok()
+        .cookie(auth_cookie(token))
+        .json(serde_json::json!({ "message": "Token refreshed" })))
+}
+
+// Lifetime of login tokens, apply_auth_env refuses the ones chrono can't represent
+fn token_ttl(auth_config: &AuthTOML) -> Result<chrono::Duration, AppError> {
+    chrono::Duration::try_hours(auth_config.token_ttl_hours)
+        .ok_or_else(|| AppError::Internal("Token lifetime out of range".to_string()))
+}
+
+// Whether the session is older than the configured maximum age
+fn session_expired(claims: &auth::Claims, auth_config: &AuthTOML) -> bool {
+    let session_age = chrono::Utc::now().timestamp() - claims.session_start();
+    auth_config.max_session_hours > 0
+        && session_age > auth_config.max_session_hours.saturating_mul(3600)
+}
+
+// Short-lived token for players that can't send the cookie, to append as ?token= to the
+// master playlist URL
+async fn media_token(
+    req: HttpRequest,
+    auth_config: web::Data<AuthTOML>,
+) -> Result<HttpResponse, AppError> {
+    let claims = auth::authenticate(&req)?;
+    let token = issue_media_token(&claims, &auth_config)?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "token": token,
+        "expires_in": auth_config.media_token_ttl_secs,
+    })))
+}
+
+fn issue_media_token(claims: &auth::Claims, auth_config: &AuthTOML) -> Result<String, AppError> {
+    // Playlists fetched with a media token hand out new ones, which stop at the login's
+    let renewable = claims.renewable_until() as i64 > chrono::Utc::now().timestamp();
+    if !renewable || session_expired(claims, auth_config) {
+        return Err(AppError::Unauthorized(
+            "Session expired, log in again".to_string(),
+        ));
     }
+    let ttl = chrono::Duration::try_seconds(auth_config.media_token_ttl_secs)
+        .ok_or_else(|| AppError::Internal("Media token lifetime out of range".to_string()))?;
+    auth::create_media_token(claims, ttl)
+        .map_err(|e| AppError::Internal(format!("Failed to create token: {}", e)))
+}
+
+fn auth_cookie(token: String) -> Cookie<'static> {
+    Cookie::build("auth", token)
+        .http_only(true)
+        .same_site(SameSite::Lax)
+        .secure(false) // Set to true in production with HTTPS!
+        .path("/")
+        .finish()
+}
 
-    HttpResponse::Unauthorized().body("Invalid credentials")
+// Blocking playlist reload parameters of low-latency streams, the part counts from 0
+// within the segment
+#[derive(Deserialize)]
+struct BlockingReload {
+    #[serde(rename = "_HLS_msn")]
+    msn: Option<u64>,
+    #[serde(rename = "_HLS_part")]
+    part: Option<u64>,
 }
 
 async fn proxy_stream(
-    _user: auth::AuthenticatedUser,
+    user: auth::MediaUser,
+    req: HttpRequest,
     path: web::Path<(String, String)>,
+    reload: web::Query<BlockingReload>,
     stream_manager: web::Data<Arc<Mutex<StreamManager>>>,
-) -> impl Responder {
+    segments_config: web::Data<SegmentsTOML>,
+    user_manager: web::Data<Arc<Mutex<UserManager>>>,
+) -> Result<HttpResponse, AppError> {
     let (stream_name, file_path) = path.into_inner();
-
-    let mut stream_manager = stream_manager.lock().unwrap();
-
-    if let Some(_) = stream_manager.active_streams.get(&stream_name) {
-        stream_manager
-            .last_access
-            .insert(stream_name.clone(), Instant::now());
-    } else {
-        return HttpResponse::NotFound().body("Stream not active");
+    if !user_manager
+        .lock()
+        .unwrap()
+        .can_access(&user.username, &stream_name)
+    {
+        return Err(AppError::Forbidden("Channel not allowed".to_string()));
     }
 
-    let stream_info = match stream_manager.streams.get(&stream_name) {
-        Some(info) => info,
-        None => return HttpResponse::NotFound().body("Stream not found"),
+    // The manager isn't held while a blocking reload waits for the playlist
+    let stream = {
+        let mut stream_manager = stream_manager.lock().unwrap();
+        lookup_stream(
+            &mut stream_manager,
+            &stream_name,
+            &file_path,
+            &user.username,
+            &req,
+        )?
     };
+    let resolved_path = resolve_in_dir(&stream.dir, &file_path)?;
+    let read_timeout = Duration::from_millis(segments_config.read_timeout_ms);
+    if let Some(audit_log) = req.app_data::<web::Data<AuditLog>>() {
+        audit_log.record(&user.username, &stream_name, &file_path);
+    }
 
     if file_path.ends_with(".m3u8") {
-        let file_content =
-            fs::read_to_string(format!("./streams/{}/{}", stream_info.id, file_path))
-                .unwrap_or_else(|_| "".to_string());
+        // ffmpeg writes the playlist only after its first segment, until then the player
+        // is asked to come back rather than given an empty playlist
+        let not_ready = || AppError::Unavailable {
+            message: "Playlist not ready".to_string(),
+            retry_after: segments_config.retry_after_secs,
+        };
+
+        if let Some(parts) = stream.parts
+            && let Some(msn) = reload.msn
+        {
+            await_media_sequence(
+                &resolved_path,
+                (msn, reload.part),
+                parts,
+                segments_config.retry_after_secs,
+                read_timeout,
+            )
+            .await?;
+        }
+
+        let path = resolved_path.clone();
+        let file_content = match read_within(read_timeout, move || fs::read_to_string(path)).await?
+        {
+            Ok(content) if content.trim().is_empty() => return Err(not_ready()),
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Err(not_ready()),
+            Err(e) => {
+                error!("Failed to read {} of {}: {}", file_path, stream_name, e);
+                return Err(AppError::Internal("Failed to read playlist".to_string()));
+            }
+        };
+        let file_content = match &stream.program_date_times {
+            Some(program_date_times) => program_date_times.correct(&file_path, &file_content),
+            None => file_content,
+        };
+        let file_content = match stream.parts {
+            Some(parts) if is_media_playlist(&file_content) => {
+                PartedPlaylist::parse(&file_content, parts).render(parts)
+            }
+            _ => file_content,
+        };
+        // A player authenticated by the URL can't be by anything else for the files the
+        // playlist points at, each gets a fresh media token
+        let auth_config = req.app_data::<web::Data<AuthTOML>>();
+        let file_content = if user.query_token
+            && let Some(auth_config) = auth_config
+        {
+            let token = issue_media_token(&user.claims, auth_config)?;
+            append_media_token(&file_content, &token)
+        } else {
+            file_content
+        };
 
-        HttpResponse::Ok()
+        Ok(HttpResponse::Ok()
             .content_type("application/vnd.apple.mpegurl")
-            .body(file_content)
-    } else if file_path.ends_with(".ts") || file_path.ends_with(".m4s") {
-        match fs::read(format!("./streams/{}/{}", stream_info.id, file_path)) {
-            Ok(data) => HttpResponse::Ok().content_type("video/mp2t").body(data),
-            Err(_) => HttpResponse::NotFound().body("Segment not found"),
+            .insert_header(("Cache-Control", "no-cache"))
+            .body(file_content))
+    } else if file_path.ends_with(".mpd") {
+        // Rewritten by ffmpeg as the window moves, like a live playlist
+        let path = resolved_path.clone();
+        let file = read_within(read_timeout, move || NamedFile::open(path))
+            .await?
+            .map_err(|_| AppError::Unavailable {
+                message: "Manifest not ready".to_string(),
+                retry_after: segments_config.retry_after_secs,
+            })?;
+        let mut response = file
+            .set_content_type("application/dash+xml".parse().unwrap())
+            .disable_content_disposition()
+            .into_response(&req);
+        response
+            .headers_mut()
+            .insert(CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+        Ok(response)
+    } else if let Some((content_type, immutable)) = segment_content_type(&file_path) {
+        let cache_control = if immutable {
+            format!("public, max-age={}", segments_config.max_age_secs)
+        } else {
+            "no-cache".to_string()
+        };
+
+        // Low-latency segments are only listed as parts, and the hinted part is requested
+        // before ffmpeg is done with it
+        if let Some(parts) = stream.parts {
+            let (dir, name) = (stream.dir.clone(), file_path.clone());
+            let segment_parts = read_within(read_timeout, move || {
+                find_in_parted_playlists(&dir, parts, |playlist| {
+                    let segment_parts = playlist.segment_parts(&name)?;
+                    Some(
+                        segment_parts
+                            .into_iter()
+                            .map(str::to_string)
+                            .collect::<Vec<_>>(),
+                    )
+                })
+            })
+            .await?;
+            if let Some(segment_parts) = segment_parts {
+                let dir = stream.dir.clone();
+                let segment = read_within(read_timeout, move || read_parts(&dir, &segment_parts))
+                    .await?
+                    .map_err(|_| AppError::NotFound("Segment not found".to_string()))?;
+                return Ok(HttpResponse::Ok()
+                    .content_type(content_type)
+                    .insert_header((CACHE_CONTROL, cache_control))
+                    .body(segment));
+            }
+            await_hinted_part(&stream.dir, &file_path, parts, read_timeout).await?;
+        }
+
+        // DASH segments are addressed by a template, there's no list to check them against
+        let listed = || {
+            let (dir, file_path) = (stream.dir.clone(), file_path.clone());
+            read_within(read_timeout, move || listed_in_playlist(&dir, &file_path))
+        };
+        if segments_config.unlisted != UnlistedSegments::Serve
+            && stream.output == OutputFormat::Hls
+            && !listed().await?
+        {
+            return Err(match segments_config.unlisted {
+                UnlistedSegments::Retry => AppError::Unavailable {
+                    message: "Segment not ready".to_string(),
+                    retry_after: segments_config.retry_after_secs,
+                },
+                _ => AppError::NotFound("Segment not found".to_string()),
+            });
+        }
+
+        // NamedFile answers Range requests with 206/416 and advertises Accept-Ranges
+        let path = resolved_path.clone();
+        let file = read_within(read_timeout, move || NamedFile::open(path))
+            .await?
+            .map_err(|_| AppError::NotFound("Segment not found".to_string()))?;
+        let mut response = file
+            .set_content_type(content_type.parse().unwrap())
+            .disable_content_disposition()
+            .into_response(&req);
+        if let Ok(value) = HeaderValue::from_str(&cache_control) {
+            response.headers_mut().insert(CACHE_CONTROL, value);
         }
+        Ok(response)
     } else {
-        HttpResponse::BadRequest().body("Invalid file type")
+        Err(AppError::BadRequest("Invalid file type".to_string()))
     }
 }
 
-async fn initialize_stream(
-    _user: auth::AuthenticatedUser,
-    stream_name: web::Path<String>,
-    stream_manager: web::Data<Arc<Mutex<StreamManager>>>,
-) -> impl Responder {
-    let stream_name = stream_name.into_inner();
+// What serving a file of a stream needs once the manager is released
+struct ServedStream {
+    dir: String,
+    output: OutputFormat,
+    parts: Option<PartLayout>,
+    program_date_times: Option<Arc<ProgramDateTimes>>,
+}
 
-    // Get the stream info and check if it exists
-    let mut stream_manager_guard = stream_manager.lock().unwrap();
-    let stream_info = match stream_manager_guard.streams.get(&stream_name) {
-        Some(info) => info.clone(),
-        None => return HttpResponse::NotFound().body("Stream not found"),
+// Records the access to an active stream, and a viewer when a playlist is fetched
+fn lookup_stream(
+    stream_manager: &mut StreamManager,
+    stream_name: &str,
+    file_path: &str,
+    username: &str,
+    req: &HttpRequest,
+) -> Result<ServedStream, AppError> {
+    if stream_manager.active_streams.contains_key(stream_name) {
+        stream_manager
+            .last_access
+            .insert(stream_name.to_string(), Instant::now());
+    } else if !stream_manager.kept_outputs.contains(stream_name) {
+        return Err(AppError::NotFound("Stream not active".to_string()));
+    }
+
+    let (dir, output, parts) = match stream_manager.streams.get(stream_name) {
+        Some(info) => (
+            stream_manager.stream_dir(&info.id),
+            info.output,
+            info.part_layout(),
+        ),
+        None => return Err(AppError::NotFound("Stream not found".to_string())),
     };
 
-    // Check if stream is already active
-    if stream_manager_guard
-        .active_streams
-        .contains_key(&stream_name)
-    {
-        return HttpResponse::Ok().body("Stream already active");
+    if file_path.ends_with(".m3u8") || file_path.ends_with(".mpd") {
+        // Players poll playlists continuously, which makes them a good sign of a viewer
+        let client = format!(
+            "{}@{}",
+            username,
+            req.connection_info()
+                .realip_remote_addr()
+                .unwrap_or("unknown")
+        );
+        stream_manager
+            .viewers
+            .entry(stream_name.to_string())
+            .or_default()
+            .insert(client, Instant::now());
+    } else {
+        let path = Path::new(&dir).join(file_path);
+        stream_manager.disk_cache.touch(path);
     }
 
-    // Create output directory
-    let output_dir = format!("./streams/{}", stream_info.id);
-    fs::create_dir_all(&output_dir).unwrap_or(());
+    Ok(ServedStream {
+        dir,
+        output,
+        parts,
+        program_date_times: stream_manager.program_date_times.get(stream_name).cloned(),
+    })
+}
 
-    // Create a new DASH to HLS converter
-    let converter = match DashToHlsConverter::new(&output_dir, stream_info.clone(), 40, 4) {
-        Ok(conv) => Arc::new(Mutex::new(conv)),
-        Err(e) => {
-            return HttpResponse::InternalServerError()
-                .body(format!("Failed to create converter: {}", e));
+// Holds a blocking playlist reload until the media playlist gets to the requested segment,
+// or part of it. As the LL-HLS spec asks, segments more than two past the last one are
+// rejected and a playlist that doesn't get there within three target durations is a 503.
+async fn await_media_sequence(
+    path: &Path,
+    (msn, part): (u64, Option<u64>),
+    parts: PartLayout,
+    retry_after: u64,
+    read_timeout: Duration,
+) -> Result<(), AppError> {
+    let deadline = Instant::now() + three_target_durations(parts);
+    loop {
+        let playlist = path.to_path_buf();
+        if let Ok(content) = read_within(read_timeout, move || fs::read_to_string(playlist)).await?
+        {
+            if !is_media_playlist(&content) {
+                // Only media playlists have a media sequence, others are served right away
+                if !content.trim().is_empty() {
+                    return Ok(());
+                }
+            } else {
+                let playlist = PartedPlaylist::parse(&content, parts);
+                if playlist.reached(msn, part) {
+                    return Ok(());
+                }
+                if playlist.last_complete().is_some_and(|last| msn > last + 2) {
+                    return Err(AppError::BadRequest(
+                        "_HLS_msn is too far ahead of the playlist".to_string(),
+                    ));
+                }
+            }
         }
-    };
-
-    let converter_clone = Arc::clone(&converter);
-    stream_manager_guard
-        .active_streams
-        .insert(stream_name, converter);
 
-    // Spawn a thread to run the converter
-    thread::spawn(move || {
-        if let Err(e) = DashToHlsConverter::run_streaming_loop(converter_clone) {
-            error!("Streaming loop error: {}", e);
+        if Instant::now() >= deadline {
+            return Err(AppError::Unavailable {
+                message: "Playlist did not reach the requested media sequence".to_string(),
+                retry_after,
+            });
         }
-    });
-
-    HttpResponse::Ok().body("Stream initialization started")
+        actix_web::rt::time::sleep(Duration::from_millis(100)).await;
+    }
 }
 
-async fn list_channels(
-    _user: auth::AuthenticatedUser,
-    stream_manager: web::Data<Arc<Mutex<StreamManager>>>,
-) -> impl Responder {
-    let stream_manager = stream_manager.lock().unwrap();
-    let channels: Vec<ChannelInfo> = stream_manager
-        .streams
-        .values()
-        .map(|info| ChannelInfo {
-            id: info.id.clone(),
-            name: info.name.clone(),
+// Holds the request of the part a low-latency playlist hints at until ffmpeg lists it, for
+// three target durations at most. Requests of other files go on right away, and are then
+// answered as any unlisted segment.
+async fn await_hinted_part(
+    dir: &str,
+    file_path: &str,
+    parts: PartLayout,
+    read_timeout: Duration,
+) -> Result<(), AppError> {
+    let deadline = Instant::now() + three_target_durations(parts);
+    loop {
+        let (dir, file_path) = (dir.to_string(), file_path.to_string());
+        let listed = read_within(read_timeout, move || {
+            if listed_in_playlist(&dir, &file_path) {
+                return Some(true);
+            }
+            find_in_parted_playlists(&dir, parts, |playlist| {
+                playlist.is_next_part(&file_path).then_some(false)
+            })
         })
-        .collect();
+        .await?;
+        if listed != Some(false) || Instant::now() >= deadline {
+            return Ok(());
+        }
+        actix_web::rt::time::sleep(Duration::from_millis(100)).await;
+    }
+}
 
-    HttpResponse::Ok().json(channels)
+fn three_target_durations(parts: PartLayout) -> Duration {
+    Duration::from_secs(3 * u64::from(parts.segment_time.max(1)))
 }
 
-async fn stream_status(
-    _user: auth::AuthenticatedUser,
-    stream_manager: web::Data<Arc<Mutex<StreamManager>>>,
-) -> impl Responder {
-    let stream_manager = stream_manager.lock().unwrap();
+fn is_media_playlist(playlist: &str) -> bool {
+    playlist.contains("#EXT-X-TARGETDURATION")
+}
 
-    let active_streams: Vec<String> = stream_manager.active_streams.keys().cloned().collect();
+// First answer of `find` among the media playlists of a low-latency stream directory
+fn find_in_parted_playlists<T>(
+    stream_dir: &str,
+    parts: PartLayout,
+    find: impl Fn(&PartedPlaylist) -> Option<T>,
+) -> Option<T> {
+    fs::read_dir(stream_dir)
+        .ok()?
+        .flatten()
+        .filter(|entry| entry.path().extension().is_some_and(|ext| ext == "m3u8"))
+        .filter_map(|entry| fs::read_to_string(entry.path()).ok())
+        .filter(|playlist| is_media_playlist(playlist))
+        .find_map(|playlist| find(&PartedPlaylist::parse(&playlist, parts)))
+}
 
-    HttpResponse::Ok().json(active_streams)
+// A low-latency segment, its parts one after the other
+fn read_parts(dir: &str, parts: &[String]) -> std::io::Result<Vec<u8>> {
+    let mut segment = Vec::new();
+    for part in parts {
+        segment.extend(fs::read(Path::new(dir).join(part))?);
+    }
+    Ok(segment)
 }
 
-async fn stream_details(
-    _user: auth::AuthenticatedUser,
-    path: web::Path<String>,
-    stream_manager: web::Data<Arc<Mutex<StreamManager>>>,
-) -> impl Responder {
-    let stream_id = path.into_inner();
-    let stream_manager = stream_manager.lock().unwrap();
+// Runs a filesystem call on the blocking pool, so a stalled disk holds a blocking thread
+// rather than the worker, which answers 504 once the time is up
+async fn read_within<T, F>(timeout: Duration, read: F) -> Result<T, AppError>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    match actix_web::rt::time::timeout(timeout, web::block(read)).await {
+        Ok(Ok(result)) => Ok(result),
+        Ok(Err(e)) => Err(AppError::Internal(format!(
+            "Failed to read the stream: {}",
+            e
+        ))),
+        Err(_) => {
+            warn!("Reading a stream file took over {:?}", timeout);
+            Err(AppError::GatewayTimeout(
+                "Reading the stream timed out".to_string(),
+            ))
+        }
+    }
+}
 
-    if let Some(stream_info) = stream_manager.streams.get(&stream_id) {
-        let is_active = stream_manager.active_streams.contains_key(&stream_id);
+// Adds ?token= to the URI lines of a playlist and to the URI attributes of its tags
+fn append_media_token(playlist: &str, token: &str) -> String {
+    let with_token = |uri: &str| {
+        let separator = if uri.contains('?') { '&' } else { '?' };
+        format!("{}{}token={}", uri, separator, token)
+    };
 
-        let details = serde_json::json!({
-            "id": stream_info.id,
-            "name": stream_info.name,
-            "active": is_active,
-            "url": format!("/streams/{}/master.m3u8", stream_info.id),
-        });
+    let mut tokenized = String::with_capacity(playlist.len());
+    for line in playlist.lines() {
+        if !line.is_empty() && !line.starts_with('#') {
+            tokenized.push_str(&with_token(line));
+        } else if let Some((tag, rest)) = line.split_once("URI=\"")
+            && let Some((uri, after)) = rest.split_once('"')
+        {
+            tokenized.push_str(&format!("{}URI=\"{}\"{}", tag, with_token(uri), after));
+        } else {
+            tokenized.push_str(line);
+        }
+        tokenized.push('\n');
+    }
+    tokenized
+}
 
-        HttpResponse::Ok().json(details)
-    } else {
-        HttpResponse::NotFound().body("Stream not found")
+// Content type of a segment, and whether it's immutable once listed. Init segments keep
+// their name when ffmpeg restarts, so they may be rewritten, and AES keys are never left
+// in shared caches.
+fn segment_content_type(file_path: &str) -> Option<(&'static str, bool)> {
+    let (_, extension) = file_path.rsplit_once('.')?;
+    match extension {
+        "ts" => Some(("video/mp2t", true)),
+        "m4s" => Some(("video/iso.segment", true)),
+        "mp4" => Some(("video/mp4", false)),
+        "vtt" | "webvtt" => Some(("text/vtt", true)),
+        "key" => Some(("application/octet-stream", false)),
+        _ => None,
     }
 }
 
-fn start_cleanup_thread(
-    secs: u64,
-    stream_manager: &Arc<Mutex<StreamManager>>,
-) -> anyhow::Result<()> {
-    let stream_manager_clone = Arc::clone(&stream_manager);
-    let timeout = Duration::from_secs(secs);
+// Path of a file requested within a stream directory. Only plain names below the directory
+// are accepted, and a file reached through a link must still be inside it.
+fn resolve_in_dir(dir: &str, file_path: &str) -> Result<PathBuf, AppError> {
+    let invalid = || AppError::BadRequest("Invalid file path".to_string());
+    let relative = Path::new(file_path);
+    if file_path.is_empty()
+        || !relative
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+    {
+        return Err(invalid());
+    }
 
-    thread::spawn(move || {
-        loop {
-            thread::sleep(Duration::from_secs(15));
+    let path = Path::new(dir).join(relative);
+    if let (Ok(canonical_dir), Ok(canonical_path)) =
+        (fs::canonicalize(dir), fs::canonicalize(&path))
+        && !canonical_path.starts_with(canonical_dir)
+    {
+        return Err(invalid());
+    }
+    Ok(path)
+}
 
-            let mut manager = stream_manager_clone.lock().unwrap();
-            let now = Instant::now();
-            let mut to_remove = vec![];
+// Whether any playlist of the stream references the file, either as a media
+// segment line or through a URI attribute such as the one of EXT-X-MAP
+fn listed_in_playlist(stream_dir: &str, file_path: &str) -> bool {
+    let Ok(entries) = fs::read_dir(stream_dir) else {
+        return false;
+    };
+    let uri_attr = format!("URI=\"{}\"", file_path);
 
-            for (stream_id, last_time) in manager.last_access.iter() {
-                if now.duration_since(*last_time) > timeout {
-                    to_remove.push(stream_id.clone());
+    entries
+        .flatten()
+        .filter(|entry| entry.path().extension().is_some_and(|ext| ext == "m3u8"))
+        .filter_map(|entry| fs::read_to_string(entry.path()).ok())
+        .any(|playlist| {
+            playlist.lines().map(str::trim).any(|line| {
+                if line.starts_with('#') {
+                    line.contains(&uri_attr)
+                } else {
+                    line == file_path
                 }
-            }
+            })
+        })
+}
 
-            for stream_id in to_remove {
-                if let Some(dashhlsconverter) = manager.active_streams.get(&stream_id) {
-                    if let Ok(mut locked) = dashhlsconverter.lock() {
-                        info!("Shutting down idle stream: {}", stream_id);
-                        if let Err(e) = locked.stop() {
-                            error!("Could not stop ffmpeg process: {}", e);
-                        }
-                    }
-                }
-                manager.active_streams.remove(&stream_id);
-                manager.last_access.remove(&stream_id);
-                info!("Removing folder: {}", &format!("./streams/{}", stream_id));
-                if let Err(e) = fs::remove_dir_all(&format!("./streams/{}", stream_id)) {
-                    error!("Error deleting folder: streams/{}: {}", stream_id, e);
-                }
-            }
-        }
-    });
+async fn initialize_stream(
+    user: auth::AdminUser,
+    request_id: web::ReqData<RequestId>,
+    stream_name: web::Path<String>,
+    stream_manager: web::Data<Arc<Mutex<StreamManager>>>,
+    user_manager: web::Data<Arc<Mutex<UserManager>>>,
+    segments_config: web::Data<SegmentsTOML>,
+) -> Result<HttpResponse, AppError> {
+    let stream_name = stream_name.into_inner();
+    if !user_manager
+        .lock()
+        .unwrap()
+        .can_access(&user.username, &stream_name)
+    {
+        return Err(AppError::Forbidden("Channel not allowed".to_string()));
+    }
 
-    Ok(())
+    // The manager stays locked until the converter is registered, so concurrent requests
+    // for the same stream start only one
+    let mut stream_manager = stream_manager.lock().unwrap();
+    let outcome = init_stream(
+        &mut stream_manager,
+        &stream_name,
+        &request_id.0,
+        segments_config.retry_after_secs,
+    )?;
+    Ok(HttpResponse::Ok().body(outcome.message()))
 }
 
-#[actix_web::main]
-async fn main() -> std::io::Result<()> {
-    env_logger::init_from_env(env_logger::Env::default().default_filter_or("info"));
-    info!("Starting DASH to HLS converter service");
+// What /init did with a stream
+#[derive(Clone, Copy)]
+enum InitOutcome {
+    Started,
+    AlreadyActive,
+    // A finished conversion kept on idle is served as is
+    Kept,
+}
 
-    // Load configuration
-    let deserializer = Deserializer::new("channels.toml".to_string(), "users.toml".to_string());
+impl InitOutcome {
+    fn status(self) -> &'static str {
+        match self {
+            InitOutcome::Started => "started",
+            InitOutcome::AlreadyActive => "already_active",
+            InitOutcome::Kept => "kept",
+        }
+    }
 
-    // Load channels
-    let channels_config = match deserializer.load_channels() {
-        Ok(channels) => channels,
-        Err(e) => {
-            error!("Error reading channels.toml: {}", e);
-            panic!("Can't continue without channels!");
+    fn message(self) -> &'static str {
+        match self {
+            InitOutcome::Started => "Stream initialization started",
+            InitOutcome::AlreadyActive => "Stream already active",
+            InitOutcome::Kept => "Serving the kept output of the stream",
         }
+    }
+}
+
+// Starts a stream unless it's running or its kept output can be served, within the limit
+// of active streams. Nothing is registered for a stream that fails to start.
+fn init_stream(
+    stream_manager: &mut StreamManager,
+    stream_name: &str,
+    request_id: &str,
+    retry_after: u64,
+) -> Result<InitOutcome, AppError> {
+    let stream_info = match stream_manager.streams.get(stream_name) {
+        Some(info) => info.clone(),
+        None => return Err(AppError::NotFound("Stream not found".to_string())),
     };
 
-    // Initialize stream manager
-    let stream_manager = Arc::new(Mutex::new(StreamManager {
-        streams: {
-            let mut map = HashMap::new();
-            for channel in channels_config.channel {
-                map.insert(channel.id.clone(), StreamInfo {
-                    id: channel.id,
-                    name: channel.name,
-                    url: channel.url,
-                    key: channel.key,
-                    init_segments: HashMap::new(),
-                });
-            }
-            map
-        },
-        active_streams: HashMap::new(),
-        last_access: HashMap::new(),
+    if stream_manager.active_streams.contains_key(stream_name) {
+        return Ok(InitOutcome::AlreadyActive);
+    }
+    // Its output is still being removed or marked as kept
+    if stream_manager.halting.contains(stream_name) {
+        return Err(AppError::Unavailable {
+            message: "Stream is stopping, try again later".to_string(),
+            retry_after,
+        });
+    }
+
+    let output_dir = stream_manager.stream_dir(&stream_info.id);
+    if stream_info.persist_output && Path::new(&output_dir).join(COMPLETE_MARKER).exists() {
+        stream_manager.kept_outputs.insert(stream_name.to_string());
+        return Ok(InitOutcome::Kept);
+    }
+
+    // A slot frees up when a stream is stopped or torn down by the cleanup thread
+    let max_active_streams = stream_manager.converter_config.max_active_streams;
+    if max_active_streams > 0 && stream_manager.active_streams.len() >= max_active_streams {
+        return Err(AppError::Unavailable {
+            message: format!(
+                "Maximum of {} active streams reached, try again later",
+                max_active_streams
+            ),
+            retry_after,
+        });
+    }
+
+    let converter = stream_manager.start_converter(stream_info, Some(request_id))?;
+
+    // Runs on this worker's runtime, the converter only takes a thread while refreshing
+    actix_web::rt::spawn(run_converter(converter));
+
+    Ok(InitOutcome::Started)
+}
+
+// Starts several streams at once, such as every channel at deploy time. Each id gets its
+// own outcome, a failing one doesn't stop the others.
+async fn initialize_streams(
+    user: auth::AdminUser,
+    request_id: web::ReqData<RequestId>,
+    stream_names: web::Json<Vec<String>>,
+    stream_manager: web::Data<Arc<Mutex<StreamManager>>>,
+    user_manager: web::Data<Arc<Mutex<UserManager>>>,
+    segments_config: web::Data<SegmentsTOML>,
+) -> impl Responder {
+    let user_manager = user_manager.lock().unwrap();
+    let mut stream_manager = stream_manager.lock().unwrap();
+
+    let results = init_streams(
+        &mut stream_manager,
+        stream_names.into_inner(),
+        |stream_name| user_manager.can_access(&user.username, stream_name),
+        &request_id.0,
+        segments_config.retry_after_secs,
+    );
+    HttpResponse::Ok().json(results)
+}
+
+// Outcome of each stream of a batch by id, for the streams `allowed` lets the user start
+fn init_streams(
+    stream_manager: &mut StreamManager,
+    stream_names: Vec<String>,
+    allowed: impl Fn(&str) -> bool,
+    request_id: &str,
+    retry_after: u64,
+) -> serde_json::Map<String, serde_json::Value> {
+    let mut results = serde_json::Map::new();
+    for stream_name in stream_names {
+        let outcome = if allowed(&stream_name) {
+            init_stream(stream_manager, &stream_name, request_id, retry_after)
+        } else {
+            Err(AppError::Forbidden("Channel not allowed".to_string()))
+        };
+        let result = match outcome {
+            Ok(outcome) => serde_json::json!({
+                "status": outcome.status(),
+                "message": outcome.message(),
+            }),
+            Err(e) => serde_json::json!({
+                "status": "error",
+                "code": e.code(),
+                "error": e.to_string(),
+            }),
+        };
+        results.insert(stream_name, result);
+    }
+    results
+}
+
+async fn list_channels(
+    user: auth::AuthenticatedUser,
+    stream_manager: web::Data<Arc<Mutex<StreamManager>>>,
+    user_manager: web::Data<Arc<Mutex<UserManager>>>,
+) -> impl Responder {
+    let user_manager = user_manager.lock().unwrap();
+    let stream_manager = stream_manager.lock().unwrap();
+    let channels: Vec<ChannelInfo> = stream_manager
+        .streams
+        .values()
+        .filter(|info| user_manager.can_access(&user.username, &info.id))
+        .map(ChannelInfo::from_stream)
+        .collect();
+
+    HttpResponse::Ok().json(channels)
+}
+
+// Extended M3U of the channels the user can access, for IPTV players. Entries point at
+// the proxied manifests, which only play once the stream is started with /init. Players
+// can't send the auth cookie, so links carry a media token when the session allows one.
+async fn channels_playlist(
+    user: auth::AuthenticatedUser,
+    req: HttpRequest,
+    stream_manager: web::Data<Arc<Mutex<StreamManager>>>,
+    user_manager: web::Data<Arc<Mutex<UserManager>>>,
+) -> impl Responder {
+    let public_url = req
+        .app_data::<web::Data<PublicUrl>>()
+        .and_then(|public_url| public_url.0.as_deref());
+    let token = req
+        .app_data::<web::Data<AuthTOML>>()
+        .and_then(|auth_config| issue_media_token(&user.claims, auth_config).ok());
+
+    let user_manager = user_manager.lock().unwrap();
+    let stream_manager = stream_manager.lock().unwrap();
+    let mut streams: Vec<&StreamInfo> = stream_manager
+        .streams
+        .values()
+        .filter(|info| user_manager.can_access(&user.username, &info.id))
+        .collect();
+    streams.sort_by(|a, b| a.id.cmp(&b.id));
+
+    HttpResponse::Ok()
+        .content_type("audio/x-mpegurl")
+        .insert_header((CACHE_CONTROL, "no-cache"))
+        .body(render_channels_playlist(
+            &streams,
+            public_url,
+            token.as_deref(),
+        ))
+}
+
+// Playlist entries link below `public_url`, or relatively to /playlist.m3u without one
+fn render_channels_playlist(
+    streams: &[&StreamInfo],
+    public_url: Option<&str>,
+    token: Option<&str>,
+) -> String {
+    let link = |path: String| {
+        let url = match public_url {
+            Some(base) => format!("{}/{}", base.trim_end_matches('/'), path),
+            None => path,
+        };
+        match token {
+            Some(token) => format!("{}?token={}", url, token),
+            None => url,
+        }
+    };
+    let attribute = |value: &str| value.replace('"', "'");
+
+    let mut playlist = String::from("#EXTM3U\n");
+    for info in streams {
+        let id = encode_path_segment(&info.id);
+        let logo = info
+            .logo_url
+            .as_ref()
+            .map(|_| {
+                let logo = link(format!("channels/{}/logo", id));
+                format!(" tvg-logo=\"{}\"", attribute(&logo))
+            })
+            .unwrap_or_default();
+        playlist.push_str(&format!(
+            "#EXTINF:-1 tvg-id=\"{}\" tvg-name=\"{}\"{},{}\n",
+            attribute(&info.id),
+            attribute(&info.name),
+            logo,
+            info.name.replace(['\r', '\n'], " ")
+        ));
+        playlist.push_str(&link(format!(
+            "streams/{}/{}",
+            id,
+            info.output.manifest_name()
+        )));
+        playlist.push('\n');
+    }
+    playlist
+}
+
+// Percent-encodes all but the unreserved characters of a URL path segment
+fn encode_path_segment(segment: &str) -> String {
+    segment
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                char::from(byte).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+async fn add_channel(
+    _user: auth::AdminUser,
+    channel: web::Json<ChannelTOML>,
+    stream_manager: web::Data<Arc<Mutex<StreamManager>>>,
+) -> Result<HttpResponse, AppError> {
+    let channel = channel.into_inner();
+    if let Err(e) = deserializer::validate_channel(&channel) {
+        return Err(AppError::BadRequest(format!("Invalid channel: {}", e)));
+    }
+
+    let mut stream_manager = stream_manager.lock().unwrap();
+    if stream_manager.streams.contains_key(&channel.id) {
+        return Err(AppError::Conflict("Channel already exists".to_string()));
+    }
+
+    info!("Adding channel {}", channel.id);
+    let stream_info = StreamInfo::from_channel(channel, &stream_manager.languages);
+    let channel_info = ChannelInfo::from_stream(&stream_info);
+    stream_manager
+        .runtime_channels
+        .insert(stream_info.id.clone());
+    stream_manager
+        .streams
+        .insert(stream_info.id.clone(), stream_info);
+
+    Ok(HttpResponse::Created().json(channel_info))
+}
+
+async fn remove_channel(
+    _user: auth::AdminUser,
+    path: web::Path<String>,
+    stream_manager: web::Data<Arc<Mutex<StreamManager>>>,
+) -> Result<HttpResponse, AppError> {
+    let stream_id = path.into_inner();
+    let halted = {
+        let mut stream_manager = stream_manager.lock().unwrap();
+        if stream_manager.streams.remove(&stream_id).is_none() {
+            return Err(AppError::NotFound("Stream not found".to_string()));
+        }
+
+        info!("Removing channel {}", stream_id);
+        stream_manager.runtime_channels.remove(&stream_id);
+        stream_manager.stop_stream(&stream_id, "channel removed")
+    };
+    finish_halted_blocking(Arc::clone(&stream_manager), halted.into_iter().collect()).await;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+// Stops a running stream right away instead of waiting for the idle timeout
+async fn stop_stream(
+    _user: auth::AdminUser,
+    path: web::Path<String>,
+    stream_manager: web::Data<Arc<Mutex<StreamManager>>>,
+) -> Result<HttpResponse, AppError> {
+    let stream_id = path.into_inner();
+    let halted = {
+        let mut stream_manager = stream_manager.lock().unwrap();
+        if !stream_manager.active_streams.contains_key(&stream_id)
+            && !stream_manager.kept_outputs.contains(&stream_id)
+        {
+            return Err(AppError::NotFound("Stream not active".to_string()));
+        }
+
+        info!("Stopping stream {}", stream_id);
+        stream_manager.stop_stream(&stream_id, "stopped")
+    };
+    finish_halted_blocking(Arc::clone(&stream_manager), halted.into_iter().collect()).await;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "message": "Stream stopped" })))
+}
+
+// Pushes stream events as JSON text messages, limited to the channels the user may access
+async fn status_events(
+    user: auth::AuthenticatedUser,
+    req: HttpRequest,
+    body: web::Payload,
+    stream_manager: web::Data<Arc<Mutex<StreamManager>>>,
+    user_manager: web::Data<Arc<Mutex<UserManager>>>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let (response, mut session, mut messages) = actix_ws::handle(&req, body)?;
+    let mut receiver = stream_manager.lock().unwrap().events.subscribe();
+    let user_manager = Arc::clone(&user_manager);
+
+    actix_web::rt::spawn(async move {
+        loop {
+            let event = std::pin::pin!(receiver.recv());
+            match select(event, messages.next()).await {
+                Either::Left((Ok(event), _)) => {
+                    if !user_manager
+                        .lock()
+                        .unwrap()
+                        .can_access(&user.username, &event.stream)
+                    {
+                        continue;
+                    }
+                    let Ok(text) = serde_json::to_string(&event) else {
+                        continue;
+                    };
+                    if session.text(text).await.is_err() {
+                        return;
+                    }
+                }
+                // A slow client misses the oldest events but stays subscribed
+                Either::Left((Err(RecvError::Lagged(_)), _)) => {}
+                Either::Left((Err(RecvError::Closed), _)) => break,
+                Either::Right((Some(Ok(Message::Ping(bytes))), _)) => {
+                    if session.pong(&bytes).await.is_err() {
+                        return;
+                    }
+                }
+                Either::Right((Some(Ok(Message::Close(reason))), _)) => {
+                    session.close(reason).await.ok();
+                    return;
+                }
+                Either::Right((Some(Ok(_)), _)) => {}
+                Either::Right((Some(Err(_)) | None, _)) => break,
+            }
+        }
+        session.close(None).await.ok();
+    });
+
+    Ok(response)
+}
+
+async fn stream_status(
+    user: auth::AuthenticatedUser,
+    stream_manager: web::Data<Arc<Mutex<StreamManager>>>,
+    user_manager: web::Data<Arc<Mutex<UserManager>>>,
+) -> impl Responder {
+    let user_manager = user_manager.lock().unwrap();
+    let stream_manager = stream_manager.lock().unwrap();
+
+    let active_streams: Vec<String> = stream_manager
+        .active_streams
+        .keys()
+        .filter(|id| user_manager.can_access(&user.username, id))
+        .cloned()
+        .collect();
+
+    HttpResponse::Ok().json(active_streams)
+}
+
+async fn stream_details(
+    user: auth::AuthenticatedUser,
+    path: web::Path<String>,
+    stream_manager: web::Data<Arc<Mutex<StreamManager>>>,
+    user_manager: web::Data<Arc<Mutex<UserManager>>>,
+) -> Result<HttpResponse, AppError> {
+    let stream_id = path.into_inner();
+    if !user_manager
+        .lock()
+        .unwrap()
+        .can_access(&user.username, &stream_id)
+    {
+        return Err(AppError::Forbidden("Channel not allowed".to_string()));
+    }
+    let stream_manager = stream_manager.lock().unwrap();
+
+    if let Some(stream_info) = stream_manager.streams.get(&stream_id) {
+        let is_active = stream_manager.active_streams.contains_key(&stream_id);
+
+        let details = serde_json::json!({
+            "id": stream_info.id,
+            "name": stream_info.name,
+            "logo_url": logo_path(stream_info),
+            "active": is_active,
+            "failure": stream_manager.failures.get(&stream_id),
+            "viewers": stream_manager.viewer_count(&stream_id),
+            "ffmpeg_restarts": stream_manager
+                .stream_stats
+                .get(&stream_id)
+                .map_or(0, |stats| stats.snapshot().ffmpeg_restarts),
+            "stats": stream_manager
+                .stream_stats
+                .get(&stream_id)
+                .map(|stats| stats.snapshot()),
+            "url": format!(
+                "/streams/{}/{}",
+                stream_info.id,
+                stream_info.output.manifest_name()
+            ),
+        });
+
+        Ok(HttpResponse::Ok().json(details))
+    } else {
+        Err(AppError::NotFound("Stream not found".to_string()))
+    }
+}
+
+async fn channel_metrics(
+    user: auth::AuthenticatedUser,
+    path: web::Path<String>,
+    stream_manager: web::Data<Arc<Mutex<StreamManager>>>,
+    user_manager: web::Data<Arc<Mutex<UserManager>>>,
+    metrics_config: web::Data<MetricsTOML>,
+) -> Result<HttpResponse, AppError> {
+    if !metrics_config.per_channel {
+        return Err(AppError::NotFound(
+            "Per-channel metrics disabled".to_string(),
+        ));
+    }
+
+    let stream_id = path.into_inner();
+    if !user_manager
+        .lock()
+        .unwrap()
+        .can_access(&user.username, &stream_id)
+    {
+        return Err(AppError::Forbidden("Channel not allowed".to_string()));
+    }
+
+    let stream_manager = stream_manager.lock().unwrap();
+    if !stream_manager.streams.contains_key(&stream_id) {
+        return Err(AppError::NotFound("Stream not found".to_string()));
+    }
+
+    // Inactive channels have no converter, hence no counters
+    let metrics = stream_manager
+        .stream_stats
+        .get(&stream_id)
+        .map(|stats| stats.snapshot());
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "id": stream_id,
+        "active": metrics.is_some(),
+        "metrics": metrics,
+    })))
+}
+
+// Latest media requests of every user, oldest first
+async fn audit_entries(_user: auth::AdminUser, audit_log: web::Data<AuditLog>) -> impl Responder {
+    HttpResponse::Ok().json(serde_json::json!({ "entries": audit_log.recent() }))
+}
+
+// Latest segment download and decryption failures of a stream, oldest first
+async fn stream_errors(
+    _user: auth::AdminUser,
+    path: web::Path<String>,
+    stream_manager: web::Data<Arc<Mutex<StreamManager>>>,
+) -> Result<HttpResponse, AppError> {
+    let stream_id = path.into_inner();
+    let stream_manager = stream_manager.lock().unwrap();
+    if !stream_manager.streams.contains_key(&stream_id) {
+        return Err(AppError::NotFound("Stream not found".to_string()));
+    }
+
+    let errors = stream_manager
+        .stream_stats
+        .get(&stream_id)
+        .map(|stats| stats.segment_errors())
+        .unwrap_or_default();
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "id": stream_id,
+        "errors": errors,
+    })))
+}
+
+// Counters of a stream for any user allowed to watch it, null while it isn't running
+async fn stream_stats(
+    user: auth::AuthenticatedUser,
+    path: web::Path<String>,
+    stream_manager: web::Data<Arc<Mutex<StreamManager>>>,
+    user_manager: web::Data<Arc<Mutex<UserManager>>>,
+) -> Result<HttpResponse, AppError> {
+    let stream_id = path.into_inner();
+    if !user_manager
+        .lock()
+        .unwrap()
+        .can_access(&user.username, &stream_id)
+    {
+        return Err(AppError::Forbidden("Channel not allowed".to_string()));
+    }
+
+    let stream_manager = stream_manager.lock().unwrap();
+    if !stream_manager.streams.contains_key(&stream_id) {
+        return Err(AppError::NotFound("Stream not found".to_string()));
+    }
+
+    let stats = stream_manager
+        .stream_stats
+        .get(&stream_id)
+        .map(|stats| stats.snapshot());
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "id": stream_id,
+        "active": stats.is_some(),
+        "stats": stats,
+    })))
+}
+
+// Current master playlist of an active stream and the upstream segments it was built
+// from, to diagnose representation selection and URL resolution remotely
+async fn channel_preview(
+    _user: auth::AdminUser,
+    path: web::Path<String>,
+    stream_manager: web::Data<Arc<Mutex<StreamManager>>>,
+    segments_config: web::Data<SegmentsTOML>,
+) -> Result<HttpResponse, AppError> {
+    let stream_id = path.into_inner();
+    let (converter, stream_dir) = {
+        let stream_manager = stream_manager.lock().unwrap();
+        if !stream_manager.streams.contains_key(&stream_id) {
+            return Err(AppError::NotFound("Stream not found".to_string()));
+        }
+        match stream_manager.active_streams.get(&stream_id) {
+            Some(converter) => (Arc::clone(converter), stream_manager.stream_dir(&stream_id)),
+            None => return Err(AppError::Conflict("Stream not active".to_string())),
+        }
+    };
+
+    // The converter stays locked while it processes a refresh
+    let (video_segments, audio_segments) = converter.lock().await.last_segment_urls();
+    let read_timeout = Duration::from_millis(segments_config.read_timeout_ms);
+    let master_playlist = read_within(read_timeout, move || {
+        fs::read_to_string(format!("{}/master.m3u8", stream_dir)).ok()
+    })
+    .await?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "id": stream_id,
+        "master_playlist": master_playlist,
+        "video_segments": video_segments,
+        "audio_segments": audio_segments,
+    })))
+}
+
+// The MPD of a channel as the origin serves it to the converter, with the URL it ended up
+// at in Content-Location. Origin errors are passed through with their status.
+async fn debug_mpd(
+    _user: auth::AdminUser,
+    path: web::Path<String>,
+    stream_manager: web::Data<Arc<Mutex<StreamManager>>>,
+) -> Result<HttpResponse, AppError> {
+    let stream_id = path.into_inner();
+    let (stream_info, timeout) = {
+        let stream_manager = stream_manager.lock().unwrap();
+        match stream_manager.streams.get(&stream_id) {
+            Some(info) => (
+                info.clone(),
+                Duration::from_secs(stream_manager.converter_config.mpd_timeout_secs),
+            ),
+            None => return Err(AppError::NotFound("Stream not found".to_string())),
+        }
+    };
+
+    let mpd = fetch_raw_mpd(&stream_info, timeout)
+        .await
+        .map_err(|e| AppError::BadGateway(format!("Failed to fetch MPD: {}", e)))?;
+
+    let status = actix_web::http::StatusCode::from_u16(mpd.status)
+        .map_err(|e| AppError::BadGateway(e.to_string()))?;
+    let content_type = if status.is_success() {
+        "application/dash+xml"
+    } else {
+        "text/plain"
+    };
+    Ok(HttpResponse::build(status)
+        .content_type(content_type)
+        .insert_header(("Content-Location", mpd.final_url))
+        .insert_header(("Cache-Control", "no-cache"))
+        .body(mpd.body))
+}
+
+// Proxies the logo of a channel, kept in memory for a while so the artwork host is only
+// reached once in a while whatever the number of clients
+async fn channel_logo(
+    user: auth::AuthenticatedUser,
+    path: web::Path<String>,
+    stream_manager: web::Data<Arc<Mutex<StreamManager>>>,
+    user_manager: web::Data<Arc<Mutex<UserManager>>>,
+    logo_cache: web::Data<LogoCache>,
+) -> Result<HttpResponse, AppError> {
+    let stream_id = path.into_inner();
+    if !user_manager
+        .lock()
+        .unwrap()
+        .can_access(&user.username, &stream_id)
+    {
+        return Err(AppError::Forbidden("Channel not allowed".to_string()));
+    }
+
+    let (url, timeout) = {
+        let stream_manager = stream_manager.lock().unwrap();
+        let Some(info) = stream_manager.streams.get(&stream_id) else {
+            return Err(AppError::NotFound("Stream not found".to_string()));
+        };
+        let Some(url) = info.logo_url.clone() else {
+            return Err(AppError::NotFound("Channel has no logo".to_string()));
+        };
+        (
+            url,
+            Duration::from_secs(stream_manager.converter_config.segment_timeout_secs),
+        )
+    };
+
+    let cached = logo_cache
+        .lock()
+        .unwrap()
+        .get(&stream_id)
+        .and_then(|cached| {
+            (cached.url == url && cached.fetched.elapsed() < Duration::from_secs(LOGO_CACHE_SECS))
+                .then(|| Arc::clone(&cached.logo))
+        });
+    let logo = match cached {
+        Some(logo) => logo,
+        None => {
+            let logo = fetch_logo(&url, timeout)
+                .await
+                .map_err(|e| AppError::BadGateway(format!("Failed to fetch logo: {}", e)))?;
+            let logo = Arc::new(logo);
+            logo_cache.lock().unwrap().insert(
+                stream_id,
+                CachedLogo {
+                    url,
+                    logo: Arc::clone(&logo),
+                    fetched: Instant::now(),
+                },
+            );
+            logo
+        }
+    };
+
+    Ok(HttpResponse::Ok()
+        .content_type(logo.content_type.as_str())
+        .insert_header((
+            "Cache-Control",
+            format!("private, max-age={}", LOGO_CACHE_SECS),
+        ))
+        .body(logo.bytes.clone()))
+}
+
+// Name, type, help and value of a per-stream Prometheus metric
+type StreamMetric = (
+    &'static str,
+    &'static str,
+    &'static str,
+    fn(&StatsSnapshot) -> u64,
+);
+
+async fn prometheus_metrics(
+    req: HttpRequest,
+    stream_manager: web::Data<Arc<Mutex<StreamManager>>>,
+    metrics_config: web::Data<MetricsTOML>,
+    health_config: web::Data<HealthTOML>,
+    ffmpeg_version: web::Data<FfmpegVersion>,
+) -> impl Responder {
+    // Scrapers don't log in, a token can be required instead
+    if let Some(token) = &metrics_config.token {
+        let authorized = req
+            .headers()
+            .get("Authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|value| auth::constant_time_eq(value.as_bytes(), token.as_bytes()));
+        if !authorized {
+            return HttpResponse::Unauthorized().body("Invalid metrics token");
+        }
+    }
+
+    let stream_manager = stream_manager.lock().unwrap();
+    let mut stats: Vec<(&String, StatsSnapshot)> = stream_manager
+        .stream_stats
+        .iter()
+        .map(|(id, stats)| (id, stats.snapshot()))
+        .collect();
+    stats.sort_by(|a, b| a.0.cmp(b.0));
+
+    let mut body = String::new();
+    body.push_str("# HELP dash_to_hls_active_streams Streams currently being converted\n");
+    body.push_str("# TYPE dash_to_hls_active_streams gauge\n");
+    body.push_str(&format!(
+        "dash_to_hls_active_streams {}\n",
+        stream_manager.active_streams.len()
+    ));
+    body.push_str(
+        "# HELP dash_to_hls_failing_ratio Share of the active streams /readyz counts as failing\n",
+    );
+    body.push_str("# TYPE dash_to_hls_failing_ratio gauge\n");
+    body.push_str(&format!(
+        "dash_to_hls_failing_ratio {}\n",
+        failing_streams(&stream_manager, &health_config).1
+    ));
+    body.push_str("# HELP dash_to_hls_ffmpeg_info ffmpeg version found at startup\n");
+    body.push_str("# TYPE dash_to_hls_ffmpeg_info gauge\n");
+    body.push_str(&format!(
+        "dash_to_hls_ffmpeg_info{{version=\"{}\"}} 1\n",
+        label_value(&ffmpeg_version.0)
+    ));
+
+    let per_stream: [StreamMetric; 9] = [
+        (
+            "dash_to_hls_segments_total",
+            "counter",
+            "Segments downloaded and handed to ffmpeg",
+            |s| s.segments_processed,
+        ),
+        (
+            "dash_to_hls_download_failures_total",
+            "counter",
+            "Origin downloads that failed after all retries",
+            |s| s.download_failures,
+        ),
+        (
+            "dash_to_hls_decrypt_failures_total",
+            "counter",
+            "Segments mp4decrypt could not decrypt",
+            |s| s.decrypt_failures,
+        ),
+        (
+            "dash_to_hls_skipped_segments_total",
+            "counter",
+            "Segments left out of the output because their download failed",
+            |s| s.segments_skipped,
+        ),
+        (
+            "dash_to_hls_pushed_bytes_total",
+            "counter",
+            "Bytes written to the ffmpeg inputs",
+            |s| s.bytes_pushed,
+        ),
+        (
+            "dash_to_hls_downloaded_bytes_total",
+            "counter",
+            "Bytes downloaded from the origin",
+            |s| s.bytes_downloaded,
+        ),
+        (
+            "dash_to_hls_ffmpeg_restarts_total",
+            "counter",
+            "Times ffmpeg was restarted",
+            |s| s.ffmpeg_restarts,
+        ),
+        (
+            "dash_to_hls_segment_lag",
+            "gauge",
+            "Segments of the last MPD refresh not handed to ffmpeg yet",
+            |s| s.backlog,
+        ),
+        (
+            "dash_to_hls_latency_milliseconds",
+            "gauge",
+            "How far the last pushed segment was behind the live edge of the MPD",
+            |s| s.latency_ms,
+        ),
+    ];
+
+    for (name, kind, help, value) in per_stream {
+        body.push_str(&format!(
+            "# HELP {} {}\n# TYPE {} {}\n",
+            name, help, name, kind
+        ));
+        for (stream_id, snapshot) in &stats {
+            body.push_str(&format!(
+                "{}{{stream=\"{}\"}} {}\n",
+                name,
+                label_value(stream_id),
+                value(snapshot)
+            ));
+        }
+    }
+
+    let name = "dash_to_hls_segment_size_bytes";
+    body.push_str(&format!(
+        "# HELP {} Sizes of the segments downloaded from the origin\n# TYPE {} histogram\n",
+        name, name
+    ));
+    for (stream_id, snapshot) in &stats {
+        for (track, sizes) in [
+            ("video", &snapshot.video_segment_sizes),
+            ("audio", &snapshot.audio_segment_sizes),
+        ] {
+            let labels = format!("stream=\"{}\",track=\"{}\"", label_value(stream_id), track);
+            for (bound, count) in SEGMENT_SIZE_BUCKETS.iter().zip(&sizes.buckets) {
+                body.push_str(&format!(
+                    "{}_bucket{{{},le=\"{}\"}} {}\n",
+                    name, labels, bound, count
+                ));
+            }
+            body.push_str(&format!(
+                "{}_bucket{{{},le=\"+Inf\"}} {}\n{}_sum{{{}}} {}\n{}_count{{{}}} {}\n",
+                name, labels, sizes.count, name, labels, sizes.sum, name, labels, sizes.count
+            ));
+        }
+    }
+
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(body)
+}
+
+// Escapes a Prometheus label value, which is written between double quotes
+fn label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+// Liveness: answering at all means the process is up
+async fn liveness() -> impl Responder {
+    HttpResponse::Ok().json(serde_json::json!({ "status": "ok" }))
+}
+
+// Build and tools the server runs with, for support requests
+async fn version(ffmpeg_version: web::Data<FfmpegVersion>) -> impl Responder {
+    HttpResponse::Ok().json(serde_json::json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "commit": env!("GIT_COMMIT"),
+        "ffmpeg": ffmpeg_version.0,
+        // Bento4 is linked in, so it's always there
+        "mp4decrypt": true,
+    }))
+}
+
+// Why ffmpeg couldn't be probed
+#[derive(Debug)]
+enum FfmpegProbeError {
+    NotFound,
+    Failed(String),
+}
+
+impl std::fmt::Display for FfmpegProbeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FfmpegProbeError::NotFound => f.write_str("ffmpeg not found"),
+            FfmpegProbeError::Failed(reason) => write!(f, "ffmpeg -version failed: {}", reason),
+        }
+    }
+}
+
+// ffmpeg version found at startup, reported by /metrics
+#[derive(Clone)]
+struct FfmpegVersion(String);
+
+// [server] public_url, the base of the links handed to players
+#[derive(Clone)]
+struct PublicUrl(Option<String>);
+
+// Runs `ffmpeg -version` and returns the version it announces, without ffmpeg no stream
+// can start
+fn probe_ffmpeg() -> Result<String, FfmpegProbeError> {
+    let output = match ffmpeg_command()
+        .arg("-version")
+        .stderr(std::process::Stdio::null())
+        .output()
+    {
+        Ok(output) => output,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Err(FfmpegProbeError::NotFound);
+        }
+        Err(e) => return Err(FfmpegProbeError::Failed(e.to_string())),
+    };
+    if !output.status.success() {
+        return Err(FfmpegProbeError::Failed(output.status.to_string()));
+    }
+
+    // First line reads like "ffmpeg version 6.1.1 Copyright (c) ..."
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .next()
+        .and_then(|line| line.strip_prefix("ffmpeg version "))
+        .and_then(|rest| rest.split_whitespace().next())
+        .map(str::to_string)
+        .ok_or_else(|| FfmpegProbeError::Failed("unrecognized version output".to_string()))
+}
+
+async fn readiness(
+    stream_manager: web::Data<Arc<Mutex<StreamManager>>>,
+    health_config: web::Data<HealthTOML>,
+    ffmpeg_version: web::Data<FfmpegVersion>,
+) -> impl Responder {
+    let stream_manager = stream_manager.lock().unwrap();
+    let active = stream_manager.active_streams.len();
+    let (failing, failing_ratio) = failing_streams(&stream_manager, &health_config);
+
+    // ffmpeg was found at startup, which refuses to go on without it
+    let body = serde_json::json!({
+        "ffmpeg": true,
+        "ffmpeg_version": ffmpeg_version.0,
+        "config_loaded": stream_manager.config_loaded,
+        "active_streams": active,
+        "failing_streams": failing,
+        "failing_ratio": failing_ratio,
+    });
+
+    // Too many broken streams means the node itself is likely unhealthy
+    if !stream_manager.config_loaded || failing_ratio > health_config.max_failing_ratio {
+        HttpResponse::ServiceUnavailable().json(body)
+    } else {
+        HttpResponse::Ok().json(body)
+    }
+}
+
+// Streams failing their refreshes or lagging past max_latency_secs, and their share of the
+// active streams
+fn failing_streams(stream_manager: &StreamManager, health_config: &HealthTOML) -> (usize, f64) {
+    let active = stream_manager.active_streams.len();
+    let max_latency = Duration::from_secs(health_config.max_latency_secs);
+    let lagging =
+        |stats: &StreamStats| health_config.max_latency_secs > 0 && stats.latency() > max_latency;
+    let failing = stream_manager
+        .stream_stats
+        .values()
+        .filter(|stats| {
+            stats.consecutive_failures() >= health_config.failing_after || lagging(stats)
+        })
+        .count();
+    let failing_ratio = if active == 0 {
+        0.0
+    } else {
+        failing as f64 / active as f64
+    };
+    (failing, failing_ratio)
+}
+
+// Merges a reloaded channels.toml: new channels are added, changed ones updated and removed
+// ones dropped. Running converters of removed channels, or whose origin or keys changed, are
+// stopped so the next start picks up the new settings.
+fn reload_channels(manager: &mut StreamManager, channels_config: ChannelConfig) -> Vec<Halted> {
+    let mut halted = Vec::new();
+    // Channels added at runtime aren't in the file, they stay until deleted
+    let mut removed: Vec<String> = manager
+        .streams
+        .keys()
+        .filter(|id| !manager.runtime_channels.contains(*id))
+        .cloned()
+        .collect();
+
+    for channel in channels_config.channel {
+        removed.retain(|id| *id != channel.id);
+        // Added to the file as well, which now defines it
+        manager.runtime_channels.remove(&channel.id);
+        let stream_info = StreamInfo::from_channel(channel, &manager.languages);
+
+        if let Some(previous) = manager.streams.get(&stream_info.id)
+            && (previous.url != stream_info.url || previous.keys != stream_info.keys)
+            && (manager.active_streams.contains_key(&stream_info.id)
+                || manager.kept_outputs.contains(&stream_info.id))
+        {
+            info!("Channel {} changed, stopping its stream", stream_info.id);
+            halted.extend(manager.stop_stream(&stream_info.id, "channel changed"));
+        }
+        manager.streams.insert(stream_info.id.clone(), stream_info);
+    }
+
+    for stream_id in removed {
+        info!("Channel {} removed from config", stream_id);
+        manager.streams.remove(&stream_id);
+        halted.extend(manager.stop_stream(&stream_id, "channel removed"));
+    }
+    halted
+}
+
+// Reloads channels.toml and users.toml whenever they change on disk. A file that fails to
+// parse is logged and the config in use is kept.
+fn start_config_watcher(
+    deserializer: Deserializer,
+    stream_manager: &Arc<Mutex<StreamManager>>,
+    user_manager: &Arc<Mutex<UserManager>>,
+) -> anyhow::Result<()> {
+    let stream_manager_clone = Arc::clone(stream_manager);
+    let user_manager_clone = Arc::clone(user_manager);
+    let channels_path = fs::canonicalize(deserializer.channels_path())?;
+    let users_path = fs::canonicalize(deserializer.users_path())?;
+
+    let (sender, receiver) = std::sync::mpsc::channel();
+    let mut watcher = notify::recommended_watcher(sender)?;
+    // Editors often replace files instead of writing them, so the directories are watched
+    for dir in [&channels_path, &users_path]
+        .iter()
+        .filter_map(|p| p.parent())
+    {
+        watcher.watch(dir, RecursiveMode::NonRecursive)?;
+    }
+
+    thread::spawn(move || {
+        // Owned by the thread so it keeps watching
+        let _watcher = watcher;
+
+        while let Ok(event) = receiver.recv() {
+            let mut paths: Vec<PathBuf> = Vec::new();
+            // Let the write settle and coalesce the burst of events it produced
+            thread::sleep(Duration::from_millis(500));
+            for event in std::iter::once(event).chain(receiver.try_iter()) {
+                match event {
+                    Ok(event) if !event.kind.is_access() => paths.extend(event.paths),
+                    Ok(_) => {}
+                    Err(e) => error!("Config watcher error: {}", e),
+                }
+            }
+
+            if paths.contains(&channels_path) {
+                match deserializer.load_channels() {
+                    Ok(channels_config) => {
+                        info!("Reloading {}", channels_path.display());
+                        let halted = {
+                            let mut manager = stream_manager_clone.lock().unwrap();
+                            manager.config_loaded = true;
+                            reload_channels(&mut manager, channels_config)
+                        };
+                        finish_halted(&stream_manager_clone, halted);
+                    }
+                    Err(e) => {
+                        error!("Keeping the current channels: {}", e);
+                        stream_manager_clone.lock().unwrap().config_loaded = false;
+                    }
+                }
+            }
+            if paths.contains(&users_path) {
+                match deserializer.load_users() {
+                    Ok(users_config) => {
+                        info!("Reloading {}", users_path.display());
+                        *user_manager_clone.lock().unwrap() =
+                            UserManager::from_config(users_config);
+                    }
+                    Err(e) => error!("Keeping the current users: {}", e),
+                }
+            }
+        }
+    });
+
+    Ok(())
+}
+
+// Cross-origin players need explicit origins to send the auth cookie, browsers refuse
+// credentials along a wildcard. Mismatching origins just get no CORS headers.
+fn cors(cors_config: &CorsTOML) -> Cors {
+    let cors = Cors::default()
+        .allow_any_method()
+        .allow_any_header()
+        .block_on_origin_mismatch(false)
+        .max_age(3600);
+
+    if cors_config
+        .allowed_origins
+        .iter()
+        .any(|origin| origin == "*")
+    {
+        return cors.allow_any_origin();
+    }
+
+    cors_config
+        .allowed_origins
+        .iter()
+        .fold(cors, |cors, origin| cors.allowed_origin(origin))
+        .supports_credentials()
+}
+
+fn start_cleanup_thread(
+    cleanup: &CleanupTOML,
+    stream_manager: &Arc<Mutex<StreamManager>>,
+) -> anyhow::Result<()> {
+    let stream_manager_clone = Arc::clone(stream_manager);
+    let timeout = Duration::from_secs(cleanup.idle_timeout_secs);
+    let interval = Duration::from_secs(cleanup.interval_secs);
+    let stall_timeout = Duration::from_secs(cleanup.stall_timeout_secs);
+    let stall_action = cleanup.stall_action;
+    // Restarted converters run on the main runtime, this thread has none
+    let arbiter = actix_web::rt::System::current().arbiter().clone();
+
+    thread::spawn(move || {
+        // Stalled streams already warned about, so each stall is logged once
+        let mut stalls_logged: HashSet<String> = HashSet::new();
+
+        loop {
+            thread::sleep(interval);
+
+            // Eviction reads and removes files, the manager isn't locked meanwhile
+            let snapshot = {
+                let manager = stream_manager_clone.lock().unwrap();
+                let output_root = manager.output_root.clone();
+                manager
+                    .disk_cache
+                    .snapshot()
+                    .map(|cache| (cache, output_root))
+            };
+            if let Some((cache, output_root)) = snapshot {
+                let (_, gone) = cache.enforce(&output_root);
+                stream_manager_clone.lock().unwrap().disk_cache.forget(gone);
+            }
+
+            let mut manager = stream_manager_clone.lock().unwrap();
+            let now = Instant::now();
+
+            // Viewers decay once they stop fetching playlists
+            let viewer_window = manager.viewer_window;
+            for clients in manager.viewers.values_mut() {
+                clients.retain(|_, seen| now.duration_since(*seen) <= viewer_window);
+            }
+
+            // Streams being watched are left alone, only idle ones without viewers go
+            let mut to_remove = collect_idle_streams(now, timeout, &manager.last_access);
+            to_remove.retain(|stream_id| manager.viewer_count(stream_id) == 0);
+
+            // Converters are stopped once the manager is unlocked again
+            let mut halted = Vec::new();
+            for stream_id in to_remove {
+                info!("Shutting down idle stream: {}", stream_id);
+                halted.extend(manager.stop_idle_stream(&stream_id));
+            }
+
+            // Converters that gave up after repeated failures release their stream
+            let failed: Vec<(String, String)> = manager
+                .stream_stats
+                .iter()
+                .filter(|(_, stats)| stats.has_failed())
+                .map(|(stream_id, stats)| {
+                    (stream_id.clone(), stats.last_error().unwrap_or_default())
+                })
+                .collect();
+            for (stream_id, reason) in failed {
+                warn!("Releasing failed stream {}: {}", stream_id, reason);
+                halted.extend(manager.stop_stream(&stream_id, "failed"));
+                manager.failures.insert(stream_id, reason);
+            }
+
+            let stalled = if stall_timeout.is_zero() {
+                Vec::new()
+            } else {
+                collect_stalled_streams(stall_timeout, &manager.stream_stats)
+            };
+            // Stalled streams started over once stopped, keeping their idle clock
+            let mut restarts = Vec::new();
+            stalls_logged.retain(|stream_id| stalled.iter().any(|(id, _)| id == stream_id));
+            for (stream_id, stalled_for) in stalled {
+                if stalls_logged.insert(stream_id.clone()) || stall_action != StallAction::Warn {
+                    warn!(
+                        "Stream {} pushed no segment for {}s",
+                        stream_id,
+                        stalled_for.as_secs()
+                    );
+                }
+                match stall_action {
+                    StallAction::Warn => {}
+                    StallAction::Stop => halted.extend(manager.stop_stream(&stream_id, "stalled")),
+                    StallAction::Restart => {
+                        let Some(info) = manager.streams.get(&stream_id).cloned() else {
+                            continue;
+                        };
+                        let last_access = manager.last_access.get(&stream_id).copied();
+                        halted.extend(manager.stop_stream(&stream_id, "stalled"));
+                        restarts.push((info, last_access));
+                    }
+                }
+            }
+
+            drop(manager);
+            finish_halted(&stream_manager_clone, halted);
+            for (info, last_access) in restarts {
+                let mut manager = stream_manager_clone.lock().unwrap();
+                let stream_id = info.id.clone();
+                match manager.start_converter(info, None) {
+                    Ok(converter) => {
+                        if let Some(last_access) = last_access {
+                            manager.last_access.insert(stream_id, last_access);
+                        }
+                        arbiter.spawn(run_converter(converter));
+                    }
+                    Err(e) => error!("Could not restart stream {}: {}", stream_id, e),
+                }
+            }
+        }
+    });
+
+    Ok(())
+}
+
+// Running live streams that pushed no segment for longer than the timeout, with how long
+fn collect_stalled_streams(
+    timeout: Duration,
+    stream_stats: &HashMap<String, Arc<StreamStats>>,
+) -> Vec<(String, Duration)> {
+    stream_stats
+        .iter()
+        .filter_map(|(stream_id, stats)| {
+            let stalled_for = stats.stalled_for()?;
+            is_stalled(stalled_for, timeout).then(|| (stream_id.clone(), stalled_for))
+        })
+        .collect()
+}
+
+fn is_stalled(stalled_for: Duration, timeout: Duration) -> bool {
+    stalled_for > timeout
+}
+
+// Streams not accessed for longer than the timeout
+fn collect_idle_streams(
+    now: Instant,
+    timeout: Duration,
+    last_access: &HashMap<String, Instant>,
+) -> Vec<String> {
+    last_access
+        .iter()
+        .filter(|(_, last_time)| now.duration_since(**last_time) > timeout)
+        .map(|(stream_id, _)| stream_id.clone())
+        .collect()
+}
+
+// Seconds each channel's MPD gets to answer the startup check
+const CHANNEL_CHECK_TIMEOUT_SECS: u64 = 5;
+
+// Logs whether each channel's MPD can be fetched and has something to convert, returning
+// how many do
+async fn check_channels(streams: &[StreamInfo]) -> usize {
+    let timeout = Duration::from_secs(CHANNEL_CHECK_TIMEOUT_SECS);
+    let mut usable = 0;
+
+    for stream_info in streams {
+        match check_channel(stream_info, timeout).await {
+            Ok(check) if check.usable() => {
+                info!(
+                    "Channel {} is usable: {} video and {} audio representations",
+                    stream_info.id, check.video, check.audio
+                );
+                usable += 1;
+            }
+            Ok(_) => warn!(
+                "Channel {} has no video or audio representations",
+                stream_info.id
+            ),
+            Err(e) => error!("Channel {} is unreachable: {}", stream_info.id, e),
+        }
+    }
+
+    info!("{} of {} channels usable", usable, streams.len());
+    usable
+}
+
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    env_logger::init_from_env(env_logger::Env::default().default_filter_or("info"));
+
+    // `--inspect <mpd-url>` describes an MPD and exits without starting the server
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("--inspect") {
+        let Some(url) = args.get(2).cloned() else {
+            return Err(std::io::Error::other("Usage: --inspect <mpd-url>"));
+        };
+
+        return match web::block(move || inspect_mpd(&url).map_err(|e| e.to_string())).await {
+            Ok(Ok(table)) => {
+                print!("{}", table);
+                Ok(())
+            }
+            Ok(Err(e)) => Err(std::io::Error::other(format!("Inspection failed: {}", e))),
+            Err(e) => Err(std::io::Error::other(e)),
+        };
+    }
+
+    info!("Starting DASH to HLS converter service");
+
+    // Config files are read from `--config-dir=<dir>`, else CONFIG_DIR, else the working
+    // directory
+    let config_dir = args
+        .iter()
+        .find_map(|arg| arg.strip_prefix("--config-dir="))
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("CONFIG_DIR").map(PathBuf::from))
+        .unwrap_or_default();
+
+    // Load configuration
+    let deserializer = Deserializer::from_dir(&config_dir);
+
+    // Load settings
+    let settings = match deserializer.load_settings() {
+        Ok(settings) => settings,
+        Err(e) => {
+            error!("Error reading {}: {}", deserializer.settings_path(), e);
+            return Err(std::io::Error::other(format!("Invalid settings: {}", e)));
+        }
+    };
+
+    if let Err(e) = auth::check_secrets() {
+        error!("{}", e);
+        return Err(std::io::Error::other(e));
+    }
+
+    // Better to refuse starting than to have every stream fail later on
+    set_ffmpeg_path(&settings.converter.ffmpeg_path);
+    let ffmpeg_version = match web::block(probe_ffmpeg).await {
+        Ok(Ok(version)) => {
+            info!("Using ffmpeg {}", version);
+            FfmpegVersion(version)
+        }
+        Ok(Err(e)) => {
+            error!(
+                "{}, install ffmpeg or point ffmpeg_path (FFMPEG_PATH) at it",
+                e
+            );
+            return Err(std::io::Error::other(e.to_string()));
+        }
+        Err(e) => return Err(std::io::Error::other(e)),
+    };
+
+    // Load channels
+    let channels_config = match deserializer.load_channels() {
+        Ok(channels) => channels,
+        Err(e) => {
+            error!("Error reading {}: {}", deserializer.channels_path(), e);
+            panic!("Can't continue without channels!");
+        }
+    };
+
+    let streams: HashMap<String, StreamInfo> = channels_config
+        .channel
+        .into_iter()
+        .map(|channel| {
+            (
+                channel.id.clone(),
+                StreamInfo::from_channel(channel, &settings.languages),
+            )
+        })
+        .collect();
+
+    // `--check-channels` tries every channel's MPD before serving, `--check-channels=strict`
+    // also refuses to start when none of them works
+    let check_mode = args
+        .iter()
+        .find_map(|arg| arg.strip_prefix("--check-channels"));
+    if let Some(mode) = check_mode {
+        let infos: Vec<StreamInfo> = streams.values().cloned().collect();
+        let usable = check_channels(&infos).await;
+        if mode == "=strict" && usable == 0 && !streams.is_empty() {
+            error!("No channel is usable, refusing to start");
+            return Err(std::io::Error::other("Every channel failed its check"));
+        }
+    }
+
+    // Initialize stream manager
+    let stream_manager = Arc::new(Mutex::new(StreamManager {
+        streams,
+        languages: settings.languages.clone(),
+        runtime_channels: HashSet::new(),
+        active_streams: HashMap::new(),
+        kept_outputs: HashSet::new(),
+        stream_stats: HashMap::new(),
+        program_date_times: HashMap::new(),
+        last_access: HashMap::new(),
+        viewers: HashMap::new(),
+        viewer_window: Duration::from_secs(settings.cleanup.viewer_window_secs),
+        converter_config: settings.converter,
+        request_id_config: settings.request_id.clone(),
+        config_loaded: true,
+        events: events::channel(),
+        output_root: settings.server.output_root.clone(),
+        disk_cache: DiskCache::new(settings.cleanup.max_output_bytes),
+        failures: HashMap::new(),
+        halting: HashSet::new(),
     }));
 
+    let health_config = settings.health;
+    let segments_config = settings.segments;
+    let metrics_config = settings.metrics;
+    let cors_config = settings.cors.clone();
+    let auth_config = settings.auth.clone();
+    let logo_cache = web::Data::new(LogoCache::default());
+    let audit_log = match AuditLog::open(&settings.audit) {
+        Ok(audit_log) => web::Data::new(audit_log),
+        Err(e) => {
+            error!("Error opening the audit file: {}", e);
+            return Err(e);
+        }
+    };
+
     // Load users
     let users_config = match deserializer.load_users() {
         Ok(users) => users,
         Err(e) => {
-            error!("Error reading users.toml: {}", e);
+            error!("Error reading {}: {}", deserializer.users_path(), e);
             panic!("Can't continue without users!");
         }
     };
 
     // Initialize user manager
-    let user_manager = Arc::new(Mutex::new(UserManager {
-        users: {
-            let mut map = HashMap::new();
-            for user in users_config.user {
-                map.insert(user.username, user.password);
-            }
-            map
-        },
-    }));
+    let user_manager = Arc::new(Mutex::new(UserManager::from_config(users_config)));
 
     // Create output directory
-    fs::create_dir_all("./streams").unwrap_or(());
+    fs::create_dir_all(&settings.server.output_root).unwrap_or(());
 
-    // Printing local address to open link from localhost (the server actually listens from all
-    // sources)
-    info!("Starting server on http://127.0.0.1:8080");
+    // Printing local address to open link from localhost (the server actually listens on the
+    // configured address)
+    let server_config = settings.server.clone();
+    info!(
+        "Starting server on http://127.0.0.1:{} ({} workers)",
+        server_config.port, server_config.workers
+    );
+
+    info!("Watching config files for changes");
+    if let Err(e) = start_config_watcher(deserializer.clone(), &stream_manager, &user_manager) {
+        error!("Error starting config watcher: {}", e);
+    }
 
     info!("Starting cleanup task");
-    if let Err(e) = start_cleanup_thread(120, &stream_manager) {
+    if let Err(e) = start_cleanup_thread(&settings.cleanup, &stream_manager) {
         error!("Error starting cleanup task: {}", e);
     }
 
+    let request_id_header = match HeaderName::try_from(settings.request_id.header.as_str()) {
+        Ok(header) => header,
+        Err(e) => {
+            error!("Invalid request id header: {}", e);
+            panic!("Can't continue with an invalid request id header!");
+        }
+    };
+
+    let access_log_format = settings.access_log.format;
+    let public_url = PublicUrl(settings.server.public_url.clone());
+
     // Start the web server
     HttpServer::new(move || {
+        let request_id_header = request_id_header.clone();
+        let request_id_log_header = request_id_header.clone();
+
         App::new()
+            .wrap_fn(move |req, srv| {
+                let request_id = request_id::assign(&req, &request_id_header);
+                let header = request_id_header.clone();
+                let response = request_id::scope(&request_id, srv.call(req));
+
+                async move {
+                    let mut response = response.await?;
+                    if let Ok(value) = HeaderValue::from_str(&request_id.0) {
+                        response.headers_mut().insert(header, value);
+                    }
+                    Ok(response)
+                }
+            })
+            .wrap(cors(&cors_config))
+            .wrap_fn(move |req, srv| {
+                let started = Instant::now();
+                let peer = req
+                    .connection_info()
+                    .realip_remote_addr()
+                    .map(str::to_string);
+                let method = req.method().to_string();
+                let path = req.path().to_string();
+                let request_id_header = request_id_log_header.clone();
+                let response = srv.call(req);
+
+                async move {
+                    let response = response.await;
+                    let status = match &response {
+                        Ok(response) => response.status(),
+                        Err(e) => e.as_response_error().status_code(),
+                    };
+                    let user = response
+                        .as_ref()
+                        .ok()
+                        .and_then(|response| auth::request_user(response.request()));
+                    let request_id = response.as_ref().ok().and_then(|response| {
+                        response
+                            .headers()
+                            .get(&request_id_header)
+                            .and_then(|value| value.to_str().ok())
+                            .map(str::to_string)
+                    });
+
+                    let entry = access_log::AccessEntry {
+                        peer: peer.as_deref(),
+                        method: &method,
+                        path: &path,
+                        status: status.as_u16(),
+                        latency: started.elapsed(),
+                        user: user.as_deref(),
+                        request_id: request_id.as_deref(),
+                    };
+                    info!(target: "access", "{}", entry.format(access_log_format));
+                    response
+                }
+            })
             .app_data(web::Data::new(stream_manager.clone()))
             .app_data(web::Data::new(user_manager.clone()))
+            .app_data(web::Data::new(health_config.clone()))
+            .app_data(web::Data::new(segments_config.clone()))
+            .app_data(web::Data::new(metrics_config.clone()))
+            .app_data(web::Data::new(auth_config.clone()))
+            .app_data(web::Data::new(ffmpeg_version.clone()))
+            .app_data(web::Data::new(public_url.clone()))
+            .app_data(logo_cache.clone())
+            .app_data(audit_log.clone())
             .route("/login", web::post().to(login))
-            .route("/init/{stream_id}", web::get().to(initialize_stream))
+            .route("/refresh", web::post().to(refresh))
+            .route("/media-token", web::post().to(media_token))
+            .service(
+                web::resource("/init/{stream_id}")
+                    .wrap(from_fn(negotiate_errors))
+                    .route(web::get().to(initialize_stream)),
+            )
+            .route("/init", web::post().to(initialize_streams))
+            .route("/stop/{stream_id}", web::post().to(stop_stream))
             .route("/status", web::get().to(stream_status))
-            .route("/details/{stream_id}", web::get().to(stream_details))
+            .route("/ws/status", web::get().to(status_events))
+            .service(
+                web::resource("/details/{stream_id}")
+                    .wrap(from_fn(negotiate_errors))
+                    .route(web::get().to(stream_details)),
+            )
+            .route("/stats/{stream_id}", web::get().to(stream_stats))
+            .route("/errors/{stream_id}", web::get().to(stream_errors))
+            .route("/audit", web::get().to(audit_entries))
             .route("/channels", web::get().to(list_channels))
+            .route("/playlist.m3u", web::get().to(channels_playlist))
+            .route("/channels", web::post().to(add_channel))
+            .route("/channels/{stream_id}", web::delete().to(remove_channel))
+            .route("/channels/{stream_id}/logo", web::get().to(channel_logo))
             .route(
-                "/streams/{stream_id}/{file_path:.*}",
-                web::get().to(proxy_stream),
+                "/channels/{stream_id}/preview",
+                web::get().to(channel_preview),
+            )
+            .route("/debug/mpd/{stream_id}", web::get().to(debug_mpd))
+            .route("/healthz", web::get().to(liveness))
+            .route("/version", web::get().to(version))
+            .route("/readyz", web::get().to(readiness))
+            .route("/metrics", web::get().to(prometheus_metrics))
+            .route("/admin/metrics/{stream_id}", web::get().to(channel_metrics))
+            .service(
+                web::resource("/streams/{stream_id}/{file_path:.*}")
+                    .wrap(from_fn(negotiate_errors))
+                    .route(web::get().to(proxy_stream)),
             )
             .service(Files::new("/", "./static").index_file("index.html"))
     })
-    .bind("[::]:8080")?
-    .workers(4)
+    .bind((server_config.bind_addr.as_str(), server_config.port))?
+    .workers(server_config.workers)
     .run()
     .await
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_manager() -> StreamManager {
+        StreamManager {
+            streams: HashMap::new(),
+            languages: HashMap::new(),
+            runtime_channels: HashSet::new(),
+            active_streams: HashMap::new(),
+            stream_stats: HashMap::new(),
+            program_date_times: HashMap::new(),
+            last_access: HashMap::new(),
+            kept_outputs: HashSet::new(),
+            viewers: HashMap::new(),
+            viewer_window: Duration::from_secs(60),
+            converter_config: ConverterTOML::default(),
+            request_id_config: RequestIdTOML::default(),
+            config_loaded: true,
+            events: events::channel(),
+            output_root: "/nonexistent".to_string(),
+            disk_cache: DiskCache::new(0),
+            failures: HashMap::new(),
+            halting: HashSet::new(),
+        }
+    }
+
+    fn user_manager(users: &str) -> UserManager {
+        UserManager::from_config(toml::from_str(users).unwrap())
+    }
+
+    #[test]
+    fn can_access_follows_the_channel_allow_list() {
+        let users = user_manager(
+            r#"
+            [[user]]
+            username = "kid"
+            password = "p"
+            channels = ["cartoons", "nature"]
+            "#,
+        );
+        assert!(users.can_access("kid", "cartoons"));
+        assert!(users.can_access("kid", "nature"));
+        assert!(!users.can_access("kid", "news"));
+    }
+
+    #[test]
+    fn can_access_every_channel_without_an_allow_list() {
+        let users = user_manager(
+            r#"
+            [[user]]
+            username = "absent"
+            password = "p"
+
+            [[user]]
+            username = "empty"
+            password = "p"
+            channels = []
+            "#,
+        );
+        assert!(users.can_access("absent", "news"));
+        assert!(users.can_access("empty", "news"));
+    }
+
+    fn channels(ids_and_urls: &[(&str, &str)]) -> ChannelConfig {
+        let channels: String = ids_and_urls
+            .iter()
+            .map(|(id, url)| {
+                format!("[[channel]]\nid = \"{id}\"\nname = \"{id}\"\nurl = \"{url}\"\n")
+            })
+            .collect();
+        toml::from_str(&channels).unwrap()
+    }
+
+    #[test]
+    fn channel_language_names_override_the_global_ones() {
+        let global = HashMap::from([
+            ("spa".to_string(), "Spanish".to_string()),
+            ("fra".to_string(), "Français".to_string()),
+        ]);
+        let channel: ChannelConfig = toml::from_str(
+            r#"
+            [[channel]]
+            id = "news"
+            name = "News"
+            url = "http://o/news.mpd"
+            languages = { spa = "Español", cat = "Català" }
+            "#,
+        )
+        .unwrap();
+
+        let stream_info =
+            StreamInfo::from_channel(channel.channel.into_iter().next().unwrap(), &global);
+        let mut languages: Vec<(&str, &str)> = stream_info
+            .languages
+            .iter()
+            .map(|(code, name)| (code.as_str(), name.as_str()))
+            .collect();
+        languages.sort();
+        assert_eq!(
+            languages,
+            [("cat", "Català"), ("fra", "Français"), ("spa", "Español")]
+        );
+    }
+
+    #[test]
+    fn reload_channels_adds_updates_and_removes() {
+        let mut manager = test_manager();
+        reload_channels(
+            &mut manager,
+            channels(&[("a", "http://o/a.mpd"), ("b", "http://o/b.mpd")]),
+        );
+        reload_channels(
+            &mut manager,
+            channels(&[("a", "http://o/a2.mpd"), ("c", "http://o/c.mpd")]),
+        );
+
+        let mut ids: Vec<&String> = manager.streams.keys().collect();
+        ids.sort();
+        assert_eq!(ids, ["a", "c"]);
+        assert_eq!(manager.streams["a"].url, "http://o/a2.mpd");
+    }
+
+    #[test]
+    fn reload_channels_keeps_channels_added_at_runtime() {
+        let mut manager = test_manager();
+        reload_channels(&mut manager, channels(&[("a", "http://o/a.mpd")]));
+        let runtime = channels(&[("live", "http://o/live.mpd")]).channel.remove(0);
+        let stream_info = StreamInfo::from_channel(runtime, &manager.languages);
+        manager.runtime_channels.insert(stream_info.id.clone());
+        manager.streams.insert(stream_info.id.clone(), stream_info);
+
+        reload_channels(&mut manager, channels(&[("b", "http://o/b.mpd")]));
+        let mut ids: Vec<&String> = manager.streams.keys().collect();
+        ids.sort();
+        assert_eq!(ids, ["b", "live"]);
+    }
+
+    #[test]
+    fn collect_idle_streams_past_the_timeout() {
+        let now = Instant::now();
+        let last_access = HashMap::from([
+            ("idle".to_string(), now - Duration::from_secs(121)),
+            ("watched".to_string(), now - Duration::from_secs(30)),
+            ("on_the_edge".to_string(), now - Duration::from_secs(120)),
+        ]);
+        let idle = collect_idle_streams(now, Duration::from_secs(120), &last_access);
+        assert_eq!(idle, vec!["idle".to_string()]);
+    }
+
+    #[test]
+    fn is_stalled_only_past_the_timeout() {
+        let timeout = Duration::from_secs(60);
+        assert!(!is_stalled(Duration::from_secs(59), timeout));
+        assert!(!is_stalled(timeout, timeout));
+        assert!(is_stalled(Duration::from_secs(61), timeout));
+    }
+
+    #[actix_web::test]
+    async fn read_within_times_out_on_a_stalled_read() {
+        let read = read_within(Duration::from_millis(50), || {
+            std::thread::sleep(Duration::from_millis(500));
+        });
+        assert!(matches!(read.await, Err(AppError::GatewayTimeout(_))));
+
+        assert_eq!(
+            read_within(Duration::from_secs(5), || 42).await.unwrap(),
+            42
+        );
+    }
+
+    #[test]
+    fn append_media_token_to_uri_lines_and_attributes() {
+        let playlist = "#EXTM3U\n\
+            #EXT-X-MAP:URI=\"init.mp4\"\n\
+            #EXTINF:4.0,\n\
+            segment_1.m4s\n\
+            #EXT-X-KEY:METHOD=AES-128,URI=\"key.bin?v=2\",IV=0x1\n\
+            segment_2.m4s\n";
+        assert_eq!(
+            append_media_token(playlist, "abc"),
+            "#EXTM3U\n\
+            #EXT-X-MAP:URI=\"init.mp4?token=abc\"\n\
+            #EXTINF:4.0,\n\
+            segment_1.m4s?token=abc\n\
+            #EXT-X-KEY:METHOD=AES-128,URI=\"key.bin?v=2&token=abc\",IV=0x1\n\
+            segment_2.m4s?token=abc\n"
+        );
+    }
+
+    #[test]
+    fn append_media_token_leaves_blank_lines_and_tags() {
+        assert_eq!(
+            append_media_token("#EXTM3U\n\n#EXT-X-ENDLIST", "t"),
+            "#EXTM3U\n\n#EXT-X-ENDLIST\n"
+        );
+    }
+
+    #[test]
+    fn resolve_in_dir_only_takes_names_below_the_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir_path = dir.path().to_str().unwrap();
+
+        assert_eq!(
+            resolve_in_dir(dir_path, "segment_001.ts").unwrap(),
+            dir.path().join("segment_001.ts")
+        );
+        for file_path in ["", "../other/index.m3u8", "a/../../x", "/etc/passwd", "./x"] {
+            assert!(
+                matches!(
+                    resolve_in_dir(dir_path, file_path),
+                    Err(AppError::BadRequest(_))
+                ),
+                "{} was accepted",
+                file_path
+            );
+        }
+    }
+
+    #[test]
+    fn resolve_in_dir_rejects_links_leaving_the_directory() {
+        let outside = tempfile::tempdir().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        std::os::unix::fs::symlink(outside.path(), dir.path().join("link")).unwrap();
+        fs::write(outside.path().join("secret"), "x").unwrap();
+
+        let dir_path = dir.path().to_str().unwrap();
+        assert!(resolve_in_dir(dir_path, "link/secret").is_err());
+    }
+
+    #[test]
+    fn init_streams_reports_each_outcome_and_registers_only_started_streams() {
+        let output_root = tempfile::tempdir().unwrap();
+        let mut manager = test_manager();
+        manager.output_root = output_root.path().to_str().unwrap().to_string();
+        reload_channels(
+            &mut manager,
+            channels(&[
+                ("kept", "http://o/kept.mpd"),
+                ("stopping", "http://o/stopping.mpd"),
+                ("broken", "http://o/broken.mpd"),
+                ("private", "http://o/private.mpd"),
+            ]),
+        );
+        manager.streams.get_mut("kept").unwrap().persist_output = true;
+        fs::create_dir_all(output_root.path().join("kept")).unwrap();
+        fs::write(output_root.path().join("kept").join(COMPLETE_MARKER), "").unwrap();
+        manager.halting.insert("stopping".to_string());
+        // A file is in the way of its output directory
+        fs::write(output_root.path().join("broken"), "").unwrap();
+
+        let names = ["kept", "stopping", "broken", "private", "unknown"];
+        let results = init_streams(
+            &mut manager,
+            names.iter().map(|name| name.to_string()).collect(),
+            |name| name != "private",
+            "req-1",
+            5,
+        );
+
+        let status = |name: &str| {
+            let result = &results[name];
+            (
+                result["status"].as_str().unwrap(),
+                result.get("code").and_then(|code| code.as_str()),
+            )
+        };
+        assert_eq!(status("kept"), ("kept", None));
+        assert_eq!(status("stopping"), ("error", Some("unavailable")));
+        assert_eq!(status("broken"), ("error", Some("conflict")));
+        assert_eq!(status("private"), ("error", Some("forbidden")));
+        assert_eq!(status("unknown"), ("error", Some("not_found")));
+
+        // The failures left nothing behind
+        assert!(manager.active_streams.is_empty());
+        assert!(manager.stream_stats.is_empty());
+        assert!(manager.kept_outputs.contains("kept"));
+    }
+
+    use actix_web::body::BoxBody;
+    use actix_web::dev::{ServiceFactory, ServiceRequest, ServiceResponse};
+    use actix_web::http::StatusCode;
+    use actix_web::test::{self, TestRequest};
+    use base64::Engine;
+
+    fn basic(credentials: &str) -> (&'static str, String) {
+        let encoded = base64::engine::general_purpose::STANDARD.encode(credentials);
+        ("Authorization", format!("Basic {}", encoded))
+    }
+
+    fn shared(manager: StreamManager) -> Arc<Mutex<StreamManager>> {
+        Arc::new(Mutex::new(manager))
+    }
+
+    // The routes under test, with users authenticated by Basic credentials so no token
+    // secret is needed
+    fn app(
+        manager: Arc<Mutex<StreamManager>>,
+        segments_config: SegmentsTOML,
+    ) -> App<
+        impl ServiceFactory<
+            ServiceRequest,
+            Config = (),
+            Response = ServiceResponse<BoxBody>,
+            Error = actix_web::Error,
+            InitError = (),
+        >,
+    > {
+        let users = user_manager(
+            r#"
+            [[user]]
+            username = "root"
+            password = "toor"
+            role = "admin"
+
+            [[user]]
+            username = "kid"
+            password = "p"
+            channels = ["cartoons"]
+            "#,
+        );
+        App::new()
+            .wrap_fn(|req, srv| {
+                request_id::assign(&req, &HeaderName::from_static("x-request-id"));
+                srv.call(req)
+            })
+            .app_data(web::Data::new(manager))
+            .app_data(web::Data::new(Arc::new(Mutex::new(users))))
+            .app_data(web::Data::new(AuthTOML {
+                basic_auth: true,
+                ..AuthTOML::default()
+            }))
+            .app_data(web::Data::new(segments_config))
+            .app_data(web::Data::new(MetricsTOML::default()))
+            .app_data(web::Data::new(HealthTOML::default()))
+            .app_data(web::Data::new(FfmpegVersion("7.1".to_string())))
+            .route("/metrics", web::get().to(prometheus_metrics))
+            .route("/readyz", web::get().to(readiness))
+            .route("/channels", web::post().to(add_channel))
+            .route("/playlist.m3u", web::get().to(channels_playlist))
+            .service(
+                web::resource("/init/{stream_id}")
+                    .wrap(from_fn(negotiate_errors))
+                    .route(web::get().to(initialize_stream)),
+            )
+            .service(
+                web::resource("/streams/{stream_id}/{file_path:.*}")
+                    .wrap(from_fn(negotiate_errors))
+                    .route(web::get().to(proxy_stream)),
+            )
+    }
+
+    #[test]
+    fn label_values_escape_quotes_backslashes_and_newlines() {
+        assert_eq!(label_value("news"), "news");
+        assert_eq!(label_value("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+
+    #[actix_web::test]
+    async fn metrics_scrape_lists_every_metric_of_the_streams() {
+        let mut manager = test_manager();
+        let stats = Arc::new(StreamStats::default());
+        stats.record_segment_size(true, 100 << 10);
+        manager.stream_stats.insert("news".to_string(), stats);
+        let app = test::init_service(app(shared(manager), SegmentsTOML::default())).await;
+
+        let request = TestRequest::get().uri("/metrics").to_request();
+        let body = test::call_and_read_body(&app, request).await;
+        let body = std::str::from_utf8(&body).unwrap();
+
+        for name in [
+            "dash_to_hls_active_streams",
+            "dash_to_hls_failing_ratio",
+            "dash_to_hls_ffmpeg_info",
+            "dash_to_hls_segments_total",
+            "dash_to_hls_download_failures_total",
+            "dash_to_hls_decrypt_failures_total",
+            "dash_to_hls_skipped_segments_total",
+            "dash_to_hls_pushed_bytes_total",
+            "dash_to_hls_downloaded_bytes_total",
+            "dash_to_hls_ffmpeg_restarts_total",
+            "dash_to_hls_segment_lag",
+            "dash_to_hls_latency_milliseconds",
+            "dash_to_hls_segment_size_bytes",
+        ] {
+            assert!(
+                body.contains(&format!("# TYPE {} ", name)),
+                "{} is missing",
+                name
+            );
+        }
+        assert!(body.contains("dash_to_hls_ffmpeg_info{version=\"7.1\"} 1\n"));
+        assert!(body.contains(
+            "dash_to_hls_segment_size_bytes_bucket{stream=\"news\",track=\"video\",le=\"131072\"} 1\n"
+        ));
+        assert!(
+            body.contains(
+                "dash_to_hls_segment_size_bytes_count{stream=\"news\",track=\"video\"} 1\n"
+            )
+        );
+    }
+
+    #[actix_web::test]
+    async fn readiness_fails_once_most_streams_are_failing() {
+        dash_to_hls::set_fake_ffmpeg();
+        let output_root = tempfile::tempdir().unwrap();
+        let mut manager = test_manager();
+        manager.output_root = output_root.path().to_str().unwrap().to_string();
+        reload_channels(
+            &mut manager,
+            channels(&[
+                ("news", "http://127.0.0.1:1/news.mpd"),
+                ("sports", "http://127.0.0.1:1/sports.mpd"),
+            ]),
+        );
+        for id in ["news", "sports"] {
+            let info = manager.streams[id].clone();
+            manager.start_converter(info, None).unwrap();
+        }
+        let fail = |manager: &Arc<Mutex<StreamManager>>, id: &str| {
+            let stats = Arc::clone(&manager.lock().unwrap().stream_stats[id]);
+            for _ in 0..HealthTOML::default().failing_after {
+                stats.record_failure("HTTP 503");
+            }
+        };
+        let manager = shared(manager);
+        let app = test::init_service(app(Arc::clone(&manager), SegmentsTOML::default())).await;
+        let ready = || TestRequest::get().uri("/readyz").to_request();
+
+        // Half of them failing is still within max_failing_ratio
+        fail(&manager, "news");
+        let resp = test::call_service(&app, ready()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        fail(&manager, "sports");
+        let resp = test::call_service(&app, ready()).await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["failing_streams"], 2);
+        assert_eq!(body["ffmpeg_version"], "7.1");
+
+        let request = TestRequest::get().uri("/metrics").to_request();
+        let body = test::call_and_read_body(&app, request).await;
+        assert!(
+            std::str::from_utf8(&body)
+                .unwrap()
+                .contains("\ndash_to_hls_failing_ratio 1\n")
+        );
+    }
+
+    #[actix_web::test]
+    async fn status_events_follow_a_stream_starting_and_stopping() {
+        dash_to_hls::set_fake_ffmpeg();
+        let output_root = tempfile::tempdir().unwrap();
+        let mut manager = test_manager();
+        manager.output_root = output_root.path().to_str().unwrap().to_string();
+        reload_channels(
+            &mut manager,
+            channels(&[("news", "http://127.0.0.1:1/news.mpd")]),
+        );
+        let mut events = manager.events.subscribe();
+
+        let info = manager.streams["news"].clone();
+        manager.start_converter(info, None).unwrap();
+        let event = serde_json::to_value(events.try_recv().unwrap()).unwrap();
+        assert_eq!(event["stream"], "news");
+        assert_eq!(event["event"], "active");
+        assert!(event["message"].is_null());
+
+        assert!(manager.stop_idle_stream("news").is_some());
+        let event = serde_json::to_value(events.try_recv().unwrap()).unwrap();
+        assert_eq!(event["stream"], "news");
+        assert_eq!(event["event"], "inactive");
+        assert_eq!(event["message"], "idle");
+        assert!(events.try_recv().is_err());
+    }
+
+    #[actix_web::test]
+    async fn channel_playlist_lists_the_channels_the_user_can_access() {
+        let mut manager = test_manager();
+        reload_channels(
+            &mut manager,
+            channels(&[
+                ("cartoons", "http://127.0.0.1:1/cartoons.mpd"),
+                ("news", "http://127.0.0.1:1/news.mpd"),
+            ]),
+        );
+        let app = test::init_service(app(shared(manager), SegmentsTOML::default())).await;
+        let playlist = |credentials: &str| {
+            TestRequest::get()
+                .uri("/playlist.m3u")
+                .insert_header(basic(credentials))
+                .to_request()
+        };
+
+        let body = test::call_and_read_body(&app, playlist("root:toor")).await;
+        let uris: Vec<&str> = std::str::from_utf8(&body)
+            .unwrap()
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .collect();
+        assert_eq!(
+            uris,
+            ["streams/cartoons/master.m3u8", "streams/news/master.m3u8"]
+        );
+
+        let body = test::call_and_read_body(&app, playlist("kid:p")).await;
+        let body = std::str::from_utf8(&body).unwrap();
+        assert_eq!(body.matches("#EXTINF").count(), 1);
+        assert!(body.contains("streams/cartoons/master.m3u8"));
+        assert!(!body.contains("news"));
+    }
+
+    #[test]
+    fn channel_playlist_links_below_the_public_url_with_a_token() {
+        let mut manager = test_manager();
+        let mut config = channels(&[("news", "http://127.0.0.1:1/news.mpd")]);
+        config.channel[0].logo_url = Some("http://127.0.0.1:1/news.png".to_string());
+        reload_channels(&mut manager, config);
+        let mut info = manager.streams["news"].clone();
+        info.id = "news 24".to_string();
+
+        assert_eq!(
+            render_channels_playlist(&[&info], Some("https://tv.example.com/"), Some("t")),
+            "#EXTM3U\n\
+            #EXTINF:-1 tvg-id=\"news 24\" tvg-name=\"news\" \
+            tvg-logo=\"https://tv.example.com/channels/news%2024/logo?token=t\",news\n\
+            https://tv.example.com/streams/news%2024/master.m3u8?token=t\n"
+        );
+    }
+
+    #[actix_web::test]
+    async fn init_refuses_streams_past_max_active_streams() {
+        // Pushers run a stand-in for ffmpeg, nothing is converted
+        dash_to_hls::set_fake_ffmpeg();
+        let output_root = tempfile::tempdir().unwrap();
+        let mut manager = test_manager();
+        manager.output_root = output_root.path().to_str().unwrap().to_string();
+        manager.converter_config.max_active_streams = 1;
+        reload_channels(
+            &mut manager,
+            channels(&[
+                ("news", "http://127.0.0.1:1/news.mpd"),
+                ("sports", "http://127.0.0.1:1/sports.mpd"),
+            ]),
+        );
+        let app = test::init_service(app(shared(manager), SegmentsTOML::default())).await;
+        let init = |stream_id: &str| {
+            TestRequest::get()
+                .uri(&format!("/init/{}", stream_id))
+                .insert_header(basic("root:toor"))
+                .to_request()
+        };
+
+        let started = test::call_service(&app, init("news")).await;
+        assert_eq!(started.status(), StatusCode::OK);
+        assert_eq!(
+            test::read_body(started).await,
+            "Stream initialization started"
+        );
+        // Streams already running don't take another slot
+        let again = test::call_service(&app, init("news")).await;
+        assert_eq!(test::read_body(again).await, "Stream already active");
+
+        let refused = test::call_service(&app, init("sports")).await;
+        assert_eq!(refused.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(refused.headers().get("Retry-After").unwrap(), "1");
+        let body = test::read_body(refused).await;
+        assert!(String::from_utf8_lossy(&body).contains("Maximum of 1 active streams"));
+    }
+
+    #[actix_web::test]
+    async fn concurrent_inits_of_a_stream_start_one_converter() {
+        dash_to_hls::set_fake_ffmpeg();
+        let output_root = tempfile::tempdir().unwrap();
+        let mut manager = test_manager();
+        manager.output_root = output_root.path().to_str().unwrap().to_string();
+        reload_channels(
+            &mut manager,
+            channels(&[("news", "http://127.0.0.1:1/news.mpd")]),
+        );
+        let manager = shared(manager);
+        let app = test::init_service(app(Arc::clone(&manager), SegmentsTOML::default())).await;
+        let init = || {
+            TestRequest::get()
+                .uri("/init/news")
+                .insert_header(basic("root:toor"))
+                .to_request()
+        };
+
+        let (first, second) = futures_util::future::join(
+            test::call_service(&app, init()),
+            test::call_service(&app, init()),
+        )
+        .await;
+        assert_eq!(first.status(), StatusCode::OK);
+        assert_eq!(second.status(), StatusCode::OK);
+        let mut bodies = [test::read_body(first).await, test::read_body(second).await];
+        bodies.sort();
+        assert_eq!(
+            bodies,
+            ["Stream already active", "Stream initialization started"]
+        );
+        assert_eq!(manager.lock().unwrap().active_streams.len(), 1);
+    }
+
+    #[actix_web::test]
+    async fn post_channels_rejects_ids_that_are_paths() {
+        let app = test::init_service(app(shared(test_manager()), SegmentsTOML::default())).await;
+        let post = |id: &str| {
+            TestRequest::post()
+                .uri("/channels")
+                .insert_header(basic("root:toor"))
+                .set_json(serde_json::json!({
+                    "id": id,
+                    "name": "News",
+                    "url": "http://o/news.mpd",
+                }))
+                .to_request()
+        };
+
+        for id in ["..", "a/../../x", "/tmp"] {
+            let resp = test::call_service(&app, post(id)).await;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{}", id);
+        }
+        let resp = test::call_service(&app, post("news")).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+    }
+
+    // A kept output of "news" in a temporary output root, with the given files
+    fn kept_stream(files: &[(&str, &str)]) -> (tempfile::TempDir, StreamManager) {
+        let output_root = tempfile::tempdir().unwrap();
+        let mut manager = test_manager();
+        manager.output_root = output_root.path().to_str().unwrap().to_string();
+        reload_channels(&mut manager, channels(&[("news", "http://o/news.mpd")]));
+        manager.kept_outputs.insert("news".to_string());
+
+        let dir = output_root.path().join("news");
+        fs::create_dir_all(&dir).unwrap();
+        for (name, content) in files {
+            fs::write(dir.join(name), content).unwrap();
+        }
+        (output_root, manager)
+    }
+
+    fn get(file_path: &str) -> TestRequest {
+        TestRequest::get()
+            .uri(&format!("/streams/news/{}", file_path))
+            .insert_header(basic("root:toor"))
+    }
+
+    const PLAYLIST: &str = "#EXTM3U\n\
+        #EXT-X-TARGETDURATION:4\n\
+        #EXT-X-KEY:METHOD=AES-128,URI=\"enc.key\"\n\
+        #EXT-X-MAP:URI=\"init.mp4\"\n\
+        #EXTINF:4.0,\n\
+        segment_0.ts\n";
+
+    #[actix_web::test]
+    async fn empty_or_missing_playlists_ask_to_retry() {
+        let segments_config = SegmentsTOML {
+            retry_after_secs: 7,
+            ..SegmentsTOML::default()
+        };
+        let (_output_root, manager) = kept_stream(&[("master.m3u8", "")]);
+        let app = test::init_service(app(shared(manager), segments_config)).await;
+
+        for file_path in ["master.m3u8", "variant_1.m3u8"] {
+            let resp = test::call_service(&app, get(file_path).to_request()).await;
+            assert_eq!(
+                resp.status(),
+                StatusCode::SERVICE_UNAVAILABLE,
+                "{}",
+                file_path
+            );
+            assert_eq!(resp.headers().get("Retry-After").unwrap(), "7");
+        }
+    }
+
+    #[actix_web::test]
+    async fn low_latency_streams_serve_segments_made_of_parts() {
+        let mut playlist =
+            "#EXTM3U\n#EXT-X-TARGETDURATION:1\n#EXT-X-MEDIA-SEQUENCE:0\n".to_string();
+        let mut files = Vec::new();
+        for (index, content) in ["a", "b", "c", "d", "e"].into_iter().enumerate() {
+            playlist.push_str(&format!("#EXTINF:1.000000,\nseg_{:03}.ts\n", index));
+            files.push((format!("seg_{:03}.ts", index), content));
+        }
+        let mut files: Vec<(&str, &str)> = files
+            .iter()
+            .map(|(name, content)| (name.as_str(), *content))
+            .collect();
+        files.push(("index.m3u8", &playlist));
+        let (_output_root, mut manager) = kept_stream(&files);
+        let news = manager.streams.get_mut("news").unwrap();
+        news.low_latency = true;
+        news.hls_segment_time = 2;
+        news.part_duration_ms = 1000;
+        let app = test::init_service(app(shared(manager), SegmentsTOML::default())).await;
+
+        let resp = test::call_service(&app, get("index.m3u8").to_request()).await;
+        let body = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+        assert!(
+            body.contains("#EXT-X-PART-INF:PART-TARGET=1.000\n"),
+            "{}",
+            body
+        );
+        assert!(
+            body.contains("#EXTINF:2.000000,\nseg_002_full.ts\n"),
+            "{}",
+            body
+        );
+        assert!(body.contains("#EXT-X-PART:DURATION=1.00000,URI=\"seg_004.ts\"\n"));
+        assert!(body.ends_with("#EXT-X-PRELOAD-HINT:TYPE=PART,URI=\"seg_005.ts\"\n"));
+
+        let segment = test::call_service(&app, get("seg_002_full.ts").to_request()).await;
+        assert_eq!(segment.status(), StatusCode::OK);
+        assert_eq!(segment.headers().get("Content-Type").unwrap(), "video/mp2t");
+        assert_eq!(test::read_body(segment).await, "cd");
+
+        // Reloads of what's listed are answered right away, too far ahead refused
+        for (query, status) in [
+            ("_HLS_msn=1", StatusCode::OK),
+            ("_HLS_msn=2&_HLS_part=0", StatusCode::OK),
+            ("_HLS_msn=4", StatusCode::BAD_REQUEST),
+        ] {
+            let req = get(&format!("index.m3u8?{}", query)).to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), status, "{}", query);
+        }
+    }
+
+    #[actix_web::test]
+    async fn dash_output_is_served_with_dash_content_types() {
+        let (_output_root, mut manager) = kept_stream(&[
+            ("manifest.mpd", "<MPD/>"),
+            ("segment_init_0.mp4", "init"),
+            ("segment_0_00001.m4s", "media"),
+        ]);
+        manager.streams.get_mut("news").unwrap().output = OutputFormat::Dash;
+        let app = test::init_service(app(shared(manager), SegmentsTOML::default())).await;
+
+        for (file, content_type, body) in [
+            ("manifest.mpd", "application/dash+xml", "<MPD/>"),
+            ("segment_init_0.mp4", "video/mp4", "init"),
+            ("segment_0_00001.m4s", "video/iso.segment", "media"),
+        ] {
+            let resp = test::call_service(&app, get(file).to_request()).await;
+            assert_eq!(resp.status(), StatusCode::OK, "{}", file);
+            assert_eq!(resp.headers().get("Content-Type").unwrap(), content_type);
+            assert_eq!(test::read_body(resp).await, body);
+        }
+    }
+
+    #[actix_web::test]
+    async fn segments_answer_range_requests() {
+        let (_output_root, manager) =
+            kept_stream(&[("master.m3u8", PLAYLIST), ("segment_0.ts", "0123456789")]);
+        let app = test::init_service(app(shared(manager), SegmentsTOML::default())).await;
+
+        let whole = test::call_service(&app, get("segment_0.ts").to_request()).await;
+        assert_eq!(whole.status(), StatusCode::OK);
+        assert_eq!(whole.headers().get("Accept-Ranges").unwrap(), "bytes");
+
+        let req = get("segment_0.ts").insert_header(("Range", "bytes=2-5"));
+        let partial = test::call_service(&app, req.to_request()).await;
+        assert_eq!(partial.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(
+            partial.headers().get("Content-Range").unwrap(),
+            "bytes 2-5/10"
+        );
+        assert_eq!(test::read_body(partial).await, "2345");
+
+        let req = get("segment_0.ts").insert_header(("Range", "bytes=20-30"));
+        let unsatisfiable = test::call_service(&app, req.to_request()).await;
+        assert_eq!(unsatisfiable.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+    }
+
+    #[actix_web::test]
+    async fn stream_files_get_their_content_type_and_caching() {
+        let (_output_root, manager) = kept_stream(&[
+            ("master.m3u8", PLAYLIST),
+            ("segment_0.ts", "ts"),
+            ("init.mp4", "init"),
+            ("enc.key", "key"),
+            ("segment_9.ts", "unlisted"),
+        ]);
+        let app = test::init_service(app(shared(manager), SegmentsTOML::default())).await;
+
+        for (file_path, content_type, cache_control) in [
+            ("master.m3u8", "application/vnd.apple.mpegurl", "no-cache"),
+            ("segment_0.ts", "video/mp2t", "public, max-age=600"),
+            ("init.mp4", "video/mp4", "no-cache"),
+            ("enc.key", "application/octet-stream", "no-cache"),
+        ] {
+            let resp = test::call_service(&app, get(file_path).to_request()).await;
+            assert_eq!(resp.status(), StatusCode::OK, "{}", file_path);
+            assert_eq!(
+                resp.headers().get("Content-Type").unwrap(),
+                content_type,
+                "{}",
+                file_path
+            );
+            assert_eq!(
+                resp.headers().get("Cache-Control").unwrap(),
+                cache_control,
+                "{}",
+                file_path
+            );
+        }
+
+        let unlisted = test::call_service(&app, get("segment_9.ts").to_request()).await;
+        assert_eq!(unlisted.status(), StatusCode::NOT_FOUND);
+        let unknown = test::call_service(&app, get("notes.txt").to_request()).await;
+        assert_eq!(unknown.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn output_keys_are_only_served_to_logged_in_users() {
+        let playlist = "#EXTM3U\n\
+            #EXT-X-KEY:METHOD=AES-128,URI=\"stream.key\"\n\
+            #EXTINF:4.0,\n\
+            segment_0.ts\n";
+        let (_output_root, manager) =
+            kept_stream(&[("index.m3u8", playlist), ("stream.key", "0123456789abcdef")]);
+        let app = test::init_service(app(shared(manager), SegmentsTOML::default())).await;
+
+        let anonymous = TestRequest::get()
+            .uri("/streams/news/stream.key")
+            .to_request();
+        let resp = test::call_service(&app, anonymous).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let resp = test::call_service(&app, get("stream.key").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(test::read_body(resp).await, "0123456789abcdef");
+    }
+
+    async fn cors_headers(allowed_origins: &[&str], origin: &str) -> (Option<String>, bool) {
+        let cors_config = CorsTOML {
+            allowed_origins: allowed_origins.iter().map(|o| o.to_string()).collect(),
+        };
+        let app = test::init_service(
+            App::new()
+                .wrap(cors(&cors_config))
+                .route("/healthz", web::get().to(liveness)),
+        )
+        .await;
+
+        let req = TestRequest::get()
+            .uri("/healthz")
+            .insert_header(("Origin", origin))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let header = |name: &str| {
+            resp.headers()
+                .get(name)
+                .map(|value| value.to_str().unwrap().to_string())
+        };
+        (
+            header("Access-Control-Allow-Origin"),
+            header("Access-Control-Allow-Credentials").as_deref() == Some("true"),
+        )
+    }
+
+    #[actix_web::test]
+    async fn cors_allows_listed_origins_with_credentials() {
+        let listed = ["https://player.example"];
+        assert_eq!(
+            cors_headers(&listed, "https://player.example").await,
+            (Some("https://player.example".to_string()), true)
+        );
+        // Other origins aren't allowed, which browsers then refuse to read
+        assert_eq!(cors_headers(&listed, "https://other.example").await.0, None);
+        assert_eq!(cors_headers(&[], "https://player.example").await.0, None);
+    }
+
+    #[actix_web::test]
+    async fn cors_wildcard_allows_any_origin_without_credentials() {
+        assert_eq!(
+            cors_headers(&["*"], "https://anyone.example").await,
+            (Some("https://anyone.example".to_string()), false)
+        );
+    }
+}
@@ -0,0 +1,54 @@
+use actix_web::HttpMessage;
+use actix_web::dev::ServiceRequest;
+use actix_web::http::header::HeaderName;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+// Identifier of the request being handled, available to handlers via `web::ReqData`
+#[derive(Clone)]
+pub struct RequestId(pub String);
+
+tokio::task_local! {
+    // Id of the request being handled, for the error bodies which can't see the request
+    static CURRENT: String;
+}
+
+// Runs the handling of a request with its id available to `current`
+pub fn scope<F: Future>(
+    request_id: &RequestId,
+    future: F,
+) -> impl Future<Output = F::Output> + use<F> {
+    CURRENT.scope(request_id.0.clone(), future)
+}
+
+pub fn current() -> Option<String> {
+    CURRENT.try_with(Clone::clone).ok()
+}
+
+// Reuses the id sent by the client when present, otherwise generates a new one, and stores it
+// in the request extensions for the handlers
+pub fn assign(req: &ServiceRequest, header: &HeaderName) -> RequestId {
+    let id = req
+        .headers()
+        .get(header)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| !value.is_empty())
+        .map(str::to_string)
+        .unwrap_or_else(generate);
+
+    let request_id = RequestId(id);
+    req.extensions_mut().insert(request_id.clone());
+    request_id
+}
+
+fn generate() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default();
+    let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+
+    format!("{:x}-{:x}", nanos, count)
+}